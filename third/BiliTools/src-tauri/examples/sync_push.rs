@@ -0,0 +1,25 @@
+//! 通过同步服务创建房间并推送一个 B 站视频。
+//!
+//! 先启动应用（同步服务默认监听 127.0.0.1:18080），再运行：
+//! `cargo run --example sync_push -- <房间名> <密码> <BV号>`
+
+use bilitools_lib::services::sync::client::SyncClient;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(room), Some(password), Some(bvid)) = (args.next(), args.next(), args.next()) else {
+        anyhow::bail!("usage: sync_push <room> <password> <bvid>");
+    };
+    let base =
+        std::env::var("VO_SYNC_URL").unwrap_or_else(|_| "http://127.0.0.1:18080".to_string());
+
+    let mut client = SyncClient::connect(&base).await?;
+    let joined = client.join_room(&room, &password).await?;
+    if joined.role != "host" {
+        anyhow::bail!("room {room} already has a host, cannot publish");
+    }
+    let media = client.resolve_media(&bvid).await?;
+    println!("room {room} is now playing {} ({})", bvid, media.url);
+    Ok(())
+}
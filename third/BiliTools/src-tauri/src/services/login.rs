@@ -13,6 +13,7 @@ use tauri::http::{header, StatusCode};
 use tokio::time::{sleep, Duration};
 
 use crate::{
+    services::sync,
     shared::{get_millis, get_sec, init_client, HEADERS},
     storage::cookies,
     TauriError, TauriResult,
@@ -312,6 +313,7 @@ pub async fn exit() -> TauriResult<isize> {
         .await?;
     }
     HEADERS.refresh().await?;
+    sync::refresh_bili_session().await;
     Ok(body.code)
 }
 
@@ -359,6 +361,7 @@ pub async fn sms_login(
         }
         cookies::insert(format!("refresh_token={}", data.refresh_token)).await?;
         HEADERS.refresh().await?;
+        sync::refresh_bili_session().await;
         Ok(body.code)
     } else {
         let message = match body.code {
@@ -422,6 +425,7 @@ pub async fn pwd_login(
         }
         cookies::insert(format!("refresh_token={}", data.refresh_token)).await?;
         HEADERS.refresh().await?;
+        sync::refresh_bili_session().await;
         Ok(data.status)
     } else {
         Err(TauriError::new(body.message, Some(body.code)))
@@ -460,6 +464,7 @@ pub async fn switch_cookie(switch_code: String) -> TauriResult<isize> {
         }
         cookies::insert(format!("refresh_token={}", data.refresh_token)).await?;
         HEADERS.refresh().await?;
+        sync::refresh_bili_session().await;
         Ok(body.code)
     } else {
         Err(TauriError::new(body.message, Some(body.code)))
@@ -510,6 +515,7 @@ pub async fn scan_login(
                     cookies::insert(format!("refresh_token={}", data.refresh_token)).await?;
                     log::info!("{}: {}", masked_key, "扫码登录成功");
                     HEADERS.refresh().await?;
+                    sync::refresh_bili_session().await;
                     return Ok(data.code);
                 }
                 86101 | 86090 => log::info!("{masked_key}: {}", data.message),
@@ -570,6 +576,7 @@ pub async fn refresh_cookie(refresh_csrf: String) -> TauriResult<isize> {
         }
         cookies::insert(format!("refresh_token={}", data.refresh_token)).await?;
         HEADERS.refresh().await?;
+        sync::refresh_bili_session().await;
     } else {
         return Err(TauriError::new(
             refresh_token_body.message,
@@ -0,0 +1,163 @@
+//! B 站解析链路的熔断器：窗口内连续失败达到阈值后打开，冷却期内直接拒绝；
+//! 冷却结束后进入半开状态，只放行一个探测请求，成功则恢复，失败则重新打开。
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    /// metrics 中的取值：0 关闭、1 打开、2 半开。
+    pub(super) fn gauge(self) -> u8 {
+        match self {
+            Self::Closed => 0,
+            Self::Open => 1,
+            Self::HalfOpen => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Phase {
+    /// `since` 是本轮连续失败中第一次失败的时刻。
+    Closed {
+        failures: u32,
+        since: Option<Instant>,
+    },
+    Open {
+        until: Instant,
+    },
+    /// 探测请求的发出时刻；探测被取消时，超过冷却期后允许再发一个。
+    HalfOpen {
+        probe_at: Instant,
+    },
+}
+
+#[derive(Debug)]
+struct Inner {
+    phase: Phase,
+    trips: u64,
+}
+
+#[derive(Debug)]
+pub(super) struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub(super) fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window,
+            cooldown,
+            inner: Mutex::new(Inner {
+                phase: Phase::Closed {
+                    failures: 0,
+                    since: None,
+                },
+                trips: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 允许发起请求时返回 Ok，否则返回建议的等待时间。
+    pub(super) fn acquire(&self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut inner = self.lock();
+        match inner.phase {
+            Phase::Closed { .. } => Ok(()),
+            Phase::Open { until } if now < until => Err(until - now),
+            Phase::HalfOpen { probe_at } if now.duration_since(probe_at) < self.cooldown => {
+                Err(self.cooldown - now.duration_since(probe_at))
+            }
+            Phase::Open { .. } | Phase::HalfOpen { .. } => {
+                inner.phase = Phase::HalfOpen { probe_at: now };
+                Ok(())
+            }
+        }
+    }
+
+    /// 记录一次请求结果。打开期间完成的旧请求不改变状态。
+    pub(super) fn record(&self, ok: bool) {
+        let now = Instant::now();
+        let mut inner = self.lock();
+        let trip = match &mut inner.phase {
+            Phase::Open { .. } => false,
+            phase if ok => {
+                *phase = Phase::Closed {
+                    failures: 0,
+                    since: None,
+                };
+                false
+            }
+            Phase::HalfOpen { .. } => true,
+            Phase::Closed { failures, since } => {
+                if since.map_or(true, |at| now.duration_since(at) > self.window) {
+                    *failures = 0;
+                    *since = Some(now);
+                }
+                *failures += 1;
+                *failures >= self.threshold
+            }
+        };
+        if trip {
+            inner.phase = Phase::Open {
+                until: now + self.cooldown,
+            };
+            inner.trips += 1;
+        }
+    }
+
+    pub(super) fn state(&self) -> BreakerState {
+        match self.lock().phase {
+            Phase::Closed { .. } => BreakerState::Closed,
+            Phase::Open { until } if Instant::now() < until => BreakerState::Open,
+            Phase::Open { .. } | Phase::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    /// 累计打开次数。
+    pub(super) fn trips(&self) -> u64 {
+        self.lock().trips
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_open_allows_single_probe() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::ZERO);
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record(false);
+        assert_eq!(breaker.trips(), 1);
+
+        // 冷却为零，立即半开并放行一个探测。
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.acquire().is_ok());
+        breaker.record(false);
+        assert_eq!(breaker.trips(), 2);
+
+        assert!(breaker.acquire().is_ok());
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
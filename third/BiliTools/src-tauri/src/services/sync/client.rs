@@ -0,0 +1,260 @@
+//! 同步服务的 Rust 客户端：封装 HTTP 接口和 WebSocket 协议，
+//! 供脚本或命令行工具创建房间、推送视频、订阅房间状态。
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::{stream::SplitSink, SinkExt, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tauri_plugin_http::reqwest;
+use tokio::{net::TcpStream, sync::Mutex};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use super::{
+    JoinMode, JoinRequest, JoinResponse, MediaResolveRequest, MediaResolveResponse, ResolveOptions,
+    RoomState,
+};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, Clone)]
+struct Session {
+    room: String,
+    password: String,
+    temp_user: String,
+}
+
+/// 服务端推送的消息，这里只关心带状态的几类。
+#[derive(Debug, Deserialize)]
+struct ServerMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    state: Option<RoomState>,
+}
+
+pub struct SyncClient {
+    base: String,
+    http: reqwest::Client,
+    session: Option<Session>,
+    /// `set_state` 复用的长连接；每次推送都重连会让房主反复“断线”。
+    writer: Mutex<Option<SplitSink<WsStream, Message>>>,
+}
+
+impl SyncClient {
+    /// `base` 形如 `http://127.0.0.1:18080`，连接前先探测 `/healthz`。
+    pub async fn connect(base: impl Into<String>) -> Result<Self> {
+        let base = base.into().trim_end_matches('/').to_string();
+        let http = reqwest::Client::new();
+        http.get(format!("{base}/healthz"))
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| format!("sync service unreachable at {base}"))?;
+        Ok(Self {
+            base,
+            http,
+            session: None,
+            writer: Mutex::new(None),
+        })
+    }
+
+    /// 加入（或创建）房间，之后的调用都以该身份进行。
+    pub async fn join_room(&mut self, room: &str, password: &str) -> Result<JoinResponse> {
+        self.join(room, password, None, None).await
+    }
+
+    /// 只创建新房间，同名房间已存在时失败。
+    pub async fn create_room(&mut self, room: &str, password: &str) -> Result<JoinResponse> {
+        self.join(room, password, None, Some(JoinMode::Create))
+            .await
+    }
+
+    /// 以指定的 temp_user 加入，便于脚本和测试得到可复现的身份。
+    pub async fn join_room_as(
+        &mut self,
+        room: &str,
+        password: &str,
+        temp_user: &str,
+    ) -> Result<JoinResponse> {
+        self.join(room, password, Some(temp_user), None).await
+    }
+
+    async fn join(
+        &mut self,
+        room: &str,
+        password: &str,
+        temp_user: Option<&str>,
+        mode: Option<JoinMode>,
+    ) -> Result<JoinResponse> {
+        let req = JoinRequest {
+            room: room.to_string(),
+            password: password.to_string(),
+            resume_token: None,
+            temp_user: temp_user.map(str::to_string),
+            mode,
+        };
+        let res: JoinResponse = self.post("/api/room/join", &req).await?;
+        self.session = Some(Session {
+            room: room.trim().to_string(),
+            password: password.trim().to_string(),
+            temp_user: res.temp_user.clone(),
+        });
+        *self.writer.lock().await = None;
+        Ok(res)
+    }
+
+    /// 解析本地路径、BV 号或链接；房主调用时服务端会直接发布到房间。
+    pub async fn resolve_media(&self, path: &str) -> Result<MediaResolveResponse> {
+        let session = self.session()?;
+        let req = MediaResolveRequest {
+            room: session.room.clone(),
+            password: session.password.clone(),
+            temp_user: session.temp_user.clone(),
+            path: path.to_string(),
+            auto_publish: true,
+            options: ResolveOptions::default(),
+        };
+        self.post("/api/media/resolve", &req).await
+    }
+
+    /// 以 `host_update` 推送播放状态，需要房主权限。
+    pub async fn set_state(&self, state: RoomState) -> Result<()> {
+        let payload = json!({ "type": "host_update", "state": state }).to_string();
+        let mut writer = self.writer.lock().await;
+        if writer.is_none() {
+            let (sink, mut stream) = self.open_ws().await?.split();
+            // 服务端的广播和错误提示在此丢弃，避免接收缓冲堆积。
+            tokio::spawn(async move { while stream.next().await.is_some() {} });
+            *writer = Some(sink);
+        }
+        let sink = writer.as_mut().expect("writer initialized above");
+        if let Err(err) = sink.send(Message::Text(payload)).await {
+            *writer = None;
+            return Err(err).context("send host_update failed");
+        }
+        Ok(())
+    }
+
+    /// 单独建立一条连接，依次产出房间的权威状态（含连接时的当前状态）。
+    pub async fn subscribe(&self) -> Result<impl Stream<Item = RoomState>> {
+        let ws = self.open_ws().await?;
+        Ok(ws.filter_map(|msg| async move {
+            let Ok(Message::Text(text)) = msg else {
+                return None;
+            };
+            let msg: ServerMessage = serde_json::from_str(&text).ok()?;
+            matches!(msg.kind.as_str(), "room_state" | "video_ended")
+                .then_some(msg.state)
+                .flatten()
+        }))
+    }
+
+    fn session(&self) -> Result<&Session> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| anyhow!("join_room must be called first"))
+    }
+
+    async fn open_ws(&self) -> Result<WsStream> {
+        let session = self.session()?;
+        let ws_base = if let Some(rest) = self.base.strip_prefix("https://") {
+            format!("wss://{rest}")
+        } else {
+            format!("ws://{}", self.base.trim_start_matches("http://"))
+        };
+        let mut url = reqwest::Url::parse(&format!("{ws_base}/ws"))?;
+        url.query_pairs_mut()
+            .append_pair("room", &session.room)
+            .append_pair("password", &session.password)
+            .append_pair("tempUser", &session.temp_user);
+        let (ws, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .context("websocket connect failed")?;
+        Ok(ws)
+    }
+
+    /// 非 2xx 时把服务端 `{"error": ...}` 中的信息带出来。
+    async fn post<T: DeserializeOwned>(&self, path: &str, body: &impl Serialize) -> Result<T> {
+        let resp = self
+            .http
+            .post(format!("{}{path}", self.base))
+            .json(body)
+            .send()
+            .await
+            .with_context(|| format!("request {path} failed"))?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body: serde_json::Value = resp.json().await.unwrap_or_default();
+            let message = body["error"].as_str().unwrap_or("unknown error");
+            return Err(anyhow!("{path} returned {status}: {message}"));
+        }
+        resp.json()
+            .await
+            .with_context(|| format!("decode {path} response failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::TcpListener;
+
+    use super::super::{build_router, AppState, Hub, Manager, SourceType};
+    use super::*;
+
+    async fn spawn_server(manager: Manager) -> String {
+        let state = AppState {
+            manager: Arc::new(manager),
+            hub: Arc::new(Hub::new()),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, build_router(state)).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn client_publishes_and_subscribes() {
+        let root = std::env::temp_dir().join("vo_sync_client");
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("movie.mp4");
+        std::fs::write(&file, b"\x00\x00\x00\x18ftypisom\x00\x00\x02\x00isommp41").unwrap();
+        let base = spawn_server(Manager::new(Some(root), true)).await;
+
+        let mut host = SyncClient::connect(&base).await.unwrap();
+        let joined = host.join_room("room", "pwd").await.unwrap();
+        assert_eq!(joined.role, "host");
+        let resolved = host.resolve_media(file.to_str().unwrap()).await.unwrap();
+        assert_eq!(resolved.source_type, SourceType::File);
+
+        let mut viewer = SyncClient::connect(&base).await.unwrap();
+        assert_eq!(
+            viewer.join_room("room", "pwd").await.unwrap().role,
+            "member"
+        );
+        let states = viewer.subscribe().await.unwrap();
+        futures_util::pin_mut!(states);
+        let current = states.next().await.unwrap();
+        assert_eq!(current.url, resolved.url);
+
+        host.set_state(RoomState {
+            current_time: 42.0,
+            paused: false,
+            ..current
+        })
+        .await
+        .unwrap();
+        let pushed = states.next().await.unwrap();
+        assert_eq!(pushed.current_time, 42.0);
+        assert!(!pushed.paused);
+    }
+
+    #[tokio::test]
+    async fn calls_before_join_fail_cleanly() {
+        let base = spawn_server(Manager::new(None, true)).await;
+        let client = SyncClient::connect(&base).await.unwrap();
+        let err = client.resolve_media("BV1xx411c7mD").await.unwrap_err();
+        assert!(err.to_string().contains("join_room"));
+        assert!(SyncClient::connect("http://127.0.0.1:1").await.is_err());
+    }
+}
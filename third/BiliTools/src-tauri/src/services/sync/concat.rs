@@ -0,0 +1,177 @@
+//! 分卷本地文件（`movie.part1.mp4`、`movie.part2.mp4` …）拼成一个虚拟文件播放：
+//! 总长度为各分卷之和，Range 请求的字节区间按分卷边界拆开后依次读取。
+
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use axum::{
+    body::Body,
+    http::{header, StatusCode},
+    response::Response,
+};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use regex::Regex;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use super::ApiError;
+
+/// 最多拼接的分卷数。
+const MAX_PARTS: u32 = 64;
+static PART_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)^(.+)\.part(\d+)\.([^.]+)$").unwrap());
+
+/// `path` 形如 `<名称>.part<N>.<扩展名>` 时，返回同目录下从 part1 起连续编号的全部分卷；
+/// 只有一卷或缺少 part1 时返回 None，按普通文件处理。
+pub(super) fn sibling_parts(path: &Path) -> Option<Vec<PathBuf>> {
+    let name = path.file_name()?.to_str()?;
+    let caps = PART_RE.captures(name)?;
+    let (stem, ext) = (&caps[1], &caps[3]);
+    let dir = path.parent()?;
+    let mut parts = Vec::new();
+    for n in 1..=MAX_PARTS {
+        let part = dir.join(format!("{stem}.part{n}.{ext}"));
+        if !part.is_file() {
+            break;
+        }
+        parts.push(part);
+    }
+    (parts.len() > 1 && parts.contains(&path.to_path_buf())).then_some(parts)
+}
+
+/// 解析单段 `Range`：`bytes=a-b`、`bytes=a-` 或后缀形式 `bytes=-n`，返回闭区间。
+/// 格式不符时返回 `Ok(None)` 按整个文件响应；起点越界时返回 `Err(())`（416）。
+fn parse_range(value: &str, total: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some((start, end)) = value
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        let Ok(suffix) = end.parse::<u64>() else {
+            return Ok(None);
+        };
+        if suffix == 0 || total == 0 {
+            return Err(());
+        }
+        (total.saturating_sub(suffix), total - 1)
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return Ok(None);
+        };
+        let end = match end {
+            "" => total.saturating_sub(1),
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => end.min(total.saturating_sub(1)),
+                _ => return Ok(None),
+            },
+        };
+        if start >= total {
+            return Err(());
+        }
+        (start, end)
+    };
+    Ok(Some(range))
+}
+
+/// 把虚拟文件中的闭区间 `[start, end]` 映射为 (分卷序号, 卷内偏移, 长度) 列表。
+fn locate(sizes: &[u64], start: u64, end: u64) -> Vec<(usize, u64, u64)> {
+    let mut pieces = Vec::new();
+    let mut base = 0;
+    for (index, &size) in sizes.iter().enumerate() {
+        let (lo, hi) = (base, base + size);
+        base = hi;
+        if size == 0 || hi <= start {
+            continue;
+        }
+        if lo > end {
+            break;
+        }
+        let from = start.max(lo);
+        let to = (end + 1).min(hi);
+        pieces.push((index, from - lo, to - from));
+    }
+    pieces
+}
+
+/// 按需打开分卷，只在读到该分卷时才占用文件句柄。
+pub(super) async fn serve(parts: &[PathBuf], range: Option<&str>) -> Result<Response, ApiError> {
+    let mut sizes = Vec::with_capacity(parts.len());
+    for part in parts {
+        let meta = tokio::fs::metadata(part)
+            .await
+            .map_err(|_| ApiError::not_found("media not found"))?;
+        sizes.push(meta.len());
+    }
+    let total: u64 = sizes.iter().sum();
+    let range = match range.map(|value| parse_range(value, total)) {
+        Some(Err(())) => {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{total}"))
+                .body(Body::empty())
+                .unwrap());
+        }
+        Some(Ok(range)) => range,
+        None => None,
+    };
+    let (start, end) = range.unwrap_or((0, total.saturating_sub(1)));
+    let pieces: Vec<(PathBuf, u64, u64)> = if total == 0 {
+        Vec::new()
+    } else {
+        locate(&sizes, start, end)
+            .into_iter()
+            .map(|(index, offset, len)| (parts[index].clone(), offset, len))
+            .collect()
+    };
+    let length: u64 = pieces.iter().map(|(_, _, len)| len).sum();
+    let body = stream::iter(pieces)
+        .then(|(path, offset, len)| async move {
+            let mut file = File::open(&path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            Ok::<_, std::io::Error>(ReaderStream::new(file.take(len)))
+        })
+        .try_flatten();
+    let mut builder = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_LENGTH, length);
+    builder = match range {
+        Some((start, end)) => builder.status(StatusCode::PARTIAL_CONTENT).header(
+            header::CONTENT_RANGE,
+            format!("bytes {start}-{end}/{total}"),
+        ),
+        None => builder.status(StatusCode::OK),
+    };
+    Ok(builder.body(Body::from_stream(body)).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_map_across_part_boundaries() {
+        let sizes = [4, 0, 6, 5];
+        assert_eq!(locate(&sizes, 0, 14), vec![(0, 0, 4), (2, 0, 6), (3, 0, 5)]);
+        assert_eq!(locate(&sizes, 3, 4), vec![(0, 3, 1), (2, 0, 1)]);
+        assert_eq!(locate(&sizes, 10, 12), vec![(3, 0, 3)]);
+        assert_eq!(locate(&sizes, 9, 9), vec![(2, 5, 1)]);
+
+        assert_eq!(parse_range("bytes=3-4", 15), Ok(Some((3, 4))));
+        assert_eq!(parse_range("bytes=10-", 15), Ok(Some((10, 14))));
+        assert_eq!(parse_range("bytes=-5", 15), Ok(Some((10, 14))));
+        assert_eq!(parse_range("bytes=12-99", 15), Ok(Some((12, 14))));
+        assert_eq!(parse_range("bytes=15-", 15), Err(()));
+        assert_eq!(parse_range("bytes=0-1,4-5", 15), Ok(None));
+    }
+}
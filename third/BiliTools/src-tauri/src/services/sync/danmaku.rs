@@ -0,0 +1,186 @@
+//! B 站弹幕：解码分段 protobuf 接口（`DmSegMobileReply`，每段 6 分钟），
+//! 转成按出现时间排序的 JSON 轨道，前端按播放进度渲染滚动评论。
+
+use serde::Serialize;
+
+/// 分段接口每段覆盖的秒数。
+const SEGMENT_SECS: u64 = 6 * 60;
+/// 最多拉取的分段数（约 4 小时），超长稿件只取前面的部分。
+const MAX_SEGMENTS: u32 = 40;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(super) struct Danmaku {
+    /// 出现时刻（秒）。
+    pub(super) time: f64,
+    /// 1-3 滚动，4 底部，5 顶部，其余为高级弹幕。
+    pub(super) mode: u32,
+    /// 0xRRGGBB。
+    pub(super) color: u32,
+    pub(super) text: String,
+}
+
+/// 时长为 0（未知）时至少拉一段。
+pub(super) fn segment_count(duration_secs: i64) -> u32 {
+    let count = duration_secs.max(1).unsigned_abs().div_ceil(SEGMENT_SECS);
+    u32::try_from(count).unwrap_or(u32::MAX).min(MAX_SEGMENTS)
+}
+
+/// `DmSegMobileReply` 只有字段 1：重复的 `DanmakuElem`。格式不符时返回 None。
+pub(super) fn parse_segment(data: &[u8]) -> Option<Vec<Danmaku>> {
+    let mut items = Vec::new();
+    for field in Fields::new(data) {
+        if let (1, Value::Bytes(elem)) = field? {
+            items.extend(parse_elem(elem));
+        }
+    }
+    Some(items)
+}
+
+/// `DanmakuElem` 里只取 progress(2, 毫秒)、mode(3)、color(5)、content(7)；
+/// 没有内容的条目丢弃。
+fn parse_elem(data: &[u8]) -> Option<Danmaku> {
+    let (mut progress, mut mode, mut color, mut text) = (0, 1, 0xFF_FF_FF, None);
+    for field in Fields::new(data) {
+        match field? {
+            (2, Value::Varint(v)) => progress = v as i32,
+            (3, Value::Varint(v)) => mode = v as u32,
+            (5, Value::Varint(v)) => color = v as u32,
+            (7, Value::Bytes(bytes)) => text = Some(std::str::from_utf8(bytes).ok()?.to_string()),
+            _ => {}
+        }
+    }
+    Some(Danmaku {
+        time: f64::from(progress.max(0)) / 1000.0,
+        mode,
+        color,
+        text: text.filter(|t| !t.is_empty())?,
+    })
+}
+
+/// 合并各分段并按时间排序，序列化为 JSON 数组。
+pub(super) fn track(mut items: Vec<Danmaku>) -> Vec<u8> {
+    items.sort_by(|a, b| a.time.total_cmp(&b.time));
+    serde_json::to_vec(&items).unwrap_or_default()
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// 定长 32/64 位字段，弹幕里用不到，只需跳过。
+    Fixed,
+}
+
+/// 最小的 protobuf 字段迭代器，遇到非法编码时产出一次 None 后结束。
+struct Fields<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, at: 0 }
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.at)?;
+            self.at += 1;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn skip(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.at..self.at.checked_add(len)?)?;
+        self.at += len;
+        Some(bytes)
+    }
+
+    fn field(&mut self) -> Option<(u64, Value<'a>)> {
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => Value::Varint(self.varint()?),
+            1 => self.skip(8).map(|_| Value::Fixed)?,
+            2 => {
+                let len = usize::try_from(self.varint()?).ok()?;
+                Value::Bytes(self.skip(len)?)
+            }
+            5 => self.skip(4).map(|_| Value::Fixed)?,
+            _ => return None,
+        };
+        Some((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Option<(u64, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.at >= self.data.len() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_none() {
+            self.at = self.data.len();
+        }
+        Some(field)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    /// 按 `DmSegMobileReply` 编码 (毫秒, 模式, 颜色, 内容)。
+    pub(in super::super) fn encode(items: &[(u64, u64, u64, &str)]) -> Vec<u8> {
+        let mut reply = Vec::new();
+        for (progress, mode, color, text) in items {
+            let mut elem = Vec::new();
+            for (field, value) in [(1, 42), (2, *progress), (3, *mode), (5, *color)] {
+                varint(field << 3, &mut elem);
+                varint(value, &mut elem);
+            }
+            varint(7 << 3 | 2, &mut elem);
+            varint(text.len() as u64, &mut elem);
+            elem.extend_from_slice(text.as_bytes());
+            // 定长字段应被跳过。
+            varint(15 << 3 | 5, &mut elem);
+            elem.extend_from_slice(&[0; 4]);
+            varint(1 << 3 | 2, &mut reply);
+            varint(elem.len() as u64, &mut reply);
+            reply.extend_from_slice(&elem);
+        }
+        reply
+    }
+
+    #[test]
+    fn decodes_segment_elements() {
+        let data = encode(&[(61_500, 1, 0xFF0000, "前方高能"), (2_000, 5, 0xFFFFFF, "")]);
+        let items = parse_segment(&data).unwrap();
+        assert_eq!(
+            items,
+            vec![Danmaku {
+                time: 61.5,
+                mode: 1,
+                color: 0xFF0000,
+                text: "前方高能".into(),
+            }]
+        );
+        assert!(parse_segment(&data[..data.len() - 2]).is_none());
+        assert_eq!(segment_count(0), 1);
+        assert_eq!(segment_count(361), 2);
+        assert_eq!(segment_count(i64::MAX), MAX_SEGMENTS);
+    }
+}
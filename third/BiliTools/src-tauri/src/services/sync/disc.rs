@@ -0,0 +1,219 @@
+//! 光盘目录结构识别：DVD 的 `VIDEO_TS` 与蓝光的 `BDMV` 把正片切成多个分段文件，
+//! 这里找出正片并按播放顺序列出分段，房间可以把它们当作一个有序列表依次播放。
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// `dir` 本身是 `VIDEO_TS`/`BDMV`，或直接包含其中之一时，返回正片分段（按播放顺序）。
+pub(super) fn main_feature(dir: &Path) -> Option<Vec<PathBuf>> {
+    let disc = disc_dir(dir)?;
+    let name = disc.file_name()?.to_str()?;
+    let segments = if name.eq_ignore_ascii_case("VIDEO_TS") {
+        dvd_feature(&disc)
+    } else {
+        bluray_feature(&disc)
+    };
+    (!segments.is_empty()).then_some(segments)
+}
+
+fn disc_dir(dir: &Path) -> Option<PathBuf> {
+    let is_disc = |path: &Path| {
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.eq_ignore_ascii_case("VIDEO_TS") || n.eq_ignore_ascii_case("BDMV"))
+    };
+    if is_disc(dir) {
+        return Some(dir.to_path_buf());
+    }
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| path.is_dir() && is_disc(path))
+}
+
+/// 同一目录下不区分大小写地查找子项，光盘镜像解出的文件名大小写并不统一。
+fn child(dir: &Path, name: &str) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+        .map(|entry| entry.path())
+}
+
+/// DVD 正片是 `VTS_xx_1.VOB` 起的连续分段，`VTS_xx_0.VOB` 与 `VIDEO_TS.VOB` 是菜单。
+/// 取总字节数最大的标题集作为正片，分段按序号排列。
+fn dvd_feature(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut title_sets: HashMap<u32, Vec<(u32, u64, PathBuf)>> = HashMap::new();
+    for entry in entries.filter_map(Result::ok) {
+        let Some((title, part)) = entry.file_name().to_str().and_then(parse_vob_name) else {
+            continue;
+        };
+        if part == 0 {
+            continue;
+        }
+        let size = entry.metadata().map(|m| m.len()).unwrap_or_default();
+        title_sets
+            .entry(title)
+            .or_default()
+            .push((part, size, entry.path()));
+    }
+    let Some(mut parts) = title_sets
+        .into_values()
+        .max_by_key(|parts| parts.iter().map(|(_, size, _)| size).sum::<u64>())
+    else {
+        return Vec::new();
+    };
+    parts.sort_by_key(|(part, _, _)| *part);
+    parts.into_iter().map(|(_, _, path)| path).collect()
+}
+
+/// `VTS_01_2.VOB` → (1, 2)。
+fn parse_vob_name(name: &str) -> Option<(u32, u32)> {
+    let upper = name.to_ascii_uppercase();
+    let stem = upper.strip_prefix("VTS_")?.strip_suffix(".VOB")?;
+    let (title, part) = stem.split_once('_')?;
+    Some((title.parse().ok()?, part.parse().ok()?))
+}
+
+/// 蓝光正片由 `PLAYLIST/*.mpls` 描述：取总时长最长的播放列表，按其 PlayItem 顺序
+/// 映射到 `STREAM/*.m2ts`。没有可解析的播放列表时退回按文件名排序的全部分段。
+fn bluray_feature(dir: &Path) -> Vec<PathBuf> {
+    let Some(stream) = child(dir, "STREAM") else {
+        return Vec::new();
+    };
+    let longest = child(dir, "PLAYLIST")
+        .and_then(|playlists| std::fs::read_dir(playlists).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| parse_mpls(&std::fs::read(entry.path()).ok()?))
+        .max_by_key(|items| items.iter().map(|item| item.duration_ticks).sum::<u64>());
+    if let Some(items) = longest {
+        let segments: Vec<PathBuf> = items
+            .iter()
+            .filter_map(|item| child(&stream, &format!("{}.m2ts", item.clip)))
+            .collect();
+        if !segments.is_empty() {
+            return segments;
+        }
+    }
+    let Ok(entries) = std::fs::read_dir(&stream) else {
+        return Vec::new();
+    };
+    let mut segments: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("m2ts"))
+        })
+        .collect();
+    segments.sort();
+    segments
+}
+
+#[derive(Debug, PartialEq)]
+struct PlayItem {
+    /// 五位数字的片段名，对应 `STREAM/<clip>.m2ts`。
+    clip: String,
+    /// OUT 与 IN 之差，单位为 45kHz 时钟周期。
+    duration_ticks: u64,
+}
+
+/// 只解析 PlayList 段里的 PlayItem：片段名与 IN/OUT 时间。格式不符时返回 None。
+fn parse_mpls(data: &[u8]) -> Option<Vec<PlayItem>> {
+    let u16_at = |at: usize| Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?));
+    let u32_at = |at: usize| Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    if data.get(..4)? != b"MPLS" {
+        return None;
+    }
+    let playlist = u32_at(8)? as usize;
+    // PlayList：length(4) reserved(2) 条目数(2) SubPath 数(2)，之后是 PlayItem。
+    let count = u16_at(playlist + 6)? as usize;
+    let mut at = playlist + 10;
+    let mut items = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = u16_at(at)? as usize;
+        let item = at + 2;
+        let clip = std::str::from_utf8(data.get(item..item + 5)?).ok()?;
+        // 片段名之后依次是编码标识(4)、标志位(2)、STC id(1)，再是 IN/OUT 时间。
+        let in_time = u32_at(item + 12)?;
+        let out_time = u32_at(item + 16)?;
+        items.push(PlayItem {
+            clip: clip.to_string(),
+            duration_ticks: u64::from(out_time.saturating_sub(in_time)),
+        });
+        at = item + len;
+    }
+    Some(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mpls(items: &[(&str, u32, u32)]) -> Vec<u8> {
+        let mut data = b"MPLS0200".to_vec();
+        data.extend_from_slice(&16u32.to_be_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&[0; 2]);
+        data.extend_from_slice(&(items.len() as u16).to_be_bytes());
+        data.extend_from_slice(&[0; 2]);
+        for (clip, in_time, out_time) in items {
+            let mut item = clip.as_bytes().to_vec();
+            item.extend_from_slice(b"M2TS");
+            item.extend_from_slice(&[0; 3]);
+            item.extend_from_slice(&in_time.to_be_bytes());
+            item.extend_from_slice(&out_time.to_be_bytes());
+            data.extend_from_slice(&(item.len() as u16).to_be_bytes());
+            data.extend_from_slice(&item);
+        }
+        data
+    }
+
+    #[test]
+    fn bluray_follows_longest_playlist_order() {
+        let root = std::env::temp_dir().join("vo_sync_disc_bdmv");
+        let _ = std::fs::remove_dir_all(&root);
+        let bdmv = root.join("BDMV");
+        std::fs::create_dir_all(bdmv.join("STREAM")).unwrap();
+        std::fs::create_dir_all(bdmv.join("PLAYLIST")).unwrap();
+        for clip in ["00001", "00002", "00003", "00010"] {
+            std::fs::write(bdmv.join(format!("STREAM/{clip}.m2ts")), b"x").unwrap();
+        }
+        let minute = 60 * 45_000;
+        std::fs::write(
+            bdmv.join("PLAYLIST/00000.mpls"),
+            mpls(&[("00010", 0, minute)]),
+        )
+        .unwrap();
+        let feature = [("00003", 0, 40 * minute), ("00001", 0, 50 * minute)];
+        std::fs::write(bdmv.join("PLAYLIST/00001.mpls"), mpls(&feature)).unwrap();
+
+        let items = parse_mpls(&mpls(&feature)).unwrap();
+        assert_eq!(items[1].clip, "00001");
+        assert_eq!(items[1].duration_ticks, 50 * minute as u64);
+        let segments = main_feature(&root).unwrap();
+        assert_eq!(
+            segments,
+            vec![
+                bdmv.join("STREAM/00003.m2ts"),
+                bdmv.join("STREAM/00001.m2ts")
+            ]
+        );
+        assert!(parse_mpls(b"not a playlist").is_none());
+    }
+}
@@ -0,0 +1,125 @@
+//! 媒体库索引：把媒体根下的文件映射为根标签与相对路径哈希得到的稳定 id。
+//! 前端用 `lib://<id>` 引用文件，不必发送完整路径；根目录整体搬迁且标签不变时 id 不变，
+//! 不同标签的根下同名文件 id 也不同。
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+pub(super) const LIB_PREFIX: &str = "lib://";
+/// 收录的文件数上限，误把超大目录设为根时不至于长时间遍历。
+const MAX_LIBRARY_FILES: usize = 50_000;
+/// id 取 `标签\0相对路径` SHA-256 的前 16 个十六进制字符。
+const ID_LEN: usize = 16;
+
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct LibraryEntry {
+    pub(super) id: String,
+    /// 所属媒体根的标签。
+    pub(super) root: String,
+    /// 相对媒体根的路径，统一用 `/` 分隔。
+    pub(super) path: String,
+}
+
+#[derive(Debug)]
+pub(super) struct LibraryIndex {
+    root: PathBuf,
+    label: String,
+    /// id 到相对路径。
+    files: HashMap<String, String>,
+}
+
+impl LibraryIndex {
+    /// 遍历 `root`，只收录 `accept` 通过的普通文件；不跟随符号链接。
+    pub(super) fn build(root: &Path, label: &str, accept: impl Fn(&Path) -> bool) -> Self {
+        let files = walkdir::WalkDir::new(root)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file() && accept(entry.path()))
+            .filter_map(|entry| relative_key(root, entry.path()))
+            .take(MAX_LIBRARY_FILES)
+            .map(|rel| (library_id(label, &rel), rel))
+            .collect();
+        Self {
+            root: root.to_path_buf(),
+            label: label.to_string(),
+            files,
+        }
+    }
+
+    pub(super) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(super) fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub(super) fn get(&self, id: &str) -> Option<PathBuf> {
+        self.files.get(id).map(|rel| self.root.join(rel))
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// 按相对路径排序，供浏览界面展示。
+    pub(super) fn entries(&self) -> Vec<LibraryEntry> {
+        let mut entries: Vec<_> = self
+            .files
+            .iter()
+            .map(|(id, path)| LibraryEntry {
+                id: id.clone(),
+                root: self.label.clone(),
+                path: path.clone(),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+}
+
+/// 相对路径各段以 `/` 连接，同一文件在不同平台上得到相同的 id；非 UTF-8 路径不收录。
+fn relative_key(root: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = rel.components().map(|c| c.as_os_str().to_str()).collect();
+    Some(parts?.join("/"))
+}
+
+fn library_id(label: &str, rel: &str) -> String {
+    let digest = format!("{:x}", Sha256::digest(format!("{label}\0{rel}")));
+    digest[..ID_LEN].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_depend_only_on_relative_path() {
+        let base = std::env::temp_dir().join("vo_sync_library_ids");
+        let (a, b) = (base.join("a"), base.join("b"));
+        for root in [&a, &b] {
+            std::fs::create_dir_all(root.join("show")).unwrap();
+            std::fs::write(root.join("show/ep1.mp4"), b"x").unwrap();
+            std::fs::write(root.join("notes.txt"), b"x").unwrap();
+        }
+        let is_mp4 = |p: &Path| p.extension().is_some_and(|ext| ext == "mp4");
+        let first = LibraryIndex::build(&a, "Movies", is_mp4);
+        let second = LibraryIndex::build(&b, "Movies", is_mp4);
+        let other = LibraryIndex::build(&b, "Anime", is_mp4);
+
+        assert_eq!(first.len(), 1);
+        let entry = &first.entries()[0];
+        assert_eq!(entry.path, "show/ep1.mp4");
+        assert_eq!(entry.id, second.entries()[0].id);
+        assert_eq!(second.get(&entry.id), Some(b.join("show/ep1.mp4")));
+        assert_eq!(first.get("missing"), None);
+        assert_eq!(other.entries()[0].root, "Anime");
+        assert_eq!(other.get(&entry.id), None);
+    }
+}
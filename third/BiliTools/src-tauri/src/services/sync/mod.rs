@@ -1,37 +1,43 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    net::SocketAddr,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, LazyLock, Mutex as StdMutex, OnceLock, Weak},
     time::{Duration, Instant},
 };
 
 use axum::{
     body::Body,
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Path as AxumPath, Query, State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, FromRequest, Path as AxumPath, Query, State,
     },
     http::response::Builder,
-    http::{HeaderMap, HeaderValue, Request, StatusCode},
+    http::{header::HeaderName, HeaderMap, HeaderValue, Request, StatusCode},
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
-use axum::extract::rejection::QueryRejection;
+use axum::extract::rejection::{JsonRejection, QueryRejection};
 use axum::extract::ws::rejection::WebSocketUpgradeRejection;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{stream, SinkExt, StreamExt};
 use log::{error, info, warn};
 use md5;
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 use time::{Duration as TimeDuration, OffsetDateTime};
 use tokio::{
     fs::File,
     net::TcpListener,
-    sync::{mpsc, RwLock},
+    sync::{mpsc, Mutex, Notify, RwLock, Semaphore},
     task::JoinHandle,
     time as tokio_time,
 };
@@ -39,13 +45,169 @@ use tokio_util::io::ReaderStream;
 use tower_http::cors::{Any, CorsLayer};
 use uuid::Uuid;
 
-use crate::shared::init_client;
-use tauri_plugin_http::reqwest;
+use crate::{
+    shared::{init_client, init_client_with_connect_timeout, random_string, Sidecar, USER_AGENT},
+    storage::{config, cookies},
+};
+use tauri_plugin_http::reqwest::{
+    self,
+    cookie::{CookieStore, Jar},
+};
+
+mod breaker;
+pub mod client;
+mod concat;
+mod danmaku;
+mod disc;
+mod library;
+mod root_policy;
+mod seal;
+mod segment_cache;
+mod sidecar;
+mod sniff;
+mod ssrf;
+mod thumbnail;
+mod transcode;
+mod webhook;
 
 /// 默认监听端口，桌面端本地服务。
 const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:18080";
 const ENV_LISTEN_ADDR: &str = "VO_SYNC_ADDR";
+/// 设置后 `/media/:token` 改由该地址上的独立监听提供，主端口只保留 API 与 WebSocket，
+/// 主端口上的媒体请求被重定向过去。
+const ENV_MEDIA_ADDR: &str = "VO_SYNC_MEDIA_ADDR";
+/// 逗号分隔的额外响应头名，追加到 CORS 的 `Access-Control-Expose-Headers` 中，供前端脚本读取。
+const ENV_CORS_EXPOSE_HEADERS: &str = "VO_SYNC_CORS_EXPOSE_HEADERS";
+/// 始终暴露的响应头：前端按 Range 分段读取媒体时需要。
+const CORS_EXPOSE_HEADERS: [HeaderName; 3] = [
+    axum::http::header::CONTENT_RANGE,
+    axum::http::header::ACCEPT_RANGES,
+    axum::http::header::CONTENT_LENGTH,
+];
+/// 成员控制模式：未设置或 1/true 时成员可直接控制播放；
+/// 设为 `proposal` 时成员的操作作为提议，需房主确认后才生效。
 const ENV_ALLOW_MEMBER_CONTROL: &str = "VO_ALLOW_MEMBER_CONTROL";
+/// 设为 1/true 时成员的状态更新也能修改共享音量/静音，默认只有房主可以。
+const ENV_MEMBER_VOLUME: &str = "VO_SYNC_MEMBER_VOLUME";
+/// 设为 1/true 时主房主切走窗口（`host_focus` 上报失焦）会自动暂停房间，回到窗口后继续播放。
+const ENV_FOCUS_PAUSE: &str = "VO_SYNC_FOCUS_PAUSE";
+const ENV_CLEANUP_INTERVAL: &str = "VO_SYNC_CLEANUP_INTERVAL_SECS";
+const ENV_TOKEN_FORMAT: &str = "VO_SYNC_TOKEN_FORMAT";
+/// 设为 1/true 时同一房间重复 resolve 同一目标复用未过期的 token，默认每次签发新 token。
+const ENV_TOKEN_REUSE: &str = "VO_SYNC_TOKEN_REUSE";
+/// 开启后允许对本地不兼容编码的文件用 ffmpeg 实时转码，CPU 开销较大，默认关闭。
+const ENV_TRANSCODE: &str = "VO_SYNC_TRANSCODE";
+/// 开启后用 ffmpeg 为本地文件截取一帧作为封面，默认关闭。
+const ENV_THUMBNAILS: &str = "VO_SYNC_THUMBNAILS";
+/// seek 软锁窗口（毫秒），未设置或为 0 时关闭。
+const ENV_SEEK_LOCK_MS: &str = "VO_SYNC_SEEK_LOCK_MS";
+/// 同时存在的房间数上限，未设置时不限制。
+const ENV_MAX_ROOMS: &str = "VO_SYNC_MAX_ROOMS";
+/// 房间状态中标题的最大字符数，超出时拒绝该状态。
+const ENV_MAX_TITLE_LEN: &str = "VO_SYNC_MAX_TITLE_LEN";
+/// 房间状态中 `url` 与 `cover` 的最大字节数。
+const ENV_MAX_URL_LEN: &str = "VO_SYNC_MAX_URL_LEN";
+/// 快照加密口令，未设置时导出明文 JSON。
+const ENV_STATE_KEY: &str = "VO_SYNC_STATE_KEY";
+/// 单个 IP 同时保持的 WebSocket 连接上限，未设置时不限制。
+/// 经反向代理暴露时所有连接共享代理 IP，需相应调大。
+const ENV_MAX_WS_PER_IP: &str = "VO_SYNC_MAX_WS_PER_IP";
+/// 远程流分段缓存的容量（MB），未设置或为 0 时关闭。
+const ENV_SEGMENT_CACHE_MB: &str = "VO_SYNC_SEGMENT_CACHE_MB";
+/// 代理远程流时领先客户端预读的字节数（KB），上游短暂停顿时由这部分数据垫上；
+/// 未设置或为 0 时只按分块数有界转发。
+const ENV_PROXY_READAHEAD_KB: &str = "VO_SYNC_PROXY_READAHEAD_KB";
+/// 预读上限，避免误配后单条流占用大量内存。
+const MAX_PROXY_READAHEAD: usize = 32 * 1024 * 1024;
+/// 代理远程流时与上游建立连接、以及之后等待响应头的超时秒数。
+const ENV_PROXY_CONNECT_TIMEOUT: &str = "VO_SYNC_PROXY_CONNECT_TIMEOUT_SECS";
+const ENV_PROXY_FIRST_BYTE_TIMEOUT: &str = "VO_SYNC_PROXY_FIRST_BYTE_TIMEOUT_SECS";
+const DEFAULT_PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PROXY_FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(30);
+/// 设为 1 时向所有 WebSocket 连接推送 `debug` 诊断消息；单个连接也可用 `debug=1` 开启。
+const ENV_WS_DEBUG: &str = "VO_SYNC_WS_DEBUG";
+/// 设为 1 时允许把文件系统根、主目录或系统目录设为媒体根。
+const ENV_ALLOW_ANY_ROOT: &str = "VO_SYNC_ALLOW_ANY_ROOT";
+/// 设为 1 时未配置媒体根也能播放绝对路径的本地文件，所在目录需通过同样的目录检查。
+const ENV_ALLOW_ABSOLUTE_WITHOUT_ROOT: &str = "VO_SYNC_ALLOW_ABSOLUTE_WITHOUT_ROOT";
+/// 设置后媒体根只能位于该目录之下。
+const ENV_MEDIA_BASE: &str = "VO_SYNC_MEDIA_BASE";
+/// 设为 0 时关闭房主恢复令牌，房主重连后不再夺回主房主身份。
+const ENV_HOST_RESUME: &str = "VO_SYNC_HOST_RESUME";
+/// 设为 0 时加入不存在的房间直接报错，需以 `mode: "create"` 显式创建，避免房间名打错时误建新房间。
+const ENV_JOIN_CREATES: &str = "VO_SYNC_JOIN_CREATES";
+/// 设为 0 时不添加 nosniff、Referrer-Policy 与状态页 CSP 等加固响应头。
+const ENV_SECURITY_HEADERS: &str = "VO_SYNC_SECURITY_HEADERS";
+/// 状态页只有静态文本和站内链接，不需要加载任何资源。
+const STATUS_PAGE_CSP: &str = "default-src 'none'; frame-ancestors 'none'; base-uri 'none'";
+/// 主房主所有连接断开后，等待该秒数仍未重连才把主房主移交给在线成员；设为 0 时不自动移交。
+const ENV_HOST_GRACE: &str = "VO_SYNC_HOST_GRACE_SECS";
+/// 房间创建、关闭和切换播放源时 POST 事件的地址，未设置时不发送。
+const ENV_WEBHOOK_URL: &str = "VO_SYNC_WEBHOOK_URL";
+const DEFAULT_HOST_GRACE: Duration = Duration::from_secs(15);
+/// 过期房间的进度、播放源与播放列表保留的秒数，期间同名同口令重建即可续播；设为 0 时不保留。
+const ENV_TOMBSTONE_SECS: &str = "VO_SYNC_TOMBSTONE_SECS";
+const DEFAULT_TOMBSTONE_TTL: Duration = Duration::from_secs(10 * 60);
+/// 房间无任何活动超过该秒数后自动暂停，设为 0 时关闭。
+const ENV_IDLE_PAUSE: &str = "VO_SYNC_IDLE_PAUSE_SECS";
+const DEFAULT_IDLE_PAUSE: Duration = Duration::from_secs(5 * 60);
+/// 本地媒体允许的扩展名，逗号分隔；设为 `*` 时不限制。
+const ENV_ALLOWED_EXTS: &str = "VO_SYNC_ALLOWED_EXTS";
+const DEFAULT_MEDIA_EXTS: &[&str] = &[
+    "mp4", "m4v", "mkv", "webm", "mov", "avi", "flv", "wmv", "ts", "m2ts", "mts", "mpg", "mpeg",
+    "rm", "rmvb", "ogv", "mp3", "m4a", "aac", "flac", "wav", "ogg", "opus", "wma",
+];
+/// 可代理的 B 站稿件最大时长（秒），未设置时不限制；房主可按次用 `allowLong` 跳过。
+const ENV_MAX_DURATION: &str = "VO_SYNC_MAX_DURATION_SECS";
+/// 每隔该秒数向播放中的房间推送一次 `seek_correction`，未设置时只在房主请求时发送。
+const ENV_SEEK_CORRECTION: &str = "VO_SYNC_SEEK_CORRECTION_SECS";
+/// 每隔该秒数向有房主在线的暂停房间重发一次权威状态，把本地拖动过进度的成员拉回房主的位置；
+/// 未设置时关闭。
+const ENV_PAUSED_RESYNC: &str = "VO_SYNC_PAUSED_RESYNC_SECS";
+/// 暂停重发的最短间隔，暂停时进度不变，没必要频繁推送。
+const MIN_PAUSED_RESYNC: Duration = Duration::from_secs(10);
+/// 成员落后少许时建议的临时倍速（相对房间倍速，如 1.05），未设置时不发送追赶提示。
+const ENV_CATCHUP_RATE: &str = "VO_SYNC_CATCHUP_RATE";
+/// 追赶倍速的上限，再快声音会明显变调。
+const MAX_CATCHUP_RATE: f64 = 1.5;
+/// 偏差小于该秒数时不处理；落后超过上限秒数（或超前超过上限）时改为硬性 `resync`。
+const CATCHUP_MIN_LAG: f64 = 0.5;
+const CATCHUP_MAX_LAG: f64 = 5.0;
+/// 房主播放/暂停/seek 时把状态的生效时刻推后该毫秒数，成员按时间同步偏移在同一时刻切换；
+/// 未设置或为 0 时关闭。
+const ENV_WRITE_AHEAD_MS: &str = "VO_SYNC_WRITE_AHEAD_MS";
+/// 预告提前量上限，过长时房主自己的操作会明显迟滞。
+const MAX_WRITE_AHEAD: Duration = Duration::from_secs(2);
+/// B 站解析在窗口内连续失败该次数后熔断，为 0 时关闭熔断。
+const ENV_BILI_BREAKER_FAILURES: &str = "VO_SYNC_BILI_BREAKER_FAILURES";
+/// 熔断后的冷却秒数，之后放行一个探测请求。
+const ENV_BILI_BREAKER_COOLDOWN: &str = "VO_SYNC_BILI_BREAKER_COOLDOWN_SECS";
+const DEFAULT_BREAKER_FAILURES: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+/// 超过该时长的失败不再计入同一轮连续失败。
+const BREAKER_WINDOW: Duration = Duration::from_secs(60);
+/// 每个成员每秒可发的聊天条数（令牌桶补充速率），设为 0 时不限速。
+const ENV_CHAT_RATE: &str = "VO_SYNC_CHAT_RATE";
+const DEFAULT_CHAT_RATE: f64 = 1.0;
+/// 令牌桶容量，即允许的瞬时连发条数。
+const CHAT_BURST: f64 = 5.0;
+/// 单条聊天的字符数上限。
+const MAX_CHAT_LEN: usize = 500;
+/// 当前播放源上报播放错误的成员比例达到该值（0-1]时标记为有问题，默认 0.5。
+const ENV_ERROR_FLAG_RATIO: &str = "VO_SYNC_ERROR_FLAG_RATIO";
+const DEFAULT_ERROR_FLAG_RATIO: f64 = 0.5;
+const DEFAULT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+const BILI_API_BASE: &str = "https://api.bilibili.com";
+/// wbi img/sub key 通常数小时才轮换一次。
+const WBI_KEY_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+/// playurl 返回该 code 说明 wbi 签名失效，需要刷新 key。
+const BILI_CODE_SIGN_INVALID: i32 = -403;
+/// 请求被判定为自动化时返回该 code（或 HTTP 412）。
+const BILI_CODE_RATE_LIMITED: i32 = -412;
+const BILI_REFERER: &str = "https://www.bilibili.com/";
+/// 依次尝试的 (qn, fnval)：部分稿件首选组合只返回 DASH，
+/// 退回 fnval=0 或较低清晰度时通常能拿到 MP4 durl。
+const DURL_ATTEMPTS: &[(u32, u32)] = &[(112, 1), (80, 0), (64, 0), (32, 0), (16, 1)];
 
 #[derive(Clone)]
 struct AppState {
@@ -56,34 +218,414 @@ struct AppState {
 #[derive(Debug, Clone)]
 struct SyncConfig {
     listen_addr: String,
+    media_addr: Option<String>,
     allow_member_control: bool,
+    member_proposals: bool,
+    member_volume: bool,
+    focus_pause: bool,
+    cleanup_interval: Duration,
+    token_format: TokenFormat,
+    token_reuse: bool,
+    transcode: bool,
+    thumbnails: bool,
+    seek_lock: Duration,
+    max_rooms: Option<usize>,
+    state_limits: StateLimits,
+    state_key: Option<String>,
+    allowed_exts: Option<HashSet<String>>,
+    max_ws_per_ip: Option<usize>,
+    idle_pause: Option<Duration>,
+    root_policy: root_policy::RootPolicy,
+    host_resume: bool,
+    join_creates: bool,
+    security_headers: bool,
+    host_grace: Option<Duration>,
+    tombstone_ttl: Option<Duration>,
+    ws_debug: bool,
+    segment_cache_bytes: Option<usize>,
+    proxy_readahead: Option<usize>,
+    proxy_timeouts: ProxyTimeouts,
+    webhook_url: Option<String>,
+    cors_expose: Vec<HeaderName>,
+    max_duration: Option<Duration>,
+    seek_correction: Option<Duration>,
+    paused_resync: Option<Duration>,
+    catchup_rate: Option<f64>,
+    write_ahead: Duration,
+    chat_rate: Option<f64>,
+    error_flag_ratio: f64,
+    /// (失败阈值, 冷却时间)，为 None 时不熔断。
+    bili_breaker: Option<(u32, Duration)>,
 }
 
 impl SyncConfig {
     fn from_env() -> Self {
         let listen_addr =
             std::env::var(ENV_LISTEN_ADDR).unwrap_or_else(|_| DEFAULT_LISTEN_ADDR.to_string());
+        let media_addr = std::env::var(ENV_MEDIA_ADDR)
+            .ok()
+            .filter(|v| !v.trim().is_empty());
         let allow_member_control = std::env::var(ENV_ALLOW_MEMBER_CONTROL)
             .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
             .unwrap_or(true);
+        let member_proposals = std::env::var(ENV_ALLOW_MEMBER_CONTROL)
+            .is_ok_and(|v| v.trim().eq_ignore_ascii_case("proposal"));
+        let member_volume = std::env::var(ENV_MEMBER_VOLUME)
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let focus_pause = std::env::var(ENV_FOCUS_PAUSE)
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let cleanup_interval = env_secs(ENV_CLEANUP_INTERVAL).unwrap_or(DEFAULT_CLEANUP_INTERVAL);
+        let token_format = std::env::var(ENV_TOKEN_FORMAT)
+            .ok()
+            .and_then(|v| TokenFormat::parse(&v))
+            .unwrap_or_default();
+        let token_reuse = std::env::var(ENV_TOKEN_REUSE)
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let transcode = std::env::var(ENV_TRANSCODE)
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let thumbnails = std::env::var(ENV_THUMBNAILS)
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let seek_lock = std::env::var(ENV_SEEK_LOCK_MS)
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::ZERO);
+        let max_rooms = std::env::var(ENV_MAX_ROOMS)
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|v| *v > 0);
+        let env_len = |key: &str| {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .filter(|v| *v > 0)
+        };
+        let defaults = StateLimits::default();
+        let state_limits = StateLimits {
+            title: env_len(ENV_MAX_TITLE_LEN).unwrap_or(defaults.title),
+            url: env_len(ENV_MAX_URL_LEN).unwrap_or(defaults.url),
+        };
+        let state_key = std::env::var(ENV_STATE_KEY).ok().filter(|v| !v.is_empty());
+        let max_ws_per_ip = std::env::var(ENV_MAX_WS_PER_IP)
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|v| *v > 0);
+        let idle_pause = match std::env::var(ENV_IDLE_PAUSE) {
+            Ok(v) => v
+                .trim()
+                .parse::<u64>()
+                .map_or(Some(DEFAULT_IDLE_PAUSE), |secs| {
+                    (secs > 0).then(|| Duration::from_secs(secs))
+                }),
+            Err(_) => Some(DEFAULT_IDLE_PAUSE),
+        };
+        let root_policy = root_policy::RootPolicy {
+            allow_dangerous: std::env::var(ENV_ALLOW_ANY_ROOT)
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            allow_unrooted: std::env::var(ENV_ALLOW_ABSOLUTE_WITHOUT_ROOT)
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            base: std::env::var(ENV_MEDIA_BASE)
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(clean_path),
+        };
+        let host_resume = std::env::var(ENV_HOST_RESUME)
+            .map(|v| !(v == "0" || v.eq_ignore_ascii_case("false")))
+            .unwrap_or(true);
+        let join_creates = std::env::var(ENV_JOIN_CREATES)
+            .map(|v| !(v == "0" || v.eq_ignore_ascii_case("false")))
+            .unwrap_or(true);
+        let security_headers = std::env::var(ENV_SECURITY_HEADERS)
+            .map(|v| !(v == "0" || v.eq_ignore_ascii_case("false")))
+            .unwrap_or(true);
+        let segment_cache_bytes = std::env::var(ENV_SEGMENT_CACHE_MB)
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|v| *v > 0)
+            .map(|mb| mb * 1024 * 1024);
+        let proxy_readahead = std::env::var(ENV_PROXY_READAHEAD_KB)
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|v| *v > 0)
+            .map(|kb| (kb * 1024).min(MAX_PROXY_READAHEAD));
+        let ws_debug = std::env::var(ENV_WS_DEBUG)
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let host_grace = match std::env::var(ENV_HOST_GRACE) {
+            Ok(v) => v
+                .trim()
+                .parse::<u64>()
+                .map_or(Some(DEFAULT_HOST_GRACE), |secs| {
+                    (secs > 0).then(|| Duration::from_secs(secs))
+                }),
+            Err(_) => Some(DEFAULT_HOST_GRACE),
+        };
+        let tombstone_ttl = match std::env::var(ENV_TOMBSTONE_SECS) {
+            Ok(v) => v
+                .trim()
+                .parse::<u64>()
+                .map_or(Some(DEFAULT_TOMBSTONE_TTL), |secs| {
+                    (secs > 0).then(|| Duration::from_secs(secs))
+                }),
+            Err(_) => Some(DEFAULT_TOMBSTONE_TTL),
+        };
+        let proxy_timeouts = ProxyTimeouts {
+            connect: env_secs(ENV_PROXY_CONNECT_TIMEOUT).unwrap_or(DEFAULT_PROXY_CONNECT_TIMEOUT),
+            first_byte: env_secs(ENV_PROXY_FIRST_BYTE_TIMEOUT)
+                .unwrap_or(DEFAULT_PROXY_FIRST_BYTE_TIMEOUT),
+        };
+        let cors_expose = std::env::var(ENV_CORS_EXPOSE_HEADERS)
+            .map(|v| {
+                v.split(',')
+                    .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let webhook_url = std::env::var(ENV_WEBHOOK_URL)
+            .ok()
+            .filter(|v| !v.trim().is_empty());
+        let max_duration = env_secs(ENV_MAX_DURATION);
+        let seek_correction = env_secs(ENV_SEEK_CORRECTION);
+        let paused_resync = env_secs(ENV_PAUSED_RESYNC).map(|v| v.max(MIN_PAUSED_RESYNC));
+        let catchup_rate = std::env::var(ENV_CATCHUP_RATE)
+            .ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|rate| *rate > 1.0 && *rate <= MAX_CATCHUP_RATE);
+        let write_ahead = std::env::var(ENV_WRITE_AHEAD_MS)
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(|ms| Duration::from_millis(ms).min(MAX_WRITE_AHEAD))
+            .unwrap_or(Duration::ZERO);
+        let chat_rate = std::env::var(ENV_CHAT_RATE)
+            .ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|rate| rate.is_finite() && *rate >= 0.0)
+            .map_or(Some(DEFAULT_CHAT_RATE), |rate| (rate > 0.0).then_some(rate));
+        let error_flag_ratio = std::env::var(ENV_ERROR_FLAG_RATIO)
+            .ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|ratio| *ratio > 0.0 && *ratio <= 1.0)
+            .unwrap_or(DEFAULT_ERROR_FLAG_RATIO);
+        let breaker_failures = std::env::var(ENV_BILI_BREAKER_FAILURES)
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(DEFAULT_BREAKER_FAILURES);
+        let breaker_cooldown =
+            env_secs(ENV_BILI_BREAKER_COOLDOWN).unwrap_or(DEFAULT_BREAKER_COOLDOWN);
+        let bili_breaker = (breaker_failures > 0).then_some((breaker_failures, breaker_cooldown));
+        let allowed_exts = match std::env::var(ENV_ALLOWED_EXTS) {
+            Ok(v) if v.trim() == "*" => None,
+            Ok(v) => Some(parse_exts(&v)),
+            Err(_) => Some(default_media_exts()),
+        };
         Self {
             listen_addr,
+            media_addr,
             allow_member_control,
+            member_proposals,
+            member_volume,
+            focus_pause,
+            cleanup_interval,
+            token_format,
+            token_reuse,
+            transcode,
+            thumbnails,
+            seek_lock,
+            max_rooms,
+            state_limits,
+            state_key,
+            allowed_exts,
+            max_ws_per_ip,
+            idle_pause,
+            root_policy,
+            host_resume,
+            join_creates,
+            security_headers,
+            host_grace,
+            tombstone_ttl,
+            ws_debug,
+            segment_cache_bytes,
+            proxy_readahead,
+            proxy_timeouts,
+            webhook_url,
+            cors_expose,
+            max_duration,
+            seek_correction,
+            paused_resync,
+            catchup_rate,
+            write_ahead,
+            chat_rate,
+            error_flag_ratio,
+            bili_breaker,
         }
     }
 }
 
+/// 客户端指定 temp_user 的长度上限。
+const MAX_TEMP_USER_LEN: usize = 64;
+/// 同时配置的媒体根数量与标签长度上限。
+const MAX_MEDIA_ROOTS: usize = 16;
+const MAX_ROOT_LABEL_LEN: usize = 64;
+
+fn validate_temp_user(id: &str) -> Result<(), ApiError> {
+    let valid = Uuid::parse_str(id).is_ok()
+        || (!id.is_empty()
+            && id.len() <= MAX_TEMP_USER_LEN
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request(format!(
+            "tempUser must be a UUID or up to {MAX_TEMP_USER_LEN} letters, digits, '-' or '_'"
+        )))
+    }
+}
+
+/// 只保留前 4 个字符，其余打码。
+fn redact(value: &str) -> String {
+    let head: String = value.chars().take(4).collect();
+    format!("{head}***")
+}
+
+fn default_media_exts() -> HashSet<String> {
+    DEFAULT_MEDIA_EXTS
+        .iter()
+        .map(|ext| ext.to_string())
+        .collect()
+}
+
+/// 解析逗号分隔的扩展名列表，忽略大小写和前导点。
+fn parse_exts(value: &str) -> HashSet<String> {
+    value
+        .split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+fn ext_allowed(allowed: Option<&HashSet<String>>, path: &Path) -> bool {
+    let Some(allowed) = allowed else {
+        return true;
+    };
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| allowed.contains(&ext.to_ascii_lowercase()))
+}
+
+/// 读取以秒为单位的正整数环境变量，非法值忽略。
+fn env_secs(key: &str) -> Option<Duration> {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .map(Duration::from_secs)
+}
+
+/// `init` 启动的服务，供宿主应用在登录状态变化时同步会话。
+static SERVICE: OnceLock<Weak<Manager>> = OnceLock::new();
+
+/// 宿主应用登录、退出或刷新 Cookie 后调用：重新读取保存的 B 站 Cookie 共享给服务，
+/// 之后的 resolve 自动带上登录态（更高清晰度、会员内容），退出后不再携带。服务尚未启动时返回 false。
+pub async fn refresh_bili_session() -> bool {
+    let Some(manager) = SERVICE.get().and_then(Weak::upgrade) else {
+        return false;
+    };
+    manager.set_bili_session(stored_bili_session().await);
+    true
+}
+
 pub async fn init() -> anyhow::Result<()> {
     let cfg = SyncConfig::from_env();
-    let manager = Arc::new(Manager::new(None, cfg.allow_member_control));
-    manager.spawn_cleanup();
-    let hub = Arc::new(Hub::new());
+    let ffmpeg = || config::read().sidecar(Sidecar::FFmpeg);
+    // 媒体端口要写进 resolve 返回的地址，需在创建 Manager 前绑定。
+    let media_listener = match &cfg.media_addr {
+        Some(addr) => Some(
+            TcpListener::bind(addr)
+                .await
+                .map_err(|err| anyhow::anyhow!("failed to bind media listener {addr}: {err}"))?,
+        ),
+        None => None,
+    };
+    let media_local = media_listener
+        .as_ref()
+        .map(TcpListener::local_addr)
+        .transpose()?;
+    let manager = Arc::new(
+        Manager::new(None, cfg.allow_member_control)
+            .with_media_port(media_local.map(|addr| addr.port()))
+            .with_cleanup_interval(cfg.cleanup_interval)
+            .with_member_proposals(cfg.member_proposals)
+            .with_member_volume(cfg.member_volume)
+            .with_focus_pause(cfg.focus_pause)
+            .with_token_format(cfg.token_format)
+            .with_token_reuse(cfg.token_reuse)
+            .with_ffmpeg(cfg.transcode.then(ffmpeg))
+            .with_thumbnails(
+                cfg.thumbnails
+                    .then(|| thumbnail::Thumbnailer::ffmpeg(ffmpeg())),
+            )
+            .with_seek_lock(cfg.seek_lock)
+            .with_max_rooms(cfg.max_rooms)
+            .with_state_limits(cfg.state_limits)
+            .with_snapshot_key(
+                cfg.state_key
+                    .as_deref()
+                    .map(seal::SnapshotKey::from_passphrase),
+            )
+            .with_allowed_exts(cfg.allowed_exts)
+            .with_idle_pause(cfg.idle_pause)
+            .with_root_policy(cfg.root_policy)
+            .with_host_resume(cfg.host_resume)
+            .with_join_creates(cfg.join_creates)
+            .with_host_grace(cfg.host_grace)
+            .with_tombstone_ttl(cfg.tombstone_ttl)
+            .with_segment_cache(cfg.segment_cache_bytes)
+            .with_proxy_readahead(cfg.proxy_readahead)
+            .with_proxy_timeouts(cfg.proxy_timeouts)
+            .with_webhook(cfg.webhook_url)
+            .with_cors_expose(cfg.cors_expose)
+            .with_max_duration(cfg.max_duration)
+            .with_seek_correction(cfg.seek_correction)
+            .with_paused_resync(cfg.paused_resync)
+            .with_catchup_rate(cfg.catchup_rate)
+            .with_write_ahead(cfg.write_ahead)
+            .with_chat_rate(cfg.chat_rate)
+            .with_error_flag_ratio(cfg.error_flag_ratio)
+            .with_bili_breaker(cfg.bili_breaker.map(|(failures, cooldown)| {
+                breaker::CircuitBreaker::new(failures, BREAKER_WINDOW, cooldown)
+            })),
+    );
+    let hub = Arc::new(
+        Hub::new()
+            .with_max_ws_per_ip(cfg.max_ws_per_ip)
+            .with_ws_debug(cfg.ws_debug),
+    );
+    manager.set_bili_session(stored_bili_session().await);
+    let _ = SERVICE.set(Arc::downgrade(&manager));
+    manager.spawn_cleanup(hub.clone());
     let (listener, actual_addr) = bind_listener(&cfg.listen_addr).await?;
     let state = AppState {
         manager: manager.clone(),
         hub: hub.clone(),
     };
-    tokio::spawn(run_server(state, listener));
+    if let (Some(listener), Some(addr)) = (media_listener, media_local) {
+        tokio::spawn(run_media_server(
+            state.clone(),
+            listener,
+            cfg.security_headers,
+        ));
+        info!("sync media listening on http://{addr}");
+    }
+    tokio::spawn(run_server(state, listener, cfg.security_headers));
     info!(
         "sync service listening on http://{} media_root=unset allow_member_control={}",
         actual_addr, cfg.allow_member_control
@@ -91,1242 +633,13258 @@ pub async fn init() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn run_server(state: AppState, listener: TcpListener) {
-    let router = Router::new()
+async fn run_server(state: AppState, listener: TcpListener, security_headers: bool) {
+    let hub = state.hub.clone();
+    let mut router = build_router(state);
+    if security_headers {
+        router = with_security_headers(router);
+    }
+    // 按 IP 限制连接数需要对端地址。
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(err) = axum::serve(listener, service).await {
+        error!("sync server quit: {err:?}");
+    }
+    hub.close_all(close_with(CLOSE_SHUTTING_DOWN, shutdown_reason()))
+        .await;
+}
+
+/// 独立的媒体监听只提供 `/media/:token`，关闭时不影响主端口上的连接。
+async fn run_media_server(state: AppState, listener: TcpListener, security_headers: bool) {
+    let mut router = build_media_router(state);
+    if security_headers {
+        router = with_security_headers(router);
+    }
+    if let Err(err) = axum::serve(listener, router).await {
+        error!("sync media server quit: {err:?}");
+    }
+}
+
+fn with_security_headers(router: Router) -> Router {
+    router.layer(axum::middleware::from_fn(security_headers))
+}
+
+/// 局域网暴露时的基础加固。媒体流不加 nosniff：本地文件的 Content-Type 按扩展名猜测，
+/// 猜错时浏览器仍需嗅探才能播放；已有的同名响应头不覆盖。
+async fn security_headers(req: Request<Body>, next: Next) -> Response {
+    let is_media = req.uri().path().starts_with("/media/");
+    let mut res = next.run(req).await;
+    let is_html = res
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    let headers = res.headers_mut();
+    headers
+        .entry(axum::http::header::REFERRER_POLICY)
+        .or_insert(HeaderValue::from_static("no-referrer"));
+    if !is_media {
+        headers
+            .entry(axum::http::header::X_CONTENT_TYPE_OPTIONS)
+            .or_insert(HeaderValue::from_static("nosniff"));
+    }
+    if is_html {
+        headers
+            .entry(axum::http::header::CONTENT_SECURITY_POLICY)
+            .or_insert(HeaderValue::from_static(STATUS_PAGE_CSP));
+    }
+    res
+}
+
+fn build_router(state: AppState) -> Router {
+    let media = if state.manager.media_port.is_some() {
+        get(redirect_media)
+    } else {
+        get(media_stream)
+    };
+    Router::new()
+        .route("/", get(status_page))
         .route("/healthz", get(|| async { "ok" }))
+        .route("/metrics", get(metrics))
         .route("/api/room/join", post(join_room))
+        .route("/api/room/check", post(check_room))
+        .route("/api/room/:room/snapshot", get(room_snapshot))
+        .route("/api/room/:room/playlist/export", get(export_playlist))
+        .route("/api/room/:room/events-stream", get(room_events_stream))
+        .route("/api/room/:room/whoami", get(whoami))
+        .route("/api/room/:room/events", get(room_events))
+        .route("/api/room/:room/sync-report", get(sync_report))
+        .route("/api/room/:room/members", get(list_members))
+        .route("/api/room/restore", post(room_restore))
+        .route("/api/room/playlist", post(set_playlist))
+        .route("/api/room/playlist/import", post(import_playlist))
+        .route("/api/room/kick", post(kick_member))
+        .route("/api/room/promote", post(promote_host))
+        .route("/api/room/demote", post(demote_host))
+        .route("/api/room/transfer", post(transfer_host))
+        .route("/api/room/rates", post(set_allowed_rates))
+        .route("/api/room/sources", post(set_allowed_sources))
+        .route("/api/room/schedule", post(set_schedule))
+        .route("/api/room/reset", post(reset_room))
+        .route("/api/room/password", post(change_password))
         .route("/api/media/resolve", post(media_resolve))
+        .route("/api/media/resolve-batch", post(media_resolve_batch))
+        .route("/api/media/prewarm", post(media_prewarm))
+        .route("/api/media/switch-quality", post(media_switch_quality))
+        .route("/api/media/favorites", post(media_favorites))
+        .route("/api/bili/probe", post(bili_probe))
+        .route("/api/bili/raw-playurl", post(bili_raw_playurl))
         .route("/api/media/root", post(set_media_root).get(get_media_root))
-        .route("/media/:token", get(media_stream))
+        .route(
+            "/api/media/roots",
+            post(set_media_roots).get(get_media_roots),
+        )
+        .route("/api/media/library", get(media_library))
+        .route("/api/media/reindex", post(media_reindex))
+        .route("/api/admin/cache", get(admin_cache))
+        .route("/api/admin/cache/clear", post(admin_cache_clear))
+        .route("/api/admin/connections", get(admin_connections))
+        .route("/api/admin/playback-errors", get(admin_playback_errors))
+        .route(
+            "/api/admin/connections/:id/close",
+            post(admin_close_connection),
+        )
+        .route("/api/media/:token/status", get(media_token_status))
+        .route("/media/:token", media)
         .route("/ws", get(ws_handler))
+        .layer(cors_layer(&state.manager.cors_expose))
         .with_state(state)
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any),
-        );
+}
 
-    if let Err(err) = axum::serve(listener, router.into_make_service()).await {
-        error!("sync server quit: {err:?}");
-    }
+fn build_media_router(state: AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/media/:token", get(media_stream))
+        .layer(cors_layer(&state.manager.cors_expose))
+        .with_state(state)
+}
+
+/// 暴露的响应头逐个列出：`Any` 不会让浏览器放行带凭据请求的自定义头，也看不出前端依赖了哪些。
+fn cors_layer(extra: &[HeaderName]) -> CorsLayer {
+    let expose: Vec<HeaderName> = CORS_EXPOSE_HEADERS.iter().chain(extra).cloned().collect();
+    CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .expose_headers(expose)
+}
+
+/// 媒体改由独立端口提供时，房间状态里的相对地址仍指向主端口，在此转到媒体端口。
+async fn redirect_media(
+    State(state): State<AppState>,
+    AxumPath(token): AxumPath<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let origin = state
+        .manager
+        .media_origin(&headers)
+        .ok_or_else(|| ApiError::bad_request("missing host header"))?;
+    let location = HeaderValue::from_str(&format!("{origin}/media/{token}"))
+        .map_err(|_| ApiError::bad_request("invalid host header"))?;
+    Ok(Response::builder()
+        .status(StatusCode::TEMPORARY_REDIRECT)
+        .header(axum::http::header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinRequest {
+    pub room: String,
+    pub password: String,
+    /// 创建房间时拿到的房主恢复令牌，重连时带上以夺回主房主身份。
+    #[serde(default)]
+    pub resume_token: Option<String>,
+    /// 指定自己的 temp_user（UUID 或不超过 64 位的字母、数字、`-`、`_`），
+    /// 供脚本和测试使用；缺省时随机生成，与房间内已有成员重复时拒绝。
+    #[serde(default)]
+    pub temp_user: Option<String>,
+    /// 缺省时按服务端配置：允许自动创建则为 `auto`，否则为 `join`。
+    #[serde(default)]
+    pub mode: Option<JoinMode>,
 }
 
+/// 加入前预检房间与口令，不要求已是成员，也不会创建房间。
 #[derive(Debug, Deserialize)]
-struct JoinRequest {
+struct CheckRequest {
     room: String,
     password: String,
 }
 
-#[derive(Debug, Serialize)]
+/// 区分创建与加入，避免房间名或口令打错时悄悄建出一个新房间。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JoinMode {
+    /// 房间不存在时创建，存在时加入。
+    Auto,
+    /// 只加入已有（或刚过期可恢复的）房间。
+    Join,
+    /// 只创建新房间，同名房间已存在时报错。
+    Create,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct JoinResponse {
+pub struct JoinResponse {
+    pub temp_user: String,
+    /// 自己在成员列表、聊天等处显示的公开标识。
+    #[serde(default)]
+    pub member_id: String,
+    pub role: String,
+    /// 仅发给主房主，需由客户端妥善保存。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_resume_token: Option<String>,
+    /// 房间刚过期又被重建，进度（已暂停）与播放列表从过期前恢复。
+    #[serde(default)]
+    pub restored: bool,
+}
+
+/// `join_room_as` 的可选参数，未给出的项按默认处理。
+#[derive(Debug, Default, Clone, Copy)]
+struct JoinOptions<'a> {
+    client_id: Option<&'a str>,
+    /// 主房主的恢复令牌，匹配时夺回主房主。
+    resume_token: Option<&'a str>,
+    /// 指定临时身份，为 None 时随机生成。
+    temp_user: Option<&'a str>,
+    /// 为 None 时按 `join_creates` 配置决定。
+    mode: Option<JoinMode>,
+}
+
+/// `join_room_as` 的结果；`hosts` 仅在凭恢复令牌夺回主房主时返回。
+#[derive(Debug)]
+struct JoinOutcome {
     temp_user: String,
-    role: String,
+    member_id: String,
+    is_host: bool,
+    host_resume_token: Option<String>,
+    hosts: Option<HostsResponse>,
+    restored: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaResolveRequest {
+    pub room: String,
+    pub password: String,
+    pub temp_user: String,
+    pub path: String,
+    /// 为 false 时只签发 token，不修改也不广播房间状态（用于预览候选项）。
+    #[serde(default = "default_true")]
+    pub auto_publish: bool,
+    #[serde(flatten)]
+    pub options: ResolveOptions,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// resolve 的可选参数，随请求体一起平铺传入。
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolveOptions {
+    /// 仅取 B 站 DASH 的音频轨，前端用 `<audio>` 播放。
+    #[serde(default)]
+    pub audio_only: bool,
+    /// 本地文件编码浏览器不支持时转码播放，需服务端开启转码。
+    #[serde(default)]
+    pub transcode: bool,
+    /// 跳过本地文件的扩展名白名单和音视频格式嗅探。
+    #[serde(default)]
+    pub allow_any: bool,
+    /// 跳过 B 站稿件的时长上限，只对房主生效。
+    #[serde(default)]
+    pub allow_long: bool,
+    /// 同时拉取 B 站弹幕并签发 JSON 轨道，见 `danmaku_url`。
+    #[serde(default)]
+    pub danmaku: bool,
+    /// 本地文件名形如 `name.partN.ext` 时，把同目录的全部分卷拼成一个媒体播放。
+    #[serde(default)]
+    pub join_parts: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaResolveResponse {
+    pub token: String,
+    pub url: String,
+    pub expires_at: i64,
+    pub source_type: SourceType,
+    pub cover: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioInfo>,
+    /// 画面尺寸与方向，只有 B 站视频能拿到。
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub dimension: Option<VideoDimension>,
+    /// B 站视频实际取到的清晰度（qn）。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u32>,
+    /// 光盘目录（`VIDEO_TS`/`BDMV`）的正片分段，按播放顺序排列；`token`/`url` 即首段。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<MediaSegment>>,
+    /// 请求 `danmaku` 时 B 站弹幕轨道的地址，内容为按时间排序的 JSON 数组。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub danmaku_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaSegment {
+    pub token: String,
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PrewarmRequest {
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PrewarmResponse {
+    ready: bool,
+    source_type: SourceType,
+    /// 远程 token 预热时上游对 `bytes=0-0` 的响应码。
+    upstream_status: Option<u16>,
+    /// 本地文件大小。
+    size: Option<u64>,
 }
 
+/// 把 B 站收藏夹展开为播放列表；`list` 可为收藏夹 id、`ml` 号或带 `fid=` 的链接。
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct MediaResolveRequest {
+struct FavoritesRequest {
     room: String,
     password: String,
     temp_user: String,
-    path: String,
+    list: String,
+    /// 缺省时使用应用内登录保存的 Cookie。
+    #[serde(default)]
+    cookie: Option<String>,
+}
+
+/// 只做诊断的 B 站解析：不需要房间，不签发 token。
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BiliProbeRequest {
+    /// BV 号或稿件链接。
+    input: String,
+    /// 期望的清晰度（qn），缺省按 resolve 的默认顺序。
+    #[serde(default)]
+    quality: Option<u32>,
+    /// 带上 Cookie 才能看到登录后可用的清晰度。
+    #[serde(default)]
+    cookie: Option<String>,
+}
+
+/// 原样返回 playurl 的调试请求，只接受 BV 号和数值参数。
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawPlayUrlRequest {
+    bvid: String,
+    /// 缺省时先查 view 取第一P的 cid。
+    #[serde(default)]
+    cid: Option<i64>,
+    #[serde(default)]
+    qn: Option<u32>,
+    /// 缺省请求全部 DASH 格式（4048）。
+    #[serde(default)]
+    fnval: Option<u32>,
+    #[serde(default)]
+    cookie: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct MediaResolveResponse {
-    token: String,
-    url: String,
-    expires_at: i64,
-    source_type: String,
+struct BiliProbeResponse {
+    bvid: String,
+    title: String,
     cover: Option<String>,
+    /// 稿件时长（秒）。
+    duration: i64,
+    qualities: Vec<BiliQuality>,
+    /// 是否存在 resolve 可以播放的 MP4 直链。
+    playable: bool,
+    /// 不可播放时的原因。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BiliQuality {
+    qn: u32,
+    description: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FavoritesResponse {
+    media_id: u64,
+    title: Option<String>,
+    playlist: Vec<PlaylistItem>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchResolveRequest {
+    room: String,
+    password: String,
+    temp_user: String,
+    paths: Vec<String>,
+    #[serde(flatten)]
+    options: ResolveOptions,
+}
+
+/// 批量 resolve 的单项结果，成功与失败按原顺序混排返回。
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchResolveItem {
+    Ok(MediaResolveResponse),
+    Err { path: String, error: String },
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResolveResponse {
+    results: Vec<BatchResolveItem>,
 }
 
 #[derive(Debug)]
 struct ResolvedMedia {
     token: String,
     url: String,
-    source_type: String,
+    source_type: SourceType,
     cover: Option<String>,
+    audio: Option<AudioInfo>,
+    dimension: Option<VideoDimension>,
+    quality: Option<u32>,
+    segments: Option<Vec<MediaSegment>>,
+    danmaku_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioInfo {
+    pub codec: String,
+    pub bitrate: u64,
+}
+
+/// 已按旋转校正后的显示尺寸，供前端调整播放器宽高比。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoDimension {
+    pub width: u32,
+    pub height: u32,
+    /// `landscape` / `portrait` / `square`
+    pub orientation: String,
+}
+
+impl VideoDimension {
+    fn new(width: u32, height: u32) -> Option<Self> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let orientation = match width.cmp(&height) {
+            std::cmp::Ordering::Greater => "landscape",
+            std::cmp::Ordering::Less => "portrait",
+            std::cmp::Ordering::Equal => "square",
+        };
+        Some(Self {
+            width,
+            height,
+            orientation: orientation.into(),
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
-struct MediaRootRequest {
-    path: String,
+#[serde(rename_all = "camelCase")]
+struct AuthQuery {
+    password: String,
+    temp_user: String,
 }
 
-#[derive(Debug, Serialize)]
-struct MediaRootResponse {
-    media_root: Option<String>,
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventsQuery {
+    password: String,
+    temp_user: String,
+    /// 上次返回的 cursor，只取其后的事件；缺省时返回整个缓冲区。
+    since: Option<u64>,
 }
 
-async fn join_room(
-    State(state): State<AppState>,
-    Json(req): Json<JoinRequest>,
-) -> Result<impl IntoResponse, ApiError> {
-    let (temp_user, is_host) = state.manager.join_room(&req.room, &req.password).await?;
-    Ok(Json(JoinResponse {
-        temp_user,
-        role: if is_host {
-            "host".into()
-        } else {
-            "member".into()
-        },
-    }))
+/// 房间事件日志中的一条记录，id 在房间内单调递增。
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomEvent {
+    id: u64,
+    kind: &'static str,
+    /// 相关成员的公开标识；事件日志所有成员都能读取，不记录 temp_user。
+    user: Option<String>,
+    at: i64,
 }
 
-async fn media_resolve(
-    State(state): State<AppState>,
-    Json(req): Json<MediaResolveRequest>,
-) -> Result<impl IntoResponse, ApiError> {
-    let resolved = state
-        .manager
-        .resolve_media_path(&req.room, &req.password, &req.temp_user, &req.path)
-        .await?;
-    let expires_at: i64 = ((OffsetDateTime::now_utc()
-        + TimeDuration::seconds(state.manager.token_ttl.as_secs() as i64))
-    .unix_timestamp_nanos()
-        / 1_000_000)
-        .try_into()
-        .unwrap_or(i64::MAX);
-    
-    // 自动创建并广播初始的 room_state
-    let initial_state = RoomState {
-        url: resolved.url.clone(),
-        title: req.path.split('/').last().unwrap_or("视频").to_string(),
-        current_time: 0.0,
-        duration: 0.0,
-        paused: true,
-        playback_rate: 1.0,
-        source_type: resolved.source_type.clone(),
-        updated_at: now_millis(),
-        cover: resolved.cover.clone(),
-    };
-    
-    // 更新房间状态
-    let updated_state = state
-        .manager
-        .update_state(&req.room, &req.temp_user, initial_state, true)
-        .await?;
-    
-    // 广播给所有客户端
-    state.hub.broadcast_state(&req.room, &updated_state).await;
-    
-    Ok(Json(MediaResolveResponse {
-        url: resolved.url,
-        token: resolved.token,
-        expires_at,
-        source_type: resolved.source_type,
-        cover: resolved.cover,
-    }))
+/// 成员提交、等待房主确认的播放调整，id 在房间内单调递增。
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Proposal {
+    id: u64,
+    /// 提议成员的公开标识，提议会转发给所有房主。
+    from: String,
+    /// 提议成员的 temp_user，只在服务端用于去重，不下发。
+    #[serde(skip)]
+    member: String,
+    state: RoomState,
+    created_at: i64,
 }
 
-async fn set_media_root(
-    State(state): State<AppState>,
-    Json(req): Json<MediaRootRequest>,
-) -> Result<impl IntoResponse, ApiError> {
-    let path = state.manager.set_media_root(&req.path).await?;
-    Ok(Json(MediaRootResponse {
-        media_root: path.to_str().map(|s| s.to_string()),
-    }))
+/// 房主在时间轴上标注的位置，成员渲染在进度条上；换源时清空，客户端在 url 变化时也应丢弃。
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Marker {
+    id: u64,
+    /// 标注的播放进度（秒）。
+    position: f64,
+    label: String,
+    created_at: i64,
 }
 
-async fn get_media_root(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
-    let path = state.manager.media_root.read().await.clone();
-    Ok(Json(MediaRootResponse {
-        media_root: path
-            .as_ref()
-            .and_then(|p| p.to_str().map(|s| s.to_string())),
-    }))
+/// 成员播放器上报的加载/解码错误，记录当时的播放源以便判断是否为源的问题。
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaybackError {
+    /// 上报成员的公开标识，会发给房主并出现在管理接口中。
+    user: String,
+    /// 上报成员的 temp_user，只用于统计在线的上报人数，不下发。
+    #[serde(skip)]
+    member: String,
+    code: String,
+    message: Option<String>,
+    url: Option<String>,
+    at: i64,
 }
 
-async fn media_stream(
-    State(state): State<AppState>,
-    AxumPath(token): AxumPath<String>,
-    req: Request<Body>,
-) -> Result<Response, ApiError> {
-    if let Ok(target) = state.manager.open_remote(&token).await {
-        match target.strategy {
-            RemoteStrategy::Redirect => {
-                return Ok(Response::builder()
-                    .status(StatusCode::TEMPORARY_REDIRECT)
-                    .header(
-                        axum::http::header::LOCATION,
-                        HeaderValue::from_str(&target.url).unwrap_or(HeaderValue::from_static("")),
-                    )
-                    .body(Body::empty())
-                    .unwrap());
-            }
-            RemoteStrategy::ProxyWithHeaders => {
-                let client = init_client()
-                    .await
-                    .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
-                let mut builder = client.get(&target.url);
-                if let Some(range) = req.headers().get(axum::http::header::RANGE) {
-                    builder = builder.header(axum::http::header::RANGE, range.clone());
-                }
-                builder = builder.header(axum::http::header::REFERER, "https://www.bilibili.com/");
-                let upstream = builder
-                    .send()
-                    .await
-                    .map_err(|e| ApiError::not_found(format!("upstream error: {e}")))?;
-                let status =
-                    StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::OK);
-                let mut resp_builder = Response::builder().status(status);
-                let headers = upstream.headers();
-                copy_header(headers, axum::http::header::CONTENT_TYPE, &mut resp_builder);
-                copy_header(
-                    headers,
-                    axum::http::header::CONTENT_LENGTH,
-                    &mut resp_builder,
-                );
-                copy_header(
-                    headers,
-                    axum::http::header::ACCEPT_RANGES,
-                    &mut resp_builder,
-                );
-                copy_header(
-                    headers,
-                    axum::http::header::CONTENT_RANGE,
-                    &mut resp_builder,
-                );
-                let stream = upstream.bytes_stream();
-                let body = Body::from_stream(stream);
-                return resp_builder
-                    .body(body)
-                    .map_err(|e| ApiError::bad_request(format!("build body failed: {e}")));
-            }
-        }
-    }
+/// 管理接口中一个房间的播放错误汇总。
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomPlaybackErrors {
+    room: String,
+    /// 当前播放源是否已被标记为有问题。
+    flagged: bool,
+    errors: Vec<PlaybackError>,
+}
 
-    let path = state.manager.open_media(&token).await?;
-    let file = File::open(&path)
-        .await
-        .map_err(|_| ApiError::not_found("media not found"))?;
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .body(body)
-        .unwrap())
+/// 成员发送的一条聊天，由服务端盖上发送者与时间后广播。
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChatMessage {
+    /// 发送者的公开标识。
+    from: String,
+    text: String,
+    at: i64,
+}
+
+/// 房间当前的全部策略，客户端重连后一次取齐；字段为 null 表示不限制。
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomSettings {
+    /// 成员的控制权：`off`、`on` 或需房主确认的 `proposal`。
+    member_control: &'static str,
+    allowed_rates: Option<Vec<f64>>,
+    allowed_sources: Option<BTreeSet<SourceKind>>,
+    scheduled_start: Option<i64>,
+    /// 房主窗口是否处于焦点，前端可据此提示“房主已离开”。
+    host_focused: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct EventsResponse {
+    events: Vec<RoomEvent>,
+    /// 下次轮询时作为 `since` 传回。
+    cursor: u64,
+}
+
+/// 同步质量报告：各成员最近上报的进度与权威进度的偏差。
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SyncReport {
+    /// 当前时刻外推出的权威进度，房间尚无状态时为 None。
+    position: Option<f64>,
+    members: Vec<MemberOffset>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MemberOffset {
+    /// 成员的公开标识。
+    id: String,
+    position: f64,
+    reported_at: i64,
+    /// 上报进度减去上报时刻的权威进度，负数表示落后。
+    offset: Option<f64>,
+    /// 客户端时钟偏差（毫秒），未做过 `time_sync` 时为 None。
+    clock_offset_ms: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestoreRequest {
+    room: String,
+    password: String,
+    snapshot: SnapshotPayload,
+}
+
+/// 对外的快照格式：配置了口令时为密文，否则为明文；导入时两种都接受。
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SnapshotPayload {
+    Sealed(seal::SealedSnapshot),
+    Plain(Box<RoomSnapshot>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RestoreResponse {
+    temp_user: String,
+    role: String,
+    state: Option<RoomState>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct WsQuery {
+struct PlaylistRequest {
     room: String,
     password: String,
     temp_user: String,
+    items: Vec<PlaylistItem>,
 }
 
-async fn ws_handler(
-    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
-    State(state): State<AppState>,
-    query: Result<Query<WsQuery>, QueryRejection>,
-) -> Result<impl IntoResponse, ApiError> {
-    let ws = match ws {
-        Ok(v) => v,
-        Err(e) => {
-            warn!("ws upgrade rejection: {}", e);
-            return Ok((e.status(), e.to_string()).into_response());
-        }
-    };
-    let query = match query {
-        Ok(v) => v.0,
-        Err(e) => {
-            warn!("ws query rejection: {}", e);
-            return Ok((e.status(), e.to_string()).into_response());
-        }
-    };
-    let is_host = match state
-        .manager
-        .authorize(&query.room, &query.password, &query.temp_user)
-        .await
-    {
-        Ok(v) => v,
-        Err(e) => {
-            warn!(
-                "ws authorize failed room={} user={} err={}",
-                query.room, query.temp_user, e
-            );
-            // return a plain 403 for clarity
-            return Ok((StatusCode::FORBIDDEN, e.to_string()).into_response());
-        }
-    };
-    let ctx = WsContext {
-        room: query.room.clone(),
-        temp_user: query.temp_user.clone(),
-        is_host,
-    };
-    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, ctx)))
+#[derive(Debug, Serialize)]
+struct PlaylistResponse {
+    playlist: Vec<PlaylistItem>,
 }
 
-#[derive(Clone)]
-struct WsContext {
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistImportRequest {
     room: String,
+    password: String,
     temp_user: String,
-    is_host: bool,
+    playlist: PlaylistExport,
+    /// 为 true 时追加到现有列表末尾，否则替换。
+    #[serde(default)]
+    append: bool,
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState, ctx: WsContext) {
-    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Message>();
-    let client_id = Uuid::new_v4().to_string();
-    state
-        .hub
-        .register(&ctx.room, &client_id, out_tx.clone())
-        .await;
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SwitchQualityRequest {
+    room: String,
+    password: String,
+    temp_user: String,
+    /// 房间当前媒体的 token，可带 `/media/` 前缀。
+    token: String,
+    /// 目标清晰度（qn），如 80 为 1080P、32 为 480P。
+    quality: u32,
+}
 
-    if let Some(current) = state.manager.current_state(&ctx.room).await {
-        if let Ok(payload) = serde_json::to_string(&WsOutgoing {
-            r#type: "room_state".into(),
-            state: Some(current),
-            error: None,
-        }) {
-            let _ = out_tx.send(Message::Text(payload));
-        }
-    } else {
-        // DEBUG: 即使没有状态，也发送一条消息证明连接成功
-        let payload = serde_json::json!({
-            "type": "debug_info",
-            "message": "Connected! Waiting for host push..."
-        }).to_string();
-        let _ = out_tx.send(Message::Text(payload));
-    }
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SwitchQualityResponse {
+    /// 与切换前相同，播放器重新加载即可。
+    url: String,
+    quality: u32,
+    state: RoomState,
+}
 
-    let (mut ws_sender, mut ws_receiver) = socket.split();
-    let mut send_task: JoinHandle<()> = tokio::spawn(async move {
-        while let Some(msg) = out_rx.recv().await {
-            if ws_sender.send(msg).await.is_err() {
-                break;
-            }
-        }
-    });
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KickRequest {
+    room: String,
+    password: String,
+    temp_user: String,
+    /// 被移出成员的公开标识。
+    target: String,
+}
 
-    let manager = state.manager.clone();
-    let hub = state.hub.clone();
-    let recv_ctx = ctx.clone();
-    let recv_client_id = client_id.clone();
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = ws_receiver.next().await {
-            if let Err(err) = handle_ws_message(msg, &manager, &hub, &recv_ctx).await {
-                warn!("ws message error: {err:?}");
-                let _ = hub
-                    .send_to(
-                        &recv_ctx.room,
-                        &recv_client_id,
-                        WsOutgoing::error(err.message.clone()),
-                    )
-                    .await;
-            }
-        }
-    });
+#[derive(Debug, Serialize)]
+struct KickResponse {
+    disconnected: usize,
+}
 
-    tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
-    }
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PasswordRequest {
+    room: String,
+    password: String,
+    temp_user: String,
+    new_password: String,
+}
 
-    state.hub.unregister(&ctx.room, &client_id).await;
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CoHostRequest {
+    room: String,
+    password: String,
+    temp_user: String,
+    /// 目标成员的公开标识。
+    target: String,
 }
 
-async fn handle_ws_message(
-    msg: Message,
-    manager: &Arc<Manager>,
-    hub: &Arc<Hub>,
-    ctx: &WsContext,
-) -> Result<(), ApiError> {
-    match msg {
-        Message::Text(text) => {
-            let incoming: WsIncoming = match serde_json::from_str(&text) {
-                Ok(v) => v,
-                Err(e) => {
-                    warn!("ws deserialize error: {} | input: {}", e, text);
-                    return Err(ApiError::bad_request("invalid message format"));
-                }
-            };
-            match incoming.r#type.as_str() {
-                "host_update" => {
-                    let state = incoming
-                        .state
-                        .ok_or_else(|| ApiError::bad_request("state required"))?;
-                    let updated = manager
-                        .update_state(&ctx.room, &ctx.temp_user, state, ctx.is_host)
-                        .await?;
-                    hub.broadcast_state(&ctx.room, &updated).await;
-                }
-                "member_ping" => {
-                    manager.touch_member(&ctx.room, &ctx.temp_user).await;
-                }
-                _ => return Err(ApiError::bad_request("unknown message type")),
-            }
-        }
-        Message::Close(_) => {}
-        _ => {}
-    }
-    Ok(())
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RatesRequest {
+    room: String,
+    password: String,
+    temp_user: String,
+    /// 为 null 时取消限制。
+    rates: Option<Vec<f64>>,
 }
 
 #[derive(Debug, Deserialize)]
-struct WsIncoming {
-    #[serde(rename = "type")]
-    r#type: String,
-    state: Option<RoomState>,
+#[serde(rename_all = "camelCase")]
+struct ResetRequest {
+    room: String,
+    password: String,
+    temp_user: String,
 }
 
-#[derive(Debug, Serialize)]
-struct WsOutgoing {
-    #[serde(rename = "type")]
-    r#type: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    state: Option<RoomState>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    error: Option<String>,
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleRequest {
+    room: String,
+    password: String,
+    temp_user: String,
+    /// 计划起播时刻（服务器毫秒时间戳），为 null 时取消计划。
+    start_at: Option<i64>,
 }
 
-impl WsOutgoing {
-    fn error(msg: impl Into<String>) -> Self {
-        Self {
-            r#type: "error".into(),
-            state: None,
-            error: Some(msg.into()),
-        }
-    }
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleResponse {
+    scheduled_start: Option<i64>,
 }
 
-/// 简化的错误响应封装，返回统一 JSON。
-#[derive(Debug)]
-struct ApiError {
-    status: StatusCode,
-    message: String,
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MemberInfo {
+    /// 成员的公开标识，房主踢出、禁言等操作以此指定对象。
+    id: String,
+    /// 前端通过 `X-Client-Id` 传入的客户端标识，仅用于分组展示。
+    client_id: Option<String>,
+    is_host: bool,
+    idle_secs: u64,
 }
 
-impl ApiError {
-    fn bad_request(msg: impl Into<String>) -> Self {
-        Self {
-            status: StatusCode::BAD_REQUEST,
-            message: msg.into(),
-        }
-    }
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RatesResponse {
+    allowed_rates: Option<Vec<f64>>,
+}
 
-    fn forbidden(msg: impl Into<String>) -> Self {
-        Self {
-            status: StatusCode::FORBIDDEN,
-            message: msg.into(),
+/// 媒体来源的类别，房主可按类别限制房间内允许 resolve 的来源。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SourceKind {
+    Local,
+    Remote,
+    Bili,
+}
+
+impl SourceKind {
+    /// 与 `resolve_source` 的分支顺序一致。
+    fn of(path: &str) -> Self {
+        if is_bilibili_source(path).is_some() {
+            Self::Bili
+        } else if path.starts_with("http://") || path.starts_with("https://") {
+            Self::Remote
+        } else {
+            Self::Local
         }
     }
 
-    fn not_found(msg: impl Into<String>) -> Self {
-        Self {
-            status: StatusCode::NOT_FOUND,
-            message: msg.into(),
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::Remote => "remote",
+            Self::Bili => "bili",
         }
     }
 }
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        let body = Json(json!({ "error": self.message }));
-        (self.status, body).into_response()
-    }
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SourcesRequest {
+    room: String,
+    password: String,
+    temp_user: String,
+    /// 为 null 时允许所有来源。
+    sources: Option<Vec<SourceKind>>,
 }
 
-impl std::fmt::Display for ApiError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.message)
-    }
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SourcesResponse {
+    allowed_sources: Option<BTreeSet<SourceKind>>,
 }
 
-impl std::error::Error for ApiError {}
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WhoamiResponse {
+    temp_user: String,
+    member_id: String,
+    /// host / member / spectator；未开启成员控制时普通成员为 spectator。
+    role: &'static str,
+    primary_host: bool,
+    can_control: bool,
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct RoomState {
-    pub url: String,
-    pub title: String,
-    pub current_time: f64,
-    pub duration: f64,
-    pub paused: bool,
-    pub playback_rate: f64,
-    pub source_type: String,
-    pub updated_at: i64,
+struct HostsResponse {
+    primary_host: Option<String>,
+    hosts: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaRootRequest {
+    path: String,
+    /// 界面上显示的名称，缺省取目录名。
     #[serde(default)]
-    pub cover: Option<String>,
+    label: Option<String>,
 }
 
-#[derive(Debug, Clone)]
-struct Room {
-    password: String,
-    host_id: Option<String>,
-    state: Option<RoomState>,
-    members: HashMap<String, Instant>,
-    last_update: Option<Instant>,
+#[derive(Debug, Deserialize)]
+struct MediaRootsRequest {
+    roots: Vec<MediaRootRequest>,
 }
 
-#[derive(Debug, Clone)]
-enum MediaTarget {
-    Local(PathBuf),
-    Remote(RemoteTarget),
+#[derive(Debug, Serialize)]
+struct MediaRootResponse {
+    media_root: Option<String>,
 }
 
-#[derive(Debug, Clone)]
-struct MediaToken {
-    target: MediaTarget,
-    expires_at: Instant,
+#[derive(Debug, Serialize)]
+struct MediaRootsResponse {
+    roots: Vec<MediaRoot>,
 }
 
-#[derive(Debug, Clone)]
-enum RemoteStrategy {
-    Redirect,
-    ProxyWithHeaders,
+/// 带标签的媒体根目录；同时配置多个时按顺序匹配。
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct MediaRoot {
+    label: String,
+    path: PathBuf,
 }
 
-#[derive(Debug, Clone)]
-struct RemoteTarget {
-    url: String,
-    strategy: RemoteStrategy,
+#[derive(Debug, Serialize)]
+struct LibraryResponse {
+    roots: Vec<LibraryGroup>,
 }
 
-#[derive(Debug)]
-struct Manager {
-    rooms: RwLock<HashMap<String, Room>>,
-    media_tokens: RwLock<HashMap<String, MediaToken>>,
-    media_root: RwLock<Option<PathBuf>>,
-    room_ttl: Duration,
-    token_ttl: Duration,
-    allow_member_control: bool,
+/// 媒体库中一个根目录下的全部条目。
+#[derive(Debug, Serialize)]
+struct LibraryGroup {
+    label: String,
+    path: PathBuf,
+    files: Vec<library::LibraryEntry>,
 }
 
-impl Manager {
-    fn new(media_root: Option<PathBuf>, allow_member_control: bool) -> Self {
-        Self {
-            rooms: RwLock::new(HashMap::new()),
-            media_tokens: RwLock::new(HashMap::new()),
-            media_root: RwLock::new(media_root.map(clean_path)),
-            room_ttl: Duration::from_secs(30 * 60),
-            token_ttl: Duration::from_secs(60 * 60),
-            allow_member_control,
-        }
+async fn join_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<JoinRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let client_id = client_id_header(&headers);
+    let outcome = state
+        .manager
+        .join_room_as(
+            &req.room,
+            &req.password,
+            JoinOptions {
+                client_id: client_id.as_deref(),
+                resume_token: req.resume_token.as_deref(),
+                temp_user: req.temp_user.as_deref(),
+                mode: req.mode,
+            },
+        )
+        .await?;
+    info!(
+        "join room={} user={} client={}",
+        req.room.trim(),
+        outcome.temp_user,
+        client_id.as_deref().unwrap_or("-")
+    );
+    if let Some(hosts) = outcome.hosts {
+        info!("room={} primary host reclaimed", req.room.trim());
+        let msg = WsOutgoing {
+            hosts: Some(hosts),
+            ..WsOutgoing::kind("host_changed")
+        };
+        state.hub.broadcast_critical(req.room.trim(), &msg).await;
     }
+    Ok(Json(JoinResponse {
+        temp_user: outcome.temp_user,
+        member_id: outcome.member_id,
+        role: if outcome.is_host {
+            "host".into()
+        } else {
+            "member".into()
+        },
+        host_resume_token: outcome.host_resume_token,
+        restored: outcome.restored,
+    }))
+}
 
-    fn spawn_cleanup(self: &Arc<Self>) {
-        let weak = Arc::downgrade(self);
-        tokio::spawn(async move {
-            let mut ticker = tokio_time::interval(Duration::from_secs(60));
-            loop {
-                ticker.tick().await;
-                if let Some(manager) = weak.upgrade() {
-                    manager.cleanup().await;
-                } else {
-                    break;
-                }
-            }
-        });
+async fn media_resolve(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<MediaResolveRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let resolved = state
+        .manager
+        .resolve_media_with(
+            &req.room,
+            &req.password,
+            &req.temp_user,
+            &req.path,
+            &req.options,
+        )
+        .await?;
+    let expires_at = token_expires_at(state.manager.token_ttl);
+
+    if req.auto_publish {
+        // 自动创建并广播初始的 room_state
+        let mut initial_state = RoomState {
+            url: resolved.url.clone(),
+            title: default_title(&req.path),
+            current_time: 0.0,
+            duration: 0.0,
+            paused: true,
+            playback_rate: 1.0,
+            source_type: resolved.source_type.clone(),
+            updated_at: now_millis(),
+            cover: resolved.cover.clone(),
+            quality: resolved.quality,
+            effective_at: None,
+            fit_mode: FitMode::Contain,
+            crop: None,
+            volume: None,
+            muted: None,
+        };
+        // 标题取自输入路径，可能很长。
+        state.manager.state_limits.clamp(&mut initial_state);
+
+        // 更新房间状态
+        let updated_state = state
+            .manager
+            .update_state(&req.room, &req.temp_user, initial_state, true)
+            .await?;
+        state.manager.set_source(&req.room, &req.path).await;
+
+        // 广播给所有客户端
+        state.hub.broadcast_state(&req.room, &updated_state).await;
     }
 
-    async fn join_room(&self, name: &str, password: &str) -> Result<(String, bool), ApiError> {
-        let name = name.trim();
-        let password = password.trim();
-        if name.is_empty() || password.is_empty() {
-            return Err(ApiError::bad_request("room name and password required"));
-        }
-        let temp_user = Uuid::new_v4().to_string();
-        let mut rooms = self.rooms.write().await;
-        let room = rooms.entry(name.to_string()).or_insert_with(|| Room {
-            password: password.to_string(),
-            host_id: None,
-            state: None,
-            members: HashMap::new(),
-            last_update: None,
-        });
-        if room.password != password {
-            return Err(ApiError::bad_request("room password mismatch"));
-        }
-        let mut is_host = false;
-        if room.host_id.is_none() {
-            room.host_id = Some(temp_user.clone());
-            is_host = true;
-        }
-        room.members.insert(temp_user.clone(), Instant::now());
-        Ok((temp_user, is_host))
+    let origin = state.manager.media_origin(&headers);
+    Ok(Json(
+        MediaResolveResponse::new(resolved, expires_at).at_origin(origin.as_deref()),
+    ))
+}
+
+/// 一次请求解析多个路径，并发但有上限；不会自动发布任何一项。
+async fn media_resolve_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<BatchResolveRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if req.paths.len() > MAX_BATCH_RESOLVE {
+        return Err(ApiError::bad_request(format!(
+            "at most {MAX_BATCH_RESOLVE} paths per batch"
+        )));
     }
+    let BatchResolveRequest {
+        room,
+        password,
+        temp_user,
+        paths,
+        options,
+    } = req;
+    let auth = Arc::new((room, password, temp_user, options));
+    let mut results = stream::iter(paths.into_iter().enumerate())
+        .map(|(idx, path)| {
+            let manager = state.manager.clone();
+            let auth = auth.clone();
+            async move {
+                let (room, password, temp_user, options) = &*auth;
+                let res = manager
+                    .resolve_media_with(room, password, temp_user, &path, options)
+                    .await;
+                (idx, path, res)
+            }
+        })
+        .buffer_unordered(BATCH_RESOLVE_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    results.sort_by_key(|(idx, ..)| *idx);
 
-    async fn authorize(
-        &self,
-        room_name: &str,
-        password: &str,
-        temp_user: &str,
-    ) -> Result<bool, ApiError> {
-        let rooms = self.rooms.read().await;
-        let room = rooms
-            .get(room_name)
-            .ok_or_else(|| ApiError::forbidden("room not found"))?;
-        if room.password != password {
-            return Err(ApiError::forbidden("room password mismatch"));
-        }
-        if !room.members.contains_key(temp_user) {
-            return Err(ApiError::forbidden("user not in room"));
+    let expires_at = token_expires_at(state.manager.token_ttl);
+    let origin = state.manager.media_origin(&headers);
+    let results = results
+        .into_iter()
+        .map(|(_, path, res)| match res {
+            Ok(resolved) => BatchResolveItem::Ok(
+                MediaResolveResponse::new(resolved, expires_at).at_origin(origin.as_deref()),
+            ),
+            Err(err) => BatchResolveItem::Err {
+                path,
+                error: err.message,
+            },
+        })
+        .collect();
+    Ok(Json(BatchResolveResponse { results }))
+}
+
+fn token_expires_at(ttl: Duration) -> i64 {
+    ((OffsetDateTime::now_utc() + TimeDuration::seconds(ttl.as_secs() as i64))
+        .unix_timestamp_nanos()
+        / 1_000_000)
+        .try_into()
+        .unwrap_or(i64::MAX)
+}
+
+impl MediaResolveResponse {
+    fn new(resolved: ResolvedMedia, expires_at: i64) -> Self {
+        Self {
+            url: resolved.url,
+            token: resolved.token,
+            expires_at,
+            source_type: resolved.source_type,
+            cover: resolved.cover,
+            audio: resolved.audio,
+            dimension: resolved.dimension,
+            quality: resolved.quality,
+            segments: resolved.segments,
+            danmaku_url: resolved.danmaku_url,
         }
-        Ok(room
-            .host_id
-            .as_ref()
-            .map(|id| id == temp_user)
-            .unwrap_or(false))
     }
 
-    async fn touch_member(&self, room_name: &str, temp_user: &str) {
-        if let Some(room) = self.rooms.write().await.get_mut(room_name) {
-            room.members.insert(temp_user.to_string(), Instant::now());
+    /// 媒体在独立端口时把 `/media/` 相对地址改写为该端口的绝对地址。
+    fn at_origin(mut self, origin: Option<&str>) -> Self {
+        let Some(origin) = origin else {
+            return self;
+        };
+        let absolute = |url: &mut String| {
+            if url.starts_with("/media/") {
+                url.insert_str(0, origin);
+            }
+        };
+        absolute(&mut self.url);
+        self.cover.as_mut().map(absolute);
+        self.danmaku_url.as_mut().map(absolute);
+        for segment in self.segments.iter_mut().flatten() {
+            absolute(&mut segment.url);
         }
+        self
     }
+}
 
-    async fn update_state(
-        &self,
-        room_name: &str,
-        _temp_user: &str,
-        mut state: RoomState,
-        is_host: bool,
-    ) -> Result<RoomState, ApiError> {
-        let mut rooms = self.rooms.write().await;
-        let room = rooms
-            .get_mut(room_name)
-            .ok_or_else(|| ApiError::bad_request("room not found"))?;
-        if is_host {
-            state.updated_at = now_millis();
-            room.state = Some(state.clone());
-            room.last_update = Some(Instant::now());
-            return Ok(state);
-        }
+/// 导出房间快照（仅房主），可保存为文件稍后通过 restore 恢复。
+async fn room_snapshot(
+    State(state): State<AppState>,
+    AxumPath(room): AxumPath<String>,
+    Query(query): Query<AuthQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let snapshot = state
+        .manager
+        .snapshot_room(&room, &query.password, &query.temp_user)
+        .await?;
+    // 加密时由口令派生密钥要做大量哈希，放到阻塞线程里。
+    let manager = state.manager.clone();
+    let payload = tokio::task::spawn_blocking(move || manager.seal_snapshot(snapshot))
+        .await
+        .map_err(|_| ApiError::unavailable("snapshot encryption failed"))??;
+    Ok(Json(payload))
+}
 
-        if !self.allow_member_control {
-            return Err(ApiError::forbidden("operation allowed for host only"));
+/// WebSocket 不可用时的只读降级：以 SSE `data:` 推送与 WebSocket 相同的消息。
+async fn room_events_stream(
+    State(state): State<AppState>,
+    AxumPath(room): AxumPath<String>,
+    Query(query): Query<AuthQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    state
+        .manager
+        .authorize(&room, &query.password, &query.temp_user)
+        .await?;
+    let (tx, rx) = mpsc::unbounded_channel::<String>();
+    let welcome = WsOutgoing {
+        seq: Some(state.hub.current_seq(&room)),
+        ..connect_state_message(&state.manager, &room).await
+    };
+    let settings = room_settings_message(&state.manager, &room).await;
+    for msg in [welcome, settings] {
+        if let Ok(payload) = serde_json::to_string(&msg) {
+            let _ = tx.send(payload);
         }
-        let existing = room
-            .state
-            .clone()
-            .ok_or_else(|| ApiError::bad_request("host has not published state"))?;
-        // 成员只能调整播放进度/暂停/倍速，不能切换源。
-        let merged = RoomState {
-            url: existing.url,
-            title: existing.title,
-            duration: existing.duration,
-            source_type: existing.source_type,
-            current_time: state.current_time,
-            paused: state.paused,
-            playback_rate: state.playback_rate,
-            updated_at: now_millis(),
-            cover: existing.cover,
-        };
-        room.state = Some(merged.clone());
-        room.last_update = Some(Instant::now());
-        Ok(merged)
     }
+    let client_id = Uuid::new_v4().to_string();
+    state
+        .hub
+        .register(&room, &client_id, &query.temp_user, ClientSender::Sse(tx))
+        .await;
+    // 客户端断开后 rx 被丢弃，Hub 在下一次广播时自动移除该订阅者。
+    let events = stream::unfold(rx, |mut rx| async move {
+        let data = rx.recv().await?;
+        Some((Ok::<_, Infallible>(Event::default().data(data)), rx))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
 
-    async fn current_state(&self, room_name: &str) -> Option<RoomState> {
-        let rooms = self.rooms.read().await;
-        rooms.get(room_name).and_then(|room| room.state.clone())
-    }
+async fn room_restore(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<RestoreRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let manager = state.manager.clone();
+    let snapshot = tokio::task::spawn_blocking(move || manager.open_snapshot(req.snapshot))
+        .await
+        .map_err(|_| ApiError::unavailable("snapshot decryption failed"))??;
+    let (temp_user, restored) = state
+        .manager
+        .restore_room(&req.room, &req.password, snapshot)
+        .await?;
+    Ok(Json(RestoreResponse {
+        temp_user,
+        role: "host".into(),
+        state: restored,
+    }))
+}
+
+/// 房主设置播放列表，当前视频 `ended` 后按顺序自动切换。
+async fn set_playlist(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<PlaylistRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let playlist = state
+        .manager
+        .set_playlist(&req.room, &req.password, &req.temp_user, req.items)
+        .await?;
+    Ok(Json(PlaylistResponse { playlist }))
+}
+
+/// 导出房间播放列表，任何成员都可以保存或分享。
+async fn export_playlist(
+    State(state): State<AppState>,
+    AxumPath(room): AxumPath<String>,
+    Query(query): Query<AuthQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let export = state
+        .manager
+        .export_playlist(&room, &query.password, &query.temp_user)
+        .await?;
+    Ok(Json(export))
+}
+
+async fn import_playlist(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<PlaylistImportRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let playlist = state
+        .manager
+        .import_playlist(
+            &req.room,
+            &req.password,
+            &req.temp_user,
+            req.playlist,
+            req.append,
+        )
+        .await?;
+    Ok(Json(PlaylistResponse { playlist }))
+}
+
+async fn media_favorites(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<FavoritesRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    state
+        .manager
+        .authorize_host(&req.room, &req.password, &req.temp_user)
+        .await?;
+    let media_id =
+        extract_fav_id(&req.list).ok_or_else(|| ApiError::bad_request("invalid favorites id"))?;
+    let res = state
+        .manager
+        .fetch_favorites(media_id, req.cookie.as_deref())
+        .await?;
+    Ok(Json(res))
+}
+
+/// 不需要房间凭据。未显式带 Cookie 时会用本机的登录会话，此时只接受本机请求。
+async fn bili_probe(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    ApiJson(req): ApiJson<BiliProbeRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if req.cookie.is_none() {
+        ensure_local(peer)?;
+    }
+    let res = state
+        .manager
+        .probe_bilibili(&req.input, req.quality, req.cookie.as_deref())
+        .await?;
+    Ok(Json(res))
+}
+
+/// 仅限本机：请求地址固定为 B 站 playurl 接口，参数只有 BV 号和数字，不能借此代理任意内容。
+async fn bili_raw_playurl(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    ApiJson(req): ApiJson<RawPlayUrlRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    ensure_local(peer)?;
+    let raw = state.manager.raw_playurl(&req).await?;
+    Ok(Json(raw))
+}
+
+async fn kick_member(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<KickRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let target = state
+        .manager
+        .kick_member(&req.room, &req.password, &req.temp_user, &req.target)
+        .await?;
+    let disconnected = state
+        .hub
+        .kick(&req.room, &target, close_with(CLOSE_KICKED, "kicked"))
+        .await;
+    Ok(Json(KickResponse { disconnected }))
+}
+
+/// 房主更换房间密码，非房主成员的连接被断开，需用新密码重连。
+async fn change_password(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<PasswordRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let keep = state
+        .manager
+        .change_password(&req.room, &req.password, &req.temp_user, &req.new_password)
+        .await?;
+    let disconnected = state
+        .hub
+        .close_except(
+            &req.room,
+            &keep,
+            close_with(CLOSE_PASSWORD_CHANGED, "password changed"),
+        )
+        .await;
+    Ok(Json(KickResponse { disconnected }))
+}
+
+/// 房主设置允许的倍速档位，并广播给成员用于渲染按钮。
+async fn set_allowed_rates(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<RatesRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let allowed_rates = state
+        .manager
+        .set_allowed_rates(&req.room, &req.password, &req.temp_user, req.rates)
+        .await?;
+    let msg = WsOutgoing {
+        allowed_rates: allowed_rates.clone(),
+        ..WsOutgoing::kind("allowed_rates")
+    };
+    state.hub.broadcast(&req.room, &msg).await;
+    Ok(Json(RatesResponse { allowed_rates }))
+}
+
+/// 设置或取消计划起播，广播 `schedule` 供客户端显示倒计时。
+async fn set_schedule(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<ScheduleRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let scheduled_start = state
+        .manager
+        .set_schedule(&req.room, &req.password, &req.temp_user, req.start_at)
+        .await?;
+    let msg = WsOutgoing {
+        scheduled_start,
+        server_time: Some(now_millis()),
+        ..WsOutgoing::kind("schedule")
+    };
+    state.hub.broadcast(&req.room, &msg).await;
+    if let Some(at) = scheduled_start {
+        spawn_scheduled_start(state, req.room, at);
+    }
+    Ok(Json(ScheduleResponse { scheduled_start }))
+}
+
+/// 房主清空当前播放内容，房间与成员保留，成员收到 `room_reset` 后清空播放器。
+async fn reset_room(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<ResetRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    state
+        .manager
+        .authorize_host(&req.room, &req.password, &req.temp_user)
+        .await?;
+    let revoked = state.manager.reset_room(&req.room, &req.temp_user).await?;
+    state.hub.broadcast(&req.room, &room_reset_message()).await;
+    Ok(Json(json!({ "revoked": revoked })))
+}
+
+/// 到点后开始播放；计划在此之前被取消或改期时 `fire_schedule` 不做任何事。
+fn spawn_scheduled_start(state: AppState, room: String, at: i64) {
+    tokio::spawn(async move {
+        let wait = (at - now_millis()).max(0) as u64;
+        tokio_time::sleep(Duration::from_millis(wait)).await;
+        if let Some(started) = state.manager.fire_schedule(&room, at).await {
+            info!("room={} scheduled start fired", room);
+            let msg = WsOutgoing {
+                reason: Some("scheduled_start".into()),
+                server_time: Some(now_millis()),
+                ..WsOutgoing::with_state("room_state", started)
+            };
+            state.hub.broadcast(&room, &msg).await;
+        }
+    });
+}
+
+async fn set_allowed_sources(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<SourcesRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let allowed_sources = state
+        .manager
+        .set_allowed_sources(&req.room, &req.password, &req.temp_user, req.sources)
+        .await?;
+    let msg = WsOutgoing {
+        allowed_sources: allowed_sources.clone(),
+        ..WsOutgoing::kind("allowed_sources")
+    };
+    state.hub.broadcast(&req.room, &msg).await;
+    Ok(Json(SourcesResponse { allowed_sources }))
+}
+
+async fn promote_host(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<CoHostRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let hosts = state
+        .manager
+        .set_co_host(&req.room, &req.password, &req.temp_user, &req.target, true)
+        .await?;
+    Ok(Json(hosts))
+}
+
+async fn demote_host(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<CoHostRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let hosts = state
+        .manager
+        .set_co_host(&req.room, &req.password, &req.temp_user, &req.target, false)
+        .await?;
+    Ok(Json(hosts))
+}
+
+async fn transfer_host(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<CoHostRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let hosts = state
+        .manager
+        .transfer_host(&req.room, &req.password, &req.temp_user, &req.target)
+        .await?;
+    Ok(Json(hosts))
+}
+
+async fn check_room(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<CheckRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let members = state
+        .manager
+        .check_credentials(&req.room, &req.password)
+        .await?;
+    Ok(Json(json!({ "ok": true, "members": members })))
+}
+
+async fn whoami(
+    State(state): State<AppState>,
+    AxumPath(room): AxumPath<String>,
+    Query(query): Query<AuthQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let me = state
+        .manager
+        .whoami(&room, &query.password, &query.temp_user)
+        .await?;
+    Ok(Json(me))
+}
+
+/// 增量拉取房间事件，供不便维持长连接的客户端轮询。
+async fn room_events(
+    State(state): State<AppState>,
+    AxumPath(room): AxumPath<String>,
+    Query(query): Query<EventsQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let events = state
+        .manager
+        .room_events(&room, &query.password, &query.temp_user, query.since)
+        .await?;
+    Ok(Json(events))
+}
+
+const CLIENT_ID_HEADER: &str = "x-client-id";
+const MAX_CLIENT_ID_LEN: usize = 64;
+
+/// 读取 `X-Client-Id`，空值或超长时忽略。
+fn client_id_header(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CLIENT_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && v.len() <= MAX_CLIENT_ID_LEN)
+        .map(str::to_string)
+}
+
+async fn list_members(
+    State(state): State<AppState>,
+    AxumPath(room): AxumPath<String>,
+    Query(query): Query<AuthQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let members = state
+        .manager
+        .members(&room, &query.password, &query.temp_user)
+        .await?;
+    Ok(Json(json!({ "members": members })))
+}
+
+async fn sync_report(
+    State(state): State<AppState>,
+    AxumPath(room): AxumPath<String>,
+    Query(query): Query<AuthQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let report = state
+        .manager
+        .sync_report(&room, &query.password, &query.temp_user)
+        .await?;
+    Ok(Json(report))
+}
+
+async fn set_media_root(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<MediaRootRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let path = state
+        .manager
+        .set_media_root(&req.path, req.label.as_deref())
+        .await?;
+    Ok(Json(MediaRootResponse {
+        media_root: path.to_str().map(|s| s.to_string()),
+    }))
+}
+
+/// 整体替换媒体根列表。
+async fn set_media_roots(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<MediaRootsRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let roots = state.manager.set_media_roots(&req.roots).await?;
+    Ok(Json(MediaRootsResponse { roots }))
+}
+
+async fn get_media_roots(State(state): State<AppState>) -> impl IntoResponse {
+    let roots = state.manager.media_roots.read().await.clone();
+    Json(MediaRootsResponse { roots })
+}
+
+/// 根路径的状态页，方便确认服务已启动。
+async fn status_page(State(state): State<AppState>) -> impl IntoResponse {
+    let html = format!(
+        "<!doctype html>\n<html><head><meta charset=\"utf-8\"><title>sync service</title></head>\n\
+         <body><h1>sync service is running</h1>\n\
+         <p>version {}</p>\n<p>active rooms: {}</p>\n\
+         <p><a href=\"/healthz\">/healthz</a></p></body></html>\n",
+        env!("CARGO_PKG_VERSION"),
+        state.manager.active_rooms().await,
+    );
+    axum::response::Html(html)
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = state.hub.metrics.render();
+    if let Some(breaker) = &state.manager.bili_breaker {
+        body.push_str(&format!(
+            "# HELP vo_sync_bili_breaker_state Bilibili resolve circuit breaker (0 closed, 1 open, 2 half-open).\n\
+             # TYPE vo_sync_bili_breaker_state gauge\n\
+             vo_sync_bili_breaker_state {}\n\
+             # HELP vo_sync_bili_breaker_trips_total Times the Bilibili circuit breaker opened.\n\
+             # TYPE vo_sync_bili_breaker_trips_total counter\n\
+             vo_sync_bili_breaker_trips_total {}\n",
+            breaker.state().gauge(),
+            breaker.trips()
+        ));
+    }
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        body,
+    )
+}
+
+/// 按媒体根分组列出媒体库索引，条目的 id 可以 `lib://<id>` 形式传给 resolve。
+async fn media_library(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    let roots = state.manager.library_groups().await?;
+    Ok(Json(LibraryResponse { roots }))
+}
+
+async fn media_reindex(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    let indexed = state.manager.reindex_library().await?;
+    Ok(Json(json!({ "indexed": indexed })))
+}
+
+async fn get_media_root(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    let roots = state.manager.media_roots.read().await;
+    Ok(Json(MediaRootResponse {
+        media_root: roots
+            .first()
+            .and_then(|root| root.path.to_str().map(|s| s.to_string())),
+    }))
+}
+
+/// 管理接口只接受本机请求；未经 connect_info 启动时拿不到地址，一律拒绝。
+fn ensure_local(peer: Option<ConnectInfo<SocketAddr>>) -> Result<(), ApiError> {
+    match peer {
+        Some(ConnectInfo(addr)) if addr.ip().is_loopback() => Ok(()),
+        _ => Err(ApiError::forbidden("admin endpoints are local only")),
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    key: String,
+    age_secs: u64,
+    hits: u64,
+    /// 缓存值，敏感内容已打码。
+    value: String,
+}
+
+/// 列出服务端缓存（目前只有 wbi mixin key）。
+async fn admin_cache(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<impl IntoResponse, ApiError> {
+    ensure_local(peer)?;
+    let entries = state.manager.cache_entries().await;
+    Ok(Json(json!({ "entries": entries })))
+}
+
+async fn admin_cache_clear(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<impl IntoResponse, ApiError> {
+    ensure_local(peer)?;
+    let cleared = state.manager.clear_cache().await;
+    info!("admin cleared {cleared} cache entries");
+    Ok(Json(json!({ "cleared": cleared })))
+}
+
+async fn admin_connections(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<impl IntoResponse, ApiError> {
+    ensure_local(peer)?;
+    let connections = state.hub.connections().await;
+    Ok(Json(json!({ "connections": connections })))
+}
+
+async fn admin_playback_errors(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<impl IntoResponse, ApiError> {
+    ensure_local(peer)?;
+    let rooms = state.manager.playback_errors().await;
+    Ok(Json(json!({ "rooms": rooms })))
+}
+
+/// 断开卡住的连接（例如一直占着代理流的页面），客户端收到 `CLOSE_ADMIN` 关闭码。
+async fn admin_close_connection(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    AxumPath(id): AxumPath<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    ensure_local(peer)?;
+    let closed = state
+        .hub
+        .close_connection(&id, close_with(CLOSE_ADMIN, "closed by admin"))
+        .await;
+    if !closed {
+        return Err(ApiError::not_found("connection not found"));
+    }
+    info!("admin closed connection {id}");
+    Ok(Json(json!({ "closed": id })))
+}
+
+/// 查询 token 最近一次上游探测的结果，尚未探测过时 `validation` 为 null。
+async fn media_token_status(
+    State(state): State<AppState>,
+    AxumPath(token): AxumPath<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let validation = state.manager.token_validation(&token).await?;
+    Ok(Json(json!({ "token": token, "validation": validation })))
+}
+
+async fn media_prewarm(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<PrewarmRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let token = req.token.strip_prefix("/media/").unwrap_or(&req.token);
+    Ok(Json(state.manager.prewarm(token).await?))
+}
+
+/// 原地替换 B 站 token 的上游直链，URL 不变；新的清晰度随房间状态广播。
+async fn media_switch_quality(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<SwitchQualityRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let token = req.token.strip_prefix("/media/").unwrap_or(&req.token);
+    let (quality, room_state) = state
+        .manager
+        .switch_quality(&req.room, &req.password, &req.temp_user, token, req.quality)
+        .await?;
+    state.hub.broadcast_state(&req.room, &room_state).await;
+    Ok(Json(SwitchQualityResponse {
+        url: room_state.url.clone(),
+        quality,
+        state: room_state,
+    }))
+}
+
+async fn media_stream(
+    State(state): State<AppState>,
+    AxumPath(token): AxumPath<String>,
+    req: Request<Body>,
+) -> Result<Response, ApiError> {
+    if let Ok(target) = state.manager.open_remote(&token).await {
+        match target.strategy {
+            RemoteStrategy::Redirect => {
+                ssrf::ensure_public_url(&target.url).await?;
+                return Ok(Response::builder()
+                    .status(StatusCode::TEMPORARY_REDIRECT)
+                    .header(
+                        axum::http::header::LOCATION,
+                        HeaderValue::from_str(&target.url).unwrap_or(HeaderValue::from_static("")),
+                    )
+                    .body(Body::empty())
+                    .unwrap());
+            }
+            RemoteStrategy::ProxyWithHeaders => {
+                let range = req
+                    .headers()
+                    .get(axum::http::header::RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                // 命中缓存直接返回；可缓存的分段回源后整段读入再写缓存。
+                let cache = state
+                    .manager
+                    .segment_cache
+                    .as_ref()
+                    .zip(range.as_deref())
+                    .filter(|(cache, range)| cache.accepts(range));
+                if let Some((cache, range)) = cache {
+                    if let Some(segment) = cache.get(&token, range) {
+                        return Ok(segment.into_response());
+                    }
+                }
+                let timeouts = state.manager.proxy_timeouts;
+                let client = init_client_with_connect_timeout(timeouts.connect)
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
+                let mut builder = client.get(&target.url);
+                if let Some(range) = req.headers().get(axum::http::header::RANGE) {
+                    builder = builder.header(axum::http::header::RANGE, range.clone());
+                }
+                builder = builder.header(axum::http::header::REFERER, "https://www.bilibili.com/");
+                let upstream = send_upstream(builder, timeouts).await?;
+                let status =
+                    StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::OK);
+                let mut resp_builder = Response::builder().status(status);
+                let headers = upstream.headers();
+                copy_header(headers, axum::http::header::CONTENT_TYPE, &mut resp_builder);
+                copy_header(
+                    headers,
+                    axum::http::header::CONTENT_LENGTH,
+                    &mut resp_builder,
+                );
+                copy_header(
+                    headers,
+                    axum::http::header::ACCEPT_RANGES,
+                    &mut resp_builder,
+                );
+                copy_header(
+                    headers,
+                    axum::http::header::CONTENT_RANGE,
+                    &mut resp_builder,
+                );
+                fill_length_headers(headers, &mut resp_builder);
+                if let Some((cache, range)) = cache {
+                    if status == StatusCode::PARTIAL_CONTENT {
+                        let body = upstream
+                            .bytes()
+                            .await
+                            .map_err(|e| ApiError::not_found(format!("upstream error: {e}")))?;
+                        let segment = segment_cache::Segment {
+                            status,
+                            headers: resp_builder.headers_ref().cloned().unwrap_or_default(),
+                            body,
+                        };
+                        cache.insert(&token, range, segment.clone());
+                        return Ok(segment.into_response());
+                    }
+                }
+                let body = match state.manager.proxy_readahead {
+                    Some(bytes) => relay_readahead(upstream.bytes_stream(), bytes),
+                    None => relay_bounded(upstream.bytes_stream(), PROXY_RELAY_CHUNKS),
+                };
+                return resp_builder
+                    .body(body)
+                    .map_err(|e| ApiError::bad_request(format!("build body failed: {e}")));
+            }
+        }
+    }
+
+    let path = match state.manager.open_media(&token).await? {
+        MediaTarget::Transcode(target) => {
+            let ffmpeg = state
+                .manager
+                .ffmpeg
+                .as_deref()
+                .ok_or_else(|| ApiError::bad_request("transcoding disabled"))?;
+            // 转码输出无法定位，只接受从头读取的请求。
+            if !transcode_range_allowed(req.headers()) {
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(axum::http::header::CONTENT_RANGE, "bytes */*")
+                    .body(Body::empty())
+                    .unwrap());
+            }
+            let slot = state
+                .manager
+                .acquire_transcode(&token)
+                .ok_or_else(|| ApiError::too_many_requests("too many transcodes for this token"))?;
+            let body = transcode::stream(ffmpeg, &target, slot)?;
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(axum::http::header::CONTENT_TYPE, "video/mp4")
+                .header(axum::http::header::ACCEPT_RANGES, "none")
+                .body(body)
+                .unwrap());
+        }
+        MediaTarget::Inline(inline) => {
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(axum::http::header::CONTENT_TYPE, inline.content_type)
+                .body(Body::from(inline.body))
+                .unwrap());
+        }
+        MediaTarget::Concat(parts) => {
+            let range = req
+                .headers()
+                .get(axum::http::header::RANGE)
+                .and_then(|v| v.to_str().ok());
+            return concat::serve(&parts, range).await;
+        }
+        MediaTarget::Local(path) => path,
+        MediaTarget::Remote(_) => return Err(ApiError::bad_request("remote requires redirect")),
+    };
+    let file = File::open(&path)
+        .await
+        .map_err(|_| ApiError::not_found("media not found"))?;
+    let stream = ReaderStream::new(file);
+    let body = Body::from_stream(stream);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(body)
+        .unwrap())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WsQuery {
+    room: String,
+    password: String,
+    temp_user: String,
+    /// `debug=1` 时该连接额外接收 `debug` 诊断消息。
+    #[serde(default)]
+    debug: Option<String>,
+    /// `ack=1` 时关键消息带 `ackId`，客户端需回复 `ack`，否则服务端会重发。
+    #[serde(default)]
+    ack: Option<String>,
+}
+
+async fn ws_handler(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    query: Result<Query<WsQuery>, QueryRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let ws = match ws {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("ws upgrade rejection: {}", e);
+            return Ok((e.status(), e.to_string()).into_response());
+        }
+    };
+    let query = match query {
+        Ok(v) => v.0,
+        Err(e) => {
+            warn!("ws query rejection: {}", e);
+            return Ok((e.status(), e.to_string()).into_response());
+        }
+    };
+    let peer_ip = peer.as_ref().map(|ConnectInfo(addr)| addr.ip());
+    // 未经 connect_info 启动（如测试直接挂路由）时拿不到对端地址，不做限制。
+    let slot = match peer {
+        Some(ConnectInfo(addr)) => Some(state.hub.acquire_ip_slot(addr.ip()).ok_or_else(|| {
+            warn!("ws connection limit reached ip={}", addr.ip());
+            ApiError::too_many_requests("too many connections from this address")
+        })?),
+        None => None,
+    };
+    if let Err(e) = state
+        .manager
+        .authorize(&query.room, &query.password, &query.temp_user)
+        .await
+    {
+        warn!(
+            "ws authorize failed room={} user={} err={}",
+            query.room, query.temp_user, e
+        );
+        // return a plain 403 for clarity
+        return Ok((StatusCode::FORBIDDEN, e.to_string()).into_response());
+    }
+    let ctx = WsContext {
+        room: query.room.clone(),
+        temp_user: query.temp_user.clone(),
+        client_id: Uuid::new_v4().to_string(),
+        debug: query
+            .debug
+            .as_deref()
+            .is_some_and(|v| v == "1" || v.eq_ignore_ascii_case("true")),
+        acks: query
+            .ack
+            .as_deref()
+            .is_some_and(|v| v == "1" || v.eq_ignore_ascii_case("true")),
+    };
+    Ok(ws.on_upgrade(move |socket| async move {
+        handle_socket(socket, state, ctx, peer_ip).await;
+        drop(slot);
+    }))
+}
+
+#[derive(Clone)]
+struct WsContext {
+    room: String,
+    temp_user: String,
+    /// 本连接在 Hub 中的 id，`ack` 按连接确认。
+    client_id: String,
+    debug: bool,
+    acks: bool,
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState, ctx: WsContext, peer: Option<IpAddr>) {
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Message>();
+    let client_id = ctx.client_id.clone();
+    state
+        .hub
+        .register(
+            &ctx.room,
+            &client_id,
+            &ctx.temp_user,
+            ClientSender::Ws(out_tx.clone()),
+        )
+        .await;
+    if let Some(peer) = peer {
+        state.hub.set_peer(&ctx.room, &client_id, peer).await;
+    }
+    if ctx.debug {
+        state.hub.enable_debug(&ctx.room, &client_id).await;
+    }
+    if ctx.acks {
+        state.hub.enable_acks(&ctx.room, &client_id).await;
+    }
+
+    let welcome = WsOutgoing {
+        seq: Some(state.hub.current_seq(&ctx.room)),
+        ..connect_state_message(&state.manager, &ctx.room).await
+    };
+    let settings = room_settings_message(&state.manager, &ctx.room).await;
+    let markers = state.manager.markers(&ctx.room).await;
+    let markers = (!markers.is_empty()).then(|| markers_message(markers));
+    for msg in [welcome, settings].into_iter().chain(markers) {
+        if let Ok(payload) = serde_json::to_string(&msg) {
+            let _ = out_tx.send(Message::Text(payload));
+        }
+    }
+
+    let (mut ws_sender, mut ws_receiver) = socket.split();
+    let mut send_task: JoinHandle<()> = tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            let closing = matches!(msg, Message::Close(_));
+            if ws_sender.send(msg).await.is_err() || closing {
+                break;
+            }
+        }
+    });
+
+    let manager = state.manager.clone();
+    let hub = state.hub.clone();
+    let recv_ctx = ctx.clone();
+    let recv_client_id = client_id.clone();
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(Ok(msg)) = ws_receiver.next().await {
+            if let Err(err) = handle_ws_message(msg, &manager, &hub, &recv_ctx).await {
+                warn!("ws message error: {err:?}");
+                let _ = hub
+                    .send_to(
+                        &recv_ctx.room,
+                        &recv_client_id,
+                        WsOutgoing::error(err.message.clone()),
+                    )
+                    .await;
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = (&mut send_task) => recv_task.abort(),
+        _ = (&mut recv_task) => send_task.abort(),
+    }
+
+    state.hub.unregister(&ctx.room, &client_id).await;
+    let online = state.hub.connected_users(&ctx.room).await;
+    if let Some(tally) = state.manager.retally_ready(&ctx.room, &online).await {
+        broadcast_ready(&state.hub, &ctx.room, tally).await;
+    }
+    schedule_host_reassign(state, ctx).await;
+}
+
+/// 主房主的最后一条连接断开后等待宽限期：期间重连则保持不变，
+/// 否则把主房主移交给仍在线的成员，网络短暂抖动不会丢失控制权。
+async fn schedule_host_reassign(state: AppState, ctx: WsContext) {
+    let Some(grace) = state.manager.host_grace else {
+        return;
+    };
+    if !state
+        .manager
+        .is_primary_host(&ctx.room, &ctx.temp_user)
+        .await
+        || state.hub.is_connected(&ctx.room, &ctx.temp_user).await
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        tokio_time::sleep(grace).await;
+        if state.hub.is_connected(&ctx.room, &ctx.temp_user).await {
+            return;
+        }
+        let online = state.hub.connected_users(&ctx.room).await;
+        if let Some(hosts) = state
+            .manager
+            .reassign_primary(&ctx.room, &ctx.temp_user, &online)
+            .await
+        {
+            info!(
+                "room={} primary host {} gone for {:?}, reassigned",
+                ctx.room, ctx.temp_user, grace
+            );
+            let msg = WsOutgoing {
+                hosts: Some(hosts),
+                ..WsOutgoing::kind("host_changed")
+            };
+            state.hub.broadcast_critical(&ctx.room, &msg).await;
+        }
+    });
+}
+
+/// 新连接收到的首条消息：取最近一次权威状态并附带服务器时间，
+/// 晚加入的成员无需等下一次广播即可精确外推进度。
+/// 房主尚未推送任何状态时返回 `waiting_for_host`，前端据此展示等待界面。
+async fn connect_state_message(manager: &Manager, room: &str) -> WsOutgoing {
+    let base = match manager.latest_state(room).await {
+        Some(latest) => WsOutgoing::with_state("room_state", latest),
+        None => WsOutgoing::kind("waiting_for_host"),
+    };
+    WsOutgoing {
+        server_time: Some(now_millis()),
+        allowed_rates: manager.allowed_rates(room).await,
+        allowed_sources: manager.allowed_sources(room).await,
+        scheduled_start: manager.scheduled_start(room).await,
+        ..base
+    }
+}
+
+/// 连接时紧随首条状态推送，以及 `get_settings` 的回复。
+async fn room_settings_message(manager: &Manager, room: &str) -> WsOutgoing {
+    WsOutgoing {
+        settings: manager.room_settings(room).await,
+        ..WsOutgoing::kind("room_settings")
+    }
+}
+
+async fn handle_ws_message(
+    msg: Message,
+    manager: &Arc<Manager>,
+    hub: &Arc<Hub>,
+    ctx: &WsContext,
+) -> Result<(), ApiError> {
+    match msg {
+        Message::Text(text) => {
+            let parsed = serde_json::from_str::<WsIncoming>(&text);
+            let kind = parsed
+                .as_ref()
+                .ok()
+                .and_then(|incoming| ws_message_kind(&incoming.r#type))
+                .unwrap_or("unknown");
+            let result = match parsed {
+                Ok(incoming) => match incoming.validate() {
+                    Ok(()) => dispatch_ws_message(incoming, manager, hub, ctx).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => {
+                    warn!("ws deserialize error: {} | input: {}", e, text);
+                    Err(ApiError::bad_request("invalid message format"))
+                }
+            };
+            hub.metrics.record(kind, result.is_ok());
+            result
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 已知的客户端消息类型；其余类型在指标中归入 `unknown`。
+const WS_MESSAGE_TYPES: &[&str] = &[
+    "host_update",
+    "member_ping",
+    "coordinated_play",
+    "set_next",
+    "next",
+    "position_report",
+    "ended",
+    "proposal",
+    "resolve_proposal",
+    "time_sync",
+    "get_settings",
+    "ack",
+    "seek_correction",
+    "add_marker",
+    "clear_markers",
+    "chat",
+    "mute_user",
+    "unmute_user",
+    "playback_error",
+    "ready",
+    "await_ready",
+    "host_focus",
+    "reset",
+];
+
+fn ws_message_kind(kind: &str) -> Option<&'static str> {
+    WS_MESSAGE_TYPES.iter().find(|t| **t == kind).copied()
+}
+
+async fn dispatch_ws_message(
+    incoming: WsIncoming,
+    manager: &Arc<Manager>,
+    hub: &Arc<Hub>,
+    ctx: &WsContext,
+) -> Result<(), ApiError> {
+    match incoming.r#type.as_str() {
+        "host_update" => {
+            let state = incoming
+                .state
+                .ok_or_else(|| ApiError::bad_request("state required"))?;
+            // 房主身份可能在连接期间被升降，按当前成员关系判断。
+            let is_host = manager.is_host(&ctx.room, &ctx.temp_user).await;
+            if !is_host && manager.member_proposals {
+                return forward_proposal(state, manager, hub, ctx).await;
+            }
+            let updated = manager
+                .update_state(&ctx.room, &ctx.temp_user, state, is_host)
+                .await?;
+            let now = now_millis();
+            hub.debug(
+                &ctx.room,
+                json!({
+                    "event": "state",
+                    "from": manager.member_public_id(&ctx.room, &ctx.temp_user).await,
+                    "state": updated,
+                    "position": updated.position_at(now),
+                    "serverTime": now,
+                }),
+            )
+            .await;
+            let msg = WsOutgoing {
+                seek_owner: manager.seek_owner(&ctx.room).await,
+                scheduled_start: manager.scheduled_start(&ctx.room).await,
+                ..WsOutgoing::with_state("room_state", updated)
+            };
+            hub.broadcast(&ctx.room, &msg).await;
+        }
+        "member_ping" => {
+            manager.touch_member(&ctx.room, &ctx.temp_user).await;
+        }
+        "coordinated_play" => {
+            let state = incoming
+                .state
+                .ok_or_else(|| ApiError::bad_request("state required"))?;
+            let (state, start_at) = manager
+                .coordinated_play(&ctx.room, &ctx.temp_user, state, incoming.start_delay_ms)
+                .await?;
+            hub.broadcast(&ctx.room, &coordinated_play_message(state, start_at))
+                .await;
+        }
+        "set_next" => {
+            let state = incoming
+                .state
+                .ok_or_else(|| ApiError::bad_request("state required"))?;
+            manager.set_next(&ctx.room, &ctx.temp_user, state).await?;
+        }
+        "next" => {
+            let state = manager.advance_next(&ctx.room, &ctx.temp_user).await?;
+            hub.broadcast_state(&ctx.room, &state).await;
+        }
+        "position_report" => {
+            let position = incoming
+                .current_time
+                .ok_or_else(|| ApiError::bad_request("currentTime required"))?;
+            let hint = manager
+                .report_position(&ctx.room, &ctx.temp_user, position)
+                .await?;
+            let reply = match hint {
+                Some(DriftHint::Catchup {
+                    rate,
+                    duration_ms,
+                    lag_ms,
+                }) => WsOutgoing {
+                    catchup_rate: Some(rate),
+                    duration_ms: Some(duration_ms),
+                    lag_ms: Some(lag_ms),
+                    ..WsOutgoing::kind("catchup")
+                },
+                Some(DriftHint::Resync(state)) => WsOutgoing {
+                    server_time: Some(state.updated_at),
+                    ..WsOutgoing::with_state("resync", state)
+                },
+                None => return Ok(()),
+            };
+            let me = HashSet::from([ctx.temp_user.clone()]);
+            hub.send_to_users(&ctx.room, &me, &reply).await;
+        }
+        "ended" => {
+            let url = incoming
+                .url
+                .ok_or_else(|| ApiError::bad_request("url required"))?;
+            let is_host = manager.is_host(&ctx.room, &ctx.temp_user).await;
+            match manager
+                .end_video(&ctx.room, &ctx.temp_user, is_host, &url)
+                .await?
+            {
+                Some(VideoEnd::Advanced(state)) => hub.broadcast_state(&ctx.room, &state).await,
+                Some(VideoEnd::Finished(state)) => {
+                    hub.broadcast(&ctx.room, &WsOutgoing::with_state("video_ended", state))
+                        .await
+                }
+                None => {}
+            }
+        }
+        "proposal" => {
+            let state = incoming
+                .state
+                .ok_or_else(|| ApiError::bad_request("state required"))?;
+            forward_proposal(state, manager, hub, ctx).await?;
+        }
+        "ack" => {
+            let ack_id = incoming
+                .ack_id
+                .ok_or_else(|| ApiError::bad_request("ackId required"))?;
+            hub.ack(&ctx.client_id, ack_id);
+        }
+        "seek_correction" => {
+            if !manager.is_host(&ctx.room, &ctx.temp_user).await {
+                return Err(ApiError::forbidden("only host can push seek correction"));
+            }
+            // 暂停时进度不会漂移，无需校准。
+            if let Some(state) = manager.seek_correction(&ctx.room).await {
+                hub.broadcast(&ctx.room, &seek_correction_message(state))
+                    .await;
+            }
+        }
+        "add_marker" => {
+            let position = incoming
+                .position
+                .ok_or_else(|| ApiError::bad_request("position required"))?;
+            let label = incoming.label.unwrap_or_default();
+            let markers = manager
+                .add_marker(&ctx.room, &ctx.temp_user, position, label)
+                .await?;
+            hub.broadcast(&ctx.room, &markers_message(markers)).await;
+        }
+        "clear_markers" => {
+            manager.clear_markers(&ctx.room, &ctx.temp_user).await?;
+            hub.broadcast(&ctx.room, &markers_message(Vec::new())).await;
+        }
+        "chat" => {
+            let text = incoming
+                .text
+                .filter(|text| !text.trim().is_empty())
+                .ok_or_else(|| ApiError::bad_request("text required"))?;
+            // 被禁言者的消息静默丢弃，不回报错误，避免其换号绕过。
+            if let Some(chat) = manager.admit_chat(&ctx.room, &ctx.temp_user, text).await? {
+                let msg = WsOutgoing {
+                    chat: Some(chat),
+                    ..WsOutgoing::kind("chat")
+                };
+                hub.broadcast(&ctx.room, &msg).await;
+            }
+        }
+        "mute_user" | "unmute_user" => {
+            let target = incoming
+                .target
+                .ok_or_else(|| ApiError::bad_request("target required"))?;
+            let muted = incoming.r#type == "mute_user";
+            manager
+                .set_muted(&ctx.room, &ctx.temp_user, &target, muted)
+                .await?;
+        }
+        "playback_error" => {
+            let code = incoming
+                .code
+                .filter(|code| !code.trim().is_empty())
+                .ok_or_else(|| ApiError::bad_request("code required"))?;
+            let (error, flagged, hosts) = manager
+                .report_playback_error(&ctx.room, &ctx.temp_user, code, incoming.message)
+                .await?;
+            let msg = WsOutgoing {
+                playback_error: Some(error),
+                source_flagged: Some(flagged),
+                ..WsOutgoing::kind("playback_error")
+            };
+            hub.send_to_users(&ctx.room, &hosts, &msg).await;
+        }
+        "ready" => {
+            let online = hub.connected_users(&ctx.room).await;
+            let tally = manager
+                .mark_ready(&ctx.room, &ctx.temp_user, &online)
+                .await?;
+            broadcast_ready(hub, &ctx.room, tally).await;
+        }
+        "await_ready" => {
+            let online = hub.connected_users(&ctx.room).await;
+            let tally = manager
+                .await_ready(
+                    &ctx.room,
+                    &ctx.temp_user,
+                    incoming.ready_threshold,
+                    incoming.start_delay_ms,
+                    &online,
+                )
+                .await?;
+            broadcast_ready(hub, &ctx.room, tally).await;
+        }
+        "reset" => {
+            manager.reset_room(&ctx.room, &ctx.temp_user).await?;
+            hub.broadcast(&ctx.room, &room_reset_message()).await;
+        }
+        "host_focus" => {
+            let focused = incoming
+                .focused
+                .ok_or_else(|| ApiError::bad_request("focused required"))?;
+            let changed = manager
+                .set_host_focus(&ctx.room, &ctx.temp_user, focused)
+                .await?;
+            if let Some(state) = changed {
+                let msg = WsOutgoing {
+                    reason: Some("host_focus".into()),
+                    server_time: Some(now_millis()),
+                    ..WsOutgoing::with_state("room_state", state)
+                };
+                hub.broadcast(&ctx.room, &msg).await;
+            }
+        }
+        "get_settings" => {
+            let me = HashSet::from([ctx.temp_user.clone()]);
+            let reply = room_settings_message(manager, &ctx.room).await;
+            hub.send_to_users(&ctx.room, &me, &reply).await;
+        }
+        "time_sync" => {
+            let client_time = incoming
+                .client_time
+                .ok_or_else(|| ApiError::bad_request("clientTime required"))?;
+            let server_time = now_millis();
+            let warn_offset = manager
+                .record_clock_offset(
+                    &ctx.room,
+                    &ctx.temp_user,
+                    client_time,
+                    incoming.rtt_ms,
+                    server_time,
+                )
+                .await?;
+            let me = HashSet::from([ctx.temp_user.clone()]);
+            let reply = WsOutgoing {
+                client_time: Some(client_time),
+                server_time: Some(server_time),
+                ..WsOutgoing::kind("time_sync")
+            };
+            hub.send_to_users(&ctx.room, &me, &reply).await;
+            if let Some(offset) = warn_offset {
+                warn!(
+                    "room={} user={} clock skew {}ms",
+                    ctx.room, ctx.temp_user, offset
+                );
+                // 客户端收到后应改用 serverTime 外推，不再信任本地时钟。
+                let warning = WsOutgoing {
+                    clock_offset_ms: Some(offset),
+                    server_time: Some(server_time),
+                    ..WsOutgoing::kind("clock_warning")
+                };
+                hub.send_to_users(&ctx.room, &me, &warning).await;
+            }
+        }
+        "resolve_proposal" => {
+            let id = incoming
+                .proposal_id
+                .ok_or_else(|| ApiError::bad_request("proposalId required"))?;
+            let accept = incoming
+                .accept
+                .ok_or_else(|| ApiError::bad_request("accept required"))?;
+            let applied = manager
+                .resolve_proposal(&ctx.room, &ctx.temp_user, id, accept)
+                .await?;
+            let msg = WsOutgoing {
+                proposal_id: Some(id),
+                accepted: Some(accept),
+                ..WsOutgoing::kind("proposal_resolved")
+            };
+            hub.broadcast(&ctx.room, &msg).await;
+            if let Some(state) = applied {
+                hub.broadcast_state(&ctx.room, &state).await;
+            }
+        }
+        _ => return Err(ApiError::bad_request("unknown message type")),
+    }
+    Ok(())
+}
+
+/// 就绪人数只发给房主；达标后向全房间广播协同起播。
+async fn broadcast_ready(hub: &Hub, room: &str, tally: ReadyTally) {
+    let msg = WsOutgoing {
+        ready_count: Some(tally.ready),
+        ready_total: Some(tally.total),
+        ..WsOutgoing::kind("ready_state")
+    };
+    hub.send_to_users(room, &tally.hosts, &msg).await;
+    if let Some((state, start_at)) = tally.start {
+        hub.broadcast(room, &coordinated_play_message(state, start_at))
+            .await;
+    }
+}
+
+/// 成员提议只发给房主，房间状态在房主接受前保持不变。
+async fn forward_proposal(
+    state: RoomState,
+    manager: &Arc<Manager>,
+    hub: &Arc<Hub>,
+    ctx: &WsContext,
+) -> Result<(), ApiError> {
+    let (proposal, hosts) = manager.propose(&ctx.room, &ctx.temp_user, state).await?;
+    let msg = WsOutgoing {
+        proposal: Some(proposal),
+        ..WsOutgoing::kind("proposal")
+    };
+    hub.send_to_users(&ctx.room, &hosts, &msg).await;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WsIncoming {
+    #[serde(rename = "type")]
+    r#type: String,
+    state: Option<RoomState>,
+    /// `position_report` 携带的本地实际播放进度（秒）。
+    current_time: Option<f64>,
+    /// `coordinated_play`/`await_ready` 中房主期望的起播缓冲（毫秒），缺省为 DEFAULT_PLAY_DELAY_MS。
+    start_delay_ms: Option<u64>,
+    /// `await_ready` 要求的就绪人数，缺省为全部非房主成员。
+    ready_threshold: Option<usize>,
+    /// `resolve_proposal` 处理的提议 id 及是否接受。
+    proposal_id: Option<u64>,
+    accept: Option<bool>,
+    /// `time_sync` 发送时的客户端毫秒时间戳，以及上一次往返的耗时。
+    client_time: Option<i64>,
+    rtt_ms: Option<u64>,
+    /// `ack` 确认的消息 id。
+    ack_id: Option<u64>,
+    /// `add_marker` 标注的进度（秒）与说明文字。
+    position: Option<f64>,
+    label: Option<String>,
+    /// `chat` 的消息内容。
+    text: Option<String>,
+    /// `mute_user`/`unmute_user` 针对成员的公开标识。
+    target: Option<String>,
+    /// `ended` 时播完的地址，与房间当前状态的 url 不符时忽略。
+    url: Option<String>,
+    /// `playback_error` 的错误码（如 `MEDIA_ERR_NETWORK`）与可选说明。
+    code: Option<String>,
+    message: Option<String>,
+    /// `host_focus` 上报的主房主窗口是否处于焦点。
+    focused: Option<bool>,
+}
+
+impl WsIncoming {
+    /// 解析后立即校验数值字段，非法状态不会进入 Manager 或广播给其他成员。
+    fn validate(&self) -> Result<(), ApiError> {
+        if let Some(state) = &self.state {
+            state.validate()?;
+        }
+        if self.current_time.is_some_and(|t| !t.is_finite() || t < 0.0) {
+            return Err(ApiError::bad_request("invalid position"));
+        }
+        if self.position.is_some_and(|t| !t.is_finite() || t < 0.0) {
+            return Err(ApiError::bad_request("invalid marker position"));
+        }
+        if self
+            .label
+            .as_ref()
+            .is_some_and(|label| label.chars().count() > MAX_MARKER_LABEL_LEN)
+        {
+            return Err(ApiError::bad_request(format!(
+                "marker label exceeds {MAX_MARKER_LABEL_LEN} characters"
+            )));
+        }
+        if self
+            .text
+            .as_ref()
+            .is_some_and(|text| text.chars().count() > MAX_CHAT_LEN)
+        {
+            return Err(ApiError::bad_request(format!(
+                "chat exceeds {MAX_CHAT_LEN} characters"
+            )));
+        }
+        if [&self.code, &self.message]
+            .into_iter()
+            .flatten()
+            .any(|text| text.chars().count() > MAX_PLAYBACK_ERROR_LEN)
+        {
+            return Err(ApiError::bad_request(format!(
+                "playback error exceeds {MAX_PLAYBACK_ERROR_LEN} characters"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WsOutgoing {
+    #[serde(rename = "type")]
+    r#type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<RoomState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// 服务器当前毫秒时间戳，客户端据此与 `updated_at` 外推播放进度。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_time: Option<i64>,
+    /// 当前持有 seek 软锁的成员的公开标识。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seek_owner: Option<String>,
+    /// `coordinated_play` 的统一起播时刻（服务器毫秒时间戳）。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_at: Option<i64>,
+    /// 非用户操作引起的状态变化原因，如 `inactivity`。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// 房间允许的倍速档位；`allowed_rates` 消息中缺省表示已取消限制。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_rates: Option<Vec<f64>>,
+    /// 房间允许的媒体来源；`allowed_sources` 消息中缺省表示不限制。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_sources: Option<BTreeSet<SourceKind>>,
+    /// `host_changed` 消息中的最新房主列表。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosts: Option<HostsResponse>,
+    /// 计划起播时刻（服务器毫秒时间戳）；`schedule` 消息中缺省表示已取消。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheduled_start: Option<i64>,
+    /// `time_sync` 回显的客户端时间戳，客户端据此计算往返耗时。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_time: Option<i64>,
+    /// `clock_warning` 中估算的客户端时钟偏差（毫秒）。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock_offset_ms: Option<i64>,
+    /// `catchup` 建议的临时倍速与持续时长，到时恢复房间倍速。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    catchup_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    /// `catchup` 时服务端估算的落后毫秒数。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lag_ms: Option<i64>,
+    /// 转发给房主的成员提议。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proposal: Option<Proposal>,
+    /// `proposal_resolved` 中被处理的提议 id 及结果。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proposal_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accepted: Option<bool>,
+    /// 关键消息的确认 id，只发给以 `ack=1` 连接的客户端，需回复 `ack`。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ack_id: Option<u64>,
+    /// 房间内带状态消息的递增序号，客户端丢弃序号不大于已见值的消息。
+    /// 连接时的首条消息携带当前序号作为基线。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    /// `room_settings` 消息中的房间策略快照。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    settings: Option<RoomSettings>,
+    /// `markers` 消息中房间当前的全部标注，空列表表示已清空。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    markers: Option<Vec<Marker>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chat: Option<ChatMessage>,
+    /// `ready_state` 中已就绪的非房主成员数与总数。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ready_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ready_total: Option<usize>,
+    /// 发给房主的成员播放错误，以及当前播放源是否因此被标记为有问题。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    playback_error: Option<PlaybackError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_flagged: Option<bool>,
+    /// `debug` 消息的诊断内容，结构随 `event` 而定，普通客户端应忽略。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<serde_json::Value>,
+}
+
+impl WsOutgoing {
+    fn kind(kind: &str) -> Self {
+        Self {
+            r#type: kind.into(),
+            ..Default::default()
+        }
+    }
+
+    fn error(msg: impl Into<String>) -> Self {
+        Self {
+            r#type: "error".into(),
+            error: Some(msg.into()),
+            ..Default::default()
+        }
+    }
+
+    fn with_state(kind: &str, state: RoomState) -> Self {
+        Self {
+            r#type: kind.into(),
+            state: Some(state),
+            ..Default::default()
+        }
+    }
+}
+
+/// 协同起播：成员先 seek 并暂停，到 `start_at` 时所有人同时开始播放。
+/// 状态的 `updated_at` 即为 `start_at`，按常规外推在此之前进度保持不动。
+fn coordinated_play_message(state: RoomState, start_at: i64) -> WsOutgoing {
+    WsOutgoing {
+        start_at: Some(start_at),
+        server_time: Some(now_millis()),
+        ..WsOutgoing::with_state("coordinated_play", state)
+    }
+}
+
+/// 时间轴标注整体下发，客户端直接替换本地列表。
+fn markers_message(markers: Vec<Marker>) -> WsOutgoing {
+    WsOutgoing {
+        markers: Some(markers),
+        ..WsOutgoing::kind("markers")
+    }
+}
+
+fn room_reset_message() -> WsOutgoing {
+    WsOutgoing {
+        server_time: Some(now_millis()),
+        ..WsOutgoing::kind("room_reset")
+    }
+}
+
+/// 比 `room_state` 更温和的校准：成员仅在本地进度与 `state` 的偏差超过自身阈值时才 seek。
+/// `state.updated_at` 与 `server_time` 相同，即消息生成时刻的权威进度。
+fn seek_correction_message(state: RoomState) -> WsOutgoing {
+    WsOutgoing {
+        server_time: Some(state.updated_at),
+        ..WsOutgoing::with_state("seek_correction", state)
+    }
+}
+
+/// 关键消息的确认等待时间与最多重发次数。
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const ACK_RETRIES: usize = 2;
+
+/// 服务端主动断开时使用的关闭码（4000-4999 为应用自定义区间）。
+/// 客户端可按关闭码决定是否重连，shutdown 的 reason 中带有建议的重连等待时间。
+const CLOSE_ROOM_CLOSED: u16 = 4000;
+const CLOSE_KICKED: u16 = 4001;
+const CLOSE_SHUTTING_DOWN: u16 = 4002;
+/// 房主修改了房间密码，成员需用新密码重新连接。
+const CLOSE_PASSWORD_CHANGED: u16 = 4003;
+/// 连接被本机管理接口强制断开。
+const CLOSE_ADMIN: u16 = 4004;
+/// shutdown 建议的基础重连等待与随机抖动，避免所有客户端同时重连。
+const RECONNECT_BASE_MS: u64 = 1_000;
+const RECONNECT_JITTER_MS: u64 = 2_000;
+
+fn close_with(code: u16, reason: impl Into<Cow<'static, str>>) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
+fn shutdown_reason() -> String {
+    let retry_ms = RECONNECT_BASE_MS + rand::random_range(0..RECONNECT_JITTER_MS);
+    format!("server shutting down, retry in {retry_ms} ms")
+}
+
+/// 简化的错误响应封装，返回统一 JSON。
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_request(msg: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: msg.into(),
+        }
+    }
+
+    fn forbidden(msg: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            message: msg.into(),
+        }
+    }
+
+    fn not_found(msg: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            message: msg.into(),
+        }
+    }
+
+    fn conflict(msg: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            message: msg.into(),
+        }
+    }
+
+    fn bad_gateway(msg: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_GATEWAY,
+            message: msg.into(),
+        }
+    }
+
+    fn gateway_timeout(msg: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::GATEWAY_TIMEOUT,
+            message: msg.into(),
+        }
+    }
+
+    fn unavailable(msg: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            message: msg.into(),
+        }
+    }
+
+    fn too_many_requests(msg: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            message: msg.into(),
+        }
+    }
+}
+
+/// 与 `Json` 相同，但请求体解析失败时返回统一的 `{ "error": ... }` 结构，
+/// 错误信息中包含缺失或非法的字段名。
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+struct ApiJson<T>(T);
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self {
+            status: rejection.status(),
+            message: rejection.body_text(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({ "error": self.message }));
+        (self.status, body).into_response()
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomState {
+    pub url: String,
+    pub title: String,
+    pub current_time: f64,
+    pub duration: f64,
+    pub paused: bool,
+    pub playback_rate: f64,
+    pub source_type: SourceType,
+    pub updated_at: i64,
+    #[serde(default)]
+    pub cover: Option<String>,
+    /// B 站源当前的清晰度（qn），其他来源缺省。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u32>,
+    /// 预告的生效时刻（毫秒，服务器时钟）：成员换算本地时钟后在该时刻切换，之前保持原状态。
+    /// 设置时与 `updated_at` 相同，生效前外推进度停在 `current_time`。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_at: Option<i64>,
+    /// 画面适配方式与裁剪区域，只由房主设置，成员端按此统一画面构图。
+    #[serde(default)]
+    pub fit_mode: FitMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<Crop>,
+    /// 共享音量（0~1）与静音，为 None 时不同步，成员在本地覆盖后也不再跟随。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub muted: Option<bool>,
+}
+
+/// 播放源的类型。客户端上报未列出的取值（如拼写错误）时反序列化失败。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceType {
+    /// 媒体根下的本地文件。
+    File,
+    /// 经 ffmpeg 实时转码的本地文件。
+    Transcode,
+    /// 分卷拼接的本地文件。
+    Concat,
+    /// 光盘目录的正片分段。
+    Disc,
+    /// 普通 http(s) 远程地址。
+    Remote,
+    Bili,
+    /// B 站仅音频。
+    BiliAudio,
+    /// 服务端生成的内存内容（封面、弹幕轨道），只出现在预热结果中。
+    Inline,
+    /// 客户端尚未拿到 resolve 结果时上报的占位值。
+    Unknown,
+}
+
+/// 视频在播放区域中的缩放方式，对应 CSS `object-fit`。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    #[default]
+    Contain,
+    Cover,
+    Fill,
+}
+
+/// 裁剪区域，各分量是相对画面宽高的比例（0~1），如去掉上下黑边。
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Crop {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Crop {
+    fn is_valid(&self) -> bool {
+        let unit = |v: f64| v.is_finite() && (0.0..=1.0).contains(&v);
+        [self.x, self.y, self.width, self.height]
+            .into_iter()
+            .all(unit)
+            && self.width > 0.0
+            && self.height > 0.0
+            && self.x + self.width <= 1.0
+            && self.y + self.height <= 1.0
+    }
+}
+
+/// 房间状态中字符串字段的长度上限：状态会转发给房间内每个成员，过长的字段会被成倍放大。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StateLimits {
+    /// 标题字符数。
+    title: usize,
+    /// `url`、`cover` 字节数。
+    url: usize,
+}
+
+impl Default for StateLimits {
+    fn default() -> Self {
+        Self {
+            title: 512,
+            url: 2048,
+        }
+    }
+}
+
+impl StateLimits {
+    fn check(&self, state: &RoomState) -> Result<(), ApiError> {
+        if state.title.chars().count() > self.title {
+            return Err(ApiError::bad_request(format!(
+                "title exceeds {} characters",
+                self.title
+            )));
+        }
+        let too_long = |v: &str| v.len() > self.url;
+        if too_long(&state.url) || state.cover.as_deref().is_some_and(too_long) {
+            return Err(ApiError::bad_request(format!(
+                "url or cover exceeds {} bytes",
+                self.url
+            )));
+        }
+        Ok(())
+    }
+
+    /// 服务端自己生成的状态不拒绝：截断标题，丢弃过长的封面地址。
+    fn clamp(&self, state: &mut RoomState) {
+        if let Some((end, _)) = state.title.char_indices().nth(self.title) {
+            state.title.truncate(end);
+        }
+        if state.cover.as_ref().is_some_and(|v| v.len() > self.url) {
+            state.cover = None;
+        }
+    }
+}
+
+impl RoomState {
+    /// 拒绝 NaN/无穷大以及负的进度、时长和倍速。
+    fn validate(&self) -> Result<(), ApiError> {
+        let floats = [self.current_time, self.duration, self.playback_rate];
+        if floats.iter().any(|v| !v.is_finite()) {
+            return Err(ApiError::bad_request("state contains non-finite number"));
+        }
+        if self.current_time < 0.0 || self.duration < 0.0 || self.playback_rate <= 0.0 {
+            return Err(ApiError::bad_request("state contains out-of-range number"));
+        }
+        if self
+            .volume
+            .is_some_and(|v| !v.is_finite() || !(0.0..=1.0).contains(&v))
+        {
+            return Err(ApiError::bad_request("volume must be between 0 and 1"));
+        }
+        if self.crop.is_some_and(|crop| !crop.is_valid()) {
+            return Err(ApiError::bad_request(
+                "crop must be a region within the frame",
+            ));
+        }
+        Ok(())
+    }
+
+    /// 成员只能调整播放进度/暂停/倍速，不能切换源或画面构图；
+    /// `volume` 为 true 时还可修改音量/静音，未携带的字段保持房主的设置。
+    fn with_member_update(self, update: &RoomState, volume: bool) -> Self {
+        let (volume, muted) = if volume {
+            (update.volume.or(self.volume), update.muted.or(self.muted))
+        } else {
+            (self.volume, self.muted)
+        };
+        RoomState {
+            current_time: update.current_time,
+            paused: update.paused,
+            playback_rate: update.playback_rate,
+            volume,
+            muted,
+            updated_at: now_millis(),
+            effective_at: None,
+            ..self
+        }
+    }
+
+    /// 同一来源上的播放/暂停切换或 seek，不含自然播放和换源。
+    fn is_transport_change(&self, next: &RoomState, at: i64) -> bool {
+        self.url == next.url
+            && (self.paused != next.paused
+                || (next.current_time - self.position_at(at)).abs() > SEEK_TOLERANCE_SECS)
+    }
+
+    /// 按 `updated_at` 外推到 `at`（毫秒）时刻的播放进度。
+    fn position_at(&self, at: i64) -> f64 {
+        if self.paused {
+            return self.current_time;
+        }
+        let elapsed = (at - self.updated_at).max(0) as f64 / 1000.0;
+        self.current_time + elapsed * self.playback_rate
+    }
+
+    /// 以 `at` 时刻外推出的进度为新基准，进度不超过时长；尚未生效的状态保持原基准。
+    fn rebased_at(&self, at: i64) -> Self {
+        let at = at.max(self.updated_at);
+        let mut current_time = self.position_at(at);
+        if self.duration > 0.0 {
+            current_time = current_time.min(self.duration);
+        }
+        Self {
+            current_time,
+            updated_at: at,
+            ..self.clone()
+        }
+    }
+}
+
+/// 播放列表条目，保存原始输入（路径/BV/URL），播放时再解析为 token。
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistItem {
+    pub path: String,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+const PLAYLIST_EXPORT_VERSION: u32 = 1;
+
+/// 可离线保存的播放列表文件，只含原始输入和标题，不含 token，导入后播放时再解析。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistExport {
+    pub version: u32,
+    pub items: Vec<PlaylistItem>,
+    #[serde(default)]
+    pub exported_at: i64,
+}
+
+impl PlaylistExport {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.version != PLAYLIST_EXPORT_VERSION {
+            return Err(ApiError::bad_request(format!(
+                "unsupported playlist version {}",
+                self.version
+            )));
+        }
+        if self.items.iter().any(|item| item.path.trim().is_empty()) {
+            return Err(ApiError::bad_request("playlist item path required"));
+        }
+        // token 只在签发它的服务上短期有效，不能跨房间复用。
+        if self
+            .items
+            .iter()
+            .any(|item| item.path.starts_with("/media/"))
+        {
+            return Err(ApiError::bad_request(
+                "playlist items must be original inputs, not media tokens",
+            ));
+        }
+        Ok(())
+    }
+}
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// 单个房间的自包含快照，不含口令与成员，只保留可恢复的播放会话。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSnapshot {
+    pub version: u32,
+    pub room: String,
+    /// 当前播放源的原始输入，恢复时据此重新签发 token。
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub state: Option<RoomState>,
+    #[serde(default)]
+    pub playlist: Vec<PlaylistItem>,
+    pub created_at: i64,
+}
+
+impl RoomSnapshot {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(ApiError::bad_request(format!(
+                "unsupported snapshot version {}",
+                self.version
+            )));
+        }
+        if let Some(state) = &self.state {
+            state.validate()?;
+        }
+        if self.source.as_deref().is_some_and(|s| s.trim().is_empty()) {
+            return Err(ApiError::bad_request("snapshot source is empty"));
+        }
+        if self.playlist.iter().any(|item| item.path.trim().is_empty()) {
+            return Err(ApiError::bad_request("playlist item path required"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    /// 对其他成员公开的随机标识。temp_user 是成员自己的凭据，不能出现在成员可见的数据里。
+    id: String,
+    last_seen: Instant,
+    client_id: Option<String>,
+    /// 最近一次 `time_sync` 估算的客户端时钟偏差（毫秒），正数表示客户端偏快。
+    clock_offset: Option<i64>,
+    /// 上一条追赶提示预计结束的时刻，期间的进度上报不再重复提示。
+    catchup_until: Option<Instant>,
+    chat_bucket: ChatBucket,
+}
+
+impl Member {
+    fn new(client_id: Option<&str>) -> Self {
+        Self {
+            id: random_string(MEMBER_ID_LEN),
+            last_seen: Instant::now(),
+            client_id: client_id.map(str::to_string),
+            clock_offset: None,
+            catchup_until: None,
+            chat_bucket: ChatBucket::new(),
+        }
+    }
+}
+
+/// 成员公开标识的长度。
+const MEMBER_ID_LEN: usize = 16;
+
+/// 聊天限速的令牌桶：按速率补充、最多攒 `CHAT_BURST` 个，每条消息消耗一个。
+#[derive(Debug, Clone)]
+struct ChatBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl ChatBucket {
+    fn new() -> Self {
+        Self {
+            tokens: CHAT_BURST,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, rate: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(CHAT_BURST);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// 根据成员上报的进度给出的纠偏建议。
+#[derive(Debug, Clone)]
+enum DriftHint {
+    /// 以 `rate` 倍速播放 `duration_ms` 后恢复房间倍速。
+    Catchup {
+        rate: f64,
+        duration_ms: u64,
+        lag_ms: i64,
+    },
+    /// 偏差过大，直接 seek 到 `state` 外推出的进度。
+    Resync(RoomState),
+}
+
+#[derive(Debug, Clone)]
+struct Room {
+    password: String,
+    /// 主房主：创建者，转移语义以它为准，不能被降级。
+    primary_host: Option<String>,
+    /// 所有拥有房主权限的用户，包含主房主。
+    host_ids: HashSet<String>,
+    state: Option<RoomState>,
+    members: HashMap<String, Member>,
+    last_update: Option<Instant>,
+    /// 最近一次 resolve 的原始输入，快照恢复时使用。
+    source: Option<String>,
+    playlist: Vec<PlaylistItem>,
+    /// 最近一次拖动进度的用户及时间，用于 seek 软锁。
+    seek_owner: Option<(String, Instant)>,
+    /// 最近的房间事件，最新的在队尾。
+    events: VecDeque<RoomEvent>,
+    last_event_id: u64,
+    /// 成员最近一次上报的 (进度, 上报时刻毫秒)。
+    positions: HashMap<String, (f64, i64)>,
+    /// 房主预先 resolve 好的下一项，`next` 时直接切换。
+    pending_next: Option<RoomState>,
+    /// 已处理过 `ended` 的播放地址：每个客户端播完都会上报，只有第一条生效。
+    /// 换源或从片尾之前重新播放时清除。
+    ended_url: Option<String>,
+    /// 允许的倍速档位，为 None 时不限制；对房主和成员同样生效。
+    allowed_rates: Option<Vec<f64>>,
+    /// 允许 resolve 的媒体来源，为 None 时不限制；对房主和成员同样生效。
+    allowed_sources: Option<BTreeSet<SourceKind>>,
+    /// 主房主的 (恢复令牌, 令牌持有者当前的 temp_user)。
+    host_resume: Option<(String, String)>,
+    /// 计划起播时刻（毫秒时间戳），到点后自动开始播放。
+    scheduled_start: Option<i64>,
+    /// 等待房主处理的成员提议，最早的在队首；每个成员只保留最新一条。
+    proposals: VecDeque<Proposal>,
+    last_proposal_id: u64,
+    /// 房主的时间轴标注，按添加顺序排列，换源时清空。
+    markers: Vec<Marker>,
+    last_marker_id: u64,
+    /// 被房主禁言的 temp_user，其聊天消息由服务端直接丢弃。
+    muted: HashSet<String>,
+    /// 成员最近上报的播放错误，最新的在队尾。
+    playback_errors: VecDeque<PlaybackError>,
+    /// 已就绪可以起播当前播放源的成员，换源时清空。
+    ready: HashSet<String>,
+    /// 房主开启的就绪握手，人数达标后自动协同起播。
+    ready_gate: Option<ReadyGate>,
+    /// 主房主最近上报的窗口焦点；协同房主不上报，换主房主时重置为 true。
+    host_focused: bool,
+    /// 当前的暂停是否由房主失焦触发；其他状态更新会清除，重新聚焦时只恢复自己造成的暂停。
+    focus_paused: bool,
+}
+
+/// 就绪握手的起播条件。
+#[derive(Debug, Clone, Copy)]
+struct ReadyGate {
+    /// 需要就绪的人数，为 None 时要求全部非房主成员就绪。
+    threshold: Option<usize>,
+    delay_ms: u64,
+}
+
+/// 一次就绪变化后的统计；人数达标时 `start` 为已生效的协同起播。
+#[derive(Debug)]
+struct ReadyTally {
+    ready: usize,
+    total: usize,
+    hosts: HashSet<String>,
+    start: Option<(RoomState, i64)>,
+}
+
+/// 过期房间留下的进度与播放列表，同名同口令的房间在保留期内重建时据此恢复。
+#[derive(Debug)]
+struct Tombstone {
+    password: String,
+    state: Option<RoomState>,
+    source: Option<String>,
+    playlist: Vec<PlaylistItem>,
+    expired_at: Instant,
+}
+
+/// 收藏夹接口单页条数上限，以及最多翻的页数。
+const FAVORITES_PAGE_SIZE: u32 = 20;
+const MAX_FAVORITES_PAGES: u32 = 50;
+/// 单次批量 resolve 的路径上限与并发度。
+const MAX_BATCH_RESOLVE: usize = 64;
+const BATCH_RESOLVE_CONCURRENCY: usize = 4;
+/// 每个房间保留的事件条数。
+const ROOM_EVENT_LOG_LEN: usize = 128;
+/// 倍速档位的数量上限，以及比较倍速时的容差。
+const MAX_RATE_PRESETS: usize = 16;
+const RATE_EPSILON: f64 = 1e-6;
+/// 协同起播的默认缓冲与上限（毫秒）。
+const DEFAULT_PLAY_DELAY_MS: u64 = 1_500;
+const MAX_PLAY_DELAY_MS: u64 = 10_000;
+/// 客户端时钟偏差超过该毫秒数时发送 `clock_warning`。
+const CLOCK_SKEW_WARN_MS: i64 = 3_000;
+/// 每个房间同时等待处理的提议上限，超出时丢弃最早的。
+const MAX_PENDING_PROPOSALS: usize = 16;
+/// 计划起播最多提前的时间（毫秒）。
+const MAX_SCHEDULE_AHEAD_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+/// 每个房间保留的播放错误条数，以及错误码和说明的字符数上限。
+const MAX_PLAYBACK_ERRORS: usize = 32;
+const MAX_PLAYBACK_ERROR_LEN: usize = 256;
+/// 每个房间的标注条数上限与标注文字的字符数上限。
+const MAX_MARKERS: usize = 64;
+const MAX_MARKER_LABEL_LEN: usize = 128;
+/// 过期房间状态的保留条数上限。
+const MAX_TOMBSTONES: usize = 64;
+/// 同一 token 同时进行的转码数上限。
+const MAX_TRANSCODES_PER_TOKEN: usize = 2;
+/// 与外推进度相差超过该秒数才视为拖动进度条。
+const SEEK_TOLERANCE_SECS: f64 = 1.5;
+
+impl Room {
+    /// 所有权威状态变更都经过这里；返回播放源（url）是否改变，
+    /// 换源时清空标注与就绪状态。
+    fn set_state(&mut self, state: RoomState) -> bool {
+        let changed = self.state.as_ref().map_or(true, |old| old.url != state.url);
+        if changed {
+            self.markers.clear();
+            self.ready.clear();
+            self.ready_gate = None;
+        }
+        if changed || (!state.paused && state.current_time + ENDED_REPLAY_MARGIN < state.duration) {
+            self.ended_url = None;
+        }
+        self.state = Some(state);
+        self.last_update = Some(Instant::now());
+        self.focus_paused = false;
+        changed
+    }
+
+    /// 最近一次成员心跳或状态更新的时间。
+    fn last_seen(&self) -> Option<Instant> {
+        self.members
+            .values()
+            .map(|member| member.last_seen)
+            .chain(self.last_update)
+            .max()
+    }
+
+    fn is_expired(&self, now: Instant, ttl: Duration) -> bool {
+        // 只有成员心跳、从未发布过状态的房间也要按最后活跃时间过期；
+        // 既无成员也无状态的房间视为刚刚活跃。
+        self.last_seen()
+            .is_some_and(|seen| now.saturating_duration_since(seen) > ttl)
+    }
+
+    fn new(password: &str) -> Self {
+        Self {
+            password: password.to_string(),
+            primary_host: None,
+            host_ids: HashSet::new(),
+            state: None,
+            members: HashMap::new(),
+            last_update: None,
+            source: None,
+            playlist: Vec::new(),
+            seek_owner: None,
+            events: VecDeque::new(),
+            last_event_id: 0,
+            positions: HashMap::new(),
+            pending_next: None,
+            ended_url: None,
+            allowed_rates: None,
+            allowed_sources: None,
+            host_resume: None,
+            scheduled_start: None,
+            proposals: VecDeque::new(),
+            last_proposal_id: 0,
+            markers: Vec::new(),
+            last_marker_id: 0,
+            muted: HashSet::new(),
+            playback_errors: VecDeque::new(),
+            ready: HashSet::new(),
+            ready_gate: None,
+            host_focused: true,
+            focus_paused: false,
+        }
+    }
+
+    fn record(&mut self, kind: &'static str, user: Option<&str>) {
+        if self.events.len() == ROOM_EVENT_LOG_LEN {
+            self.events.pop_front();
+        }
+        self.last_event_id += 1;
+        let user = user
+            .and_then(|user| self.members.get(user))
+            .map(|member| member.id.clone());
+        self.events.push_back(RoomEvent {
+            id: self.last_event_id,
+            kind,
+            user,
+            at: now_millis(),
+        });
+    }
+
+    /// seek 软锁：窗口内其他人的进度跳变被忽略，沿用当前外推进度；
+    /// 暂停和倍速不受影响。换源时清空持有者。
+    fn apply_seek_lock(&mut self, user: &str, state: &mut RoomState, window: Duration) {
+        let Some(existing) = self.state.as_ref() else {
+            return;
+        };
+        if existing.url != state.url && self.is_host(user) {
+            self.seek_owner = None;
+            return;
+        }
+        let expected = existing.position_at(now_millis());
+        if (state.current_time - expected).abs() <= SEEK_TOLERANCE_SECS {
+            return;
+        }
+        let now = Instant::now();
+        match &self.seek_owner {
+            Some((owner, at)) if owner != user && now.duration_since(*at) < window => {
+                state.current_time = expected;
+            }
+            _ => self.seek_owner = Some((user.to_string(), now)),
+        }
+    }
+
+    fn is_host(&self, user: &str) -> bool {
+        self.host_ids.contains(user)
+    }
+
+    /// 统计非房主成员的就绪人数；握手已开启且人数达标时按当前进度协同起播并关闭握手。
+    /// 只统计仍有连接的成员：`members` 在断线时不会移除，离开的人不能拖住起播。
+    fn tally_ready(&mut self, online: &HashSet<String>) -> ReadyTally {
+        let waiting: Vec<&String> = self
+            .members
+            .keys()
+            .filter(|user| !self.is_host(user) && online.contains(*user))
+            .collect();
+        let total = waiting.len();
+        let ready = waiting.iter().filter(|u| self.ready.contains(**u)).count();
+        let start = self.ready_gate.and_then(|gate| {
+            let needed = gate.threshold.unwrap_or(total).clamp(1, total.max(1));
+            if ready < needed {
+                return None;
+            }
+            let mut state = self.state.clone()?;
+            let start_at = now_millis() + gate.delay_ms as i64;
+            state.current_time = state.position_at(now_millis());
+            state.paused = false;
+            state.updated_at = start_at;
+            state.effective_at = None;
+            self.set_state(state.clone());
+            self.ready_gate = None;
+            self.record("coordinated_play", None);
+            Some((state, start_at))
+        });
+        ReadyTally {
+            ready,
+            total,
+            hosts: self.host_ids.clone(),
+            start,
+        }
+    }
+
+    /// 当前播放源上报过错误的成员占在线成员的比例是否达到 `ratio`。
+    fn source_flagged(&self, ratio: f64) -> bool {
+        let Some(url) = self.state.as_ref().map(|s| &s.url) else {
+            return false;
+        };
+        let reporters: HashSet<&str> = self
+            .playback_errors
+            .iter()
+            .filter(|e| e.url.as_ref() == Some(url) && self.members.contains_key(&e.member))
+            .map(|e| e.member.as_str())
+            .collect();
+        !self.members.is_empty() && reporters.len() as f64 / self.members.len() as f64 >= ratio
+    }
+
+    fn is_rate_allowed(&self, rate: f64) -> bool {
+        self.allowed_rates.as_ref().map_or(true, |rates| {
+            rates.iter().any(|r| (r - rate).abs() < RATE_EPSILON)
+        })
+    }
+
+    fn set_primary_host(&mut self, user: &str) {
+        self.primary_host = Some(user.to_string());
+        self.host_ids.insert(user.to_string());
+        self.host_focused = true;
+    }
+
+    /// 由公开标识找到成员的 temp_user，房主的管理操作都以公开标识指定对象。
+    fn member_by_id(&self, id: &str) -> Result<String, ApiError> {
+        self.members
+            .iter()
+            .find(|(_, member)| member.id == id)
+            .map(|(user, _)| user.clone())
+            .ok_or_else(|| ApiError::not_found("member not found"))
+    }
+
+    /// 房主列表，以公开标识表示：`host_changed` 会广播给全体成员。
+    fn hosts(&self) -> HostsResponse {
+        let public_id = |user: &String| self.members.get(user).map(|member| member.id.clone());
+        let mut hosts: Vec<String> = self.host_ids.iter().filter_map(public_id).collect();
+        hosts.sort();
+        HostsResponse {
+            primary_host: self.primary_host.as_ref().and_then(public_id),
+            hosts,
+        }
+    }
+
+    fn seek_owner(&self, window: Duration) -> Option<&str> {
+        self.seek_owner
+            .as_ref()
+            .filter(|(_, at)| at.elapsed() < window)
+            .map(|(owner, _)| owner.as_str())
+    }
+}
+
+/// 播放中的状态距片尾超过该秒数才算重新播放，之后的 `ended` 会再次生效。
+const ENDED_REPLAY_MARGIN: f64 = 1.0;
+
+/// `ended` 的处理结果：有下一项则自动切换，否则停在片尾。
+#[derive(Debug)]
+enum VideoEnd {
+    Advanced(RoomState),
+    Finished(RoomState),
+}
+
+#[derive(Debug, Clone)]
+enum MediaTarget {
+    Local(PathBuf),
+    Remote(RemoteTarget),
+    Transcode(transcode::TranscodeTarget),
+    /// 服务端生成、直接保存在内存中的小文件，如本地视频的封面。
+    Inline(InlineMedia),
+    /// 按顺序拼接的本地分卷，作为一个文件按字节区间提供。
+    Concat(Vec<PathBuf>),
+}
+
+impl MediaTarget {
+    /// 复用 token 时判断是否为同一目标；内存中的小文件不参与复用。
+    fn identity(&self) -> Option<String> {
+        match self {
+            Self::Local(path) => Some(format!("local:{}", path.display())),
+            Self::Remote(remote) => Some(format!("remote:{:?}:{}", remote.strategy, remote.url)),
+            Self::Transcode(target) => Some(format!("transcode:{}", target.path.display())),
+            Self::Concat(parts) => Some(format!(
+                "concat:{}",
+                parts
+                    .iter()
+                    .map(|part| part.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join("\0")
+            )),
+            Self::Inline(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct InlineMedia {
+    content_type: &'static str,
+    body: axum::body::Bytes,
+}
+
+/// 媒体 token 的生成格式。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum TokenFormat {
+    /// 标准 UUID v4（默认）。
+    #[default]
+    Uuid,
+    /// 16 位 base62 随机串，URL 更短。
+    Short,
+    /// 房间名哈希前缀 + 16 位 base62，方便在日志中按房间关联。
+    RoomPrefixed,
+}
+
+const SHORT_TOKEN_LEN: usize = 16;
+
+impl TokenFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "uuid" => Some(Self::Uuid),
+            "short" => Some(Self::Short),
+            "room" => Some(Self::RoomPrefixed),
+            _ => None,
+        }
+    }
+
+    fn generate(self, room_name: &str) -> String {
+        match self {
+            Self::Uuid => Uuid::new_v4().to_string(),
+            Self::Short => random_string(SHORT_TOKEN_LEN),
+            Self::RoomPrefixed => {
+                let digest = format!("{:x}", md5::compute(room_name.as_bytes()));
+                format!("{}-{}", &digest[..6], random_string(SHORT_TOKEN_LEN))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MediaToken {
+    target: MediaTarget,
+    expires_at: Instant,
+    /// B 站直链的原始输入，上游失效时据此重新取链。
+    origin: Option<BiliOrigin>,
+    validation: Option<TokenValidation>,
+}
+
+#[derive(Debug, Clone)]
+struct BiliOrigin {
+    input: String,
+    audio_only: bool,
+    /// 当前直链的清晰度，刷新和切换清晰度时沿用或替换。
+    quality: Option<u32>,
+}
+
+#[derive(Debug)]
+struct BiliStream {
+    url: String,
+    cid: i64,
+    /// 稿件时长（秒），决定弹幕分段数。
+    duration: i64,
+    cover: Option<String>,
+    audio: Option<AudioInfo>,
+    dimension: Option<VideoDimension>,
+    quality: Option<u32>,
+}
+
+/// 最近一次上游探测的结果。
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenValidation {
+    checked_at: i64,
+    /// 上游响应码，请求失败时为 None。
+    upstream_status: Option<u16>,
+    alive: bool,
+    /// 探测失败后是否已换成新的直链。
+    refreshed: bool,
+}
+
+#[derive(Debug, Clone)]
+enum RemoteStrategy {
+    Redirect,
+    ProxyWithHeaders,
+}
+
+/// 代理远程流的两段超时：建立连接，以及连接后等到响应头。
+#[derive(Debug, Clone, Copy)]
+struct ProxyTimeouts {
+    connect: Duration,
+    first_byte: Duration,
+}
+
+impl Default for ProxyTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_PROXY_CONNECT_TIMEOUT,
+            first_byte: DEFAULT_PROXY_FIRST_BYTE_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RemoteTarget {
+    url: String,
+    strategy: RemoteStrategy,
+}
+
+/// 占用一个转码名额，drop 时归还。
+struct TranscodeSlot {
+    token: String,
+    active: Arc<StdMutex<HashMap<String, usize>>>,
+}
+
+impl Drop for TranscodeSlot {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = active.get_mut(&self.token) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.token);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Manager {
+    rooms: RwLock<HashMap<String, Room>>,
+    media_tokens: RwLock<HashMap<String, MediaToken>>,
+    /// 最近过期房间的最后状态，见 `Tombstone`。
+    tombstones: StdMutex<HashMap<String, Tombstone>>,
+    /// 过期房间状态的保留时长，为 None 时过期房间直接丢弃。
+    tombstone_ttl: Option<Duration>,
+    media_roots: RwLock<Vec<MediaRoot>>,
+    /// 各媒体根下文件的 `lib://` 索引，与 `media_roots` 一一对应；首次使用或根目录变化时重建。
+    library: RwLock<Vec<library::LibraryIndex>>,
+    room_ttl: Duration,
+    token_ttl: Duration,
+    cleanup_interval: Duration,
+    token_format: TokenFormat,
+    /// `房间\0目标标识` 到已签发 token 的索引，为 None 时每次 resolve 都签发新 token。
+    token_reuse: Option<StdMutex<HashMap<String, String>>>,
+    /// ffmpeg 路径，为 None 时不提供转码。
+    ffmpeg: Option<PathBuf>,
+    /// 各 token 正在进行的转码数。
+    transcodes: Arc<StdMutex<HashMap<String, usize>>>,
+    /// 本地文件封面生成器，为 None 时本地文件没有封面。
+    thumbnails: Option<thumbnail::Thumbnailer>,
+    /// seek 软锁窗口，为零时关闭。
+    seek_lock: Duration,
+    max_rooms: Option<usize>,
+    state_limits: StateLimits,
+    /// 快照加密密钥，为 None 时导出明文。
+    snapshot_key: Option<seal::SnapshotKey>,
+    /// 本地媒体扩展名白名单（小写、无点），为 None 时不限制。
+    allowed_exts: Option<HashSet<String>>,
+    /// 无活动多久后自动暂停，为 None 时关闭。
+    idle_pause: Option<Duration>,
+    /// B 站稿件的时长上限，为 None 时不限制；普通远程和本地文件时长未知，不受限制。
+    max_duration: Option<Duration>,
+    /// 定时推送 `seek_correction` 的间隔，为 None 时关闭。
+    seek_correction: Option<Duration>,
+    /// 定时向暂停房间重发权威状态的间隔，为 None 时关闭。
+    paused_resync: Option<Duration>,
+    /// 追赶提示建议的临时倍速，为 None 时不根据进度上报纠偏。
+    catchup_rate: Option<f64>,
+    /// 房主播放/暂停/seek 的预告提前量，为零时立即生效。
+    write_ahead: Duration,
+    /// 每个成员的聊天令牌补充速率（条/秒），为 None 时不限速。
+    chat_rate: Option<f64>,
+    /// 当前播放源报错成员的占比达到该值时标记为有问题。
+    error_flag_ratio: f64,
+    root_policy: root_policy::RootPolicy,
+    /// 主房主凭恢复令牌重新加入时夺回主房主身份。
+    host_resume: bool,
+    /// 加入不存在的房间且未指定 `mode` 时是否自动创建。
+    join_creates: bool,
+    /// 主房主断线后移交前的宽限期，为 None 时不自动移交。
+    host_grace: Option<Duration>,
+    segment_cache: Option<segment_cache::SegmentCache>,
+    /// 远程流预读的字节上限，为 None 时按 `PROXY_RELAY_CHUNKS` 个分块转发。
+    proxy_readahead: Option<usize>,
+    proxy_timeouts: ProxyTimeouts,
+    /// 独立媒体监听的端口，为 None 时媒体与 API 共用主端口。
+    media_port: Option<u16>,
+    /// 在 CORS_EXPOSE_HEADERS 之外额外暴露给前端脚本的响应头。
+    cors_expose: Vec<HeaderName>,
+    /// B 站解析熔断器，为 None 时每次解析都直接请求 API。
+    bili_breaker: Option<breaker::CircuitBreaker>,
+    webhook: Option<webhook::Webhook>,
+    allow_member_control: bool,
+    /// 不允许成员直接控制时，成员的状态更新转为等待房主确认的提议。
+    member_proposals: bool,
+    /// 成员的状态更新是否可以修改共享音量/静音。
+    member_volume: bool,
+    /// 房主失焦时是否自动暂停房间。
+    focus_pause: bool,
+    /// B 站 API 根地址，测试中指向本地 mock。
+    bili_api_base: String,
+    /// 宿主应用的登录会话；请求未显式指定 Cookie 时从这里按 URL 取。
+    bili_session: StdMutex<Option<Arc<Jar>>>,
+    /// 刷新期间持锁，并发 resolve 只会触发一次 nav 请求。
+    wbi_key: Mutex<Option<WbiKey>>,
+    /// 通知后台清理任务立即退出，不必等到下一次 tick。
+    shutdown: Arc<Notify>,
+}
+
+impl Drop for Manager {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WbiKey {
+    mixin_key: String,
+    fetched_at: Instant,
+    hits: u64,
+}
+
+impl Manager {
+    fn new(media_root: Option<PathBuf>, allow_member_control: bool) -> Self {
+        Self {
+            rooms: RwLock::new(HashMap::new()),
+            media_tokens: RwLock::new(HashMap::new()),
+            tombstones: StdMutex::new(HashMap::new()),
+            tombstone_ttl: Some(DEFAULT_TOMBSTONE_TTL),
+            media_roots: RwLock::new(
+                media_root
+                    .map(|path| {
+                        let path = clean_path(path);
+                        MediaRoot {
+                            label: default_root_label(&path),
+                            path,
+                        }
+                    })
+                    .into_iter()
+                    .collect(),
+            ),
+            library: RwLock::new(Vec::new()),
+            room_ttl: Duration::from_secs(30 * 60),
+            token_ttl: Duration::from_secs(60 * 60),
+            cleanup_interval: DEFAULT_CLEANUP_INTERVAL,
+            token_format: TokenFormat::default(),
+            token_reuse: None,
+            ffmpeg: None,
+            transcodes: Arc::new(StdMutex::new(HashMap::new())),
+            thumbnails: None,
+            seek_lock: Duration::ZERO,
+            max_rooms: None,
+            state_limits: StateLimits::default(),
+            snapshot_key: None,
+            allowed_exts: Some(default_media_exts()),
+            idle_pause: None,
+            max_duration: None,
+            seek_correction: None,
+            paused_resync: None,
+            catchup_rate: None,
+            write_ahead: Duration::ZERO,
+            chat_rate: Some(DEFAULT_CHAT_RATE),
+            error_flag_ratio: DEFAULT_ERROR_FLAG_RATIO,
+            root_policy: root_policy::RootPolicy::default(),
+            host_resume: true,
+            join_creates: true,
+            host_grace: Some(DEFAULT_HOST_GRACE),
+            segment_cache: None,
+            proxy_readahead: None,
+            proxy_timeouts: ProxyTimeouts::default(),
+            media_port: None,
+            cors_expose: Vec::new(),
+            bili_breaker: None,
+            webhook: None,
+            allow_member_control,
+            member_proposals: false,
+            member_volume: false,
+            focus_pause: false,
+            bili_api_base: BILI_API_BASE.to_string(),
+            bili_session: StdMutex::new(None),
+            wbi_key: Mutex::new(None),
+            shutdown: Arc::new(Notify::new()),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_bili_api_base(mut self, base: impl Into<String>) -> Self {
+        self.bili_api_base = base.into();
+        self
+    }
+
+    fn with_cleanup_interval(mut self, interval: Duration) -> Self {
+        self.cleanup_interval = interval;
+        self
+    }
+
+    fn with_member_proposals(mut self, enabled: bool) -> Self {
+        self.member_proposals = enabled;
+        self
+    }
+
+    fn with_member_volume(mut self, enabled: bool) -> Self {
+        self.member_volume = enabled;
+        self
+    }
+
+    fn with_focus_pause(mut self, enabled: bool) -> Self {
+        self.focus_pause = enabled;
+        self
+    }
+
+    fn with_token_reuse(mut self, enabled: bool) -> Self {
+        self.token_reuse = enabled.then(|| StdMutex::new(HashMap::new()));
+        self
+    }
+
+    fn with_token_format(mut self, format: TokenFormat) -> Self {
+        self.token_format = format;
+        self
+    }
+
+    fn with_ffmpeg(mut self, ffmpeg: Option<PathBuf>) -> Self {
+        self.ffmpeg = ffmpeg;
+        self
+    }
+
+    fn with_thumbnails(mut self, thumbnails: Option<thumbnail::Thumbnailer>) -> Self {
+        self.thumbnails = thumbnails;
+        self
+    }
+
+    fn with_seek_lock(mut self, window: Duration) -> Self {
+        self.seek_lock = window;
+        self
+    }
+
+    fn with_max_rooms(mut self, max_rooms: Option<usize>) -> Self {
+        self.max_rooms = max_rooms;
+        self
+    }
+
+    fn with_state_limits(mut self, limits: StateLimits) -> Self {
+        self.state_limits = limits;
+        self
+    }
+
+    fn with_snapshot_key(mut self, key: Option<seal::SnapshotKey>) -> Self {
+        self.snapshot_key = key;
+        self
+    }
+
+    fn with_allowed_exts(mut self, exts: Option<HashSet<String>>) -> Self {
+        self.allowed_exts = exts;
+        self
+    }
+
+    fn with_idle_pause(mut self, window: Option<Duration>) -> Self {
+        self.idle_pause = window;
+        self
+    }
+
+    fn with_max_duration(mut self, limit: Option<Duration>) -> Self {
+        self.max_duration = limit;
+        self
+    }
+
+    fn with_seek_correction(mut self, interval: Option<Duration>) -> Self {
+        self.seek_correction = interval;
+        self
+    }
+
+    fn with_paused_resync(mut self, interval: Option<Duration>) -> Self {
+        self.paused_resync = interval;
+        self
+    }
+
+    fn with_catchup_rate(mut self, rate: Option<f64>) -> Self {
+        self.catchup_rate = rate;
+        self
+    }
+
+    fn with_error_flag_ratio(mut self, ratio: f64) -> Self {
+        self.error_flag_ratio = ratio;
+        self
+    }
+
+    fn with_chat_rate(mut self, rate: Option<f64>) -> Self {
+        self.chat_rate = rate;
+        self
+    }
+
+    fn with_write_ahead(mut self, delta: Duration) -> Self {
+        self.write_ahead = delta;
+        self
+    }
+
+    fn with_root_policy(mut self, policy: root_policy::RootPolicy) -> Self {
+        self.root_policy = policy;
+        self
+    }
+
+    fn with_host_resume(mut self, enabled: bool) -> Self {
+        self.host_resume = enabled;
+        self
+    }
+
+    fn with_join_creates(mut self, enabled: bool) -> Self {
+        self.join_creates = enabled;
+        self
+    }
+
+    fn with_tombstone_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    fn with_host_grace(mut self, grace: Option<Duration>) -> Self {
+        self.host_grace = grace;
+        self
+    }
+
+    fn with_segment_cache(mut self, max_bytes: Option<usize>) -> Self {
+        self.segment_cache = max_bytes.map(segment_cache::SegmentCache::new);
+        self
+    }
+
+    fn with_cors_expose(mut self, headers: Vec<HeaderName>) -> Self {
+        self.cors_expose = headers;
+        self
+    }
+
+    fn with_media_port(mut self, port: Option<u16>) -> Self {
+        self.media_port = port;
+        self
+    }
+
+    /// 按请求的 Host 换成媒体端口，得到客户端可访问的媒体地址前缀。
+    fn media_origin(&self, headers: &HeaderMap) -> Option<String> {
+        let port = self.media_port?;
+        let host = headers.get(axum::http::header::HOST)?.to_str().ok()?.trim();
+        // `[::1]:18080` 或 `example.com:18080`，去掉原端口。
+        let name = match host.rfind(':') {
+            Some(at) if !host[at..].contains(']') => &host[..at],
+            _ => host,
+        };
+        (!name.is_empty()).then(|| format!("http://{name}:{port}"))
+    }
+
+    fn with_proxy_timeouts(mut self, timeouts: ProxyTimeouts) -> Self {
+        self.proxy_timeouts = timeouts;
+        self
+    }
+
+    fn with_proxy_readahead(mut self, bytes: Option<usize>) -> Self {
+        self.proxy_readahead = bytes;
+        self
+    }
+
+    fn with_bili_breaker(mut self, breaker: Option<breaker::CircuitBreaker>) -> Self {
+        self.bili_breaker = breaker;
+        self
+    }
+
+    fn with_webhook(mut self, url: Option<String>) -> Self {
+        self.webhook = url.map(webhook::Webhook::new);
+        self
+    }
+
+    fn notify_webhook(&self, event: webhook::WebhookEvent) {
+        if let Some(webhook) = &self.webhook {
+            webhook.send(event);
+        }
+    }
+
+    /// 新状态换了播放源时通知 webhook。
+    fn notify_source_change(&self, room_name: &str, state: &RoomState, changed: bool) {
+        if changed {
+            self.notify_webhook(
+                webhook::WebhookEvent::new("source_changed", room_name).with_state(state),
+            );
+        }
+    }
+
+    fn is_allowed_ext(&self, path: &Path) -> bool {
+        ext_allowed(self.allowed_exts.as_ref(), path)
+    }
+
+    /// 新建房间前检查上限，只统计未过期的房间。
+    fn ensure_room_capacity(&self, rooms: &HashMap<String, Room>) -> Result<(), ApiError> {
+        let Some(max) = self.max_rooms else {
+            return Ok(());
+        };
+        if self.live_rooms(rooms) >= max {
+            return Err(ApiError::unavailable("room limit reached"));
+        }
+        Ok(())
+    }
+
+    fn live_rooms(&self, rooms: &HashMap<String, Room>) -> usize {
+        let now = Instant::now();
+        rooms
+            .values()
+            .filter(|room| !room.is_expired(now, self.room_ttl))
+            .count()
+    }
+
+    /// 未过期的房间数。
+    async fn active_rooms(&self) -> usize {
+        self.live_rooms(&*self.rooms.read().await)
+    }
+
+    fn spawn_cleanup(self: &Arc<Self>, hub: Arc<Hub>) {
+        let weak = Arc::downgrade(self);
+        let interval = self.cleanup_interval;
+        let shutdown = self.shutdown.clone();
+        let mut corrections = self.seek_correction.map(tokio_time::interval);
+        let mut resyncs = self.paused_resync.map(tokio_time::interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio_time::interval(interval);
+            loop {
+                let correcting = async {
+                    match corrections.as_mut() {
+                        Some(corrections) => corrections.tick().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                let resyncing = async {
+                    match resyncs.as_mut() {
+                        Some(resyncs) => resyncs.tick().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                let (correct, resync) = tokio::select! {
+                    _ = ticker.tick() => (false, false),
+                    _ = correcting => (true, false),
+                    _ = resyncing => (false, true),
+                    _ = shutdown.notified() => break,
+                };
+                let Some(manager) = weak.upgrade() else {
+                    break;
+                };
+                if correct {
+                    for (room, state) in manager.seek_corrections().await {
+                        hub.broadcast(&room, &seek_correction_message(state)).await;
+                    }
+                    continue;
+                }
+                if resync {
+                    for (room, state, hosts) in manager.paused_resyncs().await {
+                        // 没有房主在线时无人确认位置，跳过。
+                        if hub.connected_users(&room).await.is_disjoint(&hosts) {
+                            continue;
+                        }
+                        let msg = WsOutgoing {
+                            reason: Some("paused_resync".into()),
+                            server_time: Some(now_millis()),
+                            ..WsOutgoing::with_state("room_state", state)
+                        };
+                        hub.broadcast(&room, &msg).await;
+                    }
+                    continue;
+                }
+                for room in manager.cleanup().await {
+                    hub.close_room(&room, close_with(CLOSE_ROOM_CLOSED, "room closed"))
+                        .await;
+                }
+                for (room, state) in manager.pause_idle_rooms().await {
+                    let msg = WsOutgoing {
+                        reason: Some("inactivity".into()),
+                        server_time: Some(now_millis()),
+                        ..WsOutgoing::with_state("room_state", state)
+                    };
+                    hub.broadcast(&room, &msg).await;
+                }
+                manager.validate_remote_tokens().await;
+            }
+        });
+    }
+
+    /// 停止后台清理任务；`notify_one` 会保留许可，任务正忙于清理时也不会漏掉。
+    fn shutdown(&self) {
+        self.shutdown.notify_one();
+    }
+
+    #[cfg(test)]
+    async fn join_room(&self, name: &str, password: &str) -> Result<(String, bool), ApiError> {
+        let outcome = self
+            .join_room_as(name, password, JoinOptions::default())
+            .await?;
+        Ok((outcome.temp_user, outcome.is_host))
+    }
+
+    async fn join_room_as(
+        &self,
+        name: &str,
+        password: &str,
+        options: JoinOptions<'_>,
+    ) -> Result<JoinOutcome, ApiError> {
+        let name = name.trim();
+        let password = password.trim();
+        if name.is_empty() || password.is_empty() {
+            return Err(ApiError::bad_request("room name and password required"));
+        }
+        let temp_user = match options.temp_user {
+            Some(id) => {
+                validate_temp_user(id)?;
+                id.to_string()
+            }
+            None => Uuid::new_v4().to_string(),
+        };
+        let mode = options.mode.unwrap_or(if self.join_creates {
+            JoinMode::Auto
+        } else {
+            JoinMode::Join
+        });
+        let mut rooms = self.rooms.write().await;
+        let created = !rooms.contains_key(name);
+        match mode {
+            JoinMode::Join if created && !self.has_tombstone(name, password) => {
+                return Err(ApiError::not_found("room not found"));
+            }
+            JoinMode::Create if !created => {
+                return Err(ApiError::conflict("room already exists"));
+            }
+            _ => {}
+        }
+        if created {
+            self.ensure_room_capacity(&rooms)?;
+        }
+        let room = rooms
+            .entry(name.to_string())
+            .or_insert_with(|| Room::new(password));
+        if room.password != password {
+            return Err(ApiError::bad_request("room password mismatch"));
+        }
+        if room.members.contains_key(&temp_user) {
+            return Err(ApiError::conflict("temp user already in room"));
+        }
+        let mut outcome = JoinOutcome {
+            temp_user: temp_user.clone(),
+            member_id: String::new(),
+            is_host: false,
+            host_resume_token: None,
+            hosts: None,
+            restored: false,
+        };
+        if created {
+            if let Some(tombstone) = self.take_tombstone(name, password) {
+                if let Some(state) = tombstone.state {
+                    room.set_state(state);
+                }
+                room.source = tombstone.source;
+                room.playlist = tombstone.playlist;
+                room.record("restored", None);
+                outcome.restored = true;
+            }
+        }
+        if room.primary_host.is_none() {
+            room.set_primary_host(&temp_user);
+            if self.host_resume {
+                room.host_resume = Some((random_string(32), temp_user.clone()));
+            }
+            outcome.is_host = true;
+        }
+        room.members
+            .insert(temp_user.clone(), Member::new(options.client_id));
+        room.record("join", Some(&temp_user));
+        if created {
+            self.notify_webhook(webhook::WebhookEvent::new("room_created", name));
+        }
+
+        let reclaim = self.host_resume
+            && options.resume_token.is_some_and(|token| {
+                room.host_resume
+                    .as_ref()
+                    .is_some_and(|(expected, _)| expected == token)
+            });
+        if reclaim {
+            // 旧身份即断线前的自己，直接移除；期间接手的主房主退为普通成员。
+            if let Some((_, previous)) = room.host_resume.as_mut() {
+                let previous = std::mem::replace(previous, temp_user.clone());
+                // 沿用旧身份的公开标识，其他成员看到的仍是同一个人。
+                if let Some(old) = room.members.remove(&previous) {
+                    if let Some(member) = room.members.get_mut(&temp_user) {
+                        member.id = old.id;
+                    }
+                }
+                room.positions.remove(&previous);
+                room.host_ids.remove(&previous);
+            }
+            if let Some(current) = room.primary_host.take() {
+                room.host_ids.remove(&current);
+            }
+            room.set_primary_host(&temp_user);
+            room.record("reclaim", Some(&temp_user));
+            outcome.is_host = true;
+            outcome.hosts = Some(room.hosts());
+        }
+        if room.primary_host.as_deref() == Some(temp_user.as_str()) {
+            outcome.host_resume_token = room.host_resume.as_ref().map(|(token, _)| token.clone());
+        }
+        if let Some(member) = room.members.get(&temp_user) {
+            outcome.member_id = member.id.clone();
+        }
+        Ok(outcome)
+    }
+
+    async fn authorize(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+    ) -> Result<bool, ApiError> {
+        let rooms = self.rooms.read().await;
+        let room = rooms
+            .get(room_name)
+            .ok_or_else(|| ApiError::forbidden("room not found"))?;
+        if room.password != password {
+            return Err(ApiError::forbidden("room password mismatch"));
+        }
+        if !room.members.contains_key(temp_user) {
+            return Err(ApiError::forbidden("user not in room"));
+        }
+        Ok(room.is_host(temp_user))
+    }
+
+    /// 只校验房间存在且口令正确，返回当前成员数；与 `authorize` 不同，不要求调用者已加入。
+    /// 口令匹配的保留状态也算房间存在（`join` 模式可以重建），此时成员数为 0。
+    async fn check_credentials(&self, room_name: &str, password: &str) -> Result<usize, ApiError> {
+        let rooms = self.rooms.read().await;
+        match rooms.get(room_name) {
+            Some(room) if room.password == password => Ok(room.members.len()),
+            Some(_) => Err(ApiError::forbidden("room password mismatch")),
+            None if self.has_tombstone(room_name, password) => Ok(0),
+            None => Err(ApiError::not_found("room not found")),
+        }
+    }
+
+    async fn is_host(&self, room_name: &str, temp_user: &str) -> bool {
+        self.rooms
+            .read()
+            .await
+            .get(room_name)
+            .is_some_and(|room| room.is_host(temp_user))
+    }
+
+    async fn is_primary_host(&self, room_name: &str, temp_user: &str) -> bool {
+        self.rooms
+            .read()
+            .await
+            .get(room_name)
+            .is_some_and(|room| room.primary_host.as_deref() == Some(temp_user))
+    }
+
+    /// 把离线主房主的身份交给在线成员，优先协同房主；没有在线成员时保持不变。
+    /// 原房主仍留在房间内，可凭恢复令牌夺回。
+    async fn reassign_primary(
+        &self,
+        room_name: &str,
+        departed: &str,
+        online: &HashSet<String>,
+    ) -> Option<HostsResponse> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms.get_mut(room_name)?;
+        if room.primary_host.as_deref() != Some(departed) {
+            return None;
+        }
+        let mut candidates: Vec<&String> = online
+            .iter()
+            .filter(|user| user.as_str() != departed && room.members.contains_key(*user))
+            .collect();
+        // 协同房主优先，其次最近活跃的成员
+        candidates.sort_by_key(|user| {
+            (
+                !room.is_host(user),
+                std::cmp::Reverse(room.members[*user].last_seen),
+            )
+        });
+        let next = candidates.first()?.to_string();
+        room.host_ids.remove(departed);
+        room.set_primary_host(&next);
+        room.record("reassign", Some(&next));
+        Some(room.hosts())
+    }
+
+    async fn touch_member(&self, room_name: &str, temp_user: &str) {
+        if let Some(room) = self.rooms.write().await.get_mut(room_name) {
+            room.members
+                .entry(temp_user.to_string())
+                .or_insert_with(|| Member::new(None))
+                .last_seen = Instant::now();
+        }
+    }
+
+    async fn members(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+    ) -> Result<Vec<MemberInfo>, ApiError> {
+        self.authorize(room_name, password, temp_user).await?;
+        let rooms = self.rooms.read().await;
+        let room = rooms
+            .get(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let mut members: Vec<MemberInfo> = room
+            .members
+            .iter()
+            .map(|(user, member)| MemberInfo {
+                id: member.id.clone(),
+                client_id: member.client_id.clone(),
+                is_host: room.is_host(user),
+                idle_secs: member.last_seen.elapsed().as_secs(),
+            })
+            .collect();
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(members)
+    }
+
+    /// 更换房间密码，返回当前房主的 temp_user（这些连接不受影响）。
+    async fn change_password(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        new_password: &str,
+    ) -> Result<HashSet<String>, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        let new_password = new_password.trim();
+        if new_password.is_empty() {
+            return Err(ApiError::bad_request("new password required"));
+        }
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        room.password = new_password.to_string();
+        room.record("password", Some(temp_user));
+        Ok(room.host_ids.clone())
+    }
+
+    async fn set_allowed_rates(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        rates: Option<Vec<f64>>,
+    ) -> Result<Option<Vec<f64>>, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        if let Some(rates) = &rates {
+            if rates.is_empty() || rates.len() > MAX_RATE_PRESETS {
+                return Err(ApiError::bad_request(format!(
+                    "rates must contain 1 to {MAX_RATE_PRESETS} entries"
+                )));
+            }
+            if rates.iter().any(|r| !r.is_finite() || *r <= 0.0) {
+                return Err(ApiError::bad_request("rates must be positive numbers"));
+            }
+        }
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        room.allowed_rates = rates;
+        room.record("rates", Some(temp_user));
+        Ok(room.allowed_rates.clone())
+    }
+
+    /// 按一次 `time_sync` 估算成员时钟偏差：客户端发送时刻加上单程延迟（rtt/2）
+    /// 即服务器收到时的客户端时间。偏差首次超过阈值时返回该偏差，调用方据此告警。
+    async fn record_clock_offset(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        client_time: i64,
+        rtt_ms: Option<u64>,
+        server_time: i64,
+    ) -> Result<Option<i64>, ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let member = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?
+            .members
+            .get_mut(temp_user)
+            .ok_or_else(|| ApiError::forbidden("not a member of this room"))?;
+        let offset = client_time + rtt_ms.unwrap_or(0) as i64 / 2 - server_time;
+        let was_skewed = member
+            .clock_offset
+            .is_some_and(|prev| prev.abs() > CLOCK_SKEW_WARN_MS);
+        member.clock_offset = Some(offset);
+        member.last_seen = Instant::now();
+        Ok((offset.abs() > CLOCK_SKEW_WARN_MS && !was_skewed).then_some(offset))
+    }
+
+    /// 记录成员提议，返回提议和需要通知的房主。
+    async fn propose(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        state: RoomState,
+    ) -> Result<(Proposal, HashSet<String>), ApiError> {
+        if !self.member_proposals {
+            return Err(ApiError::forbidden("proposals are disabled"));
+        }
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if room.is_host(temp_user) {
+            return Err(ApiError::bad_request("hosts update state directly"));
+        }
+        if room.state.is_none() {
+            return Err(ApiError::bad_request("host has not published state"));
+        }
+        if !room.is_rate_allowed(state.playback_rate) {
+            return Err(ApiError::bad_request(
+                "playback rate not allowed in this room",
+            ));
+        }
+        let from = room
+            .members
+            .get(temp_user)
+            .map(|member| member.id.clone())
+            .ok_or_else(|| ApiError::forbidden("user not in room"))?;
+        room.proposals.retain(|p| p.member != temp_user);
+        if room.proposals.len() == MAX_PENDING_PROPOSALS {
+            room.proposals.pop_front();
+        }
+        room.last_proposal_id += 1;
+        let proposal = Proposal {
+            id: room.last_proposal_id,
+            from,
+            member: temp_user.to_string(),
+            state,
+            created_at: now_millis(),
+        };
+        room.proposals.push_back(proposal.clone());
+        room.record("proposal", Some(temp_user));
+        Ok((proposal, room.host_ids.clone()))
+    }
+
+    /// 房主接受或拒绝提议；接受时按成员更新的规则合并并返回新状态。
+    async fn resolve_proposal(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        id: u64,
+        accept: bool,
+    ) -> Result<Option<RoomState>, ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_host(temp_user) {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        let index = room
+            .proposals
+            .iter()
+            .position(|p| p.id == id)
+            .ok_or_else(|| ApiError::not_found("proposal not found"))?;
+        let Some(proposal) = room.proposals.remove(index) else {
+            return Err(ApiError::not_found("proposal not found"));
+        };
+        if !accept {
+            room.record("proposal_rejected", Some(temp_user));
+            return Ok(None);
+        }
+        let existing = room
+            .state
+            .clone()
+            .ok_or_else(|| ApiError::bad_request("host has not published state"))?;
+        let merged = existing.with_member_update(&proposal.state, self.member_volume);
+        room.set_state(merged.clone());
+        room.record("proposal_accepted", Some(temp_user));
+        Ok(Some(merged))
+    }
+
+    /// 设置或取消计划起播；计划时刻须在未来且房主已发布播放状态。
+    async fn set_schedule(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        start_at: Option<i64>,
+    ) -> Result<Option<i64>, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        if let Some(at) = start_at {
+            let now = now_millis();
+            if at <= now {
+                return Err(ApiError::bad_request(
+                    "scheduled start must be in the future",
+                ));
+            }
+            if at - now > MAX_SCHEDULE_AHEAD_MS {
+                return Err(ApiError::bad_request(
+                    "scheduled start is too far in the future",
+                ));
+            }
+        }
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if start_at.is_some() && room.state.is_none() {
+            return Err(ApiError::bad_request("host has not published state"));
+        }
+        room.scheduled_start = start_at;
+        room.record("schedule", Some(temp_user));
+        Ok(start_at)
+    }
+
+    async fn scheduled_start(&self, room_name: &str) -> Option<i64> {
+        self.rooms
+            .read()
+            .await
+            .get(room_name)
+            .and_then(|room| room.scheduled_start)
+    }
+
+    /// 计划仍为 `at` 时清除计划并从当前进度开始播放，返回新状态。
+    async fn fire_schedule(&self, room_name: &str, at: i64) -> Option<RoomState> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms.get_mut(room_name)?;
+        if room.scheduled_start != Some(at) {
+            return None;
+        }
+        room.scheduled_start = None;
+        let mut state = room.state.clone()?;
+        let now = now_millis();
+        state.current_time = state.position_at(now);
+        state.paused = false;
+        state.updated_at = now;
+        room.set_state(state.clone());
+        room.record("scheduled_start", None);
+        Some(state)
+    }
+
+    async fn set_allowed_sources(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        sources: Option<Vec<SourceKind>>,
+    ) -> Result<Option<BTreeSet<SourceKind>>, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        if sources.as_ref().is_some_and(|s| s.is_empty()) {
+            return Err(ApiError::bad_request("sources must not be empty"));
+        }
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        room.allowed_sources = sources.map(|s| s.into_iter().collect());
+        room.record("sources", Some(temp_user));
+        Ok(room.allowed_sources.clone())
+    }
+
+    async fn allowed_sources(&self, room_name: &str) -> Option<BTreeSet<SourceKind>> {
+        self.rooms
+            .read()
+            .await
+            .get(room_name)
+            .and_then(|room| room.allowed_sources.clone())
+    }
+
+    async fn room_settings(&self, room_name: &str) -> Option<RoomSettings> {
+        let rooms = self.rooms.read().await;
+        let room = rooms.get(room_name)?;
+        let member_control = match (self.member_proposals, self.allow_member_control) {
+            (true, _) => "proposal",
+            (false, true) => "on",
+            (false, false) => "off",
+        };
+        Some(RoomSettings {
+            member_control,
+            allowed_rates: room.allowed_rates.clone(),
+            allowed_sources: room.allowed_sources.clone(),
+            scheduled_start: room.scheduled_start,
+            host_focused: room.host_focused,
+        })
+    }
+
+    async fn allowed_rates(&self, room_name: &str) -> Option<Vec<f64>> {
+        self.rooms
+            .read()
+            .await
+            .get(room_name)?
+            .allowed_rates
+            .clone()
+    }
+
+    /// 房主发起协同起播，返回新状态与起播时刻。
+    async fn coordinated_play(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        mut state: RoomState,
+        delay_ms: Option<u64>,
+    ) -> Result<(RoomState, i64), ApiError> {
+        state.validate()?;
+        let delay = delay_ms
+            .unwrap_or(DEFAULT_PLAY_DELAY_MS)
+            .min(MAX_PLAY_DELAY_MS);
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_host(temp_user) {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        if !room.is_rate_allowed(state.playback_rate) {
+            return Err(ApiError::bad_request(
+                "playback rate not allowed in this room",
+            ));
+        }
+        let start_at = now_millis() + delay as i64;
+        state.paused = false;
+        state.updated_at = start_at;
+        let changed = room.set_state(state.clone());
+        room.record("coordinated_play", Some(temp_user));
+        self.notify_source_change(room_name, &state, changed);
+        Ok((state, start_at))
+    }
+
+    /// 成员报告播放器已可播放当前播放源；`online` 为房间内仍有连接的用户。
+    async fn mark_ready(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        online: &HashSet<String>,
+    ) -> Result<ReadyTally, ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.members.contains_key(temp_user) {
+            return Err(ApiError::forbidden("not a room member"));
+        }
+        if room.state.is_none() {
+            return Err(ApiError::bad_request("host has not published state"));
+        }
+        room.ready.insert(temp_user.to_string());
+        Ok(room.tally_ready(online))
+    }
+
+    /// 房主为当前播放源开启就绪握手，已达标时立即起播。
+    async fn await_ready(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        threshold: Option<usize>,
+        delay_ms: Option<u64>,
+        online: &HashSet<String>,
+    ) -> Result<ReadyTally, ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_host(temp_user) {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        if room.state.is_none() {
+            return Err(ApiError::bad_request("host has not published state"));
+        }
+        room.ready_gate = Some(ReadyGate {
+            threshold,
+            delay_ms: delay_ms
+                .unwrap_or(DEFAULT_PLAY_DELAY_MS)
+                .min(MAX_PLAY_DELAY_MS),
+        });
+        Ok(room.tally_ready(online))
+    }
+
+    /// 有成员断线时重新统计等待中的就绪握手，剩下的人都已就绪时随即起播；没有握手时返回 None。
+    async fn retally_ready(&self, room_name: &str, online: &HashSet<String>) -> Option<ReadyTally> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms.get_mut(room_name)?;
+        room.ready_gate?;
+        Some(room.tally_ready(online))
+    }
+
+    /// 记录主房主的窗口焦点。开启失焦暂停时，失焦暂停正在播放的房间，重新聚焦时恢复播放；
+    /// 返回需要广播的新状态。只认主房主，多位房主各自上报会互相覆盖。
+    async fn set_host_focus(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        focused: bool,
+    ) -> Result<Option<RoomState>, ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if room.primary_host.as_deref() != Some(temp_user) {
+            return Err(ApiError::forbidden(
+                "only the primary host can report focus",
+            ));
+        }
+        room.host_focused = focused;
+        if !self.focus_pause {
+            return Ok(None);
+        }
+        let resume = focused && room.focus_paused;
+        let Some(state) = room.state.as_ref().filter(|s| s.paused == resume) else {
+            return Ok(None);
+        };
+        let mut state = state.rebased_at(now_millis());
+        state.paused = !resume;
+        room.set_state(state.clone());
+        room.focus_paused = !resume;
+        room.record("host_focus", Some(temp_user));
+        Ok(Some(state))
+    }
+
+    /// 清空房间的播放内容：当前状态、播放列表、预加载项、标注与计划起播，
+    /// 并吊销这些状态引用的 token。成员、房主身份与房间策略保持不变。返回吊销的 token 数。
+    async fn reset_room(&self, room_name: &str, temp_user: &str) -> Result<usize, ApiError> {
+        let tokens: HashSet<String> = {
+            let mut rooms = self.rooms.write().await;
+            let room = rooms
+                .get_mut(room_name)
+                .ok_or_else(|| ApiError::bad_request("room not found"))?;
+            if !room.is_host(temp_user) {
+                return Err(ApiError::forbidden("operation allowed for host only"));
+            }
+            let tokens = room
+                .state
+                .iter()
+                .chain(room.pending_next.iter())
+                .flat_map(|state| std::iter::once(&state.url).chain(state.cover.iter()))
+                .filter_map(|url| url.strip_prefix("/media/"))
+                .map(str::to_string)
+                .collect();
+            room.state = None;
+            room.source = None;
+            room.playlist.clear();
+            room.pending_next = None;
+            room.markers.clear();
+            room.ready.clear();
+            room.ready_gate = None;
+            room.seek_owner = None;
+            room.positions.clear();
+            room.playback_errors.clear();
+            room.proposals.clear();
+            room.scheduled_start = None;
+            room.focus_paused = false;
+            room.record("reset", Some(temp_user));
+            tokens
+        };
+        let mut media_tokens = self.media_tokens.write().await;
+        let mut revoked = 0;
+        for token in &tokens {
+            if media_tokens.remove(token).is_some() {
+                revoked += 1;
+            }
+            if let Some(cache) = &self.segment_cache {
+                cache.remove_token(token);
+            }
+        }
+        if let Some(index) = &self.token_reuse {
+            index
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|_, token| !tokens.contains(token));
+        }
+        Ok(revoked)
+    }
+
+    /// 房主暂存已 resolve 的下一项（url 通常是预先签发的 `/media/:token`）。
+    async fn set_next(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        state: RoomState,
+    ) -> Result<(), ApiError> {
+        state.validate()?;
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_host(temp_user) {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        room.pending_next = Some(state);
+        Ok(())
+    }
+
+    /// 房主添加时间轴标注，返回房间当前的全部标注。
+    async fn add_marker(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        position: f64,
+        label: String,
+    ) -> Result<Vec<Marker>, ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_host(temp_user) {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        if room.state.is_none() {
+            return Err(ApiError::bad_request("host has not published state"));
+        }
+        if room.markers.len() == MAX_MARKERS {
+            return Err(ApiError::bad_request("too many markers"));
+        }
+        room.last_marker_id += 1;
+        room.markers.push(Marker {
+            id: room.last_marker_id,
+            position,
+            label,
+            created_at: now_millis(),
+        });
+        room.record("marker_added", Some(temp_user));
+        Ok(room.markers.clone())
+    }
+
+    async fn clear_markers(&self, room_name: &str, temp_user: &str) -> Result<(), ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_host(temp_user) {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        room.markers.clear();
+        room.record("markers_cleared", Some(temp_user));
+        Ok(())
+    }
+
+    /// 检查禁言与限速后生成要广播的聊天；被禁言时返回 None，超出速率时返回 429。
+    async fn admit_chat(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        text: String,
+    ) -> Result<Option<ChatMessage>, ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if room.muted.contains(temp_user) {
+            return Ok(None);
+        }
+        let member = room
+            .members
+            .get_mut(temp_user)
+            .ok_or_else(|| ApiError::forbidden("not a room member"))?;
+        if let Some(rate) = self.chat_rate {
+            if !member.chat_bucket.take(rate) {
+                return Err(ApiError::too_many_requests("chat rate limited"));
+            }
+        }
+        Ok(Some(ChatMessage {
+            from: member.id.clone(),
+            text,
+            at: now_millis(),
+        }))
+    }
+
+    /// 房主禁言或解除禁言；房主之间不能互相禁言。
+    async fn set_muted(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        target: &str,
+        muted: bool,
+    ) -> Result<(), ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_host(temp_user) {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        let target = room.member_by_id(target)?;
+        if muted {
+            if room.is_host(&target) {
+                return Err(ApiError::bad_request("cannot mute a host"));
+            }
+            room.record("user_muted", Some(&target));
+            room.muted.insert(target);
+        } else if room.muted.remove(&target) {
+            room.record("user_unmuted", Some(&target));
+        }
+        Ok(())
+    }
+
+    /// 记录成员的播放错误，返回该错误、当前源是否被标记以及需要通知的房主。
+    async fn report_playback_error(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        code: String,
+        message: Option<String>,
+    ) -> Result<(PlaybackError, bool, HashSet<String>), ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let user = room
+            .members
+            .get(temp_user)
+            .map(|member| member.id.clone())
+            .ok_or_else(|| ApiError::forbidden("not a room member"))?;
+        if room.playback_errors.len() == MAX_PLAYBACK_ERRORS {
+            room.playback_errors.pop_front();
+        }
+        let error = PlaybackError {
+            user,
+            member: temp_user.to_string(),
+            code,
+            message,
+            url: room.state.as_ref().map(|s| s.url.clone()),
+            at: now_millis(),
+        };
+        room.playback_errors.push_back(error.clone());
+        room.record("playback_error", Some(temp_user));
+        let flagged = room.source_flagged(self.error_flag_ratio);
+        Ok((error, flagged, room.host_ids.clone()))
+    }
+
+    /// 有播放错误记录的房间，供管理接口查看。
+    async fn playback_errors(&self) -> Vec<RoomPlaybackErrors> {
+        let rooms = self.rooms.read().await;
+        let mut list: Vec<RoomPlaybackErrors> = rooms
+            .iter()
+            .filter(|(_, room)| !room.playback_errors.is_empty())
+            .map(|(name, room)| RoomPlaybackErrors {
+                room: name.clone(),
+                flagged: room.source_flagged(self.error_flag_ratio),
+                errors: room.playback_errors.iter().cloned().collect(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.room.cmp(&b.room));
+        list
+    }
+
+    async fn markers(&self, room_name: &str) -> Vec<Marker> {
+        self.rooms
+            .read()
+            .await
+            .get(room_name)
+            .map(|room| room.markers.clone())
+            .unwrap_or_default()
+    }
+
+    /// 切换到预加载的下一项，不再经过 resolve，从头开始播放。
+    async fn advance_next(&self, room_name: &str, temp_user: &str) -> Result<RoomState, ApiError> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_host(temp_user) {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        let mut state = room
+            .pending_next
+            .take()
+            .ok_or_else(|| ApiError::bad_request("no preloaded next item"))?;
+        state.current_time = 0.0;
+        state.paused = false;
+        state.updated_at = now_millis();
+        room.seek_owner = None;
+        let changed = room.set_state(state.clone());
+        room.record("next", Some(temp_user));
+        self.notify_source_change(room_name, &state, changed);
+        Ok(state)
+    }
+
+    /// 记录成员实际播放进度，同时视为一次心跳。
+    /// 记录成员进度；开启追赶时，按与房间外推进度的偏差返回纠偏建议。
+    async fn report_position(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        position: f64,
+    ) -> Result<Option<DriftHint>, ApiError> {
+        if !position.is_finite() || position < 0.0 {
+            return Err(ApiError::bad_request("invalid position"));
+        }
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let member = room
+            .members
+            .get_mut(temp_user)
+            .ok_or_else(|| ApiError::forbidden("user not in room"))?;
+        let now = Instant::now();
+        member.last_seen = now;
+        let reported_at = now_millis();
+        let catching_up = member.catchup_until.is_some_and(|until| now < until);
+        room.positions
+            .insert(temp_user.to_string(), (position, reported_at));
+        let (Some(rate), Some(state)) = (self.catchup_rate, room.state.as_ref()) else {
+            return Ok(None);
+        };
+        // 预告的状态生效前成员仍按旧状态播放，此时的偏差不作数。
+        if state.paused || catching_up || state.effective_at.is_some_and(|at| at > reported_at) {
+            return Ok(None);
+        }
+        let lag = state.position_at(reported_at) - position;
+        let hint = if lag.abs() < CATCHUP_MIN_LAG {
+            None
+        } else if lag > CATCHUP_MAX_LAG || -lag > CATCHUP_MAX_LAG {
+            Some(DriftHint::Resync(state.rebased_at(reported_at)))
+        } else if lag > 0.0 {
+            // 以 rate 倍速播放时每秒追回 playback_rate * (rate - 1) 秒。
+            let gain = state.playback_rate * (rate - 1.0);
+            let duration = Duration::from_secs_f64(lag / gain);
+            let member = room
+                .members
+                .get_mut(temp_user)
+                .expect("member checked above");
+            member.catchup_until = Some(now + duration);
+            Some(DriftHint::Catchup {
+                rate: state.playback_rate * rate,
+                duration_ms: duration.as_millis() as u64,
+                lag_ms: (lag * 1000.0) as i64,
+            })
+        } else {
+            // 略微超前的成员等下一次房间状态自然校正。
+            None
+        };
+        Ok(hint)
+    }
+
+    async fn sync_report(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+    ) -> Result<SyncReport, ApiError> {
+        self.authorize(room_name, password, temp_user).await?;
+        let rooms = self.rooms.read().await;
+        let room = rooms
+            .get(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let mut members: Vec<MemberOffset> = room
+            .positions
+            .iter()
+            .filter_map(|(user, &(position, reported_at))| {
+                let member = room.members.get(user)?;
+                Some(MemberOffset {
+                    id: member.id.clone(),
+                    position,
+                    reported_at,
+                    offset: room
+                        .state
+                        .as_ref()
+                        .map(|state| position - state.position_at(reported_at)),
+                    clock_offset_ms: member.clock_offset,
+                })
+            })
+            .collect();
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(SyncReport {
+            position: room
+                .state
+                .as_ref()
+                .map(|state| state.position_at(now_millis())),
+            members,
+        })
+    }
+
+    async fn update_state(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        mut state: RoomState,
+        is_host: bool,
+    ) -> Result<RoomState, ApiError> {
+        self.state_limits.check(&state)?;
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if !room.is_rate_allowed(state.playback_rate) {
+            return Err(ApiError::bad_request(
+                "playback rate not allowed in this room",
+            ));
+        }
+        if !self.seek_lock.is_zero() {
+            room.apply_seek_lock(temp_user, &mut state, self.seek_lock);
+        }
+        if is_host {
+            state.updated_at = now_millis();
+            state.effective_at = None;
+            let scheduled = !self.write_ahead.is_zero()
+                && room
+                    .state
+                    .as_ref()
+                    .is_some_and(|existing| existing.is_transport_change(&state, state.updated_at));
+            if scheduled {
+                state.updated_at += self.write_ahead.as_millis() as i64;
+                state.effective_at = Some(state.updated_at);
+            }
+            let changed = room.set_state(state.clone());
+            room.record("state", Some(temp_user));
+            self.notify_source_change(room_name, &state, changed);
+            return Ok(state);
+        }
+
+        if !self.allow_member_control {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        let existing = room
+            .state
+            .clone()
+            .ok_or_else(|| ApiError::bad_request("host has not published state"))?;
+        let merged = existing.with_member_update(&state, self.member_volume);
+        room.set_state(merged.clone());
+        room.record("state", Some(temp_user));
+        Ok(merged)
+    }
+
+    /// 视频播放结束：播放列表非空时解析并切到下一项，否则暂停在片尾。
+    /// 同一地址的 `ended` 只处理第一条：核对地址、标记已处理并取出下一项在同一把写锁内完成，
+    /// 其余客户端随后上报的 `ended` 返回 None。
+    async fn end_video(
+        &self,
+        room_name: &str,
+        temp_user: &str,
+        is_host: bool,
+        url: &str,
+    ) -> Result<Option<VideoEnd>, ApiError> {
+        if !is_host && !self.allow_member_control {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        let item = {
+            let mut rooms = self.rooms.write().await;
+            let room = rooms
+                .get_mut(room_name)
+                .ok_or_else(|| ApiError::bad_request("room not found"))?;
+            let Some(current) = room.state.as_ref() else {
+                return Err(ApiError::bad_request("host has not published state"));
+            };
+            if current.url != url || room.ended_url.as_deref() == Some(url) {
+                return Ok(None);
+            }
+            if room.playlist.is_empty() {
+                let mut state = current.clone();
+                state.paused = true;
+                state.current_time = state.duration;
+                state.updated_at = now_millis();
+                room.set_state(state.clone());
+                room.ended_url = Some(url.to_string());
+                room.record("ended", Some(temp_user));
+                return Ok(Some(VideoEnd::Finished(state)));
+            }
+            room.ended_url = Some(url.to_string());
+            room.playlist.remove(0)
+        };
+
+        let resolved = match self
+            .resolve_source(room_name, &item.path, &ResolveOptions::default())
+            .await
+        {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                // 解析失败时放回队首，下一次 `ended` 可以重试。
+                let mut rooms = self.rooms.write().await;
+                if let Some(room) = rooms.get_mut(room_name) {
+                    if room.ended_url.as_deref() == Some(url) {
+                        room.ended_url = None;
+                        room.playlist.insert(0, item);
+                    }
+                }
+                return Err(err);
+            }
+        };
+        let state = RoomState {
+            url: resolved.url,
+            title: item
+                .title
+                .clone()
+                .unwrap_or_else(|| default_title(&item.path)),
+            current_time: 0.0,
+            duration: 0.0,
+            paused: false,
+            playback_rate: 1.0,
+            source_type: resolved.source_type,
+            updated_at: now_millis(),
+            cover: resolved.cover,
+            quality: resolved.quality,
+            effective_at: None,
+            fit_mode: FitMode::Contain,
+            crop: None,
+            volume: None,
+            muted: None,
+        };
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let changed = room.set_state(state.clone());
+        room.source = Some(item.path);
+        room.record("advance", Some(temp_user));
+        self.notify_source_change(room_name, &state, changed);
+        Ok(Some(VideoEnd::Advanced(state)))
+    }
+
+    /// 成员的公开标识，不在房间内时为 None；对外消息中以此代替 temp_user。
+    async fn member_public_id(&self, room_name: &str, temp_user: &str) -> Option<String> {
+        let rooms = self.rooms.read().await;
+        let room = rooms.get(room_name)?;
+        room.members.get(temp_user).map(|member| member.id.clone())
+    }
+
+    /// seek 软锁窗口内持有者的公开标识，未开启或已过期时为 None。
+    async fn seek_owner(&self, room_name: &str) -> Option<String> {
+        if self.seek_lock.is_zero() {
+            return None;
+        }
+        let rooms = self.rooms.read().await;
+        let room = rooms.get(room_name)?;
+        let owner = room.seek_owner(self.seek_lock)?;
+        room.members.get(owner).map(|member| member.id.clone())
+    }
+
+    async fn latest_state(&self, room_name: &str) -> Option<RoomState> {
+        let rooms = self.rooms.read().await;
+        let room = rooms.get(room_name)?;
+        room.state.clone()
+    }
+
+    async fn current_state(&self, room_name: &str) -> Option<RoomState> {
+        let rooms = self.rooms.read().await;
+        rooms.get(room_name).and_then(|room| room.state.clone())
+    }
+
+    async fn resolve_media_path(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        path: &str,
+    ) -> Result<ResolvedMedia, ApiError> {
+        self.resolve_media_with(
+            room_name,
+            password,
+            temp_user,
+            path,
+            &ResolveOptions::default(),
+        )
+        .await
+    }
+
+    async fn resolve_media_with(
+        &self,
+        room_name: &str,
+        password: &str,
+        _temp_user: &str,
+        path: &str,
+        options: &ResolveOptions,
+    ) -> Result<ResolvedMedia, ApiError> {
+        let rooms = self.rooms.read().await;
+        let room = rooms
+            .get(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if room.password != password {
+            return Err(ApiError::forbidden("room password mismatch"));
+        }
+        let is_host = room.is_host(_temp_user);
+        if !is_host && !self.allow_member_control {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        let kind = SourceKind::of(path);
+        if room
+            .allowed_sources
+            .as_ref()
+            .is_some_and(|allowed| !allowed.contains(&kind))
+        {
+            return Err(ApiError::forbidden(format!(
+                "{} sources are not allowed in this room",
+                kind.as_str()
+            )));
+        }
+        drop(rooms);
+        let options = ResolveOptions {
+            allow_long: options.allow_long && is_host,
+            ..options.clone()
+        };
+        self.resolve_source(room_name, path, &options).await
+    }
+
+    /// 把原始输入解析为媒体 token，不做房间鉴权。
+    async fn resolve_source(
+        &self,
+        room_name: &str,
+        path: &str,
+        options: &ResolveOptions,
+    ) -> Result<ResolvedMedia, ApiError> {
+        if is_bilibili_source(path).is_some() {
+            let resolved = self.resolve_bilibili(room_name, path, options).await?;
+            return Ok(resolved);
+        }
+
+        if path.starts_with("http://") || path.starts_with("https://") {
+            ssrf::ensure_public_url(path).await?;
+            let token = self
+                .mint_token(
+                    room_name,
+                    MediaTarget::Remote(RemoteTarget {
+                        url: path.to_string(),
+                        strategy: RemoteStrategy::Redirect,
+                    }),
+                )
+                .await;
+            return Ok(ResolvedMedia {
+                url: format!("/media/{token}"),
+                token,
+                source_type: SourceType::Remote,
+                cover: None,
+                audio: None,
+                dimension: None,
+                quality: None,
+                segments: None,
+                danmaku_url: None,
+            });
+        }
+
+        let roots = self.media_roots.read().await.clone();
+        let path = match path.strip_prefix(library::LIB_PREFIX) {
+            Some(_) if roots.is_empty() => {
+                return Err(ApiError::bad_request("media root not configured"));
+            }
+            Some(id) => self.library_path(id).await?,
+            None => PathBuf::from(path),
+        };
+        let (clean, root) = if roots.is_empty() {
+            self.unrooted_path(&path)?
+        } else {
+            let clean = clean_path(path);
+            let root = roots
+                .iter()
+                .map(|root| root.path.clone())
+                .find(|root| is_under_root(&clean, root))
+                .ok_or_else(|| ApiError::forbidden("media path forbidden"))?;
+            (clean, root)
+        };
+        let meta = std::fs::metadata(&clean).map_err(|_| ApiError::bad_request("invalid path"))?;
+        if meta.is_dir() {
+            let segments = disc::main_feature(&clean)
+                .ok_or_else(|| ApiError::bad_request("path is directory"))?;
+            return self.resolve_disc(room_name, &root, segments).await;
+        }
+        if !options.allow_any {
+            if !self.is_allowed_ext(&clean) {
+                return Err(ApiError::bad_request(
+                    "file extension not allowed (pass allowAny to override)",
+                ));
+            }
+            sniff::ensure_media_file(&clean).await?;
+        }
+        // 分卷都在同一目录下，已经位于媒体根内；拼接流无法转码，直接按原样提供。
+        if let Some(parts) = concat::sibling_parts(&clean).filter(|_| options.join_parts) {
+            let token = self.mint_token(room_name, MediaTarget::Concat(parts)).await;
+            return Ok(ResolvedMedia {
+                url: format!("/media/{token}"),
+                token,
+                source_type: SourceType::Concat,
+                cover: None,
+                audio: None,
+                dimension: None,
+                quality: None,
+                segments: None,
+                danmaku_url: None,
+            });
+        }
+
+        let cover = self.local_cover(room_name, &clean).await;
+        let (target, source_type) = match self.transcode_target(&clean, options).await {
+            Some(target) => (MediaTarget::Transcode(target), SourceType::Transcode),
+            None => (MediaTarget::Local(clean), SourceType::File),
+        };
+        let token = self.mint_token(room_name, target).await;
+        Ok(ResolvedMedia {
+            url: format!("/media/{token}"),
+            token,
+            source_type,
+            cover,
+            audio: None,
+            dimension: None,
+            quality: None,
+            segments: None,
+            danmaku_url: None,
+        })
+    }
+
+    /// 未配置媒体根时：相对路径无从解析；绝对路径需开启配置，并把所在目录当作临时媒体根，
+    /// 同样经过目录策略检查。返回规范化后的路径与该目录。
+    fn unrooted_path(&self, path: &Path) -> Result<(PathBuf, PathBuf), ApiError> {
+        if path.is_relative() {
+            return Err(ApiError::bad_request(
+                "relative path needs a media root, configure one first",
+            ));
+        }
+        if !self.root_policy.allow_unrooted {
+            return Err(ApiError::bad_request(format!(
+                "media root not configured, set one or enable {ENV_ALLOW_ABSOLUTE_WITHOUT_ROOT} \
+                 to play absolute paths"
+            )));
+        }
+        let clean = clean_path(path);
+        let dir = if clean.is_dir() {
+            clean.clone()
+        } else {
+            clean
+                .parent()
+                .ok_or_else(|| ApiError::bad_request("invalid path"))?
+                .to_path_buf()
+        };
+        self.root_policy.check(&dir)?;
+        Ok((clean, dir))
+    }
+
+    /// 光盘正片的每个分段各签发一个 token，首段作为主媒体，完整顺序放在 `segments` 中，
+    /// 前端按序播放即可。分段由目录结构确定，不再做扩展名和魔数检查。
+    async fn resolve_disc(
+        &self,
+        room_name: &str,
+        root: &Path,
+        segments: Vec<PathBuf>,
+    ) -> Result<ResolvedMedia, ApiError> {
+        let mut resolved = Vec::with_capacity(segments.len());
+        for path in segments {
+            let clean = clean_path(path);
+            if !is_under_root(&clean, root) {
+                return Err(ApiError::forbidden("media path forbidden"));
+            }
+            let token = self.mint_token(room_name, MediaTarget::Local(clean)).await;
+            resolved.push(MediaSegment {
+                url: format!("/media/{token}"),
+                token,
+            });
+        }
+        let first = resolved
+            .first()
+            .cloned()
+            .ok_or_else(|| ApiError::bad_request("disc has no playable segments"))?;
+        Ok(ResolvedMedia {
+            token: first.token,
+            url: first.url,
+            source_type: SourceType::Disc,
+            cover: None,
+            audio: None,
+            dimension: None,
+            quality: None,
+            segments: Some(resolved),
+            danmaku_url: None,
+        })
+    }
+
+    /// 开启封面生成时为本地文件截图，并签发一个指向该图片的 token。
+    async fn local_cover(&self, room_name: &str, path: &Path) -> Option<String> {
+        let jpeg = self.thumbnails.as_ref()?.thumbnail(path).await?;
+        let target = MediaTarget::Inline(InlineMedia {
+            content_type: "image/jpeg",
+            body: jpeg,
+        });
+        let token = self.mint_token(room_name, target).await;
+        Some(format!("/media/{token}"))
+    }
+
+    /// 仅在请求要求、服务端开启且探测到浏览器不支持的编码时才转码。
+    async fn transcode_target(
+        &self,
+        path: &Path,
+        options: &ResolveOptions,
+    ) -> Option<transcode::TranscodeTarget> {
+        let ffmpeg = self.ffmpeg.as_deref().filter(|_| options.transcode)?;
+        match transcode::probe(ffmpeg, path).await {
+            Ok(codecs) if codecs.needs_transcode() => Some(transcode::TranscodeTarget {
+                path: path.to_path_buf(),
+                codecs,
+            }),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("probe {} failed, serving as-is: {err}", path.display());
+                None
+            }
+        }
+    }
+
+    async fn mint_token(&self, room_name: &str, target: MediaTarget) -> String {
+        self.mint_token_with_origin(room_name, target, None).await
+    }
+
+    async fn mint_token_with_origin(
+        &self,
+        room_name: &str,
+        target: MediaTarget,
+        origin: Option<BiliOrigin>,
+    ) -> String {
+        let mut tokens = self.media_tokens.write().await;
+        let reuse = self
+            .token_reuse
+            .as_ref()
+            .zip(target.identity())
+            .map(|(index, identity)| (index, format!("{room_name}\0{identity}"), identity));
+        if let Some((index, key, identity)) = &reuse {
+            let index = index.lock().unwrap_or_else(|e| e.into_inner());
+            // 刷新或切换清晰度后 token 的目标可能已变，需再次核对。
+            let existing = index.get(key).and_then(|token| {
+                let entry = tokens.get_mut(token)?;
+                let live = Instant::now() < entry.expires_at
+                    && entry.target.identity().as_ref() == Some(identity);
+                live.then_some((token, entry))
+            });
+            if let Some((token, entry)) = existing {
+                entry.expires_at = Instant::now() + self.token_ttl;
+                return token.clone();
+            }
+        }
+        let token = loop {
+            let candidate = self.token_format.generate(room_name);
+            if !tokens.contains_key(&candidate) {
+                break candidate;
+            }
+        };
+        if let Some((index, key, _)) = reuse {
+            index
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(key, token.clone());
+        }
+        tokens.insert(
+            token.clone(),
+            MediaToken {
+                target,
+                expires_at: Instant::now() + self.token_ttl,
+                origin,
+                validation: None,
+            },
+        );
+        token
+    }
+
+    /// 仅房主可执行的操作统一走这里鉴权。
+    async fn authorize_host(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+    ) -> Result<(), ApiError> {
+        if !self.authorize(room_name, password, temp_user).await? {
+            return Err(ApiError::forbidden("operation allowed for host only"));
+        }
+        Ok(())
+    }
+
+    async fn set_source(&self, room_name: &str, source: &str) {
+        if let Some(room) = self.rooms.write().await.get_mut(room_name) {
+            room.source = Some(source.to_string());
+        }
+    }
+
+    /// 房主按公开标识把成员移出房间，返回被移出者的 temp_user 以便断开其连接；
+    /// 被移出者需重新 join 才能再次连接。
+    async fn kick_member(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        target: &str,
+    ) -> Result<String, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let target = room.member_by_id(target)?;
+        if target == temp_user {
+            return Err(ApiError::bad_request("host cannot kick itself"));
+        }
+        if room.primary_host.as_deref() == Some(target.as_str()) {
+            return Err(ApiError::forbidden("primary host cannot be kicked"));
+        }
+        room.record("kick", Some(&target));
+        room.members.remove(&target);
+        room.host_ids.remove(&target);
+        room.positions.remove(&target);
+        Ok(target)
+    }
+
+    /// 升级或撤销协同房主。任何房主都能升级成员，只有主房主能撤销，主房主本身不能被撤销。
+    async fn set_co_host(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        target: &str,
+        promote: bool,
+    ) -> Result<HostsResponse, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let target = room.member_by_id(target)?;
+        let target = target.as_str();
+        if promote {
+            room.host_ids.insert(target.to_string());
+            room.record("promote", Some(target));
+        } else {
+            if room.primary_host.as_deref() != Some(temp_user) {
+                return Err(ApiError::forbidden("only the primary host can demote"));
+            }
+            if room.primary_host.as_deref() == Some(target) {
+                return Err(ApiError::bad_request("primary host cannot be demoted"));
+            }
+            room.host_ids.remove(target);
+            room.record("demote", Some(target));
+        }
+        Ok(room.hosts())
+    }
+
+    /// 主房主把主房主身份移交给另一名成员，自己退为普通成员。
+    async fn transfer_host(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        target: &str,
+    ) -> Result<HostsResponse, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        if room.primary_host.as_deref() != Some(temp_user) {
+            return Err(ApiError::forbidden("only the primary host can transfer"));
+        }
+        let target = room.member_by_id(target)?;
+        let target = target.as_str();
+        room.host_ids.remove(temp_user);
+        room.set_primary_host(target);
+        // 主动移交后旧令牌作废，避免原房主重连时又夺回。
+        room.host_resume = None;
+        room.record("transfer", Some(target));
+        Ok(room.hosts())
+    }
+
+    async fn whoami(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+    ) -> Result<WhoamiResponse, ApiError> {
+        let is_host = self.authorize(room_name, password, temp_user).await?;
+        let rooms = self.rooms.read().await;
+        let room = rooms.get(room_name);
+        let primary_host = room.is_some_and(|room| room.primary_host.as_deref() == Some(temp_user));
+        let member_id = room
+            .and_then(|room| room.members.get(temp_user))
+            .map(|member| member.id.clone())
+            .unwrap_or_default();
+        let role = match (is_host, self.allow_member_control) {
+            (true, _) => "host",
+            (false, true) => "member",
+            (false, false) => "spectator",
+        };
+        Ok(WhoamiResponse {
+            temp_user: temp_user.to_string(),
+            member_id,
+            role,
+            primary_host,
+            can_control: is_host || self.allow_member_control,
+        })
+    }
+
+    async fn room_events(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        since: Option<u64>,
+    ) -> Result<EventsResponse, ApiError> {
+        self.authorize(room_name, password, temp_user).await?;
+        let rooms = self.rooms.read().await;
+        let room = rooms
+            .get(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let since = since.unwrap_or(0);
+        Ok(EventsResponse {
+            events: room
+                .events
+                .iter()
+                .filter(|event| event.id > since)
+                .cloned()
+                .collect(),
+            cursor: room.last_event_id,
+        })
+    }
+
+    async fn set_playlist(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        items: Vec<PlaylistItem>,
+    ) -> Result<Vec<PlaylistItem>, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        if items.iter().any(|item| item.path.trim().is_empty()) {
+            return Err(ApiError::bad_request("playlist item path required"));
+        }
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        room.playlist = items;
+        room.record("playlist", Some(temp_user));
+        Ok(room.playlist.clone())
+    }
+
+    async fn export_playlist(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+    ) -> Result<PlaylistExport, ApiError> {
+        self.authorize(room_name, password, temp_user).await?;
+        let rooms = self.rooms.read().await;
+        let room = rooms
+            .get(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        Ok(PlaylistExport {
+            version: PLAYLIST_EXPORT_VERSION,
+            items: room.playlist.clone(),
+            exported_at: now_millis(),
+        })
+    }
+
+    async fn import_playlist(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        export: PlaylistExport,
+        append: bool,
+    ) -> Result<Vec<PlaylistItem>, ApiError> {
+        export.validate()?;
+        let items = if append {
+            let rooms = self.rooms.read().await;
+            let existing = rooms
+                .get(room_name)
+                .map(|room| room.playlist.clone())
+                .unwrap_or_default();
+            existing.into_iter().chain(export.items).collect()
+        } else {
+            export.items
+        };
+        self.set_playlist(room_name, password, temp_user, items)
+            .await
+    }
+
+    /// 只展开列表，不解析媒体；条目在播放时再按 BV 号逐个 resolve。
+    async fn fetch_favorites(
+        &self,
+        media_id: u64,
+        cookie: Option<&str>,
+    ) -> Result<FavoritesResponse, ApiError> {
+        let url = format!("{}/x/v3/fav/resource/list", self.bili_api_base);
+        let cookie = self
+            .bili_cookie(cookie, &url)
+            .ok_or_else(|| ApiError::forbidden("bilibili login required"))?;
+        let client = init_client()
+            .await
+            .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
+        let mut title = None;
+        let mut playlist = Vec::new();
+        for page in 1..=MAX_FAVORITES_PAGES {
+            let request = client
+                .get(&url)
+                .query(&[
+                    ("media_id", media_id.to_string()),
+                    ("pn", page.to_string()),
+                    ("ps", FAVORITES_PAGE_SIZE.to_string()),
+                    ("platform", "web".to_string()),
+                ])
+                .header(reqwest::header::COOKIE, cookie.as_ref());
+            let resp: FavListResp = bili_json(request, "favorites").await?;
+            match resp.code {
+                0 => {}
+                -101 => return Err(ApiError::forbidden("bilibili login required")),
+                -403 => return Err(ApiError::forbidden("favorites folder is private")),
+                _ => {
+                    return Err(ApiError::bad_request(format!(
+                        "favorites error {}: {}",
+                        resp.code, resp.message
+                    )))
+                }
+            }
+            let Some(data) = resp.data else { break };
+            if title.is_none() {
+                title = data.info.map(|info| info.title);
+            }
+            playlist.extend(
+                data.medias
+                    .into_iter()
+                    .flatten()
+                    // type 2 为视频，12 为音频；已失效条目 attr 非 0
+                    .filter(|m| m.kind == 2 && m.attr == 0 && !m.bvid.is_empty())
+                    .map(|m| PlaylistItem {
+                        path: m.bvid,
+                        title: Some(m.title),
+                    }),
+            );
+            if !data.has_more {
+                break;
+            }
+        }
+        Ok(FavoritesResponse {
+            media_id,
+            title,
+            playlist,
+        })
+    }
+
+    async fn snapshot_room(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+    ) -> Result<RoomSnapshot, ApiError> {
+        self.authorize_host(room_name, password, temp_user).await?;
+        let rooms = self.rooms.read().await;
+        let room = rooms
+            .get(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        Ok(RoomSnapshot {
+            version: SNAPSHOT_VERSION,
+            room: room_name.to_string(),
+            source: room.source.clone(),
+            state: room.state.clone(),
+            playlist: room.playlist.clone(),
+            created_at: now_millis(),
+        })
+    }
+
+    fn seal_snapshot(&self, snapshot: RoomSnapshot) -> Result<SnapshotPayload, ApiError> {
+        let Some(key) = &self.snapshot_key else {
+            return Ok(SnapshotPayload::Plain(Box::new(snapshot)));
+        };
+        let json = serde_json::to_vec(&snapshot)
+            .map_err(|e| ApiError::bad_request(format!("snapshot encode failed: {e}")))?;
+        Ok(SnapshotPayload::Sealed(seal::seal(key, &json)?))
+    }
+
+    fn open_snapshot(&self, payload: SnapshotPayload) -> Result<RoomSnapshot, ApiError> {
+        let sealed = match payload {
+            SnapshotPayload::Plain(snapshot) => return Ok(*snapshot),
+            SnapshotPayload::Sealed(sealed) => sealed,
+        };
+        let key = self
+            .snapshot_key
+            .as_ref()
+            .ok_or_else(|| ApiError::bad_request("snapshot is encrypted but no key is set"))?;
+        let json = seal::open(key, &sealed)?;
+        serde_json::from_slice(&json)
+            .map_err(|e| ApiError::bad_request(format!("snapshot decode failed: {e}")))
+    }
+
+    /// 用快照新建房间，调用者成为房主；旧 token 不复用，统一重新签发。
+    async fn restore_room(
+        &self,
+        name: &str,
+        password: &str,
+        snapshot: RoomSnapshot,
+    ) -> Result<(String, Option<RoomState>), ApiError> {
+        let name = name.trim();
+        let password = password.trim();
+        if name.is_empty() || password.is_empty() {
+            return Err(ApiError::bad_request("room name and password required"));
+        }
+        snapshot.validate()?;
+        if self.rooms.read().await.contains_key(name) {
+            return Err(ApiError::conflict("room already exists"));
+        }
+
+        let mut state = snapshot.state;
+        if let Some(state) = state.as_mut() {
+            let url = match &snapshot.source {
+                Some(source) => {
+                    self.resolve_source(name, source, &ResolveOptions::default())
+                        .await?
+                        .url
+                }
+                None => self.reissue_url(name, &state.url).await?,
+            };
+            state.url = url;
+            state.updated_at = now_millis();
+        }
+
+        let temp_user = Uuid::new_v4().to_string();
+        let mut room = Room::new(password);
+        room.set_primary_host(&temp_user);
+        room.members.insert(temp_user.clone(), Member::new(None));
+        if let Some(state) = state.clone() {
+            room.set_state(state);
+        }
+        room.source = snapshot.source;
+        room.playlist = snapshot.playlist;
+        room.record("restore", Some(&temp_user));
+
+        let mut rooms = self.rooms.write().await;
+        if rooms.contains_key(name) {
+            return Err(ApiError::conflict("room already exists"));
+        }
+        self.ensure_room_capacity(&rooms)?;
+        rooms.insert(name.to_string(), room);
+        self.notify_webhook(webhook::WebhookEvent::new("room_created", name));
+        Ok((temp_user, state))
+    }
+
+    /// `/media/{token}` 形式的地址换发新 token，外部地址原样保留。
+    async fn reissue_url(&self, room_name: &str, url: &str) -> Result<String, ApiError> {
+        let Some(old) = url.strip_prefix("/media/") else {
+            return Ok(url.to_string());
+        };
+        let (target, origin) = self
+            .media_tokens
+            .read()
+            .await
+            .get(old)
+            .filter(|entry| Instant::now() <= entry.expires_at)
+            .map(|entry| (entry.target.clone(), entry.origin.clone()))
+            .ok_or_else(|| ApiError::bad_request("snapshot media no longer available"))?;
+        let token = self.mint_token_with_origin(room_name, target, origin).await;
+        Ok(format!("/media/{token}"))
+    }
+
+    /// 超过 `MAX_TRANSCODES_PER_TOKEN` 时返回 None。
+    fn acquire_transcode(&self, token: &str) -> Option<TranscodeSlot> {
+        let mut active = self.transcodes.lock().unwrap_or_else(|e| e.into_inner());
+        let count = active.entry(token.to_string()).or_default();
+        if *count >= MAX_TRANSCODES_PER_TOKEN {
+            return None;
+        }
+        *count += 1;
+        Some(TranscodeSlot {
+            token: token.to_string(),
+            active: self.transcodes.clone(),
+        })
+    }
+
+    async fn open_media(&self, token: &str) -> Result<MediaTarget, ApiError> {
+        let tokens = self.media_tokens.read().await;
+        let entry = tokens
+            .get(token)
+            .ok_or_else(|| ApiError::not_found("token not found"))?;
+        if Instant::now() > entry.expires_at {
+            return Err(ApiError::not_found("token expired"));
+        }
+        match &entry.target {
+            MediaTarget::Remote(_) => Err(ApiError::bad_request("remote requires redirect")),
+            target => Ok(target.clone()),
+        }
+    }
+
+    async fn open_remote(&self, token: &str) -> Result<RemoteTarget, ApiError> {
+        let tokens = self.media_tokens.read().await;
+        let entry = tokens
+            .get(token)
+            .ok_or_else(|| ApiError::not_found("token not found"))?;
+        if Instant::now() > entry.expires_at {
+            return Err(ApiError::not_found("token expired"));
+        }
+        match &entry.target {
+            MediaTarget::Remote(target) => Ok(target.clone()),
+            _ => Err(ApiError::bad_request("not a remote token")),
+        }
+    }
+
+    /// 只设置一个媒体根，替换现有列表。
+    async fn set_media_root(&self, path: &str, label: Option<&str>) -> Result<PathBuf, ApiError> {
+        let roots = self
+            .set_media_roots(&[MediaRootRequest {
+                path: path.to_string(),
+                label: label.map(str::to_string),
+            }])
+            .await?;
+        Ok(roots.into_iter().next().expect("one root requested").path)
+    }
+
+    /// 校验全部根目录后整体替换；任一项不合法时保持原列表不变。
+    async fn set_media_roots(
+        &self,
+        specs: &[MediaRootRequest],
+    ) -> Result<Vec<MediaRoot>, ApiError> {
+        if specs.is_empty() || specs.len() > MAX_MEDIA_ROOTS {
+            return Err(ApiError::bad_request(format!(
+                "between 1 and {MAX_MEDIA_ROOTS} media roots required"
+            )));
+        }
+        let mut roots: Vec<MediaRoot> = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let candidate = clean_path(&spec.path);
+            let meta = std::fs::metadata(&candidate)
+                .map_err(|_| ApiError::bad_request("media root not found"))?;
+            if !meta.is_dir() {
+                return Err(ApiError::bad_request("media root must be directory"));
+            }
+            self.root_policy.check(&candidate)?;
+            let label = match spec.label.as_deref().map(str::trim) {
+                Some(label) if !label.is_empty() => label.to_string(),
+                _ => default_root_label(&candidate),
+            };
+            if label.chars().count() > MAX_ROOT_LABEL_LEN {
+                return Err(ApiError::bad_request("media root label too long"));
+            }
+            if roots
+                .iter()
+                .any(|root| root.label == label || root.path == candidate)
+            {
+                return Err(ApiError::bad_request("duplicate media root or label"));
+            }
+            roots.push(MediaRoot {
+                label,
+                path: candidate,
+            });
+        }
+        *self.media_roots.write().await = roots.clone();
+        self.reindex_library().await?;
+        Ok(roots)
+    }
+
+    /// 重新遍历所有媒体根，只收录扩展名在白名单内的文件。返回收录的文件总数。
+    async fn reindex_library(&self) -> Result<usize, ApiError> {
+        let roots = self.media_roots.read().await.clone();
+        if roots.is_empty() {
+            return Err(ApiError::bad_request("media root not configured"));
+        }
+        let exts = self.allowed_exts.clone();
+        let indexes = tokio::task::spawn_blocking(move || {
+            roots
+                .iter()
+                .map(|root| {
+                    library::LibraryIndex::build(&root.path, &root.label, |path| {
+                        ext_allowed(exts.as_ref(), path)
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(|_| ApiError::unavailable("library index failed"))?;
+        let indexed = indexes.iter().map(library::LibraryIndex::len).sum();
+        *self.library.write().await = indexes;
+        Ok(indexed)
+    }
+
+    /// 索引与当前媒体根列表不一致时视为过期。
+    async fn library_is_stale(&self) -> bool {
+        let roots = self.media_roots.read().await;
+        let library = self.library.read().await;
+        roots.len() != library.len()
+            || roots
+                .iter()
+                .zip(library.iter())
+                .any(|(root, index)| root.path != index.root() || root.label != index.label())
+    }
+
+    /// 索引不存在、根目录已变化或 id 未命中时重建一次，新加入的文件无需手动 reindex。
+    async fn library_path(&self, id: &str) -> Result<PathBuf, ApiError> {
+        let lookup = || async {
+            if self.library_is_stale().await {
+                return None;
+            }
+            let library = self.library.read().await;
+            library.iter().find_map(|index| index.get(id))
+        };
+        if let Some(path) = lookup().await {
+            return Ok(path);
+        }
+        self.reindex_library().await?;
+        lookup()
+            .await
+            .ok_or_else(|| ApiError::not_found("library id not found"))
+    }
+
+    /// 按媒体根的配置顺序分组。
+    async fn library_groups(&self) -> Result<Vec<LibraryGroup>, ApiError> {
+        if self.library_is_stale().await {
+            self.reindex_library().await?;
+        }
+        let library = self.library.read().await;
+        Ok(library
+            .iter()
+            .map(|index| LibraryGroup {
+                label: index.label().to_string(),
+                path: index.root().to_path_buf(),
+                files: index.entries(),
+            })
+            .collect())
+    }
+
+    async fn resolve_bilibili(
+        &self,
+        room_name: &str,
+        input: &str,
+        options: &ResolveOptions,
+    ) -> Result<ResolvedMedia, ApiError> {
+        let stream = self
+            .fetch_bilibili_stream(
+                input,
+                options.audio_only,
+                self.max_duration.filter(|_| !options.allow_long),
+                None,
+            )
+            .await?;
+        let token = self
+            .mint_token_with_origin(
+                room_name,
+                MediaTarget::Remote(RemoteTarget {
+                    url: stream.url,
+                    strategy: RemoteStrategy::ProxyWithHeaders,
+                }),
+                Some(BiliOrigin {
+                    input: input.to_string(),
+                    audio_only: options.audio_only,
+                    quality: stream.quality,
+                }),
+            )
+            .await;
+        let source_type = if options.audio_only {
+            SourceType::BiliAudio
+        } else {
+            SourceType::Bili
+        };
+        let danmaku_url = if options.danmaku {
+            self.danmaku_track(room_name, stream.cid, stream.duration)
+                .await
+        } else {
+            None
+        };
+        Ok(ResolvedMedia {
+            url: format!("/media/{token}"),
+            token,
+            source_type,
+            cover: stream.cover,
+            audio: stream.audio,
+            dimension: stream.dimension,
+            quality: stream.quality,
+            segments: None,
+            danmaku_url,
+        })
+    }
+
+    /// 拉取全部弹幕分段并签发指向 JSON 轨道的 token；弹幕只是附加内容，失败时不影响 resolve。
+    async fn danmaku_track(&self, room_name: &str, cid: i64, duration: i64) -> Option<String> {
+        let items = match self.fetch_danmaku(cid, duration).await {
+            Ok(items) => items,
+            Err(err) => {
+                warn!("fetch danmaku cid={cid} failed: {}", err.message);
+                return None;
+            }
+        };
+        let target = MediaTarget::Inline(InlineMedia {
+            content_type: "application/json",
+            body: danmaku::track(items).into(),
+        });
+        let token = self.mint_token(room_name, target).await;
+        Some(format!("/media/{token}"))
+    }
+
+    /// 按 6 分钟一段依次请求分段弹幕接口；某段为空不影响后续分段。
+    async fn fetch_danmaku(
+        &self,
+        cid: i64,
+        duration: i64,
+    ) -> Result<Vec<danmaku::Danmaku>, ApiError> {
+        let client = init_client()
+            .await
+            .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
+        let url = format!("{}/x/v2/dm/web/seg.so", self.bili_api_base);
+        let cookie = self.bili_cookie(None, &url);
+        let mut items = Vec::new();
+        for index in 1..=danmaku::segment_count(duration) {
+            let mut request = client
+                .get(&url)
+                .query(&[
+                    ("type", 1),
+                    ("oid", cid),
+                    ("segment_index", i64::from(index)),
+                ])
+                .header(reqwest::header::REFERER, BILI_REFERER);
+            if let Some(cookie) = cookie.as_deref() {
+                request = request.header(reqwest::header::COOKIE, cookie);
+            }
+            let resp = request
+                .send()
+                .await
+                .map_err(|e| ApiError::bad_gateway(format!("danmaku request failed: {e}")))?;
+            if !resp.status().is_success() {
+                return Err(ApiError::bad_gateway(format!(
+                    "danmaku segment {index} returned {}",
+                    resp.status()
+                )));
+            }
+            let body = resp
+                .bytes()
+                .await
+                .map_err(|e| ApiError::bad_gateway(format!("danmaku read failed: {e}")))?;
+            let segment = danmaku::parse_segment(&body)
+                .ok_or_else(|| ApiError::bad_gateway("invalid danmaku segment"))?;
+            items.extend(segment);
+        }
+        Ok(items)
+    }
+
+    /// 取 B 站稿件当前可用的直链，resolve、失效刷新和切换清晰度共用。
+    /// 熔断期间直接返回 503，不再请求 API；只有限流和上游故障计入熔断。
+    async fn fetch_bilibili_stream(
+        &self,
+        input: &str,
+        audio_only: bool,
+        max_duration: Option<Duration>,
+        quality: Option<u32>,
+    ) -> Result<BiliStream, ApiError> {
+        let bvid =
+            extract_bvid(input).ok_or_else(|| ApiError::bad_request("invalid bilibili id"))?;
+        let Some(breaker) = &self.bili_breaker else {
+            return self
+                .request_bilibili_stream(&bvid, audio_only, max_duration, quality)
+                .await;
+        };
+        if let Err(wait) = breaker.acquire() {
+            return Err(ApiError::unavailable(format!(
+                "Bilibili temporarily unavailable, retry in {}s",
+                wait.as_secs().max(1)
+            )));
+        }
+        let result = self
+            .request_bilibili_stream(&bvid, audio_only, max_duration, quality)
+            .await;
+        breaker.record(result.as_ref().err().map_or(true, |err| {
+            err.status != StatusCode::TOO_MANY_REQUESTS && !err.status.is_server_error()
+        }));
+        result
+    }
+
+    /// 超过 `max_duration` 的稿件在请求 playurl 之前即被拒绝。
+    async fn request_bilibili_stream(
+        &self,
+        bvid: &str,
+        audio_only: bool,
+        max_duration: Option<Duration>,
+        quality: Option<u32>,
+    ) -> Result<BiliStream, ApiError> {
+        let client = init_client()
+            .await
+            .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
+
+        let view = self.fetch_view(&client, bvid, None).await?;
+        if let Some(limit) = max_duration {
+            let duration = Duration::from_secs(view.data.duration.max(0) as u64);
+            if duration > limit {
+                return Err(ApiError::bad_request(format!(
+                    "video is {} min long, over the {} min limit (hosts can pass allowLong to override)",
+                    duration.as_secs().div_ceil(60),
+                    limit.as_secs() / 60
+                )));
+            }
+        }
+
+        if audio_only {
+            let (url, info) = self.fetch_best_audio(&client, bvid, view.data.cid).await?;
+            return Ok(BiliStream {
+                url,
+                cid: view.data.cid,
+                duration: view.data.duration,
+                cover: view.data.pic,
+                audio: Some(info),
+                dimension: None,
+                quality: None,
+            });
+        }
+        let (url, qn) = self
+            .fetch_durl(&client, bvid, view.data.cid, None, quality)
+            .await?;
+        Ok(BiliStream {
+            url,
+            cid: view.data.cid,
+            duration: view.data.duration,
+            cover: view.data.pic,
+            audio: None,
+            dimension: view.data.dimension.and_then(|d| d.display()),
+            quality: Some(qn),
+        })
+    }
+
+    fn set_bili_session(&self, jar: Option<Arc<Jar>>) {
+        *self.bili_session.lock().unwrap_or_else(|e| e.into_inner()) = jar;
+    }
+
+    /// 显式传入的 Cookie 优先，否则使用共享会话中匹配 `url` 的 Cookie。
+    fn bili_cookie<'a>(&self, explicit: Option<&'a str>, url: &str) -> Option<Cow<'a, str>> {
+        if let Some(cookie) = explicit {
+            return Some(Cow::Borrowed(cookie));
+        }
+        let jar = self
+            .bili_session
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()?;
+        let header = jar.cookies(&url.parse().ok()?)?;
+        header.to_str().ok().map(|v| Cow::Owned(v.to_string()))
+    }
+
+    async fn fetch_view(
+        &self,
+        client: &reqwest::Client,
+        bvid: &str,
+        cookie: Option<&str>,
+    ) -> Result<ViewResp, ApiError> {
+        let url = format!("{}/x/web-interface/view", self.bili_api_base);
+        let cookie = self.bili_cookie(cookie, &url);
+        let cookie = cookie.as_deref();
+        self.retry_rate_limited(move || {
+            let mut request = client.get(&url).query(&[("bvid", bvid)]);
+            if let Some(cookie) = cookie {
+                request = request.header(reqwest::header::COOKIE, cookie);
+            }
+            bili_json(request, "view")
+        })
+        .await
+    }
+
+    /// 被 B 站风控时丢弃 wbi key（下次请求重新拉取并重签）后重试一次，
+    /// 仍失败则把限流错误返回给调用方。
+    async fn retry_rate_limited<T, F, Fut>(&self, mut op: F) -> Result<T, ApiError>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<T, ApiError>>,
+    {
+        match op().await {
+            Err(err) if err.status == StatusCode::TOO_MANY_REQUESTS => {
+                warn!("rate limited by bilibili, retrying with fresh wbi key");
+                self.wbi_key.lock().await.take();
+                op().await
+            }
+            result => result,
+        }
+    }
+
+    /// 走与 resolve 相同的 view/playurl 流程，但只返回元数据和可播放性，
+    /// 不签发 token、不触碰任何房间。
+    async fn probe_bilibili(
+        &self,
+        input: &str,
+        quality: Option<u32>,
+        cookie: Option<&str>,
+    ) -> Result<BiliProbeResponse, ApiError> {
+        let bvid =
+            extract_bvid(input).ok_or_else(|| ApiError::bad_request("invalid bilibili id"))?;
+        let client = init_client()
+            .await
+            .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
+
+        let view = self.fetch_view(&client, &bvid, cookie).await?;
+        let cid = view.data.cid;
+
+        let (qn, fnval) = quality.map_or(DURL_ATTEMPTS[0], |qn| (qn, 1));
+        let data = self
+            .fetch_playurl(&client, &bvid, cid, qn, fnval, cookie)
+            .await?;
+        let qualities = data
+            .accept_quality
+            .iter()
+            .zip(&data.accept_description)
+            .map(|(&qn, description)| BiliQuality {
+                qn,
+                description: description.clone(),
+            })
+            .collect();
+        let error = if data.durl.is_empty() {
+            self.fetch_durl(&client, &bvid, cid, cookie, None)
+                .await
+                .err()
+                .map(|e| e.message)
+        } else {
+            None
+        };
+        Ok(BiliProbeResponse {
+            bvid,
+            title: view.data.title,
+            cover: view.data.pic,
+            duration: view.data.duration,
+            qualities,
+            playable: error.is_none(),
+            error,
+        })
+    }
+
+    /// 只取 DASH 中码率最高的音频轨，同样走带 Referer 的代理。
+    async fn fetch_best_audio(
+        &self,
+        client: &reqwest::Client,
+        bvid: &str,
+        cid: i64,
+    ) -> Result<(String, AudioInfo), ApiError> {
+        let data = self.fetch_playurl(client, bvid, cid, 112, 16, None).await?;
+        let audio = data
+            .dash
+            .and_then(|dash| dash.audio)
+            .unwrap_or_default()
+            .into_iter()
+            .max_by_key(|a| a.bandwidth)
+            .ok_or_else(|| ApiError::bad_request("no audio stream"))?;
+        let info = AudioInfo {
+            codec: audio.codecs,
+            bitrate: audio.bandwidth,
+        };
+        Ok((audio.base_url, info))
+    }
+
+    /// fnval=1/0 为 MP4 格式（包含音频），fnval=16 是 DASH（音视频分离）。
+    /// 首个组合没有 durl 时按 DURL_ATTEMPTS 逐个重试；指定 `quality` 时只尝试该清晰度。
+    /// 返回直链及其实际清晰度。
+    async fn fetch_durl(
+        &self,
+        client: &reqwest::Client,
+        bvid: &str,
+        cid: i64,
+        cookie: Option<&str>,
+        quality: Option<u32>,
+    ) -> Result<(String, u32), ApiError> {
+        let requested;
+        let attempts = match quality {
+            Some(qn) => {
+                requested = [(qn, 1), (qn, 0)];
+                &requested[..]
+            }
+            None => DURL_ATTEMPTS,
+        };
+        let mut saw_dash = false;
+        for (i, &(qn, fnval)) in attempts.iter().enumerate() {
+            let data = self
+                .fetch_playurl(client, bvid, cid, qn, fnval, cookie)
+                .await?;
+            let actual = if data.quality > 0 { data.quality } else { qn };
+            if let Some(d) = data.durl.into_iter().next() {
+                if i > 0 {
+                    info!("playurl durl fallback succeeded bvid={bvid} qn={qn} fnval={fnval}");
+                }
+                return Ok((d.url, actual));
+            }
+            saw_dash |= data.dash.is_some();
+        }
+        if saw_dash {
+            // DASH 格式音视频分离，需要客户端支持 MSE，这里暂不支持
+            Err(ApiError::bad_request(
+                "DASH format not supported (audio/video separated)",
+            ))
+        } else {
+            Err(ApiError::bad_request("no playable stream"))
+        }
+    }
+
+    async fn fetch_playurl(
+        &self,
+        client: &reqwest::Client,
+        bvid: &str,
+        cid: i64,
+        qn: u32,
+        fnval: u32,
+        cookie: Option<&str>,
+    ) -> Result<PlayUrlData, ApiError> {
+        let mut params = BTreeMap::new();
+        params.insert("bvid".into(), bvid.to_string());
+        params.insert("cid".into(), cid.to_string());
+        params.insert("qn".into(), qn.to_string());
+        params.insert("fnval".into(), fnval.to_string());
+        params.insert("fourk".into(), "1".into());
+
+        let request = |params: BTreeMap<String, String>| {
+            self.retry_rate_limited(move || {
+                self.request_playurl::<PlayUrlResp>(client, params.clone(), cookie)
+            })
+        };
+        let mut play_resp = request(params.clone()).await?;
+        if play_resp.code == BILI_CODE_SIGN_INVALID {
+            // key 可能已轮换，丢弃缓存后重签一次。
+            self.wbi_key.lock().await.take();
+            play_resp = request(params).await?;
+        }
+        if play_resp.code != 0 {
+            return Err(ApiError::bad_request(format!(
+                "playurl error: {}",
+                play_resp.message
+            )));
+        }
+        Ok(play_resp.data)
+    }
+
+    async fn request_playurl<T: DeserializeOwned>(
+        &self,
+        client: &reqwest::Client,
+        params: BTreeMap<String, String>,
+        cookie: Option<&str>,
+    ) -> Result<T, ApiError> {
+        let mixin_key = self.wbi_key(client).await?;
+        let query = wbi_sign(&mixin_key, params);
+        let play_url = format!("{}/x/player/wbi/playurl?{query}", self.bili_api_base);
+        let cookie = self.bili_cookie(cookie, &play_url);
+        let mut request = client.get(play_url);
+        if let Some(cookie) = cookie.as_deref() {
+            request = request.header(reqwest::header::COOKIE, cookie);
+        }
+        bili_json(request, "playurl").await
+    }
+
+    /// 签名后请求 playurl 并原样返回响应 JSON，含 resolve 不展示的 DASH 轨道与备用地址。
+    async fn raw_playurl(&self, req: &RawPlayUrlRequest) -> Result<serde_json::Value, ApiError> {
+        let bvid = extract_bvid(&req.bvid)
+            .filter(|bvid| bvid.eq_ignore_ascii_case(req.bvid.trim()))
+            .ok_or_else(|| ApiError::bad_request("bvid must be a bare BV id"))?;
+        let client = init_client()
+            .await
+            .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
+        let cookie = req.cookie.as_deref();
+        let cid = match req.cid {
+            Some(cid) => cid,
+            None => self.fetch_view(&client, &bvid, cookie).await?.data.cid,
+        };
+        let mut params = BTreeMap::new();
+        params.insert("bvid".into(), bvid);
+        params.insert("cid".into(), cid.to_string());
+        params.insert("qn".into(), req.qn.unwrap_or(80).to_string());
+        params.insert("fnval".into(), req.fnval.unwrap_or(4048).to_string());
+        params.insert("fourk".into(), "1".into());
+        let raw: serde_json::Value = self
+            .retry_rate_limited(|| self.request_playurl(&client, params.clone(), cookie))
+            .await?;
+        if !raw.is_object() || raw["code"].as_i64().is_none() {
+            return Err(ApiError::bad_gateway("unexpected playurl response"));
+        }
+        Ok(raw)
+    }
+
+    /// 返回缓存的 mixin_key，过期或缺失时通过 nav 接口刷新。
+    async fn wbi_key(&self, client: &reqwest::Client) -> Result<String, ApiError> {
+        let mut cached = self.wbi_key.lock().await;
+        if let Some(key) = cached.as_mut() {
+            if key.fetched_at.elapsed() < WBI_KEY_TTL {
+                key.hits += 1;
+                return Ok(key.mixin_key.clone());
+            }
+        }
+        let mixin_key = fetch_mixin_key(client, &self.bili_api_base).await?;
+        *cached = Some(WbiKey {
+            mixin_key: mixin_key.clone(),
+            fetched_at: Instant::now(),
+            hits: 0,
+        });
+        Ok(mixin_key)
+    }
+
+    async fn cache_entries(&self) -> Vec<CacheEntry> {
+        self.wbi_key
+            .lock()
+            .await
+            .iter()
+            .map(|key| CacheEntry {
+                key: "wbi_mixin_key".into(),
+                age_secs: key.fetched_at.elapsed().as_secs(),
+                hits: key.hits,
+                value: redact(&key.mixin_key),
+            })
+            .collect()
+    }
+
+    /// 清空缓存，返回清除的条目数；下次 resolve 会重新拉取。
+    async fn clear_cache(&self) -> usize {
+        usize::from(self.wbi_key.lock().await.take().is_some())
+    }
+
+    /// 长时间无人活动但仍在播放的房间，在外推出的进度处暂停，保留续播位置。
+    async fn pause_idle_rooms(&self) -> Vec<(String, RoomState)> {
+        let Some(window) = self.idle_pause else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        let mut paused = Vec::new();
+        let mut rooms = self.rooms.write().await;
+        for (name, room) in rooms.iter_mut() {
+            let idle = room
+                .last_seen()
+                .is_some_and(|seen| now.saturating_duration_since(seen) > window);
+            let Some(state) = room.state.as_ref().filter(|s| idle && !s.paused) else {
+                continue;
+            };
+            let mut state = state.rebased_at(now_millis());
+            state.paused = true;
+            // 自动暂停不算活动，保持原有的过期时间。
+            let last_update = room.last_update;
+            room.set_state(state.clone());
+            room.last_update = last_update;
+            room.record("idle_pause", None);
+            paused.push((name.clone(), state));
+        }
+        paused
+    }
+
+    /// 正在播放的房间按服务器时钟外推出的权威进度，不修改房间状态。
+    async fn seek_corrections(&self) -> Vec<(String, RoomState)> {
+        let now = now_millis();
+        self.rooms
+            .read()
+            .await
+            .iter()
+            .filter_map(|(name, room)| {
+                let state = room.state.as_ref().filter(|s| !s.paused)?;
+                Some((name.clone(), state.rebased_at(now)))
+            })
+            .collect()
+    }
+
+    /// 暂停中的房间的权威状态及其房主，由调用方按在线情况筛选。
+    async fn paused_resyncs(&self) -> Vec<(String, RoomState, HashSet<String>)> {
+        self.rooms
+            .read()
+            .await
+            .iter()
+            .filter_map(|(name, room)| {
+                let state = room.state.as_ref().filter(|s| s.paused)?;
+                Some((name.clone(), state.clone(), room.host_ids.clone()))
+            })
+            .collect()
+    }
+
+    async fn seek_correction(&self, room_name: &str) -> Option<RoomState> {
+        let rooms = self.rooms.read().await;
+        let state = rooms.get(room_name)?.state.as_ref()?;
+        (!state.paused).then(|| state.rebased_at(now_millis()))
+    }
+
+    /// 以新清晰度为房间当前的 B 站 token 重新取链，token 不变。
+    /// 房间状态记录新清晰度，并以切换时刻外推出的进度为基准，重新加载后从原位置继续。
+    async fn switch_quality(
+        &self,
+        room_name: &str,
+        password: &str,
+        temp_user: &str,
+        token: &str,
+        quality: u32,
+    ) -> Result<(u32, RoomState), ApiError> {
+        let url = format!("/media/{token}");
+        {
+            let rooms = self.rooms.read().await;
+            let room = rooms
+                .get(room_name)
+                .ok_or_else(|| ApiError::bad_request("room not found"))?;
+            if room.password != password {
+                return Err(ApiError::forbidden("room password mismatch"));
+            }
+            if !room.is_host(temp_user) && !self.allow_member_control {
+                return Err(ApiError::forbidden("operation allowed for host only"));
+            }
+            if room.state.as_ref().map(|s| s.url.as_str()) != Some(url.as_str()) {
+                return Err(ApiError::bad_request(
+                    "token is not the room's current media",
+                ));
+            }
+        }
+        let origin = {
+            let tokens = self.media_tokens.read().await;
+            let entry = tokens
+                .get(token)
+                .ok_or_else(|| ApiError::not_found("token not found"))?;
+            match (&entry.target, &entry.origin) {
+                (MediaTarget::Remote(_), Some(origin)) if !origin.audio_only => origin.clone(),
+                _ => return Err(ApiError::bad_request("not a bilibili video token")),
+            }
+        };
+        let stream = self
+            .fetch_bilibili_stream(&origin.input, false, None, Some(quality))
+            .await?;
+        let actual = stream.quality.unwrap_or(quality);
+        {
+            let mut tokens = self.media_tokens.write().await;
+            let entry = tokens
+                .get_mut(token)
+                .ok_or_else(|| ApiError::not_found("token not found"))?;
+            if let MediaTarget::Remote(target) = &mut entry.target {
+                target.url = stream.url;
+            }
+            if let Some(origin) = &mut entry.origin {
+                origin.quality = Some(actual);
+            }
+        }
+        if let Some(cache) = &self.segment_cache {
+            cache.remove_token(token);
+        }
+
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ApiError::bad_request("room not found"))?;
+        let state = room
+            .state
+            .as_ref()
+            .filter(|s| s.url == url)
+            .ok_or_else(|| ApiError::conflict("room media changed during switch"))?;
+        let state = RoomState {
+            quality: Some(actual),
+            ..state.rebased_at(now_millis())
+        };
+        room.set_state(state.clone());
+        room.record("switch_quality", Some(temp_user));
+        Ok((actual, state))
+    }
+
+    /// 探测各房间当前及预加载媒体的 B 站上游，CDN 链接提前失效时重新取链，
+    /// 原 token 保持不变，客户端无感。返回刷新的 token 数。
+    async fn validate_remote_tokens(&self) -> usize {
+        let active: HashSet<String> = self
+            .rooms
+            .read()
+            .await
+            .values()
+            .flat_map(|room| room.state.iter().chain(room.pending_next.iter()))
+            .filter_map(|state| state.url.strip_prefix("/media/"))
+            .map(str::to_string)
+            .collect();
+        let candidates: Vec<(String, String, BiliOrigin)> = {
+            let tokens = self.media_tokens.read().await;
+            active
+                .iter()
+                .filter_map(|token| {
+                    let entry = tokens.get(token)?;
+                    match (&entry.target, &entry.origin) {
+                        (MediaTarget::Remote(target), Some(origin)) => {
+                            Some((token.clone(), target.url.clone(), origin.clone()))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect()
+        };
+        if candidates.is_empty() {
+            return 0;
+        }
+        let client = match init_client().await {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("token validation skipped, client init failed: {err}");
+                return 0;
+            }
+        };
+
+        let mut refreshed = 0;
+        for (token, url, origin) in candidates {
+            let upstream_status = client
+                .get(&url)
+                .header(axum::http::header::RANGE, "bytes=0-0")
+                .header(axum::http::header::REFERER, "https://www.bilibili.com/")
+                .send()
+                .await
+                .ok()
+                .map(|resp| resp.status().as_u16());
+            let alive = upstream_status.is_some_and(|code| (200..300).contains(&code));
+            let fresh = if alive {
+                None
+            } else {
+                match self
+                    .fetch_bilibili_stream(&origin.input, origin.audio_only, None, origin.quality)
+                    .await
+                {
+                    Ok(stream) => Some(stream.url),
+                    Err(err) => {
+                        warn!("refresh upstream for token {token} failed: {err}");
+                        None
+                    }
+                }
+            };
+            let mut tokens = self.media_tokens.write().await;
+            let Some(entry) = tokens.get_mut(&token) else {
+                continue;
+            };
+            if let (Some(url), MediaTarget::Remote(target)) = (&fresh, &mut entry.target) {
+                info!("upstream for token {token} returned {upstream_status:?}, refreshed");
+                target.url = url.clone();
+                refreshed += 1;
+                if let Some(cache) = &self.segment_cache {
+                    cache.remove_token(&token);
+                }
+            }
+            entry.validation = Some(TokenValidation {
+                checked_at: now_millis(),
+                upstream_status,
+                alive,
+                refreshed: fresh.is_some(),
+            });
+        }
+        refreshed
+    }
+
+    /// 播放器接入前预先探一次：远程 token 发一个 `bytes=0-0` 请求建立 CDN 连接，
+    /// 本地 token 只确认文件仍在。
+    async fn prewarm(&self, token: &str) -> Result<PrewarmResponse, ApiError> {
+        let target = {
+            let tokens = self.media_tokens.read().await;
+            let entry = tokens
+                .get(token)
+                .ok_or_else(|| ApiError::not_found("token not found"))?;
+            if Instant::now() > entry.expires_at {
+                return Err(ApiError::not_found("token expired"));
+            }
+            entry.target.clone()
+        };
+        let (path, source_type) = match target {
+            MediaTarget::Local(path) => (path, SourceType::File),
+            MediaTarget::Transcode(target) => (target.path, SourceType::Transcode),
+            MediaTarget::Inline(inline) => {
+                return Ok(PrewarmResponse {
+                    ready: true,
+                    source_type: SourceType::Inline,
+                    upstream_status: None,
+                    size: Some(inline.body.len() as u64),
+                });
+            }
+            MediaTarget::Concat(parts) => {
+                let mut size = 0;
+                for part in &parts {
+                    let meta = tokio::fs::metadata(part)
+                        .await
+                        .map_err(|_| ApiError::not_found("media not found"))?;
+                    size += meta.len();
+                }
+                return Ok(PrewarmResponse {
+                    ready: true,
+                    source_type: SourceType::Concat,
+                    upstream_status: None,
+                    size: Some(size),
+                });
+            }
+            MediaTarget::Remote(target) => {
+                if matches!(target.strategy, RemoteStrategy::Redirect) {
+                    ssrf::ensure_public_url(&target.url).await?;
+                }
+                let client = init_client()
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
+                let upstream_status = client
+                    .get(&target.url)
+                    .header(axum::http::header::RANGE, "bytes=0-0")
+                    .header(axum::http::header::REFERER, "https://www.bilibili.com/")
+                    .send()
+                    .await
+                    .ok()
+                    .map(|resp| resp.status().as_u16());
+                return Ok(PrewarmResponse {
+                    ready: upstream_status.is_some_and(|code| (200..300).contains(&code)),
+                    source_type: SourceType::Remote,
+                    upstream_status,
+                    size: None,
+                });
+            }
+        };
+        let meta = tokio::fs::metadata(&path)
+            .await
+            .ok()
+            .filter(|m| m.is_file());
+        Ok(PrewarmResponse {
+            ready: meta.is_some(),
+            source_type,
+            upstream_status: None,
+            size: meta.map(|m| m.len()),
+        })
+    }
+
+    async fn token_validation(&self, token: &str) -> Result<Option<TokenValidation>, ApiError> {
+        self.media_tokens
+            .read()
+            .await
+            .get(token)
+            .map(|entry| entry.validation.clone())
+            .ok_or_else(|| ApiError::not_found("token not found"))
+    }
+
+    /// 记下过期房间的进度与播放列表：进度按最后活跃时刻外推后暂停，避免重建后直接开播。
+    /// 既没有播放状态也没有播放列表的房间不值得保留。
+    fn bury(&self, name: &str, room: Room) {
+        let Some(ttl) = self.tombstone_ttl else {
+            return;
+        };
+        if room.state.is_none() && room.playlist.is_empty() {
+            return;
+        }
+        let idle = room
+            .last_seen()
+            .map_or(Duration::ZERO, |seen| seen.elapsed());
+        let state = room.state.map(|mut state| {
+            let last_active = now_millis() - idle.as_millis() as i64;
+            state.current_time = state.position_at(last_active);
+            if state.duration > 0.0 {
+                state.current_time = state.current_time.min(state.duration);
+            }
+            state.paused = true;
+            state.updated_at = now_millis();
+            state
+        });
+
+        let mut tombstones = self.tombstones.lock().unwrap_or_else(|e| e.into_inner());
+        tombstones.retain(|_, t| t.expired_at.elapsed() <= ttl);
+        tombstones.insert(
+            name.to_string(),
+            Tombstone {
+                password: room.password,
+                state,
+                source: room.source,
+                playlist: room.playlist,
+                expired_at: Instant::now(),
+            },
+        );
+        while tombstones.len() > MAX_TOMBSTONES {
+            let Some(oldest) = tombstones
+                .iter()
+                .min_by_key(|(_, t)| t.expired_at)
+                .map(|(name, _)| name.clone())
+            else {
+                break;
+            };
+            tombstones.remove(&oldest);
+        }
+    }
+
+    /// 取出并移除房间的保留状态；无论口令是否匹配，重建后旧状态都不再保留。
+    /// 口令匹配且未超出保留期的墓碑视为房间仍然存在，`join` 模式可据此重建。
+    fn has_tombstone(&self, name: &str, password: &str) -> bool {
+        let Some(ttl) = self.tombstone_ttl else {
+            return false;
+        };
+        self.tombstones
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(name)
+            .is_some_and(|t| t.password == password && t.expired_at.elapsed() <= ttl)
+    }
+
+    /// 口令不符时保留原记录，打错口令的加入不会丢掉原房间的状态。
+    fn take_tombstone(&self, name: &str, password: &str) -> Option<Tombstone> {
+        let ttl = self.tombstone_ttl?;
+        let mut tombstones = self.tombstones.lock().unwrap_or_else(|e| e.into_inner());
+        if tombstones.get(name)?.password != password {
+            return None;
+        }
+        tombstones
+            .remove(name)
+            .filter(|t| t.expired_at.elapsed() <= ttl)
+    }
+
+    /// 分两阶段清理：先在读锁下收集过期 key，再用短写锁删除，
+    /// 且 rooms 与 media_tokens 不同时持锁，避免大表清理时阻塞请求。
+    /// 清理过期房间和 token，返回被移除的房间名。
+    async fn cleanup(&self) -> Vec<String> {
+        let now = Instant::now();
+        let expired_rooms: Vec<String> = self
+            .rooms
+            .read()
+            .await
+            .iter()
+            .filter(|(_, room)| room.is_expired(now, self.room_ttl))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut removed = Vec::new();
+        if !expired_rooms.is_empty() {
+            let mut rooms = self.rooms.write().await;
+            let now = Instant::now();
+            for name in expired_rooms {
+                // 两阶段之间房间可能被重新激活，删除前再确认一次。
+                if rooms
+                    .get(&name)
+                    .is_some_and(|room| room.is_expired(now, self.room_ttl))
+                {
+                    if let Some(room) = rooms.remove(&name) {
+                        self.bury(&name, room);
+                    }
+                    self.notify_webhook(webhook::WebhookEvent::new("room_closed", &name));
+                    removed.push(name);
+                }
+            }
+        }
+
+        let expired_tokens: Vec<String> = self
+            .media_tokens
+            .read()
+            .await
+            .iter()
+            .filter(|(_, token)| now > token.expires_at)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if !expired_tokens.is_empty() {
+            let mut tokens = self.media_tokens.write().await;
+            for key in expired_tokens {
+                tokens.remove(&key);
+                if let Some(cache) = &self.segment_cache {
+                    cache.remove_token(&key);
+                }
+            }
+            if let Some(index) = &self.token_reuse {
+                index
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .retain(|_, token| tokens.contains_key(token));
+            }
+        }
+        removed
+    }
+}
+
+/// Hub 订阅者的发送端：WebSocket 接收完整帧，SSE 只接收文本负载。
+#[derive(Clone)]
+enum ClientSender {
+    Ws(mpsc::UnboundedSender<Message>),
+    Sse(mpsc::UnboundedSender<String>),
+}
+
+impl ClientSender {
+    fn send_text(&self, payload: &str) -> bool {
+        match self {
+            Self::Ws(tx) => tx.send(Message::Text(payload.to_string())).is_ok(),
+            Self::Sse(tx) => tx.send(payload.to_string()).is_ok(),
+        }
+    }
+
+    /// SSE 没有关闭帧，从 Hub 移除后 channel 关闭，流随之结束。
+    fn close(&self, close: Message) {
+        if let Self::Ws(tx) = self {
+            let _ = tx.send(close);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ClientHandle {
+    temp_user: String,
+    tx: ClientSender,
+    /// 是否接收 `debug` 诊断消息。
+    debug: bool,
+    /// 是否使用确认协议接收关键消息。
+    acks: bool,
+    /// 注册时刻（毫秒）。
+    connected_at: i64,
+    /// 对端地址，SSE 与测试中直接注册的连接没有。
+    peer: Option<IpAddr>,
+}
+
+/// 连接超时由 client 的 connect_timeout 保证，之后等待响应头不超过 `first_byte`；
+/// 超时返回 504，连接失败返回 502，请求 future 随之丢弃并释放连接。
+async fn send_upstream(
+    builder: reqwest::RequestBuilder,
+    timeouts: ProxyTimeouts,
+) -> Result<reqwest::Response, ApiError> {
+    match tokio_time::timeout(timeouts.first_byte, builder.send()).await {
+        Ok(Ok(resp)) => Ok(resp),
+        Ok(Err(e)) if e.is_timeout() => Err(ApiError::gateway_timeout(format!(
+            "upstream connect timed out after {}s",
+            timeouts.connect.as_secs_f64()
+        ))),
+        Ok(Err(e)) if e.is_connect() => {
+            Err(ApiError::bad_gateway(format!("upstream unreachable: {e}")))
+        }
+        Ok(Err(e)) => Err(ApiError::not_found(format!("upstream error: {e}"))),
+        Err(_) => Err(ApiError::gateway_timeout(format!(
+            "upstream sent no response within {}s",
+            timeouts.first_byte.as_secs_f64()
+        ))),
+    }
+}
+
+/// 管理接口列出的一条连接。
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConnectionInfo {
+    client_id: String,
+    room: String,
+    temp_user: String,
+    connected_at: i64,
+    peer: Option<IpAddr>,
+    /// `ws` 或 `sse`。
+    transport: &'static str,
+}
+
+type RoomClients = HashMap<String, ClientHandle>;
+
+struct Hub {
+    clients: Arc<RwLock<HashMap<String, RoomClients>>>,
+    /// 每个对端 IP 当前保持的 WebSocket 连接数。
+    ip_conns: Arc<StdMutex<HashMap<IpAddr, usize>>>,
+    max_ws_per_ip: Option<usize>,
+    metrics: WsMetrics,
+    /// 所有连接都接收 `debug` 诊断消息。
+    debug_all: bool,
+    /// 每个房间最近一次带状态广播的序号。
+    state_seq: Arc<StdMutex<HashMap<String, u64>>>,
+    pending_acks: Arc<StdMutex<PendingAcks>>,
+    /// 关键消息未确认时的重发间隔。
+    ack_timeout: Duration,
+}
+
+/// 已发出、尚未确认的关键消息，按 (连接 id, 确认 id) 记录。
+#[derive(Debug, Default)]
+struct PendingAcks {
+    last_id: u64,
+    pending: HashSet<(String, u64)>,
+}
+
+/// 按消息类型统计收到的 WebSocket 消息数与处理失败数。
+#[derive(Debug, Default)]
+struct WsMetrics {
+    counts: StdMutex<BTreeMap<&'static str, WsTypeCount>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct WsTypeCount {
+    received: u64,
+    errors: u64,
+}
+
+impl WsMetrics {
+    fn record(&self, kind: &'static str, ok: bool) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = counts.entry(kind).or_default();
+        entry.received += 1;
+        if !ok {
+            entry.errors += 1;
+        }
+    }
+
+    #[cfg(test)]
+    fn get(&self, kind: &str) -> WsTypeCount {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts.get(kind).copied().unwrap_or_default()
+    }
+
+    /// Prometheus 文本格式。
+    fn render(&self) -> String {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::from(
+            "# HELP vo_sync_ws_messages_total WebSocket messages received by type.\n\
+             # TYPE vo_sync_ws_messages_total counter\n",
+        );
+        for (kind, count) in counts.iter() {
+            out.push_str(&format!(
+                "vo_sync_ws_messages_total{{type=\"{kind}\"}} {}\n",
+                count.received
+            ));
+        }
+        out.push_str(
+            "# HELP vo_sync_ws_message_errors_total WebSocket messages rejected by type.\n\
+             # TYPE vo_sync_ws_message_errors_total counter\n",
+        );
+        for (kind, count) in counts.iter() {
+            out.push_str(&format!(
+                "vo_sync_ws_message_errors_total{{type=\"{kind}\"}} {}\n",
+                count.errors
+            ));
+        }
+        out
+    }
+}
+
+fn member_count_debug(room_clients: &RoomClients) -> serde_json::Value {
+    let users: HashSet<&str> = room_clients
+        .values()
+        .map(|client| client.temp_user.as_str())
+        .collect();
+    json!({
+        "event": "members",
+        "connections": room_clients.len(),
+        "users": users.len(),
+    })
+}
+
+/// 占用一个 IP 连接名额，drop 时归还。
+struct IpSlot {
+    ip: IpAddr,
+    conns: Arc<StdMutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut conns = self.conns.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = conns.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                conns.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Hub {
+    fn new() -> Self {
+        Self {
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            ip_conns: Arc::new(StdMutex::new(HashMap::new())),
+            max_ws_per_ip: None,
+            metrics: WsMetrics::default(),
+            debug_all: false,
+            state_seq: Arc::new(StdMutex::new(HashMap::new())),
+            pending_acks: Arc::new(StdMutex::new(PendingAcks::default())),
+            ack_timeout: ACK_TIMEOUT,
+        }
+    }
+
+    fn with_ws_debug(mut self, enabled: bool) -> Self {
+        self.debug_all = enabled;
+        self
+    }
+
+    fn with_max_ws_per_ip(mut self, max: Option<usize>) -> Self {
+        self.max_ws_per_ip = max;
+        self
+    }
+
+    /// 超过上限时返回 None。
+    fn acquire_ip_slot(&self, ip: IpAddr) -> Option<IpSlot> {
+        let mut conns = self.ip_conns.lock().unwrap_or_else(|e| e.into_inner());
+        let count = conns.entry(ip).or_default();
+        if self.max_ws_per_ip.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            ip,
+            conns: self.ip_conns.clone(),
+        })
+    }
+
+    async fn register(&self, room: &str, client_id: &str, temp_user: &str, tx: ClientSender) {
+        let mut clients = self.clients.write().await;
+        let room_clients = clients.entry(room.to_string()).or_default();
+        room_clients.insert(
+            client_id.to_string(),
+            ClientHandle {
+                temp_user: temp_user.to_string(),
+                tx,
+                debug: false,
+                acks: false,
+                connected_at: now_millis(),
+                peer: None,
+            },
+        );
+        self.send_debug(room_clients, &member_count_debug(room_clients));
+    }
+
+    async fn set_peer(&self, room: &str, client_id: &str, peer: IpAddr) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients
+            .get_mut(room)
+            .and_then(|room_clients| room_clients.get_mut(client_id))
+        {
+            client.peer = Some(peer);
+        }
+    }
+
+    async fn enable_debug(&self, room: &str, client_id: &str) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients
+            .get_mut(room)
+            .and_then(|room_clients| room_clients.get_mut(client_id))
+        {
+            client.debug = true;
+        }
+    }
+
+    async fn enable_acks(&self, room: &str, client_id: &str) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients
+            .get_mut(room)
+            .and_then(|room_clients| room_clients.get_mut(client_id))
+        {
+            client.acks = true;
+        }
+    }
+
+    /// 只发给开启诊断的连接；没有这样的连接时不做序列化。
+    fn send_debug(&self, room_clients: &RoomClients, payload: &serde_json::Value) {
+        let mut targets = room_clients
+            .values()
+            .filter(|client| self.debug_all || client.debug)
+            .peekable();
+        if targets.peek().is_none() {
+            return;
+        }
+        let msg = WsOutgoing {
+            debug: Some(payload.clone()),
+            server_time: Some(now_millis()),
+            ..WsOutgoing::kind("debug")
+        };
+        let Ok(text) = serde_json::to_string(&msg) else {
+            return;
+        };
+        for client in targets {
+            client.tx.send_text(&text);
+        }
+    }
+
+    async fn debug(&self, room: &str, payload: serde_json::Value) {
+        if let Some(room_clients) = self.clients.read().await.get(room) {
+            self.send_debug(room_clients, &payload);
+        }
+    }
+
+    async fn is_connected(&self, room: &str, temp_user: &str) -> bool {
+        self.clients
+            .read()
+            .await
+            .get(room)
+            .is_some_and(|clients| clients.values().any(|client| client.temp_user == temp_user))
+    }
+
+    async fn connected_users(&self, room: &str) -> HashSet<String> {
+        self.clients
+            .read()
+            .await
+            .get(room)
+            .map(|clients| {
+                clients
+                    .values()
+                    .map(|client| client.temp_user.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    async fn unregister(&self, room: &str, client_id: &str) {
+        let mut clients = self.clients.write().await;
+        self.remove_client(&mut clients, room, client_id);
+    }
+
+    fn remove_client(
+        &self,
+        clients: &mut HashMap<String, RoomClients>,
+        room: &str,
+        client_id: &str,
+    ) -> Option<ClientHandle> {
+        self.lock_acks()
+            .pending
+            .retain(|(client, _)| client != client_id);
+        let room_clients = clients.get_mut(room)?;
+        let removed = room_clients.remove(client_id);
+        if room_clients.is_empty() {
+            clients.remove(room);
+            self.reset_seq(room);
+        } else {
+            self.send_debug(room_clients, &member_count_debug(room_clients));
+        }
+        removed
+    }
+
+    /// 所有房间的连接，按房间与建立时间排序。
+    async fn connections(&self) -> Vec<ConnectionInfo> {
+        let clients = self.clients.read().await;
+        let mut list: Vec<ConnectionInfo> = clients
+            .iter()
+            .flat_map(|(room, room_clients)| {
+                room_clients.iter().map(move |(id, client)| ConnectionInfo {
+                    client_id: id.clone(),
+                    room: room.clone(),
+                    temp_user: client.temp_user.clone(),
+                    connected_at: client.connected_at,
+                    peer: client.peer,
+                    transport: match client.tx {
+                        ClientSender::Ws(_) => "ws",
+                        ClientSender::Sse(_) => "sse",
+                    },
+                })
+            })
+            .collect();
+        list.sort_by(|a, b| (&a.room, a.connected_at).cmp(&(&b.room, b.connected_at)));
+        list
+    }
+
+    /// 从 Hub 移除指定连接并发送关闭帧，连接不存在时返回 false。
+    async fn close_connection(&self, client_id: &str, close: Message) -> bool {
+        let mut clients = self.clients.write().await;
+        let Some(room) = clients
+            .iter()
+            .find(|(_, room_clients)| room_clients.contains_key(client_id))
+            .map(|(room, _)| room.clone())
+        else {
+            return false;
+        };
+        match self.remove_client(&mut clients, &room, client_id) {
+            Some(client) => {
+                client.tx.close(close);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn broadcast_state(&self, room: &str, state: &RoomState) {
+        self.broadcast(room, &WsOutgoing::with_state("room_state", state.clone()))
+            .await;
+    }
+
+    async fn broadcast(&self, room: &str, msg: &WsOutgoing) {
+        let started = Instant::now();
+        let mut clients = self.clients.write().await;
+        if let Some(room_clients) = clients.get_mut(room) {
+            // 持有写锁时编号，序号顺序与投递顺序一致。
+            let payload = match msg.state {
+                Some(_) => serde_json::to_string(&WsOutgoing {
+                    seq: Some(self.next_seq(room)),
+                    ..msg.clone()
+                }),
+                None => serde_json::to_string(msg),
+            }
+            .unwrap();
+            room_clients.retain(|_, client| client.tx.send_text(&payload));
+            self.send_debug(
+                room_clients,
+                &json!({
+                    "event": "broadcast",
+                    "kind": msg.r#type,
+                    "recipients": room_clients.len(),
+                    "elapsedUs": started.elapsed().as_micros() as u64,
+                }),
+            );
+        }
+    }
+
+    /// 与 `broadcast` 相同，但开启确认协议的连接会收到带 `ackId` 的副本，
+    /// 未在 `ack_timeout` 内确认时重发，最多 `ACK_RETRIES` 次。
+    /// 踢人、关闭房间使用关闭帧，连接随即结束，不在此列。
+    async fn broadcast_critical(&self, room: &str, msg: &WsOutgoing) {
+        let clients = self.clients.read().await;
+        let Some(room_clients) = clients.get(room) else {
+            return;
+        };
+        let plain = serde_json::to_string(msg).unwrap();
+        for (client_id, client) in room_clients {
+            if !client.acks {
+                client.tx.send_text(&plain);
+                continue;
+            }
+            let ack_id = {
+                let mut acks = self.lock_acks();
+                acks.last_id += 1;
+                let id = acks.last_id;
+                acks.pending.insert((client_id.clone(), id));
+                id
+            };
+            let payload = serde_json::to_string(&WsOutgoing {
+                ack_id: Some(ack_id),
+                ..msg.clone()
+            })
+            .unwrap();
+            client.tx.send_text(&payload);
+            self.spawn_ack_retry(room, client_id, ack_id, payload);
+        }
+    }
+
+    fn spawn_ack_retry(&self, room: &str, client_id: &str, ack_id: u64, payload: String) {
+        let key = (client_id.to_string(), ack_id);
+        let room = room.to_string();
+        let clients = self.clients.clone();
+        let pending = self.pending_acks.clone();
+        let timeout = self.ack_timeout;
+        tokio::spawn(async move {
+            let acks = || pending.lock().unwrap_or_else(|e| e.into_inner());
+            for _ in 0..ACK_RETRIES {
+                tokio::time::sleep(timeout).await;
+                if !acks().pending.contains(&key) {
+                    return;
+                }
+                let tx = clients
+                    .read()
+                    .await
+                    .get(&room)
+                    .and_then(|room_clients| room_clients.get(&key.0))
+                    .map(|client| client.tx.clone());
+                match tx {
+                    Some(tx) if tx.send_text(&payload) => {}
+                    _ => break,
+                }
+            }
+            tokio::time::sleep(timeout).await;
+            if acks().pending.remove(&key) {
+                warn!("room={room} client={} never acked message {ack_id}", key.0);
+            }
+        });
+    }
+
+    /// 返回该消息此前是否在等待确认。
+    fn ack(&self, client_id: &str, ack_id: u64) -> bool {
+        self.lock_acks()
+            .pending
+            .remove(&(client_id.to_string(), ack_id))
+    }
+
+    fn lock_acks(&self) -> std::sync::MutexGuard<'_, PendingAcks> {
+        self.pending_acks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn send_to(&self, room: &str, client_id: &str, msg: WsOutgoing) -> Result<(), ApiError> {
+        let mut clients = self.clients.write().await;
+        if let Some(room_clients) = clients.get_mut(room) {
+            if let Some(client) = room_clients.get(client_id) {
+                let payload = serde_json::to_string(&msg).unwrap_or_else(|_| "{}".into());
+                if !client.tx.send_text(&payload) {
+                    return Err(ApiError::bad_request("send error"));
+                }
+                return Ok(());
+            }
+        }
+        Err(ApiError::not_found("client not found"))
+    }
+
+    /// 向某用户在该房间的所有连接发送关闭帧并移除，返回断开的连接数。
+    /// 只发给 `users` 中的用户的所有连接。
+    async fn send_to_users(&self, room: &str, users: &HashSet<String>, msg: &WsOutgoing) {
+        let payload = serde_json::to_string(msg).unwrap();
+        if let Some(room_clients) = self.clients.read().await.get(room) {
+            for client in room_clients
+                .values()
+                .filter(|client| users.contains(&client.temp_user))
+            {
+                client.tx.send_text(&payload);
+            }
+        }
+    }
+
+    async fn kick(&self, room: &str, temp_user: &str, close: Message) -> usize {
+        self.close_where(room, close, |client| client.temp_user == temp_user)
+            .await
+    }
+
+    /// 断开不在 `keep` 中的所有订阅者，返回断开的连接数。
+    async fn close_except(&self, room: &str, keep: &HashSet<String>, close: Message) -> usize {
+        self.close_where(room, close, |client| !keep.contains(&client.temp_user))
+            .await
+    }
+
+    async fn close_where(
+        &self,
+        room: &str,
+        close: Message,
+        matches: impl Fn(&ClientHandle) -> bool,
+    ) -> usize {
+        let mut clients = self.clients.write().await;
+        let Some(room_clients) = clients.get_mut(room) else {
+            return 0;
+        };
+        let before = room_clients.len();
+        room_clients.retain(|_, client| {
+            if !matches(client) {
+                return true;
+            }
+            client.tx.close(close.clone());
+            false
+        });
+        let closed = before - room_clients.len();
+        if room_clients.is_empty() {
+            clients.remove(room);
+        }
+        closed
+    }
+
+    fn next_seq(&self, room: &str) -> u64 {
+        let mut seqs = self.state_seq.lock().unwrap_or_else(|e| e.into_inner());
+        let seq = seqs.entry(room.to_string()).or_default();
+        *seq += 1;
+        *seq
+    }
+
+    fn current_seq(&self, room: &str) -> u64 {
+        let seqs = self.state_seq.lock().unwrap_or_else(|e| e.into_inner());
+        seqs.get(room).copied().unwrap_or_default()
+    }
+
+    /// 房间内已无连接，之后的新连接会从首条消息重新获得基线。
+    fn reset_seq(&self, room: &str) {
+        let mut seqs = self.state_seq.lock().unwrap_or_else(|e| e.into_inner());
+        seqs.remove(room);
+    }
+
+    async fn close_room(&self, room: &str, close: Message) {
+        if let Some(room_clients) = self.clients.write().await.remove(room) {
+            for client in room_clients.into_values() {
+                client.tx.close(close.clone());
+            }
+        }
+        self.reset_seq(room);
+    }
+
+    async fn close_all(&self, close: Message) {
+        let mut clients = self.clients.write().await;
+        for client in clients.drain().flat_map(|(_, room)| room.into_values()) {
+            client.tx.close(close.clone());
+        }
+    }
+}
+
+async fn bind_listener(addr: &str) -> anyhow::Result<(TcpListener, SocketAddr)> {
+    let mut candidates = Vec::new();
+    candidates.push(addr.to_string());
+    if addr == DEFAULT_LISTEN_ADDR {
+        for port in 18080..18090 {
+            candidates.push(format!("127.0.0.1:{port}"));
+        }
+        candidates.push("127.0.0.1:0".to_string());
+    }
+    for candidate in candidates {
+        match TcpListener::bind(&candidate).await {
+            Ok(listener) => {
+                let local = listener.local_addr()?;
+                if candidate != addr {
+                    warn!("sync server fallback to {}", local);
+                }
+                return Ok((listener, local));
+            }
+            Err(err) => {
+                warn!("bind {} failed: {err}", candidate);
+            }
+        }
+    }
+    Err(anyhow::anyhow!(
+        "failed to bind sync server; consider setting {}",
+        ENV_LISTEN_ADDR
+    ))
+}
+
+fn now_millis() -> i64 {
+    let now = OffsetDateTime::now_utc();
+    (now.unix_timestamp_nanos() / 1_000_000)
+        .try_into()
+        .unwrap_or(i64::MAX)
+}
+
+/// 取路径最后一段作为默认标题。
+fn default_title(path: &str) -> String {
+    path.rsplit(['/', '\\'])
+        .next()
+        .filter(|s| !s.is_empty())
+        .unwrap_or("视频")
+        .to_string()
+}
+
+fn clean_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let p = path.as_ref();
+    std::fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf())
+}
+
+/// 未指定标签的媒体根以目录名显示。
+fn default_root_label(path: &Path) -> String {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("media")
+        .to_string()
+}
+
+fn is_under_root(path: &Path, root: &Path) -> bool {
+    path.starts_with(root)
+}
+
+fn is_bilibili_source(input: &str) -> Option<&str> {
+    if input.starts_with("BV")
+        || input.starts_with("bv")
+        || input.starts_with("ep")
+        || input.starts_with("EP")
+    {
+        return Some(input);
+    }
+    if input.starts_with("http://") || input.starts_with("https://") {
+        if input.contains("bilibili.com") || input.contains("bilivideo.com") {
+            return Some(input);
+        }
+    }
+    None
+}
+
+/// 代理转发时最多缓冲的上游分块数。
+const PROXY_RELAY_CHUNKS: usize = 8;
+
+/// 经有界通道转发上游数据：客户端读得慢时通道写满，上游读取随之暂停，
+/// 单条流的内存占用不超过 `capacity` 个分块；客户端断开后上游连接一并释放。
+fn relay_bounded<S, E>(upstream: S, capacity: usize) -> Body
+where
+    S: futures_util::Stream<Item = Result<axum::body::Bytes, E>> + Send + 'static,
+    E: Into<axum::BoxError> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(capacity);
+    tokio::spawn(async move {
+        futures_util::pin_mut!(upstream);
+        while let Some(chunk) = upstream.next().await {
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+/// 按字节数有界的预读转发：上游在客户端读取之前最多领先 `budget` 字节，
+/// 客户端取走一块即归还相应额度；额度用尽时上游读取暂停，与 `relay_bounded` 一样受背压约束。
+fn relay_readahead<S, E>(upstream: S, budget: usize) -> Body
+where
+    S: futures_util::Stream<Item = Result<axum::body::Bytes, E>> + Send + 'static,
+    E: Into<axum::BoxError> + Send + 'static,
+{
+    let budget = budget.clamp(1, u32::MAX as usize);
+    let credit = Arc::new(Semaphore::new(budget));
+    let (tx, rx) = mpsc::unbounded_channel::<(Result<axum::body::Bytes, E>, _)>();
+    tokio::spawn(async move {
+        futures_util::pin_mut!(upstream);
+        while let Some(chunk) = upstream.next().await {
+            // 单块超过总额度时按总额度计，否则永远等不到足够的额度。
+            let cost = chunk.as_ref().map_or(0, |bytes| bytes.len().min(budget));
+            let permit = tokio::select! {
+                permit = credit.clone().acquire_many_owned(cost as u32) => permit.ok(),
+                _ = tx.closed() => break,
+            };
+            let failed = chunk.is_err();
+            if tx.send((chunk, permit)).is_err() || failed {
+                break;
+            }
+        }
+    });
+    Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        // 额度随 permit 一起在此处归还。
+        rx.recv().await.map(|(chunk, _permit)| (chunk, rx))
+    }))
+}
+
+/// 浏览器首次请求 `<video>` 时常带 `bytes=0-`，与不带 Range 一样视为从头读取。
+fn transcode_range_allowed(headers: &HeaderMap) -> bool {
+    match headers.get(axum::http::header::RANGE) {
+        None => true,
+        Some(value) => value.to_str().is_ok_and(|v| v.trim() == "bytes=0-"),
+    }
+}
+
+fn copy_header(headers: &HeaderMap, key: axum::http::header::HeaderName, builder: &mut Builder) {
+    if let Some(val) = headers.get(&key) {
+        if let Some(map) = builder.headers_mut() {
+            map.insert(key.clone(), val.clone());
+        }
+    }
+}
+
+/// 上游分块传输、没有 `Content-Length` 时，尽量从 `Content-Range` 推出本段长度；
+/// 并总是声明支持 Range，播放器据此显示总长、允许拖动。
+fn fill_length_headers(upstream: &HeaderMap, builder: &mut Builder) {
+    let Some(map) = builder.headers_mut() else {
+        return;
+    };
+    if !upstream.contains_key(axum::http::header::CONTENT_LENGTH) {
+        if let Some(len) = upstream
+            .get(axum::http::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(content_range_len)
+        {
+            map.insert(axum::http::header::CONTENT_LENGTH, HeaderValue::from(len));
+        }
+    }
+    if !upstream.contains_key(axum::http::header::ACCEPT_RANGES) {
+        map.insert(
+            axum::http::header::ACCEPT_RANGES,
+            HeaderValue::from_static("bytes"),
+        );
+    }
+}
+
+/// `bytes 0-99/1000` 形式的 `Content-Range` 对应的本段字节数。
+fn content_range_len(value: &str) -> Option<u64> {
+    let (range, _total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end: u64 = end.trim().parse().ok()?;
+    end.checked_sub(start).map(|len| len + 1)
+}
+
+async fn log_requests(req: Request<Body>, next: Next) -> impl IntoResponse {
+    info!("sync request {} {}", req.method(), req.uri());
+    let res = next.run(req).await;
+    res
+}
+
+const MIXIN_KEY_ENC_TAB: [usize; 64] = [
+    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29,
+    28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25,
+    54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52,
+];
+
+#[derive(Debug, Deserialize)]
+struct NavResp {
+    data: NavData,
+}
+
+#[derive(Debug, Deserialize)]
+struct NavData {
+    wbi_img: WbiImg,
+}
+
+#[derive(Debug, Deserialize)]
+struct WbiImg {
+    img_url: String,
+    sub_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FavListResp {
+    code: i32,
+    #[serde(default)]
+    message: String,
+    data: Option<FavListData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FavListData {
+    info: Option<FavInfo>,
+    medias: Option<Vec<FavMedia>>,
+    #[serde(default)]
+    has_more: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct FavInfo {
+    title: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FavMedia {
+    #[serde(rename = "type")]
+    kind: u32,
+    #[serde(default)]
+    attr: u32,
+    #[serde(default)]
+    bvid: String,
+    #[serde(default)]
+    title: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ViewResp {
+    data: ViewData,
+}
+
+#[derive(Debug, Deserialize)]
+struct ViewData {
+    bvid: String,
+    cid: i64,
+    title: String,
+    #[serde(default)]
+    pic: Option<String>, // Bilibili cover
+    #[serde(default)]
+    duration: i64,
+    #[serde(default)]
+    dimension: Option<ViewDimension>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ViewDimension {
+    width: u32,
+    height: u32,
+    /// 为 1 时宽高需要对调。
+    #[serde(default)]
+    rotate: u8,
+}
+
+impl ViewDimension {
+    fn display(&self) -> Option<VideoDimension> {
+        if self.rotate == 1 {
+            VideoDimension::new(self.height, self.width)
+        } else {
+            VideoDimension::new(self.width, self.height)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PlayUrlResp {
+    code: i32,
+    message: String,
+    data: PlayUrlData,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlayUrlData {
+    #[serde(default)]
+    durl: Vec<Durl>,
+    #[serde(default)]
+    dash: Option<Dash>,
+    /// 本次返回的清晰度，可能低于请求的 qn。
+    #[serde(default)]
+    quality: u32,
+    /// 当前账号可选的清晰度及其名称，两者一一对应。
+    #[serde(default)]
+    accept_quality: Vec<u32>,
+    #[serde(default)]
+    accept_description: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Durl {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Dash {
+    #[serde(default)]
+    video: Vec<DashStream>,
+    /// 部分稿件（如纯视频无音轨）返回 null。
+    #[serde(default)]
+    audio: Option<Vec<DashStream>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DashStream {
+    #[serde(rename = "baseUrl")]
+    base_url: String,
+    #[serde(default)]
+    bandwidth: u64,
+    #[serde(default)]
+    codecs: String,
+}
+
+/// BV 号：`BV` + 10 位 base58 字符（不含 0/I/O/l），前后不能紧邻其他字母数字。
+static BVID_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:^|[^0-9A-Za-z])[Bb][Vv]([1-9A-HJ-NP-Za-km-z]{10})(?:[^0-9A-Za-z]|$)").unwrap()
+});
+
+fn extract_bvid(input: &str) -> Option<String> {
+    BVID_RE
+        .captures(input)
+        .map(|caps| format!("BV{}", &caps[1]))
+}
+
+/// 收藏夹 id：纯数字，或链接中的 `fid=` / `ml` 前缀。
+static FAV_ID_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:[?&]fid=|\bml)(\d+)").unwrap());
+
+fn extract_fav_id(input: &str) -> Option<u64> {
+    let input = input.trim();
+    if let Ok(id) = input.parse() {
+        return Some(id);
+    }
+    FAV_ID_RE
+        .captures(input)
+        .and_then(|caps| caps[1].parse().ok())
+}
+
+/// 应用内登录保存的 Cookie 转成会话，未登录或读取失败时为 None。
+async fn stored_bili_session() -> Option<Arc<Jar>> {
+    let cookie = stored_bili_cookie().await.ok()?;
+    let url = reqwest::Url::parse(BILI_REFERER).ok()?;
+    let jar = Jar::default();
+    for pair in cookie.split("; ") {
+        jar.add_cookie_str(&format!("{pair}; Domain=.bilibili.com; Path=/"), &url);
+    }
+    Some(Arc::new(jar))
+}
+
+/// 应用内登录后保存的 B 站 Cookie，拼成请求头格式。
+async fn stored_bili_cookie() -> Result<String, ApiError> {
+    let stored = cookies::load()
+        .await
+        .map_err(|e| ApiError::unavailable(format!("load cookies failed: {e}")))?;
+    if !stored.contains_key("SESSDATA") {
+        return Err(ApiError::forbidden("bilibili login required"));
+    }
+    Ok(stored
+        .iter()
+        .map(|(name, value)| format!("{name}={}", value.trim_matches('"')))
+        .collect::<Vec<_>>()
+        .join("; "))
+}
+
+/// 从 nav 接口拉取 img/sub key 并生成 mixin_key。
+/// B 站接口统一经此发送：显式带上浏览器 UA 和 Referer，不依赖全局请求头；
+/// HTTP 412 或 `code: -412` 视为风控，返回 429 供调用方重试。
+async fn bili_json<T: DeserializeOwned>(
+    request: reqwest::RequestBuilder,
+    what: &str,
+) -> Result<T, ApiError> {
+    let resp = request
+        .header(reqwest::header::USER_AGENT, USER_AGENT)
+        .header(reqwest::header::REFERER, BILI_REFERER)
+        .send()
+        .await
+        .map_err(|e| ApiError::bad_gateway(format!("{what} request failed: {e}")))?;
+    let rate_limited = || ApiError::too_many_requests("rate limited by Bilibili, try again later");
+    if resp.status().as_u16() == 412 {
+        return Err(rate_limited());
+    }
+    let body: serde_json::Value = resp
+        .json()
+        .await
+        .map_err(|e| ApiError::bad_gateway(format!("{what} parse failed: {e}")))?;
+    if body["code"].as_i64() == Some(BILI_CODE_RATE_LIMITED.into()) {
+        return Err(rate_limited());
+    }
+    serde_json::from_value(body)
+        .map_err(|e| ApiError::bad_gateway(format!("{what} parse failed: {e}")))
+}
+
+async fn fetch_mixin_key(client: &reqwest::Client, api_base: &str) -> Result<String, ApiError> {
+    let nav: NavResp =
+        bili_json(client.get(format!("{api_base}/x/web-interface/nav")), "nav").await?;
+    let img_key = nav
+        .data
+        .wbi_img
+        .img_url
+        .rsplit('/')
+        .next()
+        .and_then(|s| s.split('.').next())
+        .unwrap_or("");
+    let sub_key = nav
+        .data
+        .wbi_img
+        .sub_url
+        .rsplit('/')
+        .next()
+        .and_then(|s| s.split('.').next())
+        .unwrap_or("");
+    let mixin_source = format!("{img_key}{sub_key}");
+    let mixin_key: String = MIXIN_KEY_ENC_TAB
+        .iter()
+        .filter_map(|idx| mixin_source.chars().nth(*idx))
+        .take(32)
+        .collect();
+    Ok(mixin_key)
+}
+
+fn wbi_sign(mixin_key: &str, mut params: BTreeMap<String, String>) -> String {
+    let curr_time = OffsetDateTime::now_utc().unix_timestamp();
+    params.insert("wts".into(), curr_time.to_string());
+
+    let chr_filter = |s: &str| s.replace(&['\'', '!', '(', ')', '*'][..], "");
+    let query = params
+        .iter()
+        .map(|(k, v)| (k.as_str(), chr_filter(v)))
+        .collect::<Vec<_>>();
+    let mut query_sorted = query;
+    query_sorted.sort_by(|a, b| a.0.cmp(b.0));
+    let encoded = query_sorted
+        .into_iter()
+        .map(|(k, v)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(k, NON_ALPHANUMERIC),
+                utf8_percent_encode(&v, NON_ALPHANUMERIC)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let sign = format!("{:x}", md5::compute(format!("{encoded}{mixin_key}")));
+    format!("{encoded}&w_rid={sign}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::File as StdFile,
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// 写一个只有 ftyp box 的最小 mp4，足以通过格式嗅探。
+    fn write_mp4(path: &Path) {
+        let mut file = StdFile::create(path).unwrap();
+        file.write_all(b"\x00\x00\x00\x18ftypisom\x00\x00\x02\x00isommp41")
+            .unwrap();
+    }
+
+    /// 只给出来源的房间状态，其余字段取默认值，测试中配合结构体更新语法使用。
+    fn room_state(url: &str) -> RoomState {
+        RoomState {
+            url: url.into(),
+            title: String::new(),
+            current_time: 0.0,
+            duration: 0.0,
+            paused: true,
+            playback_rate: 1.0,
+            source_type: SourceType::File,
+            updated_at: 0,
+            cover: None,
+            quality: None,
+            effective_at: None,
+            fit_mode: FitMode::Contain,
+            crop: None,
+            volume: None,
+            muted: None,
+        }
+    }
+
+    /// 成员的公开标识，房主操作以此指定对象。
+    async fn member_id(manager: &Manager, room: &str, temp_user: &str) -> String {
+        manager.rooms.read().await[room].members[temp_user]
+            .id
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn join_and_authorize_flow() {
+        let manager = Manager::new(None, true);
+        let (host, is_host) = manager.join_room("room", "pwd").await.unwrap();
+        assert!(is_host);
+        let (member, member_host) = manager.join_room("room", "pwd").await.unwrap();
+        assert!(!member_host);
+        assert!(manager.authorize("room", "pwd", &host).await.unwrap());
+        assert!(!manager.authorize("room", "pwd", &member).await.unwrap());
+        assert!(manager.is_host("room", &host).await);
+        assert!(!manager.is_host("room", &member).await);
+    }
+
+    #[tokio::test]
+    async fn member_control_merges_playback_only() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let host_state = RoomState {
+            title: "Movie".into(),
+            duration: 120.0,
+            paused: false,
+            ..room_state("file:///movie.mp4")
+        };
+        manager
+            .update_state("room", &host, host_state, true)
+            .await
+            .unwrap();
+
+        let member_update = RoomState {
+            title: "hijack".into(),
+            current_time: 30.0,
+            duration: 999.0,
+            playback_rate: 1.5,
+            source_type: SourceType::Remote,
+            ..room_state("hijack")
+        };
+        let merged = manager
+            .update_state("room", &member, member_update, false)
+            .await
+            .unwrap();
+        assert_eq!(merged.url, "file:///movie.mp4");
+        assert_eq!(merged.title, "Movie");
+        assert_eq!(merged.duration, 120.0);
+        assert_eq!(merged.source_type, SourceType::File);
+        assert_eq!(merged.current_time, 30.0);
+        assert_eq!(merged.paused, true);
+        assert_eq!(merged.playback_rate, 1.5);
+    }
+
+    #[tokio::test]
+    async fn resolve_local_media_and_block_online() {
+        let root = std::env::temp_dir().join("vo_sync_test");
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("sample.mp4");
+        write_mp4(&file_path);
+
+        let manager = Manager::new(Some(root.clone()), true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let res = manager
+            .resolve_media_path("room", "pwd", &host, file_path.to_str().unwrap())
+            .await
+            .expect("should generate token");
+        assert_eq!(res.source_type, SourceType::File);
+        assert!(res.url.contains("/media/"));
+        assert!(!res.token.is_empty());
+
+        let err = manager
+            .resolve_media_path("room", "pwd", &host, "https://bilibili.com/video/BVxxx")
+            .await
+            .unwrap_err();
+        let resp = err.into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let remote = manager
+            .resolve_media_path("room", "pwd", &host, "https://1.1.1.1/video.mp4")
+            .await
+            .expect("remote should be tokenized");
+        assert_eq!(remote.source_type, SourceType::Remote);
+        assert!(remote.url.contains("/media/"));
+    }
+
+    #[tokio::test]
+    async fn resolve_rejects_internal_remote_targets() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        for url in [
+            "http://127.0.0.1:18080/healthz",
+            "http://169.254.169.254/latest/meta-data/",
+        ] {
+            let err = manager
+                .resolve_media_path("room", "pwd", &host, url)
+                .await
+                .unwrap_err();
+            assert_eq!(err.status, StatusCode::FORBIDDEN);
+        }
+        let public = manager
+            .resolve_media_path("room", "pwd", &host, "http://1.1.1.1/video.mp4")
+            .await
+            .expect("public host should be tokenized");
+        assert_eq!(public.source_type, SourceType::Remote);
+    }
+
+    #[tokio::test]
+    async fn set_media_root_and_resolve_local() {
+        let root = std::env::temp_dir().join("vo_sync_root_set");
+        std::fs::create_dir_all(&root).unwrap();
+        let manager = Manager::new(None, true);
+        manager
+            .set_media_root(root.to_str().unwrap(), None)
+            .await
+            .expect("set media root");
+        let file_path = root.join("movie.mp4");
+        write_mp4(&file_path);
+        let (host, _) = manager.join_room("r", "p").await.unwrap();
+        let res = manager
+            .resolve_media_path("r", "p", &host, file_path.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::File);
+
+        let err = manager.set_media_root("/", None).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        let roots = manager.media_roots.read().await;
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].path, clean_path(&root));
+    }
+
+    #[tokio::test]
+    async fn snapshot_restore_round_trip() {
+        let root = std::env::temp_dir().join("vo_sync_snapshot");
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("movie.mp4");
+        write_mp4(&file_path);
+        let source = file_path.to_str().unwrap().to_string();
+
+        let manager = Manager::new(Some(root.clone()), true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let resolved = manager
+            .resolve_media_path("room", "pwd", &host, &source)
+            .await
+            .unwrap();
+        let state = RoomState {
+            title: "Movie".into(),
+            current_time: 42.0,
+            duration: 120.0,
+            playback_rate: 1.25,
+            ..room_state(&resolved.url)
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+        manager.set_source("room", &source).await;
+        let playlist = vec![PlaylistItem {
+            path: "BV1xx411c7mD".into(),
+            title: Some("Next".into()),
+        }];
+        manager
+            .set_playlist("room", "pwd", &host, playlist.clone())
+            .await
+            .unwrap();
+
+        let snapshot = manager.snapshot_room("room", "pwd", &host).await.unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let parsed: RoomSnapshot = serde_json::from_str(&json).unwrap();
+
+        let (new_host, restored) = manager
+            .restore_room("restored", "pwd2", parsed)
+            .await
+            .unwrap();
+        let restored = restored.expect("state restored");
+        assert_eq!(restored.current_time, 42.0);
+        assert_eq!(restored.playback_rate, 1.25);
+        assert_eq!(restored.title, "Movie");
+        assert!(restored.url.starts_with("/media/"));
+        assert_ne!(restored.url, resolved.url);
+        assert!(manager
+            .authorize("restored", "pwd2", &new_host)
+            .await
+            .unwrap());
+
+        let again = manager
+            .snapshot_room("restored", "pwd2", &new_host)
+            .await
+            .unwrap();
+        assert_eq!(again.playlist, playlist);
+        assert_eq!(again.source.as_deref(), Some(source.as_str()));
+
+        let err = manager
+            .restore_room("restored", "pwd2", again)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_invalid_snapshot() {
+        let manager = Manager::new(None, true);
+        let snapshot = RoomSnapshot {
+            version: SNAPSHOT_VERSION,
+            room: "room".into(),
+            source: None,
+            state: Some(RoomState {
+                title: "a".into(),
+                current_time: f64::NAN,
+                duration: 10.0,
+                source_type: SourceType::Remote,
+                ..room_state("https://example.com/a.mp4")
+            }),
+            playlist: Vec::new(),
+            created_at: 0,
+        };
+        let err = manager
+            .restore_room("room", "pwd", snapshot.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        let mut future = snapshot;
+        future.version = SNAPSHOT_VERSION + 1;
+        future.state = None;
+        assert!(manager.restore_room("room", "pwd", future).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn cleanup_does_not_deadlock_with_concurrent_joins() {
+        let mut manager = Manager::new(None, true);
+        manager.room_ttl = Duration::ZERO;
+        let manager = Arc::new(manager);
+        for i in 0..200 {
+            manager
+                .mint_token(
+                    "room",
+                    MediaTarget::Local(PathBuf::from(format!("/tmp/{i}"))),
+                )
+                .await;
+        }
+
+        let mut tasks = Vec::new();
+        for i in 0..8 {
+            let m = manager.clone();
+            tasks.push(tokio::spawn(async move {
+                for j in 0..100 {
+                    m.join_room(&format!("room-{i}-{j}"), "pwd").await.unwrap();
+                }
+            }));
+            let m = manager.clone();
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..50 {
+                    m.cleanup().await;
+                    tokio::task::yield_now().await;
+                }
+            }));
+        }
+        tokio_time::timeout(Duration::from_secs(10), async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        })
+        .await
+        .expect("cleanup and join_room should not deadlock");
+    }
+
+    #[tokio::test]
+    async fn ended_advances_to_next_playlist_item() {
+        let root = std::env::temp_dir().join("vo_sync_ended");
+        std::fs::create_dir_all(&root).unwrap();
+        let first = root.join("ep1.mp4");
+        let second = root.join("ep2.mp4");
+        for path in [&first, &second] {
+            write_mp4(path);
+        }
+
+        let manager = Manager::new(Some(root.clone()), false);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let resolved = manager
+            .resolve_media_path("room", "pwd", &host, first.to_str().unwrap())
+            .await
+            .unwrap();
+        let state = RoomState {
+            title: "ep1.mp4".into(),
+            current_time: 100.0,
+            duration: 120.0,
+            paused: false,
+            ..room_state(&resolved.url)
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+        manager
+            .set_playlist(
+                "room",
+                "pwd",
+                &host,
+                vec![PlaylistItem {
+                    path: second.to_str().unwrap().into(),
+                    title: None,
+                }],
+            )
+            .await
+            .unwrap();
+
+        // 成员无控制权时不能触发 ended
+        let url = resolved.url.as_str();
+        assert!(manager
+            .end_video("room", &member, false, url)
+            .await
+            .is_err());
+
+        // 每个客户端播完都会上报，同一地址只推进一次。
+        let manager = Arc::new(manager);
+        let ends: Vec<_> = (0..4)
+            .map(|_| {
+                let (manager, host, url) = (manager.clone(), host.clone(), url.to_string());
+                tokio::spawn(async move { manager.end_video("room", &host, true, &url).await })
+            })
+            .collect();
+        let mut advanced = Vec::new();
+        for end in ends {
+            if let Some(VideoEnd::Advanced(next)) = end.await.unwrap().unwrap() {
+                advanced.push(next);
+            }
+        }
+        assert_eq!(advanced.len(), 1);
+        let next = advanced.pop().unwrap();
+        assert_ne!(next.url, resolved.url);
+        assert_eq!(next.title, "ep2.mp4");
+        assert_eq!(next.current_time, 0.0);
+        assert!(!next.paused);
+        assert_eq!(manager.current_state("room").await.unwrap().url, next.url);
+        let stale = manager.end_video("room", &host, true, url).await.unwrap();
+        assert!(stale.is_none());
+
+        let Some(VideoEnd::Finished(done)) = manager
+            .end_video("room", &host, true, &next.url)
+            .await
+            .unwrap()
+        else {
+            panic!("expected end of playlist");
+        };
+        assert!(done.paused);
+        assert_eq!(done.url, next.url);
+        let repeated = manager
+            .end_video("room", &host, true, &next.url)
+            .await
+            .unwrap();
+        assert!(repeated.is_none());
+    }
+
+    #[test]
+    fn extract_bvid_cases() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("BV1xx411c7mD", Some("BV1xx411c7mD")),
+            ("bv1xx411c7mD", Some("BV1xx411c7mD")),
+            (
+                "https://www.bilibili.com/video/BV1xx411c7mD?t=10",
+                Some("BV1xx411c7mD"),
+            ),
+            (
+                "https://www.bilibili.com/video/BV1xx411c7mD/?spm_id_from=333.1007&vd_source=abc",
+                Some("BV1xx411c7mD"),
+            ),
+            (
+                "https://m.bilibili.com/video/BV1GJ411x7h7",
+                Some("BV1GJ411x7h7"),
+            ),
+            ("【分享】BV1GJ411x7h7 快来看", Some("BV1GJ411x7h7")),
+            (
+                "https://example.com/?q=BVBVBV&v=BV1GJ411x7h7",
+                Some("BV1GJ411x7h7"),
+            ),
+            // 紧跟多余字母数字：原实现会截取前 12 位
+            ("BV1xx411c7mDextra", None),
+            ("https://www.bilibili.com/video/BV1xx411c7mD2", None),
+            // "BV" 出现在更长的 token 中间
+            ("ABV1xx411c7mD", None),
+            ("https://example.com/SUBVERSION1234", None),
+            // 长度不足 / 含非 base58 字符
+            ("BV1xx411c7m", None),
+            ("BV1xx411c70D", None),
+            ("BV1xx411cIlO", None),
+            ("BV", None),
+            ("", None),
+            ("https://bilibili.com/video/BVxxx", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(extract_bvid(input).as_deref(), *expected, "input: {input}");
+        }
+    }
+
+    /// 在随机端口启动一个 mock 服务，返回其根地址。
+    async fn spawn_mock(router: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    /// 模拟 B 站 view/nav/playurl 三个接口。
+    fn mock_bili_router(playurl: serde_json::Value, nav_hits: Arc<AtomicUsize>) -> Router {
+        mock_bili_router_with(move |_| playurl.clone(), nav_hits)
+    }
+
+    /// 同上，但 playurl 的响应由查询参数决定。
+    fn mock_bili_router_with(
+        playurl: impl Fn(&HashMap<String, String>) -> serde_json::Value + Clone + Send + Sync + 'static,
+        nav_hits: Arc<AtomicUsize>,
+    ) -> Router {
+        Router::new()
+            .route(
+                "/x/web-interface/view",
+                get(|| async {
+                    Json(json!({
+                        "code": 0,
+                        "data": {
+                            "bvid": "BV1xx411c7mD",
+                            "cid": 1176840,
+                            "title": "mock",
+                            "pic": "https://i0.hdslb.com/bfs/archive/mock.jpg",
+                            "duration": 240,
+                            "dimension": { "width": 1080, "height": 1920, "rotate": 0 }
+                        }
+                    }))
+                }),
+            )
+            .route(
+                "/x/web-interface/nav",
+                get(move || async move {
+                    nav_hits.fetch_add(1, Ordering::SeqCst);
+                    Json(json!({
+                        "data": {
+                            "wbi_img": {
+                                "img_url": "https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png",
+                                "sub_url": "https://i0.hdslb.com/bfs/wbi/4932caff0ff746eab6f01bf08b70ac45.png"
+                            }
+                        }
+                    }))
+                }),
+            )
+            .route(
+                "/x/player/wbi/playurl",
+                get(move |Query(params): Query<HashMap<String, String>>| {
+                    let body = playurl(&params);
+                    async move { Json(body) }
+                }),
+            )
+    }
+
+    #[tokio::test]
+    async fn relay_pauses_upstream_for_slow_client() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = produced.clone();
+        let upstream = stream::iter(0..).map(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, std::io::Error>(axum::body::Bytes::from(vec![0u8; 1024]))
+        });
+        let body = relay_bounded(upstream, 4);
+
+        // 客户端尚未读取：上游最多填满通道，再多一块阻塞在 send 上
+        tokio_time::sleep(Duration::from_millis(50)).await;
+        let buffered = produced.load(Ordering::SeqCst);
+        assert!(buffered <= 5, "upstream read {buffered} chunks ahead");
+
+        let mut data = body.into_data_stream();
+        for _ in 0..3 {
+            data.next().await.unwrap().unwrap();
+        }
+        tokio_time::sleep(Duration::from_millis(50)).await;
+        assert!(produced.load(Ordering::SeqCst) <= 5 + 3);
+
+        // 客户端断开后上游停止读取
+        drop(data);
+        tokio_time::sleep(Duration::from_millis(50)).await;
+        let stopped = produced.load(Ordering::SeqCst);
+        tokio_time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(produced.load(Ordering::SeqCst), stopped);
+    }
+
+    #[tokio::test]
+    async fn readahead_absorbs_upstream_stall() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let bursty = |produced: Arc<AtomicUsize>| {
+            stream::iter(0..16).then(move |i| {
+                let produced = produced.clone();
+                async move {
+                    // 前 8 块之后上游停顿 200ms。
+                    if i == 8 {
+                        tokio_time::sleep(Duration::from_millis(200)).await;
+                    }
+                    produced.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, std::io::Error>(axum::body::Bytes::from(vec![0u8; 1024]))
+                }
+            })
+        };
+        let drain = |body: Body| async move {
+            let started = Instant::now();
+            let mut data = body.into_data_stream();
+            let mut total = 0;
+            while let Some(chunk) = data.next().await {
+                total += chunk.unwrap().len();
+            }
+            (total, started.elapsed())
+        };
+
+        // 客户端播放缓冲期间不读取；预读额度足够时停顿在此期间被消化。
+        let body = relay_readahead(bursty(produced.clone()), 16 * 1024);
+        tokio_time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(produced.load(Ordering::SeqCst), 16);
+        let (total, elapsed) = drain(body).await;
+        assert_eq!(total, 16 * 1024);
+        assert!(elapsed < Duration::from_millis(100), "{elapsed:?}");
+
+        let body = relay_bounded(bursty(Arc::default()), 4);
+        tokio_time::sleep(Duration::from_millis(300)).await;
+        let (_, elapsed) = drain(body).await;
+        assert!(elapsed >= Duration::from_millis(150), "{elapsed:?}");
+
+        // 额度用尽后上游暂停，领先量不超过预算。
+        let produced = Arc::new(AtomicUsize::new(0));
+        let _body = relay_readahead(bursty(produced.clone()), 4 * 1024);
+        tokio_time::sleep(Duration::from_millis(50)).await;
+        assert!(produced.load(Ordering::SeqCst) <= 5);
+    }
+
+    #[test]
+    fn extract_fav_id_cases() {
+        assert_eq!(extract_fav_id("1052622027"), Some(1052622027));
+        assert_eq!(
+            extract_fav_id("https://space.bilibili.com/2/favlist?fid=1052622027&ftype=create"),
+            Some(1052622027)
+        );
+        assert_eq!(
+            extract_fav_id("https://www.bilibili.com/medialist/detail/ml1052622027"),
+            Some(1052622027)
+        );
+        assert_eq!(extract_fav_id("BV1xx411c7mD"), None);
+    }
+
+    #[tokio::test]
+    async fn favorites_expand_into_playlist() {
+        let router = Router::new().route(
+            "/x/v3/fav/resource/list",
+            get(
+                |Query(params): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+                    let cookie = headers.get("cookie").and_then(|v| v.to_str().ok());
+                    if cookie != Some("SESSDATA=abc") {
+                        return Json(json!({ "code": -101, "message": "账号未登录" }));
+                    }
+                    assert_eq!(params.get("media_id").map(String::as_str), Some("42"));
+                    let body = match params.get("pn").map(String::as_str) {
+                        Some("1") => json!({
+                            "info": { "title": "稍后一起看" },
+                            "medias": [
+                                { "type": 2, "attr": 0, "bvid": "BV1xx411c7mD", "title": "one" },
+                                { "type": 2, "attr": 9, "bvid": "BV1aa411c7mD", "title": "已失效视频" },
+                                { "type": 12, "attr": 0, "bvid": "", "title": "audio" }
+                            ],
+                            "has_more": true
+                        }),
+                        _ => json!({
+                            "info": { "title": "稍后一起看" },
+                            "medias": [
+                                { "type": 2, "attr": 0, "bvid": "BV1bb411c7mD", "title": "two" }
+                            ],
+                            "has_more": false
+                        }),
+                    };
+                    Json(json!({ "code": 0, "message": "0", "data": body }))
+                },
+            ),
+        );
+        let base = spawn_mock(router).await;
+        let manager = Manager::new(None, true).with_bili_api_base(base.clone());
+
+        let res = manager
+            .fetch_favorites(42, Some("SESSDATA=abc"))
+            .await
+            .unwrap();
+        assert_eq!(res.title.as_deref(), Some("稍后一起看"));
+        let paths: Vec<_> = res.playlist.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, ["BV1xx411c7mD", "BV1bb411c7mD"]);
+
+        let err = manager.fetch_favorites(42, Some("")).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        // 未显式传 Cookie 时用共享的登录会话，未登录则直接拒绝
+        let err = manager.fetch_favorites(42, None).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        let jar = Jar::default();
+        jar.add_cookie_str("SESSDATA=abc; Path=/", &base.parse().unwrap());
+        manager.set_bili_session(Some(Arc::new(jar)));
+        assert_eq!(
+            manager
+                .fetch_favorites(42, None)
+                .await
+                .unwrap()
+                .playlist
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn audio_only_resolves_best_audio_track() {
+        let base = spawn_mock(mock_bili_router(json!({
+            "code": 0,
+            "message": "0",
+            "data": {
+                "durl": [],
+                "dash": {
+                    "video": [
+                        { "id": 80, "baseUrl": "https://cdn.bilivideo.com/video.m4s", "bandwidth": 2000000, "codecs": "avc1.640032" }
+                    ],
+                    "audio": [
+                        { "id": 30216, "baseUrl": "https://cdn.bilivideo.com/audio-64k.m4s", "bandwidth": 64000, "codecs": "mp4a.40.2" },
+                        { "id": 30280, "baseUrl": "https://cdn.bilivideo.com/audio-192k.m4s", "bandwidth": 192000, "codecs": "mp4a.40.2" }
+                    ]
+                }
+            }
+        }), Arc::default()))
+        .await;
+        let manager = Manager::new(None, true).with_bili_api_base(base);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let options = ResolveOptions {
+            audio_only: true,
+            ..Default::default()
+        };
+        let res = manager
+            .resolve_media_with("room", "pwd", &host, "BV1xx411c7mD", &options)
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::BiliAudio);
+        let audio = res.audio.expect("audio info");
+        assert_eq!(audio.codec, "mp4a.40.2");
+        assert_eq!(audio.bitrate, 192000);
+
+        let target = manager.open_remote(&res.token).await.unwrap();
+        assert_eq!(target.url, "https://cdn.bilivideo.com/audio-192k.m4s");
+        assert_ne!(target.url, "https://cdn.bilivideo.com/video.m4s");
+    }
+
+    #[tokio::test]
+    async fn wbi_key_is_cached_across_resolves() {
+        let nav_hits = Arc::new(AtomicUsize::new(0));
+        let base = spawn_mock(mock_bili_router(
+            json!({
+                "code": 0,
+                "message": "0",
+                "data": { "durl": [{ "url": "https://cdn.bilivideo.com/v.mp4" }] }
+            }),
+            nav_hits.clone(),
+        ))
+        .await;
+        let manager = Manager::new(None, true).with_bili_api_base(base);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        for _ in 0..2 {
+            let res = manager
+                .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+                .await
+                .unwrap();
+            assert_eq!(res.source_type, SourceType::Bili);
+        }
+        assert_eq!(nav_hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_durl_retries_with_mp4_fallback() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let base = spawn_mock(mock_bili_router_with(
+            move |params| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let data = if params.get("fnval").map(String::as_str) == Some("0") {
+                    json!({ "durl": [{ "url": "https://cdn.bilivideo.com/fallback.mp4" }] })
+                } else {
+                    json!({
+                        "durl": [],
+                        "dash": { "video": [], "audio": [] }
+                    })
+                };
+                json!({ "code": 0, "message": "0", "data": data })
+            },
+            Arc::default(),
+        ))
+        .await;
+        let manager = Manager::new(None, true).with_bili_api_base(base);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let res = manager
+            .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::Bili);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        let target = manager.open_remote(&res.token).await.unwrap();
+        assert_eq!(target.url, "https://cdn.bilivideo.com/fallback.mp4");
+        let dimension = res.dimension.expect("dimension");
+        assert_eq!((dimension.width, dimension.height), (1080, 1920));
+        assert_eq!(dimension.orientation, "portrait");
+    }
+
+    #[test]
+    fn view_dimension_accounts_for_rotation() {
+        let rotated = ViewDimension {
+            width: 1920,
+            height: 1080,
+            rotate: 1,
+        };
+        assert_eq!(rotated.display().unwrap().orientation, "portrait");
+        assert_eq!(
+            VideoDimension::new(1920, 1080).unwrap().orientation,
+            "landscape"
+        );
+        assert_eq!(VideoDimension::new(720, 720).unwrap().orientation, "square");
+        assert!(VideoDimension::new(0, 1080).is_none());
+    }
+
+    #[tokio::test]
+    async fn events_since_cursor_returns_only_newer() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let first = manager
+            .room_events("room", "pwd", &member, None)
+            .await
+            .unwrap();
+        assert_eq!(first.events.len(), 2);
+        assert_eq!(first.cursor, 2);
+
+        let state = RoomState {
+            title: "a".into(),
+            duration: 10.0,
+            paused: false,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+        let target = member_id(&manager, "room", &member).await;
+        manager
+            .set_co_host("room", "pwd", &host, &target, true)
+            .await
+            .unwrap();
+
+        let next = manager
+            .room_events("room", "pwd", &member, Some(first.cursor))
+            .await
+            .unwrap();
+        let kinds: Vec<_> = next.events.iter().map(|e| (e.id, e.kind)).collect();
+        assert_eq!(kinds, vec![(3, "state"), (4, "promote")]);
+        assert_eq!(next.cursor, 4);
+        // 成员可见的日志里只有公开标识
+        let users: Vec<_> = next.events.iter().map(|e| e.user.clone()).collect();
+        let host_id = member_id(&manager, "room", &host).await;
+        assert_eq!(users, vec![Some(host_id), Some(target)]);
+
+        let empty = manager
+            .room_events("room", "pwd", &member, Some(next.cursor))
+            .await
+            .unwrap();
+        assert!(empty.events.is_empty());
+        assert_eq!(empty.cursor, 4);
+    }
+
+    #[tokio::test]
+    async fn sync_report_shows_member_offsets() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (a, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (b, _) = manager.join_room("room", "pwd").await.unwrap();
+        let state = RoomState {
+            title: "a".into(),
+            current_time: 100.0,
+            duration: 600.0,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+        manager.report_position("room", &a, 98.5).await.unwrap();
+        manager.report_position("room", &b, 101.0).await.unwrap();
+        assert!(manager.report_position("room", &b, f64::NAN).await.is_err());
+
+        let report = manager.sync_report("room", "pwd", &host).await.unwrap();
+        assert_eq!(report.position, Some(100.0));
+        assert_eq!(report.members.len(), 2);
+        let (a, b) = (
+            member_id(&manager, "room", &a).await,
+            member_id(&manager, "room", &b).await,
+        );
+        let offset = |id: &str| {
+            report
+                .members
+                .iter()
+                .find(|m| m.id == id)
+                .and_then(|m| m.offset)
+                .unwrap()
+        };
+        assert_eq!(offset(&a), -1.5);
+        assert_eq!(offset(&b), 1.0);
+    }
+
+    #[tokio::test]
+    async fn ws_messages_are_counted_by_type() {
+        let manager = Arc::new(Manager::new(None, true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let ctx = WsContext {
+            room: "room".into(),
+            temp_user: host,
+            client_id: "client".into(),
+            debug: false,
+            acks: false,
+        };
+        let send = |text: &str| Message::Text(text.to_string());
+
+        handle_ws_message(send(r#"{"type":"member_ping"}"#), &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        handle_ws_message(send(r#"{"type":"member_ping"}"#), &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        // 缺少 state 的 host_update 计为一次错误
+        assert!(
+            handle_ws_message(send(r#"{"type":"host_update"}"#), &manager, &hub, &ctx)
+                .await
+                .is_err()
+        );
+        assert!(
+            handle_ws_message(send(r#"{"type":"emote"}"#), &manager, &hub, &ctx)
+                .await
+                .is_err()
+        );
+        assert!(handle_ws_message(send("not json"), &manager, &hub, &ctx)
+            .await
+            .is_err());
+
+        let count = |kind| hub.metrics.get(kind);
+        assert_eq!(
+            count("member_ping"),
+            WsTypeCount {
+                received: 2,
+                errors: 0
+            }
+        );
+        assert_eq!(
+            count("host_update"),
+            WsTypeCount {
+                received: 1,
+                errors: 1
+            }
+        );
+        assert_eq!(
+            count("unknown"),
+            WsTypeCount {
+                received: 2,
+                errors: 2
+            }
+        );
+        let text = hub.metrics.render();
+        assert!(text.contains("vo_sync_ws_messages_total{type=\"member_ping\"} 2"));
+        assert!(text.contains("vo_sync_ws_message_errors_total{type=\"unknown\"} 2"));
+    }
+
+    #[tokio::test]
+    async fn ws_rejects_invalid_numbers_before_dispatch() {
+        let manager = Arc::new(Manager::new(None, true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let ctx = WsContext {
+            room: "room".into(),
+            temp_user: host,
+            client_id: "client".into(),
+            debug: false,
+            acks: false,
+        };
+        let host_update = |current_time: &str, duration: &str| {
+            Message::Text(format!(
+                r#"{{"type":"host_update","state":{{"url":"/media/t","title":"t","currentTime":{current_time},"duration":{duration},"paused":false,"playbackRate":1.0,"sourceType":"file","updatedAt":0}}}}"#
+            ))
+        };
+
+        let err = handle_ws_message(host_update("NaN", "10"), &manager, &hub, &ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(err.message, "invalid message format");
+        let err = handle_ws_message(host_update("1", "-5"), &manager, &hub, &ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains("out-of-range"));
+        let report = Message::Text(r#"{"type":"position_report","currentTime":-1}"#.into());
+        assert!(handle_ws_message(report, &manager, &hub, &ctx)
+            .await
+            .is_err());
+        assert!(manager.latest_state("room").await.is_none());
+
+        handle_ws_message(host_update("1", "10"), &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        assert_eq!(manager.latest_state("room").await.unwrap().duration, 10.0);
+    }
+
+    #[tokio::test]
+    async fn prewarm_reports_local_token_ready() {
+        let root = std::env::temp_dir().join("vo_sync_prewarm");
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("movie.mp4");
+        std::fs::write(&file, b"0123456789").unwrap();
+        let manager = Manager::new(None, true);
+        let token = manager
+            .mint_token("room", MediaTarget::Local(file.clone()))
+            .await;
+
+        let res = manager.prewarm(&token).await.unwrap();
+        assert!(res.ready);
+        assert_eq!(res.source_type, SourceType::File);
+        assert_eq!(res.size, Some(10));
+
+        std::fs::remove_file(&file).unwrap();
+        assert!(!manager.prewarm(&token).await.unwrap().ready);
+        let err = manager.prewarm("missing").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn set_next_switches_without_resolving() {
+        let root = std::env::temp_dir().join("vo_sync_set_next");
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("next.mp4");
+        write_mp4(&file_path);
+
+        let manager = Manager::new(Some(root.clone()), true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        assert!(manager.advance_next("room", &host).await.is_err());
+
+        let preloaded = manager
+            .resolve_media_path("room", "pwd", &host, file_path.to_str().unwrap())
+            .await
+            .unwrap();
+        let next = RoomState {
+            title: "next".into(),
+            current_time: 12.0,
+            duration: 60.0,
+            source_type: preloaded.source_type,
+            ..room_state(&preloaded.url)
+        };
+        assert!(manager
+            .set_next("room", &member, next.clone())
+            .await
+            .is_err());
+        manager.set_next("room", &host, next).await.unwrap();
+
+        let tokens = manager.media_tokens.read().await.len();
+        let state = manager.advance_next("room", &host).await.unwrap();
+        assert_eq!(state.url, preloaded.url);
+        assert_eq!(state.current_time, 0.0);
+        assert!(!state.paused);
+        assert_eq!(manager.media_tokens.read().await.len(), tokens);
+        assert_eq!(
+            manager.latest_state("room").await.unwrap().url,
+            preloaded.url
+        );
+        // 预加载项只能消费一次。
+        assert!(manager.advance_next("room", &host).await.is_err());
+    }
+
+    /// 发起一次原始的 WebSocket 握手，返回连接与响应状态码。
+    async fn ws_handshake(addr: SocketAddr, query: &str) -> (tokio::net::TcpStream, u16) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET /ws?{query} HTTP/1.1\r\nHost: {addr}\r\nConnection: Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = [0u8; 12];
+        stream.read_exact(&mut buf).await.unwrap();
+        let status = std::str::from_utf8(&buf[9..12]).unwrap().parse().unwrap();
+        (stream, status)
+    }
+
+    #[tokio::test]
+    async fn ws_connections_are_limited_per_ip() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new().with_max_ws_per_ip(Some(2))),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = build_router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+
+        let query = format!("room=room&password=pwd&tempUser={host}");
+        let (first, status) = ws_handshake(addr, &query).await;
+        assert_eq!(status, 101);
+        let (_second, status) = ws_handshake(addr, &query).await;
+        assert_eq!(status, 101);
+        let (_, status) = ws_handshake(addr, &query).await;
+        assert_eq!(status, 429);
+
+        // 断开一个连接后名额归还。
+        drop(first);
+        let mut status = 0;
+        for _ in 0..50 {
+            let (_stream, s) = ws_handshake(addr, &query).await;
+            status = s;
+            if status == 101 {
+                break;
+            }
+            tokio_time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(status, 101);
+    }
+
+    #[tokio::test]
+    async fn rate_presets_reject_other_rates() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let state = |rate: f64| RoomState {
+            title: "a".into(),
+            duration: 10.0,
+            paused: false,
+            playback_rate: rate,
+            ..room_state("/media/a")
+        };
+        assert!(manager
+            .set_allowed_rates("room", "pwd", &member, Some(vec![1.0]))
+            .await
+            .is_err());
+        assert!(manager
+            .set_allowed_rates("room", "pwd", &host, Some(vec![]))
+            .await
+            .is_err());
+        manager
+            .set_allowed_rates("room", "pwd", &host, Some(vec![1.0, 1.25, 1.5]))
+            .await
+            .unwrap();
+
+        manager
+            .update_state("room", &host, state(1.25), true)
+            .await
+            .unwrap();
+        let err = manager
+            .update_state("room", &host, state(2.0), true)
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("rate not allowed"));
+        assert!(manager
+            .update_state("room", &member, state(1.75), false)
+            .await
+            .is_err());
+        assert_eq!(
+            connect_state_message(&manager, "room").await.allowed_rates,
+            Some(vec![1.0, 1.25, 1.5])
+        );
+
+        manager
+            .set_allowed_rates("room", "pwd", &host, None)
+            .await
+            .unwrap();
+        manager
+            .update_state("room", &member, state(1.75), false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_upstream_triggers_token_refresh() {
+        let base = Arc::new(std::sync::OnceLock::<String>::new());
+        let playurl_hits = Arc::new(AtomicUsize::new(0));
+        let expired = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (url_base, hits) = (base.clone(), playurl_hits.clone());
+        let gate = expired.clone();
+        let router = mock_bili_router_with(
+            move |_| {
+                let n = hits.fetch_add(1, Ordering::SeqCst) + 1;
+                let url = format!("{}/cdn/v{n}.mp4", url_base.get().unwrap());
+                json!({ "code": 0, "message": "0", "data": { "durl": [{ "url": url }] } })
+            },
+            Arc::default(),
+        )
+        .route(
+            "/cdn/:name",
+            get(move |AxumPath(name): AxumPath<String>| {
+                let dead = name == "v1.mp4" && gate.load(Ordering::SeqCst);
+                async move {
+                    if dead {
+                        StatusCode::FORBIDDEN
+                    } else {
+                        StatusCode::PARTIAL_CONTENT
+                    }
+                }
+            }),
+        );
+        base.set(spawn_mock(router).await).unwrap();
+
+        let manager = Manager::new(None, true).with_bili_api_base(base.get().unwrap().clone());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let res = manager
+            .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+            .await
+            .unwrap();
+        let state = RoomState {
+            title: "bili".into(),
+            duration: 240.0,
+            paused: false,
+            source_type: res.source_type,
+            ..room_state(&res.url)
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+
+        assert_eq!(manager.validate_remote_tokens().await, 0);
+        let status = manager.token_validation(&res.token).await.unwrap().unwrap();
+        assert!(status.alive);
+        assert_eq!(status.upstream_status, Some(206));
+
+        expired.store(true, Ordering::SeqCst);
+        assert_eq!(manager.validate_remote_tokens().await, 1);
+        let status = manager.token_validation(&res.token).await.unwrap().unwrap();
+        assert!(!status.alive && status.refreshed);
+        assert_eq!(status.upstream_status, Some(403));
+        let target = manager.open_remote(&res.token).await.unwrap();
+        assert!(target.url.ends_with("/cdn/v2.mp4"));
+        assert_eq!(playurl_hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn repeated_range_requests_hit_segment_cache() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let upstream = spawn_mock(Router::new().route(
+            "/video.mp4",
+            get(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async {
+                    (
+                        StatusCode::PARTIAL_CONTENT,
+                        [
+                            (axum::http::header::CONTENT_RANGE, "bytes 0-3/100"),
+                            (axum::http::header::CONTENT_TYPE, "video/mp4"),
+                        ],
+                        "abcd",
+                    )
+                }
+            }),
+        ))
+        .await;
+        let manager = Manager::new(None, true).with_segment_cache(Some(1024 * 1024));
+        let token = manager
+            .mint_token(
+                "room",
+                MediaTarget::Remote(RemoteTarget {
+                    url: format!("{upstream}/video.mp4"),
+                    strategy: RemoteStrategy::ProxyWithHeaders,
+                }),
+            )
+            .await;
+        let state = AppState {
+            manager: Arc::new(manager),
+            hub: Arc::new(Hub::new()),
+        };
+        let base = spawn_mock(build_router(state)).await;
+        let client = reqwest::Client::new();
+
+        for _ in 0..2 {
+            let resp = client
+                .get(format!("{base}/media/{token}"))
+                .header("range", "bytes=0-3")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status().as_u16(), 206);
+            assert_eq!(resp.headers()["content-range"], "bytes 0-3/100");
+            assert_eq!(resp.text().await.unwrap(), "abcd");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // 开放式 Range 不走缓存
+        client
+            .get(format!("{base}/media/{token}"))
+            .header("range", "bytes=0-")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn malformed_json_body_uses_error_envelope() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let base = spawn_mock(build_router(state)).await;
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .post(format!("{base}/api/room/join"))
+            .json(&json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 422);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let error = body["error"].as_str().unwrap();
+        assert!(error.contains("missing field `room`"), "{error}");
+
+        let resp = client
+            .post(format!("{base}/api/media/resolve"))
+            .header("content-type", "application/json")
+            .body("{not json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 400);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn idle_rooms_are_paused_at_current_position() {
+        let manager = Manager::new(None, true).with_idle_pause(Some(Duration::from_millis(20)));
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let state = RoomState {
+            title: "a".into(),
+            current_time: 10.0,
+            duration: 600.0,
+            paused: false,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+        assert!(manager.pause_idle_rooms().await.is_empty());
+
+        tokio_time::sleep(Duration::from_millis(50)).await;
+        let paused = manager.pause_idle_rooms().await;
+        assert_eq!(paused.len(), 1);
+        let (room, state) = &paused[0];
+        assert_eq!(room, "room");
+        assert!(state.paused);
+        assert!(state.current_time > 10.0 && state.current_time < 11.0);
+        assert!(manager.latest_state("room").await.unwrap().paused);
+        // 已暂停的房间不会重复处理。
+        assert!(manager.pause_idle_rooms().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn debug_messages_only_reach_opted_in_clients() {
+        let drain = |rx: &mut mpsc::UnboundedReceiver<Message>| {
+            let mut kinds = Vec::new();
+            while let Ok(Message::Text(text)) = rx.try_recv() {
+                let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+                kinds.push(value["type"].as_str().unwrap().to_string());
+            }
+            kinds
+        };
+        let hub = Hub::new();
+        let (plain_tx, mut plain_rx) = mpsc::unbounded_channel();
+        let (debug_tx, mut debug_rx) = mpsc::unbounded_channel();
+        hub.register("room", "c1", "a", ClientSender::Ws(plain_tx))
+            .await;
+        hub.register("room", "c2", "b", ClientSender::Ws(debug_tx))
+            .await;
+        hub.enable_debug("room", "c2").await;
+
+        hub.broadcast("room", &WsOutgoing::kind("waiting_for_host"))
+            .await;
+        hub.unregister("room", "c1").await;
+        assert_eq!(drain(&mut plain_rx), ["waiting_for_host"]);
+        assert_eq!(drain(&mut debug_rx), ["waiting_for_host", "debug", "debug"]);
+
+        // 全局开启时所有连接都会收到
+        let hub = Hub::new().with_ws_debug(true);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "c1", "a", ClientSender::Ws(tx)).await;
+        hub.debug("room", json!({ "event": "test" })).await;
+        assert_eq!(drain(&mut rx), ["debug", "debug"]);
+    }
+
+    #[tokio::test]
+    async fn debug_state_event_names_sender_by_public_id() {
+        let manager = Arc::new(Manager::new(None, true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let host_id = member_id(&manager, "room", &host).await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "c1", &member, ClientSender::Ws(tx))
+            .await;
+        hub.enable_debug("room", "c1").await;
+        while rx.try_recv().is_ok() {}
+
+        let ctx = WsContext {
+            room: "room".into(),
+            temp_user: host.clone(),
+            client_id: "c0".into(),
+            debug: false,
+            acks: false,
+        };
+        let update = json!({
+            "type": "host_update",
+            "state": RoomState {
+                title: "a".into(),
+                duration: 60.0,
+                ..room_state("/media/a")
+            },
+        });
+        handle_ws_message(Message::Text(update.to_string()), &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        let mut seen = false;
+        while let Ok(Message::Text(text)) = rx.try_recv() {
+            assert!(!text.contains(&host), "{text}");
+            let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if value["type"] == "debug" && value["debug"]["event"] == "state" {
+                assert_eq!(value["debug"]["from"], host_id.as_str());
+                seen = true;
+            }
+        }
+        assert!(seen);
+    }
+
+    #[tokio::test]
+    async fn host_keeps_primary_when_reconnecting_within_grace() {
+        let state = AppState {
+            manager: Arc::new(
+                Manager::new(None, true).with_host_grace(Some(Duration::from_millis(80))),
+            ),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (member_tx, mut member_rx) = mpsc::unbounded_channel();
+        state
+            .hub
+            .register("room", "c1", &member, ClientSender::Ws(member_tx))
+            .await;
+        let ctx = WsContext {
+            room: "room".into(),
+            temp_user: host.clone(),
+            client_id: "client".into(),
+            debug: false,
+            acks: false,
+        };
+
+        // 断线后很快重连
+        schedule_host_reassign(state.clone(), ctx.clone()).await;
+        tokio_time::sleep(Duration::from_millis(20)).await;
+        let (host_tx, _host_rx) = mpsc::unbounded_channel();
+        state
+            .hub
+            .register("room", "c2", &host, ClientSender::Ws(host_tx))
+            .await;
+        tokio_time::sleep(Duration::from_millis(120)).await;
+        assert!(state.manager.is_primary_host("room", &host).await);
+
+        // 再次断线且未重连
+        state.hub.unregister("room", "c2").await;
+        schedule_host_reassign(state.clone(), ctx).await;
+        tokio_time::sleep(Duration::from_millis(20)).await;
+        assert!(state.manager.is_primary_host("room", &host).await);
+        tokio_time::sleep(Duration::from_millis(120)).await;
+        assert!(state.manager.is_primary_host("room", &member).await);
+        assert!(!state.manager.is_host("room", &host).await);
+        state.manager.authorize("room", "pwd", &host).await.unwrap();
+
+        // host_changed 广播给全体成员，只带公开标识
+        let mut changed = None;
+        while let Ok(Message::Text(text)) = member_rx.try_recv() {
+            let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if msg["type"] == "host_changed" {
+                assert!(!text.contains(&host) && !text.contains(&member));
+                changed = Some(msg);
+            }
+        }
+        let changed = changed.expect("host_changed broadcast");
+        let member_id = member_id(&state.manager, "room", &member).await;
+        assert_eq!(changed["hosts"]["primaryHost"], member_id.as_str());
+        assert_eq!(changed["hosts"]["hosts"], json!([member_id]));
+    }
+
+    #[tokio::test]
+    async fn password_change_rejects_old_password_and_drops_members() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "old").await.unwrap();
+        let (member, _) = state.manager.join_room("room", "old").await.unwrap();
+        let (member_tx, mut member_rx) = mpsc::unbounded_channel();
+        let (host_tx, mut host_rx) = mpsc::unbounded_channel();
+        state
+            .hub
+            .register("room", "c1", &member, ClientSender::Ws(member_tx))
+            .await;
+        state
+            .hub
+            .register("room", "c2", &host, ClientSender::Ws(host_tx))
+            .await;
+
+        let req: PasswordRequest = serde_json::from_value(json!({
+            "room": "room",
+            "password": "old",
+            "tempUser": member,
+            "newPassword": "new",
+        }))
+        .unwrap();
+        assert!(change_password(State(state.clone()), ApiJson(req))
+            .await
+            .is_err());
+
+        let req: PasswordRequest = serde_json::from_value(json!({
+            "room": "room",
+            "password": "old",
+            "tempUser": host,
+            "newPassword": "new",
+        }))
+        .unwrap();
+        change_password(State(state.clone()), ApiJson(req))
+            .await
+            .unwrap();
+
+        match member_rx.recv().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, CLOSE_PASSWORD_CHANGED),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+        assert!(host_rx.try_recv().is_err());
+        assert!(state
+            .manager
+            .authorize("room", "old", &member)
+            .await
+            .is_err());
+        assert!(!state
+            .manager
+            .authorize("room", "new", &member)
+            .await
+            .unwrap());
+        assert!(state.manager.authorize("room", "new", &host).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn coordinated_play_schedules_future_start() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let state = RoomState {
+            title: "a".into(),
+            current_time: 42.0,
+            duration: 600.0,
+            ..room_state("/media/a")
+        };
+        assert!(manager
+            .coordinated_play("room", &member, state.clone(), None)
+            .await
+            .is_err());
+
+        let before = now_millis();
+        let (played, start_at) = manager
+            .coordinated_play("room", &host, state, Some(2_000))
+            .await
+            .unwrap();
+        assert!(start_at >= before + 2_000);
+        assert!(!played.paused);
+        // 起播前外推进度保持在起点。
+        assert_eq!(played.position_at(now_millis()), 42.0);
+
+        let msg = serde_json::to_value(coordinated_play_message(played, start_at)).unwrap();
+        assert_eq!(msg["type"], "coordinated_play");
+        assert!(msg["startAt"].as_i64().unwrap() > msg["serverTime"].as_i64().unwrap());
+    }
+
+    #[tokio::test]
+    async fn admin_cache_lists_and_clears_wbi_key() {
+        let bili = spawn_mock(mock_bili_router(
+            json!({
+                "code": 0,
+                "message": "0",
+                "data": { "durl": [{ "url": "https://cdn.bilivideo.com/v.mp4" }] }
+            }),
+            Arc::default(),
+        ))
+        .await;
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true).with_bili_api_base(bili)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        for _ in 0..2 {
+            state
+                .manager
+                .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+                .await
+                .unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = build_router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+        let client = reqwest::Client::new();
+        let list = || async {
+            client
+                .get(format!("http://{addr}/api/admin/cache"))
+                .send()
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap()
+        };
+
+        let body = list().await;
+        let entries = body["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["hits"], 1);
+        assert!(entries[0]["value"].as_str().unwrap().ends_with("***"));
+
+        let cleared: serde_json::Value = client
+            .post(format!("http://{addr}/api/admin/cache/clear"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(cleared["cleared"], 1);
+        assert!(list().await["entries"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_id_header_appears_in_members() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let base = spawn_mock(build_router(state)).await;
+        let client = reqwest::Client::new();
+        let join = |client_id: Option<&'static str>| {
+            let mut req = client
+                .post(format!("{base}/api/room/join"))
+                .json(&json!({ "room": "room", "password": "pwd" }));
+            if let Some(id) = client_id {
+                req = req.header("X-Client-Id", id);
+            }
+            async move {
+                let body: serde_json::Value = req.send().await.unwrap().json().await.unwrap();
+                let field = |key: &str| body[key].as_str().unwrap().to_string();
+                (field("tempUser"), field("memberId"))
+            }
+        };
+        let host = join(Some("alice@desktop")).await;
+        let member = join(None).await;
+
+        let body: serde_json::Value = client
+            .get(format!("{base}/api/room/room/members"))
+            .query(&[("password", "pwd"), ("tempUser", member.0.as_str())])
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let members = body["members"].as_array().unwrap();
+        assert_eq!(members.len(), 2);
+        // 列表只给公开标识，成员拿不到别人的 temp_user 去冒充房主。
+        assert!(!body.to_string().contains(&host.0));
+        let find = |user: &(String, String)| members.iter().find(|m| m["id"] == user.1).unwrap();
+        assert_eq!(find(&host)["clientId"], "alice@desktop");
+        assert_eq!(find(&host)["isHost"], true);
+        assert!(find(&member)["clientId"].is_null());
+    }
+
+    #[tokio::test]
+    async fn resolve_without_auto_publish_keeps_state() {
+        let root = std::env::temp_dir().join("vo_sync_auto_publish");
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("preview.mp4");
+        write_mp4(&file_path);
+
+        let state = AppState {
+            manager: Arc::new(Manager::new(Some(root.clone()), true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let body = json!({
+            "room": "room",
+            "password": "pwd",
+            "tempUser": host,
+            "path": file_path.to_str().unwrap(),
+            "autoPublish": false,
+        });
+        let req: MediaResolveRequest = serde_json::from_value(body.clone()).unwrap();
+        media_resolve(State(state.clone()), HeaderMap::new(), ApiJson(req))
+            .await
+            .unwrap();
+        assert!(state.manager.current_state("room").await.is_none());
+
+        // 缺省时保持旧行为：自动发布初始状态
+        let mut body = body;
+        body.as_object_mut().unwrap().remove("autoPublish");
+        let req: MediaResolveRequest = serde_json::from_value(body).unwrap();
+        assert!(req.auto_publish);
+        media_resolve(State(state.clone()), HeaderMap::new(), ApiJson(req))
+            .await
+            .unwrap();
+        assert!(state.manager.current_state("room").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn connect_message_carries_fresh_server_time() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        assert!(connect_state_message(&manager, "room")
+            .await
+            .state
+            .is_none());
+
+        for t in [10.0, 20.0, 30.0] {
+            let state = RoomState {
+                title: "a".into(),
+                current_time: t,
+                duration: 120.0,
+                paused: false,
+                source_type: SourceType::Remote,
+                ..room_state("https://1.1.1.1/a.mp4")
+            };
+            manager
+                .update_state("room", &host, state, true)
+                .await
+                .unwrap();
+        }
+
+        let before = now_millis();
+        let msg = connect_state_message(&manager, "room").await;
+        assert_eq!(msg.r#type, "room_state");
+        let server_time = msg.server_time.expect("server time included");
+        assert!(server_time >= before);
+        let state = msg.state.unwrap();
+        assert_eq!(state.current_time, 30.0);
+        assert!(state.updated_at <= server_time);
+
+        let payload = serde_json::to_value(connect_state_message(&manager, "room").await).unwrap();
+        assert!(payload["serverTime"].is_i64());
+    }
+
+    #[tokio::test]
+    async fn token_formats_are_unique_and_well_formed() {
+        let uuid = Manager::new(None, true);
+        let token = uuid
+            .mint_token("room", MediaTarget::Local(PathBuf::from("/tmp/a")))
+            .await;
+        assert!(Uuid::parse_str(&token).is_ok());
+
+        let short = Manager::new(None, true).with_token_format(TokenFormat::Short);
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..500 {
+            let token = short
+                .mint_token("room", MediaTarget::Local(PathBuf::from("/tmp/a")))
+                .await;
+            assert_eq!(token.len(), SHORT_TOKEN_LEN);
+            assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+            assert!(seen.insert(token));
+        }
+        assert_eq!(short.media_tokens.read().await.len(), 500);
+
+        let prefixed = Manager::new(None, true).with_token_format(TokenFormat::RoomPrefixed);
+        let a = prefixed
+            .mint_token("movie-night", MediaTarget::Local(PathBuf::from("/tmp/a")))
+            .await;
+        let b = prefixed
+            .mint_token("movie-night", MediaTarget::Local(PathBuf::from("/tmp/b")))
+            .await;
+        let (prefix_a, rest) = a.split_once('-').unwrap();
+        assert_eq!(prefix_a.len(), 6);
+        assert_eq!(rest.len(), SHORT_TOKEN_LEN);
+        assert_eq!(prefix_a, b.split_once('-').unwrap().0);
+        assert_ne!(a, b);
+
+        assert_eq!(TokenFormat::parse("SHORT"), Some(TokenFormat::Short));
+        assert_eq!(TokenFormat::parse("bogus"), None);
+    }
+
+    #[tokio::test]
+    async fn stateless_room_greets_with_waiting_for_host() {
+        let manager = Manager::new(None, true);
+        manager.join_room("room", "pwd").await.unwrap();
+
+        let payload = serde_json::to_value(connect_state_message(&manager, "room").await).unwrap();
+        assert_eq!(payload["type"], "waiting_for_host");
+        assert!(payload.get("state").is_none());
+        assert!(payload.get("message").is_none());
+        assert!(payload["serverTime"].is_i64());
+    }
+
+    #[tokio::test]
+    async fn resolve_batch_returns_mixed_results_in_order() {
+        let root = std::env::temp_dir().join("vo_sync_batch");
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("ep1.mp4");
+        write_mp4(&file_path);
+
+        let state = AppState {
+            manager: Arc::new(Manager::new(Some(root.clone()), true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let req: BatchResolveRequest = serde_json::from_value(json!({
+            "room": "room",
+            "password": "pwd",
+            "tempUser": host,
+            "paths": [file_path.to_str().unwrap(), "/etc/passwd"],
+        }))
+        .unwrap();
+        let resp = media_resolve_batch(State(state.clone()), HeaderMap::new(), ApiJson(req))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let results = body["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["sourceType"], "file");
+        assert!(results[0]["url"].as_str().unwrap().starts_with("/media/"));
+        assert_eq!(results[1]["path"], "/etc/passwd");
+        assert!(results[1]["error"].is_string());
+
+        // 批量接口不发布任何状态
+        assert!(state.manager.current_state("room").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn kicked_client_receives_close_code() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (member_tx, mut member_rx) = mpsc::unbounded_channel();
+        let (host_tx, mut host_rx) = mpsc::unbounded_channel();
+        state
+            .hub
+            .register("room", "c1", &member, ClientSender::Ws(member_tx))
+            .await;
+        state
+            .hub
+            .register("room", "c2", &host, ClientSender::Ws(host_tx))
+            .await;
+
+        let req: KickRequest = serde_json::from_value(json!({
+            "room": "room",
+            "password": "pwd",
+            "tempUser": host,
+            "target": member_id(&state.manager, "room", &member).await,
+        }))
+        .unwrap();
+        kick_member(State(state.clone()), ApiJson(req))
+            .await
+            .unwrap();
+
+        match member_rx.recv().await {
+            Some(Message::Close(Some(frame))) => {
+                assert_eq!(frame.code, CLOSE_KICKED);
+                assert_eq!(frame.reason, "kicked");
+            }
+            other => panic!("expected close frame, got {other:?}"),
+        }
+        assert!(host_rx.try_recv().is_err());
+        assert!(state
+            .manager
+            .authorize("room", "pwd", &member)
+            .await
+            .is_err());
+
+        let reason = shutdown_reason();
+        assert!(reason.starts_with("server shutting down, retry in "));
+    }
+
+    #[tokio::test]
+    async fn transcode_falls_back_to_file_without_usable_ffmpeg() {
+        let root = std::env::temp_dir().join("vo_sync_transcode");
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("movie.mkv");
+        write_mp4(&file_path);
+        let options = ResolveOptions {
+            transcode: true,
+            ..Default::default()
+        };
+
+        for ffmpeg in [None, Some(root.join("missing-ffmpeg"))] {
+            let manager = Manager::new(Some(root.clone()), true).with_ffmpeg(ffmpeg);
+            let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+            let res = manager
+                .resolve_media_with("room", "pwd", &host, file_path.to_str().unwrap(), &options)
+                .await
+                .unwrap();
+            assert_eq!(res.source_type, SourceType::File);
+            assert!(matches!(
+                manager.open_media(&res.token).await.unwrap(),
+                MediaTarget::Local(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn transcode_slots_are_capped_per_token() {
+        let manager = Manager::new(None, true);
+        let slots: Vec<_> = (0..MAX_TRANSCODES_PER_TOKEN)
+            .map(|_| manager.acquire_transcode("a").unwrap())
+            .collect();
+        assert!(manager.acquire_transcode("a").is_none());
+        assert!(manager.acquire_transcode("b").is_some());
+        drop(slots);
+        assert!(manager.acquire_transcode("a").is_some());
+        assert!(manager.transcodes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn transcode_accepts_only_ranges_from_start() {
+        let mut headers = HeaderMap::new();
+        assert!(transcode_range_allowed(&headers));
+        headers.insert(
+            axum::http::header::RANGE,
+            HeaderValue::from_static("bytes=0-"),
+        );
+        assert!(transcode_range_allowed(&headers));
+        headers.insert(
+            axum::http::header::RANGE,
+            HeaderValue::from_static("bytes=100-"),
+        );
+        assert!(!transcode_range_allowed(&headers));
+        headers.insert(
+            axum::http::header::RANGE,
+            HeaderValue::from_static("bytes=0-99"),
+        );
+        assert!(!transcode_range_allowed(&headers));
+    }
+
+    #[tokio::test]
+    async fn seek_lock_ignores_competing_seek_within_window() {
+        let manager = Manager::new(None, true).with_seek_lock(Duration::from_secs(2));
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (alice, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (bob, _) = manager.join_room("room", "pwd").await.unwrap();
+        let alice_id = member_id(&manager, "room", &alice).await;
+        let state = |current_time: f64, paused: bool, playback_rate: f64| RoomState {
+            title: "Movie".into(),
+            current_time,
+            duration: 600.0,
+            paused,
+            playback_rate,
+            ..room_state("file:///movie.mp4")
+        };
+        manager
+            .update_state("room", &host, state(0.0, true, 1.0), true)
+            .await
+            .unwrap();
+
+        let first = manager
+            .update_state("room", &alice, state(100.0, true, 1.0), false)
+            .await
+            .unwrap();
+        assert_eq!(first.current_time, 100.0);
+        assert_eq!(
+            manager.seek_owner("room").await.as_deref(),
+            Some(alice_id.as_str())
+        );
+
+        // 窗口内 bob 的跳转被忽略，但暂停/倍速照常生效
+        let second = manager
+            .update_state("room", &bob, state(300.0, false, 1.5), false)
+            .await
+            .unwrap();
+        assert_eq!(second.current_time, 100.0);
+        assert!(!second.paused);
+        assert_eq!(second.playback_rate, 1.5);
+        assert_eq!(
+            manager.seek_owner("room").await.as_deref(),
+            Some(alice_id.as_str())
+        );
+
+        // 持有者自己可以继续拖动
+        let third = manager
+            .update_state("room", &alice, state(200.0, true, 1.0), false)
+            .await
+            .unwrap();
+        assert_eq!(third.current_time, 200.0);
+    }
+
+    #[tokio::test]
+    async fn co_hosts_can_be_promoted_and_demoted() {
+        let manager = Manager::new(None, false);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (co, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (host_id, co_id) = (
+            member_id(&manager, "room", &host).await,
+            member_id(&manager, "room", &co).await,
+        );
+
+        assert!(manager
+            .set_co_host("room", "pwd", &member, &co_id, true)
+            .await
+            .is_err());
+        let hosts = manager
+            .set_co_host("room", "pwd", &host, &co_id, true)
+            .await
+            .unwrap();
+        assert_eq!(hosts.primary_host.as_deref(), Some(host_id.as_str()));
+        assert_eq!(hosts.hosts.len(), 2);
+        assert!(manager.authorize("room", "pwd", &co).await.unwrap());
+        assert!(!manager.authorize("room", "pwd", &member).await.unwrap());
+
+        // 协同房主可以发布状态，普通成员不行（未开启成员控制）
+        let state = RoomState {
+            title: "Movie".into(),
+            duration: 120.0,
+            ..room_state("file:///movie.mp4")
+        };
+        let is_host = manager.is_host("room", &co).await;
+        manager
+            .update_state("room", &co, state.clone(), is_host)
+            .await
+            .unwrap();
+        let is_host = manager.is_host("room", &member).await;
+        assert!(manager
+            .update_state("room", &member, state, is_host)
+            .await
+            .is_err());
+
+        // 只有主房主能撤销，且主房主本身不能被撤销
+        assert!(manager
+            .set_co_host("room", "pwd", &co, &host_id, false)
+            .await
+            .is_err());
+        assert!(manager
+            .set_co_host("room", "pwd", &host, &host_id, false)
+            .await
+            .is_err());
+        let hosts = manager
+            .set_co_host("room", "pwd", &host, &co_id, false)
+            .await
+            .unwrap();
+        assert_eq!(hosts.hosts, vec![host_id.clone()]);
+        assert!(!manager.is_host("room", &co).await);
+    }
+
+    async fn next_sse_event(body: &mut axum::body::BodyDataStream) -> serde_json::Value {
+        let chunk = tokio_time::timeout(Duration::from_secs(2), body.next())
+            .await
+            .expect("sse event in time")
+            .unwrap()
+            .unwrap();
+        let text = String::from_utf8(chunk.to_vec()).unwrap();
+        let data = text.trim().strip_prefix("data: ").unwrap();
+        serde_json::from_str(data).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sse_subscriber_receives_state_broadcast() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let query = AuthQuery {
+            password: "pwd".into(),
+            temp_user: member,
+        };
+        let resp = room_events_stream(State(state.clone()), AxumPath("room".into()), Query(query))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(
+            resp.headers()[axum::http::header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        let mut body = resp.into_body().into_data_stream();
+        assert_eq!(next_sse_event(&mut body).await["type"], "waiting_for_host");
+        assert_eq!(next_sse_event(&mut body).await["type"], "room_settings");
+
+        let room_state = RoomState {
+            title: "Movie".into(),
+            current_time: 12.0,
+            duration: 120.0,
+            paused: false,
+            ..room_state("file:///movie.mp4")
+        };
+        let updated = state
+            .manager
+            .update_state("room", &host, room_state, true)
+            .await
+            .unwrap();
+        state.hub.broadcast_state("room", &updated).await;
+
+        let event = next_sse_event(&mut body).await;
+        assert_eq!(event["type"], "room_state");
+        assert_eq!(event["state"]["currentTime"], 12.0);
+    }
+
+    #[tokio::test]
+    async fn resolve_rejects_non_media_files_unless_allowed() {
+        let root = std::env::temp_dir().join("vo_sync_sniff");
+        std::fs::create_dir_all(&root).unwrap();
+        let video = root.join("movie.mp4");
+        write_mp4(&video);
+        let text = root.join("notes.mp4");
+        let mut file = StdFile::create(&text).unwrap();
+        writeln!(file, "just some text").unwrap();
+
+        let manager = Manager::new(Some(root.clone()), true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let res = manager
+            .resolve_media_path("room", "pwd", &host, video.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::File);
+
+        let err = manager
+            .resolve_media_path("room", "pwd", &host, text.to_str().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains("not a recognized"));
+
+        let options = ResolveOptions {
+            allow_any: true,
+            ..Default::default()
+        };
+        manager
+            .resolve_media_with("room", "pwd", &host, text.to_str().unwrap(), &options)
+            .await
+            .expect("allowAny skips sniffing");
+    }
+
+    #[tokio::test]
+    async fn resolve_enforces_extension_allowlist() {
+        let root = std::env::temp_dir().join("vo_sync_allowed_exts");
+        std::fs::create_dir_all(&root).unwrap();
+        let video = root.join("clip.MP4");
+        write_mp4(&video);
+        // 内容是合法的 mp4 头，仅扩展名不在白名单内。
+        let text = root.join("readme.txt");
+        write_mp4(&text);
+
+        let manager = Manager::new(Some(root.clone()), true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        manager
+            .resolve_media_path("room", "pwd", &host, video.to_str().unwrap())
+            .await
+            .unwrap();
+        let err = manager
+            .resolve_media_path("room", "pwd", &host, text.to_str().unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("extension not allowed"));
+
+        let custom = Manager::new(Some(root.clone()), true)
+            .with_allowed_exts(Some(parse_exts(" .TXT, mp4")));
+        let (host, _) = custom.join_room("room", "pwd").await.unwrap();
+        custom
+            .resolve_media_path("room", "pwd", &host, text.to_str().unwrap())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cleanup_task_exits_promptly_on_shutdown() {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let baseline = metrics.num_alive_tasks();
+
+        let manager = Arc::new(Manager::new(None, true));
+        manager.spawn_cleanup(Arc::new(Hub::new()));
+        tokio::task::yield_now().await;
+        assert_eq!(metrics.num_alive_tasks(), baseline + 1);
+
+        manager.shutdown();
+        tokio_time::timeout(Duration::from_secs(1), async {
+            while metrics.num_alive_tasks() > baseline {
+                tokio_time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("cleanup task should stop well before the next tick");
+
+        // 直接 drop Manager 也会让任务退出
+        let manager = Arc::new(Manager::new(None, true));
+        manager.spawn_cleanup(Arc::new(Hub::new()));
+        tokio::task::yield_now().await;
+        drop(manager);
+        tokio_time::timeout(Duration::from_secs(1), async {
+            while metrics.num_alive_tasks() > baseline {
+                tokio_time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("dropping the manager should stop the cleanup task");
+    }
+
+    #[tokio::test]
+    async fn join_rejects_new_rooms_beyond_cap() {
+        let manager = Manager::new(None, true).with_max_rooms(Some(3));
+        for i in 0..3 {
+            manager
+                .join_room(&format!("room-{i}"), "pwd")
+                .await
+                .unwrap();
+        }
+        let err = manager.join_room("room-3", "pwd").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // 已有房间仍可加入
+        let (_, is_host) = manager.join_room("room-0", "pwd").await.unwrap();
+        assert!(!is_host);
+
+        // 过期房间不计入上限
+        let mut manager = manager;
+        manager.room_ttl = Duration::ZERO;
+        tokio_time::sleep(Duration::from_millis(5)).await;
+        manager.join_room("room-3", "pwd").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn host_reclaims_primary_with_resume_token() {
+        let manager = Manager::new(None, true);
+        let created = manager
+            .join_room_as("room", "pwd", JoinOptions::default())
+            .await
+            .unwrap();
+        let token = created.host_resume_token.clone().expect("resume token");
+        let host_id = created.member_id.clone();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let member_join = manager
+            .join_room_as("room", "pwd", JoinOptions::default())
+            .await
+            .unwrap();
+        assert!(member_join.host_resume_token.is_none());
+
+        // 房主断线期间主房主被临时交给成员
+        {
+            let mut rooms = manager.rooms.write().await;
+            let room = rooms.get_mut("room").unwrap();
+            room.host_ids.remove(&created.temp_user);
+            room.set_primary_host(&member);
+        }
+        assert!(!manager.is_host("room", &created.temp_user).await);
+
+        let wrong = manager
+            .join_room_as(
+                "room",
+                "pwd",
+                JoinOptions {
+                    resume_token: Some("not-the-token"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(!wrong.is_host);
+        assert!(wrong.hosts.is_none());
+
+        let back = manager
+            .join_room_as(
+                "room",
+                "pwd",
+                JoinOptions {
+                    resume_token: Some(&token),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(back.is_host);
+        assert_eq!(back.host_resume_token.as_deref(), Some(token.as_str()));
+        // 广播给全体成员的 host_changed 只含公开标识，重连后沿用原来的标识。
+        let hosts = back.hosts.expect("host_changed payload");
+        assert_eq!(back.member_id, host_id);
+        assert_eq!(hosts.primary_host.as_deref(), Some(host_id.as_str()));
+        assert_eq!(hosts.hosts, vec![host_id.clone()]);
+        assert!(!manager.is_host("room", &member).await);
+        // 断线前的旧身份被移除
+        let err = manager
+            .authorize("room", "pwd", &created.temp_user)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn host_resume_can_be_disabled() {
+        let manager = Manager::new(None, true).with_host_resume(false);
+        let created = manager
+            .join_room_as("room", "pwd", JoinOptions::default())
+            .await
+            .unwrap();
+        assert!(created.is_host);
+        assert!(created.host_resume_token.is_none());
+        let again = manager
+            .join_room_as(
+                "room",
+                "pwd",
+                JoinOptions {
+                    resume_token: Some(""),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(!again.is_host);
+    }
+
+    #[tokio::test]
+    async fn whoami_reflects_host_transfer() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, false)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let ask = |user: &str| {
+            let query = AuthQuery {
+                password: "pwd".into(),
+                temp_user: user.to_string(),
+            };
+            whoami(State(state.clone()), AxumPath("room".into()), Query(query))
+        };
+        let body = |resp: Response| async move {
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+
+        let me = body(ask(&member).await.unwrap().into_response()).await;
+        assert_eq!(me["role"], "spectator");
+        assert_eq!(me["canControl"], false);
+
+        let target = member_id(&state.manager, "room", &member).await;
+        state
+            .manager
+            .transfer_host("room", "pwd", &host, &target)
+            .await
+            .unwrap();
+
+        let me = body(ask(&member).await.unwrap().into_response()).await;
+        assert_eq!(me["tempUser"], member.as_str());
+        assert_eq!(me["role"], "host");
+        assert_eq!(me["primaryHost"], true);
+        assert_eq!(me["canControl"], true);
+        let old = body(ask(&host).await.unwrap().into_response()).await;
+        assert_eq!(old["role"], "spectator");
+
+        let query = AuthQuery {
+            password: "wrong".into(),
+            temp_user: member,
+        };
+        assert!(
+            whoami(State(state.clone()), AxumPath("room".into()), Query(query))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_export_round_trips_with_and_without_key() {
+        let key = || Some(seal::SnapshotKey::from_passphrase("s3cret"));
+        for encrypted in [false, true] {
+            let manager =
+                Manager::new(None, true).with_snapshot_key(if encrypted { key() } else { None });
+            let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+            manager
+                .set_playlist(
+                    "room",
+                    "pwd",
+                    &host,
+                    vec![PlaylistItem {
+                        path: "/secret/movie.mp4".into(),
+                        title: None,
+                    }],
+                )
+                .await
+                .unwrap();
+            let snapshot = manager.snapshot_room("room", "pwd", &host).await.unwrap();
+            let exported =
+                serde_json::to_string(&manager.seal_snapshot(snapshot).unwrap()).unwrap();
+            assert_eq!(exported.contains("/secret/movie.mp4"), !encrypted);
+
+            let payload: SnapshotPayload = serde_json::from_str(&exported).unwrap();
+            let opened = manager.open_snapshot(payload).unwrap();
+            assert_eq!(opened.playlist[0].path, "/secret/movie.mp4");
+            manager.restore_room("copy", "pwd", opened).await.unwrap();
+        }
+
+        let manager = Manager::new(None, true).with_snapshot_key(key());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let snapshot = manager.snapshot_room("room", "pwd", &host).await.unwrap();
+        let exported = serde_json::to_string(&manager.seal_snapshot(snapshot).unwrap()).unwrap();
+        let wrong = Manager::new(None, true)
+            .with_snapshot_key(Some(seal::SnapshotKey::from_passphrase("x")));
+        let err = wrong
+            .open_snapshot(serde_json::from_str(&exported).unwrap())
+            .unwrap_err();
+        assert!(err.message.contains("wrong key"));
+        let keyless = Manager::new(None, true);
+        let err = keyless
+            .open_snapshot(serde_json::from_str(&exported).unwrap())
+            .unwrap_err();
+        assert!(err.message.contains("no key"));
+    }
+
+    #[tokio::test]
+    async fn webhook_receives_room_and_source_events() {
+        let (tx, mut rx) = mpsc::unbounded_channel::<serde_json::Value>();
+        let hook = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(body).unwrap();
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+        let base = spawn_mock(hook).await;
+        let manager = Manager::new(None, true).with_webhook(Some(format!("{base}/hook")));
+        async fn next_event(
+            rx: &mut mpsc::UnboundedReceiver<serde_json::Value>,
+        ) -> serde_json::Value {
+            tokio_time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+
+        let (host, _) = manager.join_room("room", "secret").await.unwrap();
+        let created = next_event(&mut rx).await;
+        assert_eq!(created["event"], "room_created");
+        assert_eq!(created["room"], "room");
+        assert!(!created.to_string().contains("secret"));
+
+        let state = RoomState {
+            title: "movie".into(),
+            duration: 10.0,
+            paused: false,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, state.clone(), true)
+            .await
+            .unwrap();
+        // 同一播放源的进度更新不触发事件
+        manager
+            .update_state(
+                "room",
+                &host,
+                RoomState {
+                    current_time: 5.0,
+                    ..state.clone()
+                },
+                true,
+            )
+            .await
+            .unwrap();
+        manager
+            .update_state(
+                "room",
+                &host,
+                RoomState {
+                    url: "/media/b".into(),
+                    title: "sequel".into(),
+                    ..state
+                },
+                true,
+            )
+            .await
+            .unwrap();
+
+        let first = next_event(&mut rx).await;
+        assert_eq!(first["event"], "source_changed");
+        assert_eq!(first["title"], "movie");
+        assert_eq!(first["sourceType"], "file");
+        assert!(first.get("url").is_none());
+        let second = next_event(&mut rx).await;
+        assert_eq!(second["event"], "source_changed");
+        assert_eq!(second["title"], "sequel");
+    }
+
+    #[tokio::test]
+    async fn scheduled_start_unpauses_and_broadcasts() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let at = now_millis() + 200;
+        let err = state
+            .manager
+            .set_schedule("room", "pwd", &host, Some(at))
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("not published"));
+        let paused = RoomState {
+            title: "a".into(),
+            current_time: 12.0,
+            duration: 100.0,
+            ..room_state("/media/a")
+        };
+        state
+            .manager
+            .update_state("room", &host, paused, true)
+            .await
+            .unwrap();
+        assert!(state
+            .manager
+            .set_schedule("room", "pwd", &member, Some(at))
+            .await
+            .is_err());
+        assert!(state
+            .manager
+            .set_schedule("room", "pwd", &host, Some(now_millis() - 1))
+            .await
+            .is_err());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state
+            .hub
+            .register("room", "c1", &member, ClientSender::Ws(tx))
+            .await;
+        state
+            .manager
+            .set_schedule("room", "pwd", &host, Some(at))
+            .await
+            .unwrap();
+        spawn_scheduled_start(state.clone(), "room".into(), at);
+        assert_eq!(
+            connect_state_message(&state.manager, "room")
+                .await
+                .scheduled_start,
+            Some(at)
+        );
+        tokio_time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+        assert!(state.manager.latest_state("room").await.unwrap().paused);
+
+        let msg = tokio_time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let Message::Text(text) = msg else {
+            panic!("expected text message");
+        };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["type"], "room_state");
+        assert_eq!(msg["reason"], "scheduled_start");
+        assert_eq!(msg["state"]["paused"], false);
+        assert_eq!(msg["state"]["currentTime"], 12.0);
+        assert!(now_millis() >= at);
+        assert_eq!(state.manager.scheduled_start("room").await, None);
+
+        // 取消后的计划到点不再触发
+        state
+            .manager
+            .update_state(
+                "room",
+                &host,
+                RoomState {
+                    paused: true,
+                    ..state.manager.latest_state("room").await.unwrap()
+                },
+                true,
+            )
+            .await
+            .unwrap();
+        let at = now_millis() + 50;
+        state
+            .manager
+            .set_schedule("room", "pwd", &host, Some(at))
+            .await
+            .unwrap();
+        spawn_scheduled_start(state.clone(), "room".into(), at);
+        state
+            .manager
+            .set_schedule("room", "pwd", &host, None)
+            .await
+            .unwrap();
+        tokio_time::sleep(Duration::from_millis(120)).await;
+        assert!(state.manager.latest_state("room").await.unwrap().paused);
+    }
+
+    #[tokio::test]
+    async fn state_broadcasts_carry_monotonic_seq() {
+        let hub = Hub::new();
+        let (a_tx, mut a_rx) = mpsc::unbounded_channel();
+        let (b_tx, mut b_rx) = mpsc::unbounded_channel();
+        hub.register("room", "a", "ua", ClientSender::Ws(a_tx))
+            .await;
+        hub.register("room", "b", "ub", ClientSender::Ws(b_tx))
+            .await;
+        let state = RoomState {
+            title: "a".into(),
+            duration: 10.0,
+            paused: false,
+            ..room_state("/media/a")
+        };
+        for _ in 0..3 {
+            hub.broadcast_state("room", &state).await;
+            hub.broadcast("room", &WsOutgoing::kind("schedule")).await;
+        }
+        hub.broadcast_state("other", &state).await;
+
+        let seqs = |rx: &mut mpsc::UnboundedReceiver<Message>| {
+            let mut seqs = Vec::new();
+            while let Ok(Message::Text(text)) = rx.try_recv() {
+                let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+                seqs.push(msg["seq"].as_u64());
+            }
+            seqs
+        };
+        let expected = [Some(1), None, Some(2), None, Some(3), None];
+        assert_eq!(seqs(&mut a_rx), expected);
+        assert_eq!(seqs(&mut b_rx), expected);
+        assert_eq!(hub.current_seq("room"), 3);
+        assert_eq!(hub.current_seq("other"), 0);
+
+        hub.unregister("room", "a").await;
+        hub.unregister("room", "b").await;
+        assert_eq!(hub.current_seq("room"), 0);
+    }
+
+    #[tokio::test]
+    async fn local_resolve_attaches_generated_cover() {
+        let root = std::env::temp_dir().join("vo_sync_thumbnail");
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("movie.mp4");
+        write_mp4(&file);
+        let path = file.to_str().unwrap();
+
+        let plain = Manager::new(Some(root.clone()), true);
+        let resolved = plain
+            .resolve_source("room", path, &ResolveOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(resolved.cover, None);
+
+        let manager = Manager::new(Some(root), true)
+            .with_thumbnails(Some(thumbnail::Thumbnailer::fixed(b"\xff\xd8jpeg")));
+        let resolved = manager
+            .resolve_source("room", path, &ResolveOptions::default())
+            .await
+            .unwrap();
+        let cover = resolved.cover.unwrap();
+        let token = cover.strip_prefix("/media/").unwrap();
+        let MediaTarget::Inline(inline) = manager.open_media(token).await.unwrap() else {
+            panic!("cover should be served inline");
+        };
+        assert_eq!(inline.content_type, "image/jpeg");
+        assert_eq!(&inline.body[..], b"\xff\xd8jpeg");
+
+        // 同一文件再次 resolve 命中缓存
+        let again = manager
+            .resolve_source("room", path, &ResolveOptions::default())
+            .await
+            .unwrap();
+        assert!(again.cover.is_some());
+        assert_eq!(manager.thumbnails.as_ref().unwrap().generated(), 1);
+    }
+
+    #[tokio::test]
+    async fn member_seek_waits_for_host_to_accept_proposal() {
+        let manager = Arc::new(Manager::new(None, false).with_member_proposals(true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let published = RoomState {
+            title: "a".into(),
+            duration: 100.0,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, published.clone(), true)
+            .await
+            .unwrap();
+        let (host_tx, mut host_rx) = mpsc::unbounded_channel();
+        let (member_tx, mut member_rx) = mpsc::unbounded_channel();
+        hub.register("room", "c1", &host, ClientSender::Ws(host_tx))
+            .await;
+        hub.register("room", "c2", &member, ClientSender::Ws(member_tx))
+            .await;
+        let ctx = |user: &str| WsContext {
+            room: "room".into(),
+            temp_user: user.to_string(),
+            client_id: "client".into(),
+            debug: false,
+            acks: false,
+        };
+        let drain = |rx: &mut mpsc::UnboundedReceiver<Message>| {
+            let mut msgs = Vec::new();
+            while let Ok(Message::Text(text)) = rx.try_recv() {
+                msgs.push(serde_json::from_str::<serde_json::Value>(&text).unwrap());
+            }
+            msgs
+        };
+
+        let seek = json!({
+            "type": "host_update",
+            "state": RoomState {
+                current_time: 42.0,
+                url: "/media/other".into(),
+                ..published
+            },
+        });
+        handle_ws_message(
+            Message::Text(seek.to_string()),
+            &manager,
+            &hub,
+            &ctx(&member),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            manager.latest_state("room").await.unwrap().current_time,
+            0.0
+        );
+        assert!(drain(&mut member_rx).is_empty());
+        let forwarded = drain(&mut host_rx);
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0]["type"], "proposal");
+        let member_public = member_id(&manager, "room", &member).await;
+        assert_eq!(forwarded[0]["proposal"]["from"], member_public.as_str());
+        assert!(!forwarded[0].to_string().contains(&member));
+        let id = forwarded[0]["proposal"]["id"].as_u64().unwrap();
+
+        let resolve = json!({ "type": "resolve_proposal", "proposalId": id, "accept": true });
+        assert!(handle_ws_message(
+            Message::Text(resolve.to_string()),
+            &manager,
+            &hub,
+            &ctx(&member),
+        )
+        .await
+        .is_err());
+        handle_ws_message(
+            Message::Text(resolve.to_string()),
+            &manager,
+            &hub,
+            &ctx(&host),
+        )
+        .await
+        .unwrap();
+        let applied = manager.latest_state("room").await.unwrap();
+        assert_eq!(applied.current_time, 42.0);
+        assert_eq!(applied.url, "/media/a");
+        let types: Vec<_> = drain(&mut member_rx)
+            .into_iter()
+            .map(|msg| msg["type"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(types, ["proposal_resolved", "room_state"]);
+
+        // 已处理的提议不能再次处理
+        let err = manager
+            .resolve_proposal("room", &host, id, true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn skewed_member_clock_triggers_warning_once() {
+        let manager = Arc::new(Manager::new(None, true));
+        let hub = Arc::new(Hub::new());
+        let (_host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "c1", &member, ClientSender::Ws(tx))
+            .await;
+        let ctx = WsContext {
+            room: "room".into(),
+            temp_user: member.clone(),
+            client_id: "client".into(),
+            debug: false,
+            acks: false,
+        };
+        let sync = |client_time: i64| {
+            Message::Text(
+                json!({ "type": "time_sync", "clientTime": client_time, "rttMs": 40 }).to_string(),
+            )
+        };
+        let received = |rx: &mut mpsc::UnboundedReceiver<Message>| {
+            let mut msgs = Vec::new();
+            while let Ok(Message::Text(text)) = rx.try_recv() {
+                msgs.push(serde_json::from_str::<serde_json::Value>(&text).unwrap());
+            }
+            msgs
+        };
+
+        // 时钟准确的客户端只收到回显
+        handle_ws_message(sync(now_millis()), &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        let msgs = received(&mut rx);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0]["type"], "time_sync");
+        assert!(msgs[0]["serverTime"].is_i64());
+
+        // 客户端时钟快了 5 分钟
+        let skew = 5 * 60 * 1000;
+        handle_ws_message(sync(now_millis() + skew), &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        let msgs = received(&mut rx);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[1]["type"], "clock_warning");
+        let offset = msgs[1]["clockOffsetMs"].as_i64().unwrap();
+        assert!((offset - skew).abs() < 1_000, "offset {offset}");
+
+        // 偏差持续存在时不重复告警
+        handle_ws_message(sync(now_millis() + skew), &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        assert_eq!(received(&mut rx).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn root_serves_html_status_page() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        state.manager.join_room("room", "pwd").await.unwrap();
+        let base = spawn_mock(build_router(state)).await;
+        let resp = reqwest::get(format!("{base}/")).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        let content_type = resp.headers()["content-type"].to_str().unwrap();
+        assert!(content_type.starts_with("text/html"));
+        let body = resp.text().await.unwrap();
+        assert!(body.contains("active rooms: 1"));
+        assert!(body.contains("href=\"/healthz\""));
+    }
+
+    #[tokio::test]
+    async fn local_only_room_rejects_bilibili_resolve() {
+        let root = std::env::temp_dir().join("vo_sync_sources");
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("movie.mp4");
+        write_mp4(&file);
+        let manager = Manager::new(Some(root), true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        assert!(manager
+            .set_allowed_sources("room", "pwd", &member, Some(vec![SourceKind::Local]))
+            .await
+            .is_err());
+        assert!(manager
+            .set_allowed_sources("room", "pwd", &host, Some(vec![]))
+            .await
+            .is_err());
+        let allowed = manager
+            .set_allowed_sources("room", "pwd", &host, Some(vec![SourceKind::Local]))
+            .await
+            .unwrap();
+        assert_eq!(allowed, Some(BTreeSet::from([SourceKind::Local])));
+        let welcome = serde_json::to_value(connect_state_message(&manager, "room").await).unwrap();
+        assert_eq!(welcome["allowedSources"], json!(["local"]));
+
+        let options = ResolveOptions::default();
+        let err = manager
+            .resolve_media_with("room", "pwd", &host, "BV1xx411c7mD", &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(err.message.contains("bili sources are not allowed"));
+        assert!(manager
+            .resolve_media_with("room", "pwd", &member, "https://1.1.1.1/a.mp4", &options)
+            .await
+            .is_err());
+        manager
+            .resolve_media_with("room", "pwd", &member, file.to_str().unwrap(), &options)
+            .await
+            .unwrap();
+
+        // 取消限制后恢复默认
+        manager
+            .set_allowed_sources("room", "pwd", &host, None)
+            .await
+            .unwrap();
+        manager
+            .resolve_media_with("room", "pwd", &host, "https://1.1.1.1/a.mp4", &options)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn proxy_derives_content_length_from_content_range() {
+        let chunked = |content_range: Option<&'static str>| {
+            let mut resp = Response::new(Body::from_stream(stream::iter([
+                Ok::<_, Infallible>("ab"),
+                Ok("cd"),
+            ])));
+            if let Some(range) = content_range {
+                *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+                resp.headers_mut().insert(
+                    axum::http::header::CONTENT_RANGE,
+                    HeaderValue::from_static(range),
+                );
+            }
+            resp
+        };
+        let upstream = spawn_mock(
+            Router::new()
+                .route(
+                    "/ranged.mp4",
+                    get(move || async move { chunked(Some("bytes 10-13/100")) }),
+                )
+                .route("/plain.mp4", get(move || async move { chunked(None) })),
+        )
+        .await;
+        let manager = Manager::new(None, true);
+        let mut tokens = Vec::new();
+        for name in ["ranged", "plain"] {
+            let target = MediaTarget::Remote(RemoteTarget {
+                url: format!("{upstream}/{name}.mp4"),
+                strategy: RemoteStrategy::ProxyWithHeaders,
+            });
+            tokens.push(manager.mint_token("room", target).await);
+        }
+        let state = AppState {
+            manager: Arc::new(manager),
+            hub: Arc::new(Hub::new()),
+        };
+        let base = spawn_mock(build_router(state)).await;
+
+        let resp = reqwest::get(format!("{base}/media/{}", tokens[0]))
+            .await
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 206);
+        assert_eq!(resp.headers()["content-length"], "4");
+        assert_eq!(resp.headers()["accept-ranges"], "bytes");
+        assert_eq!(resp.text().await.unwrap(), "abcd");
 
-    async fn resolve_media_path(
-        &self,
-        room_name: &str,
-        password: &str,
-        _temp_user: &str,
-        path: &str,
-    ) -> Result<ResolvedMedia, ApiError> {
-        let rooms = self.rooms.read().await;
-        let room = rooms
-            .get(room_name)
-            .ok_or_else(|| ApiError::bad_request("room not found"))?;
-        if room.password != password {
-            return Err(ApiError::forbidden("room password mismatch"));
+        let resp = reqwest::get(format!("{base}/media/{}", tokens[1]))
+            .await
+            .unwrap();
+        assert!(resp.headers().get("content-length").is_none());
+        assert_eq!(resp.headers()["accept-ranges"], "bytes");
+        assert_eq!(content_range_len("bytes 5-4/10"), None);
+    }
+
+    #[tokio::test]
+    async fn connect_pushes_room_settings_after_state() {
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, false).with_member_proposals(true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        state
+            .manager
+            .set_allowed_rates("room", "pwd", &host, Some(vec![1.0, 1.5]))
+            .await
+            .unwrap();
+        state
+            .manager
+            .set_allowed_sources("room", "pwd", &host, Some(vec![SourceKind::Bili]))
+            .await
+            .unwrap();
+        let base = spawn_mock(build_router(state)).await;
+
+        let url = format!(
+            "{}/ws?room=room&password=pwd&tempUser={host}",
+            base.replace("http://", "ws://")
+        );
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        async fn next<S>(ws: &mut S) -> serde_json::Value
+        where
+            S: futures_util::Stream<
+                    Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>,
+                > + Unpin,
+        {
+            loop {
+                if let WsMessage::Text(text) = ws.next().await.unwrap().unwrap() {
+                    return serde_json::from_str(&text).unwrap();
+                }
+            }
         }
-        if room.host_id.as_deref() != Some(_temp_user) && !self.allow_member_control {
-            return Err(ApiError::forbidden("operation allowed for host only"));
+        assert_eq!(next(&mut ws).await["type"], "waiting_for_host");
+        let settings = next(&mut ws).await;
+        assert_eq!(settings["type"], "room_settings");
+        assert_eq!(
+            settings["settings"],
+            json!({
+                "memberControl": "proposal",
+                "allowedRates": [1.0, 1.5],
+                "allowedSources": ["bili"],
+                "scheduledStart": null,
+                "hostFocused": true,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn rejoining_expired_room_restores_last_position() {
+        let mut manager = Manager::new(None, true);
+        manager.room_ttl = Duration::ZERO;
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let playing = RoomState {
+            title: "Movie".into(),
+            current_time: 42.0,
+            duration: 120.0,
+            paused: false,
+            updated_at: now_millis(),
+            ..room_state("/media/token")
+        };
+        manager
+            .update_state("room", &host, playing, true)
+            .await
+            .unwrap();
+        manager.join_room("other", "pwd").await.unwrap();
+        assert_eq!(manager.cleanup().await.len(), 2);
+
+        let outcome = manager
+            .join_room_as("room", "pwd", JoinOptions::default())
+            .await
+            .unwrap();
+        assert!(outcome.restored);
+        assert!(outcome.is_host);
+        let restored = manager.latest_state("room").await.unwrap();
+        assert!(restored.paused);
+        assert_eq!(restored.url, "/media/token");
+        assert!((restored.current_time - 42.0).abs() < 1.0);
+
+        // 没有状态的房间不留记录；口令不同则按新房间处理。
+        let outcome = manager
+            .join_room_as("other", "pwd", JoinOptions::default())
+            .await
+            .unwrap();
+        assert!(!outcome.restored);
+        assert_eq!(manager.cleanup().await.len(), 2);
+        let outcome = manager
+            .join_room_as("room", "new", JoinOptions::default())
+            .await
+            .unwrap();
+        assert!(!outcome.restored);
+        assert!(manager.latest_state("room").await.is_none());
+        assert!(manager.has_tombstone("room", "pwd"));
+    }
+
+    #[tokio::test]
+    async fn bili_probe_reports_metadata_without_rooms() {
+        let bili = spawn_mock(mock_bili_router(
+            json!({
+                "code": 0,
+                "message": "0",
+                "data": {
+                    "durl": [{ "url": "https://upos.bilivideo.com/video.mp4" }],
+                    "accept_quality": [80, 64, 32],
+                    "accept_description": ["高清 1080P", "高清 720P", "清晰 480P"]
+                }
+            }),
+            Arc::new(AtomicUsize::new(0)),
+        ))
+        .await;
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true).with_bili_api_base(bili)),
+            hub: Arc::new(Hub::new()),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let service =
+            build_router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+        // 拿不到对端地址时视为远程：不带 Cookie 会借用本机登录会话，必须拒绝。
+        let remote = spawn_mock(build_router(state.clone())).await;
+        let probe = |base: String, body: serde_json::Value| async move {
+            reqwest::Client::new()
+                .post(format!("{base}/api/bili/probe"))
+                .json(&body)
+                .send()
+                .await
+                .unwrap()
+        };
+        let input = "https://www.bilibili.com/video/BV1xx411c7mD";
+
+        let resp = probe(remote.clone(), json!({ "input": input })).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = probe(remote, json!({ "input": input, "cookie": "SESSDATA=x" })).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let res: serde_json::Value = probe(
+            format!("http://{local}"),
+            json!({ "input": input, "quality": 80 }),
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+        assert_eq!(res["bvid"], "BV1xx411c7mD");
+        assert_eq!(res["title"], "mock");
+        assert_eq!(res["duration"], 240);
+        assert_eq!(res["playable"], true);
+        assert_eq!(
+            res["qualities"][0],
+            json!({ "qn": 80, "description": "高清 1080P" })
+        );
+        assert_eq!(res["qualities"].as_array().unwrap().len(), 3);
+        assert!(state.manager.media_tokens.read().await.is_empty());
+        assert!(state.manager.rooms.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn long_bilibili_videos_are_capped_unless_host_overrides() {
+        let router = Router::new()
+            .route(
+                "/x/web-interface/view",
+                get(|| async {
+                    Json(json!({
+                        "code": 0,
+                        "data": { "bvid": "BV1xx411c7mD", "cid": 1, "title": "long", "duration": 3 * 60 * 60 }
+                    }))
+                }),
+            )
+            .route(
+                "/x/web-interface/nav",
+                get(|| async {
+                    Json(json!({
+                        "data": {
+                            "wbi_img": {
+                                "img_url": "https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png",
+                                "sub_url": "https://i0.hdslb.com/bfs/wbi/4932caff0ff746eab6f01bf08b70ac45.png"
+                            }
+                        }
+                    }))
+                }),
+            )
+            .route(
+                "/x/player/wbi/playurl",
+                get(|| async {
+                    Json(json!({
+                        "code": 0,
+                        "message": "0",
+                        "data": { "durl": [{ "url": "https://upos.bilivideo.com/long.mp4" }] }
+                    }))
+                }),
+            );
+        let base = spawn_mock(router).await;
+        let manager = Manager::new(None, true)
+            .with_bili_api_base(base)
+            .with_max_duration(Some(Duration::from_secs(2 * 60 * 60)));
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+
+        let err = manager
+            .resolve_media_with(
+                "room",
+                "pwd",
+                &host,
+                "BV1xx411c7mD",
+                &ResolveOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains("180 min long, over the 120 min limit"));
+
+        let allow_long = ResolveOptions {
+            allow_long: true,
+            ..Default::default()
+        };
+        assert!(manager
+            .resolve_media_with("room", "pwd", &member, "BV1xx411c7mD", &allow_long)
+            .await
+            .is_err());
+        let res = manager
+            .resolve_media_with("room", "pwd", &host, "BV1xx411c7mD", &allow_long)
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::Bili);
+    }
+
+    #[tokio::test]
+    async fn bilibili_rate_limit_is_retried_once() {
+        let view_hits = Arc::new(AtomicUsize::new(0));
+        let play_hits = Arc::new(AtomicUsize::new(0));
+        let nav_hits = Arc::new(AtomicUsize::new(0));
+        let (views, plays, navs) = (view_hits.clone(), play_hits.clone(), nav_hits.clone());
+        let router = Router::new()
+            .route(
+                "/x/web-interface/view",
+                get(move |headers: HeaderMap| async move {
+                    let ua = headers[axum::http::header::USER_AGENT].to_str().unwrap();
+                    assert!(ua.starts_with("Mozilla/5.0"));
+                    if views.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return StatusCode::PRECONDITION_FAILED.into_response();
+                    }
+                    Json(json!({
+                        "code": 0,
+                        "data": { "bvid": "BV1xx411c7mD", "cid": 1, "title": "mock" }
+                    }))
+                    .into_response()
+                }),
+            )
+            .route(
+                "/x/web-interface/nav",
+                get(move || async move {
+                    navs.fetch_add(1, Ordering::SeqCst);
+                    Json(json!({
+                        "data": {
+                            "wbi_img": {
+                                "img_url": "https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png",
+                                "sub_url": "https://i0.hdslb.com/bfs/wbi/4932caff0ff746eab6f01bf08b70ac45.png"
+                            }
+                        }
+                    }))
+                }),
+            )
+            .route(
+                "/x/player/wbi/playurl",
+                get(move || async move {
+                    if plays.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return Json(json!({ "code": -412, "message": "请求被拦截", "data": null }));
+                    }
+                    Json(json!({
+                        "code": 0,
+                        "message": "0",
+                        "data": { "durl": [{ "url": "https://upos.bilivideo.com/v.mp4" }] }
+                    }))
+                }),
+            );
+        let base = spawn_mock(router).await;
+        let manager = Manager::new(None, true).with_bili_api_base(base);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+
+        let res = manager
+            .resolve_media_with(
+                "room",
+                "pwd",
+                &host,
+                "BV1xx411c7mD",
+                &ResolveOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::Bili);
+        assert_eq!(view_hits.load(Ordering::SeqCst), 2);
+        assert_eq!(play_hits.load(Ordering::SeqCst), 2);
+        // 被拦截后重新拉取了 wbi key
+        assert_eq!(nav_hits.load(Ordering::SeqCst), 2);
+
+        // 重试后仍被拦截时返回明确的限流错误
+        let blocked = spawn_mock(Router::new().route(
+            "/x/web-interface/view",
+            get(|| async { StatusCode::PRECONDITION_FAILED }),
+        ))
+        .await;
+        let manager = Manager::new(None, true).with_bili_api_base(blocked);
+        let err = manager
+            .probe_bilibili("BV1xx411c7mD", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(err.message.contains("rate limited by Bilibili"));
+    }
+
+    #[tokio::test]
+    async fn unacked_critical_messages_are_retried() {
+        let manager = Arc::new(Manager::new(None, true));
+        let mut hub = Hub::new();
+        hub.ack_timeout = Duration::from_millis(30);
+        let hub = Arc::new(hub);
+        let (acking_tx, mut acking_rx) = mpsc::unbounded_channel();
+        let (plain_tx, mut plain_rx) = mpsc::unbounded_channel();
+        hub.register("room", "acking", "u1", ClientSender::Ws(acking_tx))
+            .await;
+        hub.register("room", "plain", "u2", ClientSender::Ws(plain_tx))
+            .await;
+        hub.enable_acks("room", "acking").await;
+
+        let msg = WsOutgoing::kind("host_changed");
+        hub.broadcast_critical("room", &msg).await;
+        let received = |msg: Option<Message>| match msg {
+            Some(Message::Text(text)) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            other => panic!("unexpected {other:?}"),
+        };
+        let first = received(acking_rx.recv().await);
+        assert_eq!(first["type"], "host_changed");
+        let ack_id = first["ackId"].as_u64().unwrap();
+        assert!(received(plain_rx.recv().await).get("ackId").is_none());
+
+        // 未确认时按间隔重发同一条消息
+        let retry = tokio_time::timeout(Duration::from_secs(1), acking_rx.recv())
+            .await
+            .unwrap();
+        assert_eq!(received(retry)["ackId"], ack_id);
+
+        let ctx = WsContext {
+            room: "room".into(),
+            temp_user: "u1".into(),
+            client_id: "acking".into(),
+            debug: false,
+            acks: true,
+        };
+        let ack = json!({ "type": "ack", "ackId": ack_id }).to_string();
+        handle_ws_message(Message::Text(ack), &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        tokio_time::sleep(Duration::from_millis(100)).await;
+        assert!(acking_rx.try_recv().is_err());
+        assert!(plain_rx.try_recv().is_err());
+        assert!(!hub.ack("acking", ack_id));
+    }
+
+    #[tokio::test]
+    async fn idle_rooms_keep_playlist_and_position_past_ttl() {
+        let playlist = vec![
+            PlaylistItem {
+                path: "BV1xx411c7mD".into(),
+                title: Some("one".into()),
+            },
+            PlaylistItem {
+                path: "BV1bb411c7mD".into(),
+                title: None,
+            },
+        ];
+        let paused = RoomState {
+            title: "Movie".into(),
+            current_time: 600.0,
+            duration: 3600.0,
+            source_type: SourceType::Bili,
+            updated_at: now_millis(),
+            ..room_state("/media/token")
+        };
+        for ttl in [Some(Duration::from_secs(60)), None] {
+            let mut manager = Manager::new(None, true).with_tombstone_ttl(ttl);
+            manager.room_ttl = Duration::ZERO;
+            let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+            manager
+                .set_playlist("room", "pwd", &host, playlist.clone())
+                .await
+                .unwrap();
+            manager
+                .update_state("room", &host, paused.clone(), true)
+                .await
+                .unwrap();
+            assert_eq!(manager.cleanup().await, ["room"]);
+
+            let outcome = manager
+                .join_room_as("room", "pwd", JoinOptions::default())
+                .await
+                .unwrap();
+            let rooms = manager.rooms.read().await;
+            let room = &rooms["room"];
+            if ttl.is_some() {
+                assert!(outcome.restored);
+                assert_eq!(room.playlist, playlist);
+                assert_eq!(room.state.as_ref().unwrap().current_time, 600.0);
+            } else {
+                assert!(!outcome.restored);
+                assert!(room.playlist.is_empty());
+                assert!(room.state.is_none());
+            }
         }
-        drop(rooms);
+    }
+
+    #[tokio::test]
+    async fn join_accepts_a_requested_temp_user() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let base = spawn_mock(build_router(state.clone())).await;
+        let join = |temp_user: &'static str| {
+            reqwest::Client::new()
+                .post(format!("{base}/api/room/join"))
+                .json(&json!({ "room": "room", "password": "pwd", "tempUser": temp_user }))
+                .send()
+        };
+
+        let resp = join("script-host").await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        let body: JoinResponse = resp.json().await.unwrap();
+        assert_eq!(body.temp_user, "script-host");
+        assert_eq!(body.role, "host");
+        state
+            .manager
+            .authorize("room", "pwd", "script-host")
+            .await
+            .unwrap();
+
+        assert_eq!(join("script-host").await.unwrap().status().as_u16(), 409);
+        assert_eq!(join("has space").await.unwrap().status().as_u16(), 400);
+        let id = Uuid::new_v4().to_string();
+        let outcome = state
+            .manager
+            .join_room_as(
+                "room",
+                "pwd",
+                JoinOptions {
+                    temp_user: Some(&id),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.temp_user, id);
+        assert!(!outcome.is_host);
+    }
+
+    #[tokio::test]
+    async fn security_headers_skip_media_streams() {
+        let root = std::env::temp_dir().join("vo_sync_security_headers");
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("movie.mp4");
+        write_mp4(&file);
+        let manager = Manager::new(Some(root), true);
+        let token = manager
+            .mint_token("room", MediaTarget::Local(file.clone()))
+            .await;
+        let state = AppState {
+            manager: Arc::new(manager),
+            hub: Arc::new(Hub::new()),
+        };
+        let base = spawn_mock(with_security_headers(build_router(state))).await;
+
+        let resp = reqwest::get(format!("{base}/healthz")).await.unwrap();
+        assert_eq!(resp.headers()["x-content-type-options"], "nosniff");
+        assert_eq!(resp.headers()["referrer-policy"], "no-referrer");
+        assert!(resp.headers().get("content-security-policy").is_none());
+
+        let resp = reqwest::get(format!("{base}/")).await.unwrap();
+        assert_eq!(resp.headers()["content-security-policy"], STATUS_PAGE_CSP);
+
+        let resp = reqwest::get(format!("{base}/media/{token}")).await.unwrap();
+        assert!(resp.status().is_success());
+        assert!(resp.headers().get("x-content-type-options").is_none());
+        assert!(resp.headers().get("content-security-policy").is_none());
+        assert_eq!(resp.bytes().await.unwrap(), std::fs::read(&file).unwrap());
+    }
+
+    #[tokio::test]
+    async fn seek_correction_carries_extrapolated_position() {
+        let manager = Arc::new(Manager::new(None, false));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "member", &member, ClientSender::Ws(tx))
+            .await;
+        let state = RoomState {
+            title: "a".into(),
+            current_time: 10.0,
+            duration: 600.0,
+            paused: false,
+            playback_rate: 2.0,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+        tokio_time::sleep(Duration::from_millis(100)).await;
+
+        let ctx = |temp_user: &str| WsContext {
+            room: "room".into(),
+            temp_user: temp_user.into(),
+            client_id: "client".into(),
+            debug: false,
+            acks: false,
+        };
+        let request = Message::Text(r#"{"type":"seek_correction"}"#.into());
+        let err = handle_ws_message(request.clone(), &manager, &hub, &ctx(&member))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        handle_ws_message(request, &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+
+        let Ok(Message::Text(text)) = rx.try_recv() else {
+            panic!("member should receive seek_correction");
+        };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["type"], "seek_correction");
+        assert_eq!(msg["serverTime"], msg["state"]["updatedAt"]);
+        let position = msg["state"]["currentTime"].as_f64().unwrap();
+        assert!(position > 10.2 && position < 12.0, "{position}");
+        // 外推只用于消息，房间状态保持原基准。
+        assert_eq!(
+            manager.latest_state("room").await.unwrap().current_time,
+            10.0
+        );
+
+        let mut paused = manager.latest_state("room").await.unwrap();
+        paused.paused = true;
+        manager
+            .update_state("room", &host, paused, true)
+            .await
+            .unwrap();
+        assert!(manager.seek_corrections().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn library_ids_resolve_to_indexed_files() {
+        let root = std::env::temp_dir().join("vo_sync_library_resolve");
+        std::fs::create_dir_all(root.join("show")).unwrap();
+        let file = root.join("show/ep1.mp4");
+        write_mp4(&file);
+        write_mp4(&root.join("other.mp4"));
+        let manager = Manager::new(None, true);
+        manager
+            .set_media_root(root.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        let (host, _) = manager.join_room("r", "p").await.unwrap();
+
+        let groups = manager.library_groups().await.unwrap();
+        let entry = groups
+            .iter()
+            .flat_map(|group| &group.files)
+            .find(|entry| entry.path == "show/ep1.mp4")
+            .expect("file indexed");
+        let res = manager
+            .resolve_media_path("r", "p", &host, &format!("lib://{}", entry.id))
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::File);
+        let tokens = manager.media_tokens.read().await;
+        let MediaTarget::Local(path) = &tokens[&res.token].target else {
+            panic!("expected local target");
+        };
+        assert_eq!(path, &clean_path(&file));
+        drop(tokens);
+
+        let err = manager
+            .resolve_media_path("r", "p", &host, "lib://0000000000000000")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn switch_quality_keeps_token_and_updates_upstream() {
+        // 非大会员拿不到 112，默认顺序退到 80（1080P）。
+        let base = spawn_mock(mock_bili_router_with(
+            |params| {
+                let qn: u32 = params["qn"].parse().unwrap();
+                let data = if qn == 112 {
+                    json!({ "durl": [] })
+                } else {
+                    json!({
+                        "quality": qn,
+                        "durl": [{ "url": format!("https://cdn.bilivideo.com/{qn}.mp4") }]
+                    })
+                };
+                json!({ "code": 0, "message": "0", "data": data })
+            },
+            Arc::default(),
+        ))
+        .await;
+        let manager = Manager::new(None, false).with_bili_api_base(base);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let res = manager
+            .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+            .await
+            .unwrap();
+        assert_eq!(res.quality, Some(80));
+        let state = RoomState {
+            title: "bili".into(),
+            current_time: 30.0,
+            duration: 240.0,
+            source_type: res.source_type,
+            quality: res.quality,
+            ..room_state(&res.url)
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+
+        let err = manager
+            .switch_quality("room", "pwd", &member, &res.token, 32)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        let (quality, state) = manager
+            .switch_quality("room", "pwd", &host, &res.token, 32)
+            .await
+            .unwrap();
+        assert_eq!(quality, 32);
+        assert_eq!(state.url, res.url);
+        assert_eq!(state.quality, Some(32));
+        assert_eq!(state.current_time, 30.0);
+        assert_eq!(
+            manager.latest_state("room").await.unwrap().quality,
+            Some(32)
+        );
+        let target = manager.open_remote(&res.token).await.unwrap();
+        assert_eq!(target.url, "https://cdn.bilivideo.com/32.mp4");
+    }
+
+    #[tokio::test]
+    async fn playlist_export_round_trips_into_fresh_room() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("a", "pwd").await.unwrap();
+        let items = vec![
+            PlaylistItem {
+                path: "BV1xx411c7mD".into(),
+                title: Some("first".into()),
+            },
+            PlaylistItem {
+                path: "https://example.com/second.mp4".into(),
+                title: None,
+            },
+        ];
+        manager
+            .set_playlist("a", "pwd", &host, items.clone())
+            .await
+            .unwrap();
+        let (member, _) = manager.join_room("a", "pwd").await.unwrap();
+        let export = manager.export_playlist("a", "pwd", &member).await.unwrap();
+        let file = serde_json::to_string(&export).unwrap();
+
+        let (other, _) = manager.join_room("b", "pwd").await.unwrap();
+        let imported: PlaylistExport = serde_json::from_str(&file).unwrap();
+        let playlist = manager
+            .import_playlist("b", "pwd", &other, imported.clone(), false)
+            .await
+            .unwrap();
+        assert_eq!(playlist, items);
+        let appended = manager
+            .import_playlist("b", "pwd", &other, imported, true)
+            .await
+            .unwrap();
+        assert_eq!(appended.len(), 4);
+
+        let future = PlaylistExport {
+            version: PLAYLIST_EXPORT_VERSION + 1,
+            ..export.clone()
+        };
+        let err = manager
+            .import_playlist("b", "pwd", &other, future, false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        let tokens = PlaylistExport {
+            items: vec![PlaylistItem {
+                path: "/media/abc".into(),
+                title: None,
+            }],
+            ..export
+        };
+        assert!(manager
+            .import_playlist("b", "pwd", &other, tokens, false)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn join_mode_controls_room_creation() {
+        // 默认配置下打错房间名会悄悄建出新房间，自己成为房主。
+        let manager = Manager::new(None, true);
+        manager.join_room("movie", "pwd").await.unwrap();
+        let (_, is_host) = manager.join_room("moive", "pwd").await.unwrap();
+        assert!(is_host);
+        let err = manager
+            .join_room_as(
+                "other",
+                "pwd",
+                JoinOptions {
+                    mode: Some(JoinMode::Join),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert!(!manager.rooms.read().await.contains_key("other"));
+
+        let strict = Manager::new(None, true).with_join_creates(false);
+        let err = strict.join_room("movie", "pwd").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert!(strict.rooms.read().await.is_empty());
+        let created = strict
+            .join_room_as(
+                "movie",
+                "pwd",
+                JoinOptions {
+                    mode: Some(JoinMode::Create),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(created.is_host);
+        let err = strict
+            .join_room_as(
+                "movie",
+                "typo",
+                JoinOptions {
+                    mode: Some(JoinMode::Create),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        let err = strict.join_room("movie", "typo").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        let (_, is_host) = strict.join_room("movie", "pwd").await.unwrap();
+        assert!(!is_host);
+    }
 
-        if let Some(_) = is_bilibili_source(path) {
-            let resolved = self.resolve_bilibili(path).await?;
-            return Ok(resolved);
+    #[tokio::test]
+    async fn video_ts_directory_resolves_to_ordered_segments() {
+        let root = std::env::temp_dir().join("vo_sync_disc_dvd");
+        let _ = std::fs::remove_dir_all(&root);
+        let video_ts = root.join("movie").join("VIDEO_TS");
+        std::fs::create_dir_all(&video_ts).unwrap();
+        // 标题集 1 是片花，2 是正片；序号 0 与 VIDEO_TS.VOB 是菜单。
+        let files = [
+            ("VIDEO_TS.VOB", 64),
+            ("VTS_01_0.VOB", 64),
+            ("VTS_01_1.VOB", 128),
+            ("VTS_02_0.VOB", 64),
+            ("VTS_02_3.VOB", 256),
+            ("VTS_02_1.VOB", 512),
+            ("vts_02_2.vob", 512),
+        ];
+        for (name, len) in files {
+            std::fs::write(video_ts.join(name), vec![0u8; len]).unwrap();
         }
+        let manager = Manager::new(Some(root.clone()), true);
+        let (host, _) = manager.join_room("r", "p").await.unwrap();
+        let res = manager
+            .resolve_media_path("r", "p", &host, root.join("movie").to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::Disc);
+        let segments = res.segments.expect("disc segments");
+        assert_eq!(segments[0].token, res.token);
 
-        if path.starts_with("http://") || path.starts_with("https://") {
-            let token = Uuid::new_v4().to_string();
-            self.media_tokens.write().await.insert(
-                token.clone(),
-                MediaToken {
-                    target: MediaTarget::Remote(RemoteTarget {
-                        url: path.to_string(),
-                        strategy: RemoteStrategy::Redirect,
-                    }),
-                    expires_at: Instant::now() + self.token_ttl,
-                },
-            );
-            return Ok(ResolvedMedia {
-                url: format!("/media/{token}"),
-                token,
-                source_type: "remote".into(),
-                cover: None,
-            });
-        }
+        let tokens = manager.media_tokens.read().await;
+        let names: Vec<String> = segments
+            .iter()
+            .map(|segment| match &tokens[&segment.token].target {
+                MediaTarget::Local(path) => {
+                    path.file_name().unwrap().to_string_lossy().into_owned()
+                }
+                _ => panic!("expected local segment"),
+            })
+            .collect();
+        assert_eq!(names, ["VTS_02_1.VOB", "vts_02_2.vob", "VTS_02_3.VOB"]);
+        drop(tokens);
 
-        let root = self
-            .media_root
-            .read()
+        let err = manager
+            .resolve_media_path("r", "p", &host, root.to_str().unwrap())
             .await
-            .clone()
-            .ok_or_else(|| ApiError::bad_request("media root not configured"))?;
-        let path = PathBuf::from(path);
-        let clean = clean_path(path);
-        if !is_under_root(&clean, &root) {
-            return Err(ApiError::forbidden("media path forbidden"));
-        }
-        let meta = std::fs::metadata(&clean).map_err(|_| ApiError::bad_request("invalid path"))?;
-        if meta.is_dir() {
-            return Err(ApiError::bad_request("path is directory"));
-        }
+            .unwrap_err();
+        assert_eq!(err.message, "path is directory");
+    }
 
-        let token = Uuid::new_v4().to_string();
-        self.media_tokens.write().await.insert(
-            token.clone(),
-            MediaToken {
-                target: MediaTarget::Local(clean),
-                expires_at: Instant::now() + self.token_ttl,
-            },
-        );
-        Ok(ResolvedMedia {
-            url: format!("/media/{token}"),
-            token,
-            source_type: "file".into(),
-            cover: None,
-        })
+    #[tokio::test]
+    async fn lagging_members_get_catchup_or_resync() {
+        let manager = Manager::new(None, true).with_catchup_rate(Some(1.05));
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (slow, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (lost, _) = manager.join_room("room", "pwd").await.unwrap();
+        let state = RoomState {
+            title: "a".into(),
+            current_time: 100.0,
+            duration: 600.0,
+            paused: false,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+
+        let hint = manager.report_position("room", &host, 100.1).await.unwrap();
+        assert!(hint.is_none());
+        let hint = manager.report_position("room", &slow, 99.0).await.unwrap();
+        let Some(DriftHint::Catchup {
+            rate,
+            duration_ms,
+            lag_ms,
+        }) = hint
+        else {
+            panic!("expected catchup, got {hint:?}");
+        };
+        assert_eq!(rate, 1.05);
+        assert!((950..1100).contains(&lag_ms), "{lag_ms}");
+        // 每秒追回 0.05 秒，落后约 1 秒需要约 20 秒。
+        assert!((19_000..22_000).contains(&duration_ms), "{duration_ms}");
+        // 追赶期间不重复提示。
+        assert!(manager
+            .report_position("room", &slow, 99.0)
+            .await
+            .unwrap()
+            .is_none());
+
+        let hint = manager.report_position("room", &lost, 60.0).await.unwrap();
+        let Some(DriftHint::Resync(state)) = hint else {
+            panic!("expected resync, got {hint:?}");
+        };
+        assert!(state.current_time >= 100.0 && state.current_time < 101.0);
     }
 
-    async fn open_media(&self, token: &str) -> Result<PathBuf, ApiError> {
-        let tokens = self.media_tokens.read().await;
-        let entry = tokens
-            .get(token)
-            .ok_or_else(|| ApiError::not_found("token not found"))?;
-        if Instant::now() > entry.expires_at {
-            return Err(ApiError::not_found("token expired"));
+    #[tokio::test]
+    async fn shared_bili_session_cookies_reach_api_requests() {
+        let seen = Arc::new(StdMutex::new(Vec::<(String, Option<String>)>::new()));
+        let log = seen.clone();
+        let router = mock_bili_router(
+            json!({ "code": 0, "message": "0", "data": { "durl": [{ "url": "https://cdn.bilivideo.com/a.mp4" }] } }),
+            Arc::default(),
+        )
+        .layer(axum::middleware::from_fn(
+            move |req: Request<Body>, next: Next| {
+                let cookie = req
+                    .headers()
+                    .get(axum::http::header::COOKIE)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                log.lock()
+                    .unwrap()
+                    .push((req.uri().path().to_string(), cookie));
+                next.run(req)
+            },
+        ));
+        let base = spawn_mock(router).await;
+        let manager = Manager::new(None, true).with_bili_api_base(base.clone());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+
+        manager
+            .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+            .await
+            .unwrap();
+        assert!(seen
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(_, cookie)| cookie.is_none()));
+
+        let jar = Jar::default();
+        jar.add_cookie_str("SESSDATA=logged-in; Path=/", &base.parse().unwrap());
+        manager.set_bili_session(Some(Arc::new(jar)));
+        seen.lock().unwrap().clear();
+        manager
+            .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+            .await
+            .unwrap();
+        let seen = seen.lock().unwrap();
+        for path in ["/x/web-interface/view", "/x/player/wbi/playurl"] {
+            let (_, cookie) = seen.iter().find(|(p, _)| p == path).expect(path);
+            assert_eq!(cookie.as_deref(), Some("SESSDATA=logged-in"));
         }
-        match &entry.target {
-            MediaTarget::Local(p) => Ok(p.clone()),
-            MediaTarget::Remote(_) => Err(ApiError::bad_request("remote requires redirect")),
+    }
+
+    #[tokio::test]
+    async fn host_play_is_scheduled_write_ahead() {
+        let manager = Manager::new(None, true)
+            .with_write_ahead(Duration::from_millis(300))
+            .with_catchup_rate(Some(1.05));
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let paused = RoomState {
+            title: "a".into(),
+            current_time: 10.0,
+            duration: 600.0,
+            ..room_state("/media/a")
+        };
+        // 首次发布不是切换，立即生效。
+        let first = manager
+            .update_state("room", &host, paused.clone(), true)
+            .await
+            .unwrap();
+        assert!(first.effective_at.is_none());
+
+        let before = now_millis();
+        let play = RoomState {
+            paused: false,
+            ..paused
+        };
+        let state = manager
+            .update_state("room", &host, play, true)
+            .await
+            .unwrap();
+        let after = now_millis();
+        let effective_at = state.effective_at.expect("play is scheduled");
+        assert!(effective_at >= before + 300 && effective_at <= after + 300);
+        assert_eq!(state.updated_at, effective_at);
+        // 生效前外推进度停在起点，成员的偏差也不触发纠偏。
+        assert_eq!(state.position_at(after), 10.0);
+        assert_eq!(state.rebased_at(after).updated_at, effective_at);
+        assert!(manager
+            .report_position("room", &member, 7.0)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn admin_closes_listed_connection() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let (host, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = state.manager.join_room("room", "pwd").await.unwrap();
+        let (host_tx, mut host_rx) = mpsc::unbounded_channel();
+        let (member_tx, mut member_rx) = mpsc::unbounded_channel();
+        state
+            .hub
+            .register("room", "c1", &host, ClientSender::Ws(host_tx))
+            .await;
+        state
+            .hub
+            .register("room", "c2", &member, ClientSender::Ws(member_tx))
+            .await;
+        let peer: IpAddr = "192.0.2.7".parse().unwrap();
+        state.hub.set_peer("room", "c2", peer).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service =
+            build_router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+        let client = reqwest::Client::new();
+        let list = || async {
+            client
+                .get(format!("http://{addr}/api/admin/connections"))
+                .send()
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap()
+        };
+
+        let body = list().await;
+        let connections = body["connections"].as_array().unwrap();
+        assert_eq!(connections.len(), 2);
+        let target = connections
+            .iter()
+            .find(|c| c["tempUser"] == member.as_str())
+            .unwrap();
+        assert_eq!(target["peer"], "192.0.2.7");
+        assert_eq!(target["transport"], "ws");
+        let id = target["clientId"].as_str().unwrap();
+
+        let res = client
+            .post(format!("http://{addr}/api/admin/connections/{id}/close"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        match member_rx.recv().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, CLOSE_ADMIN),
+            other => panic!("expected close frame, got {other:?}"),
         }
+        assert!(host_rx.try_recv().is_err());
+        let body = list().await;
+        let remaining = body["connections"].as_array().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0]["clientId"], "c1");
+
+        let res = client
+            .post(format!("http://{addr}/api/admin/connections/{id}/close"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
     }
 
-    async fn open_remote(&self, token: &str) -> Result<RemoteTarget, ApiError> {
-        let tokens = self.media_tokens.read().await;
-        let entry = tokens
-            .get(token)
-            .ok_or_else(|| ApiError::not_found("token not found"))?;
-        if Instant::now() > entry.expires_at {
-            return Err(ApiError::not_found("token expired"));
+    #[tokio::test]
+    async fn framing_hints_are_host_controlled() {
+        let manager = Manager::new(None, true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let value = json!({
+            "url": "/media/a",
+            "title": "a",
+            "currentTime": 5.0,
+            "duration": 600.0,
+            "paused": false,
+            "playbackRate": 1.0,
+            "sourceType": "file",
+            "updatedAt": 0
+        });
+        let plain: RoomState = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(plain.fit_mode, FitMode::Contain);
+        assert!(plain.crop.is_none());
+
+        let mut framed = value;
+        framed["fitMode"] = json!("cover");
+        framed["crop"] = json!({ "x": 0.0, "y": 0.125, "width": 1.0, "height": 0.75 });
+        let framed: RoomState = serde_json::from_value(framed).unwrap();
+        let state = manager
+            .update_state("room", &host, framed, true)
+            .await
+            .unwrap();
+        let round_trip: RoomState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(round_trip.fit_mode, FitMode::Cover);
+        assert_eq!(round_trip.crop, state.crop);
+
+        let merged = manager
+            .update_state("room", &member, plain.clone(), false)
+            .await
+            .unwrap();
+        assert_eq!(merged.fit_mode, FitMode::Cover);
+        assert_eq!(merged.crop.unwrap().height, 0.75);
+
+        let outside = RoomState {
+            crop: Some(Crop {
+                x: 0.5,
+                y: 0.0,
+                width: 0.75,
+                height: 1.0,
+            }),
+            ..plain
+        };
+        assert!(outside.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn tripped_breaker_fails_fast_without_calling_api() {
+        let playurl_hits = Arc::new(AtomicUsize::new(0));
+        let hits = playurl_hits.clone();
+        let base = spawn_mock(mock_bili_router_with(
+            move |_| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                json!({ "code": BILI_CODE_RATE_LIMITED, "message": "blocked" })
+            },
+            Arc::default(),
+        ))
+        .await;
+        let state = AppState {
+            manager: Arc::new(
+                Manager::new(None, true)
+                    .with_bili_api_base(base)
+                    .with_bili_breaker(Some(breaker::CircuitBreaker::new(
+                        2,
+                        Duration::from_secs(60),
+                        Duration::from_secs(60),
+                    ))),
+            ),
+            hub: Arc::new(Hub::new()),
+        };
+        let manager = &state.manager;
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+
+        for _ in 0..2 {
+            let err = manager
+                .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+                .await
+                .unwrap_err();
+            assert_eq!(err.status, StatusCode::TOO_MANY_REQUESTS);
         }
-        match &entry.target {
-            MediaTarget::Remote(target) => Ok(target.clone()),
-            MediaTarget::Local(_) => Err(ApiError::bad_request("not a remote token")),
+        let before = playurl_hits.load(Ordering::SeqCst);
+        assert!(before > 0);
+
+        let err = manager
+            .resolve_media_path("room", "pwd", &host, "BV1xx411c7mD")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(err.message.contains("temporarily unavailable"));
+        assert_eq!(playurl_hits.load(Ordering::SeqCst), before);
+
+        let body = metrics(State(state.clone())).await.into_response();
+        let body = axum::body::to_bytes(body.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("vo_sync_bili_breaker_state 1\n"));
+        assert!(text.contains("vo_sync_bili_breaker_trips_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn shared_volume_follows_member_control_flag() {
+        let hub = Arc::new(Hub::new());
+        for member_volume in [false, true] {
+            let manager = Arc::new(Manager::new(None, true).with_member_volume(member_volume));
+            let room = format!("room-{member_volume}");
+            let (host, _) = manager.join_room(&room, "pwd").await.unwrap();
+            let (member, _) = manager.join_room(&room, "pwd").await.unwrap();
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            hub.register(&room, "member", &member, ClientSender::Ws(tx))
+                .await;
+            let update = |volume: f64, muted: bool| {
+                Message::Text(format!(
+                    r#"{{"type":"host_update","state":{{"url":"/media/t","title":"t","currentTime":1,"duration":10,"paused":false,"playbackRate":1.0,"sourceType":"file","updatedAt":0,"volume":{volume},"muted":{muted}}}}}"#
+                ))
+            };
+            let ctx = |user: &str| WsContext {
+                room: room.clone(),
+                temp_user: user.to_string(),
+                client_id: user.to_string(),
+                debug: false,
+                acks: false,
+            };
+
+            handle_ws_message(update(0.5, false), &manager, &hub, &ctx(&host))
+                .await
+                .unwrap();
+            let Some(Message::Text(text)) = rx.recv().await else {
+                panic!("expected broadcast");
+            };
+            let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(msg["state"]["volume"], 0.5);
+            assert_eq!(msg["state"]["muted"], false);
+
+            handle_ws_message(update(0.2, true), &manager, &hub, &ctx(&member))
+                .await
+                .unwrap();
+            let state = manager.latest_state(&room).await.unwrap();
+            if member_volume {
+                assert_eq!((state.volume, state.muted), (Some(0.2), Some(true)));
+            } else {
+                assert_eq!((state.volume, state.muted), (Some(0.5), Some(false)));
+            }
         }
     }
 
-    async fn set_media_root(&self, path: &str) -> Result<PathBuf, ApiError> {
-        let candidate = clean_path(path);
-        let meta = std::fs::metadata(&candidate)
-            .map_err(|_| ApiError::bad_request("media root not found"))?;
-        if !meta.is_dir() {
-            return Err(ApiError::bad_request("media root must be directory"));
+    #[tokio::test]
+    async fn raw_playurl_returns_upstream_json_verbatim() {
+        let upstream = json!({
+            "code": 0,
+            "message": "0",
+            "data": {
+                "quality": 80,
+                "accept_quality": [116, 80, 64],
+                "dash": {
+                    "video": [{ "id": 80, "baseUrl": "https://upos.bilivideo.com/v.m4s", "backupUrl": ["https://bak.bilivideo.com/v.m4s"] }],
+                    "audio": [{ "id": 30280, "baseUrl": "https://upos.bilivideo.com/a.m4s" }]
+                }
+            }
+        });
+        let seen = Arc::new(StdMutex::new(HashMap::new()));
+        let log = seen.clone();
+        let body = upstream.clone();
+        let bili = spawn_mock(mock_bili_router_with(
+            move |params| {
+                *log.lock().unwrap() = params.clone();
+                body.clone()
+            },
+            Arc::default(),
+        ))
+        .await;
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true).with_bili_api_base(bili)),
+            hub: Arc::new(Hub::new()),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service =
+            build_router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+        let client = reqwest::Client::new();
+        let post = |body: serde_json::Value| {
+            client
+                .post(format!("http://{addr}/api/bili/raw-playurl"))
+                .json(&body)
+                .send()
+        };
+
+        let res = post(json!({ "bvid": "BV1xx411c7mD", "qn": 116 }))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.json::<serde_json::Value>().await.unwrap(), upstream);
+        {
+            let params = seen.lock().unwrap();
+            assert_eq!(params["cid"], "1176840");
+            assert_eq!(params["qn"], "116");
+            assert_eq!(params["fnval"], "4048");
+            assert!(params.contains_key("w_rid"));
         }
-        *self.media_root.write().await = Some(candidate.clone());
-        Ok(candidate)
+
+        let res = post(json!({ "bvid": "https://evil.example/BV1xx411c7mD" }))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // 未经 connect_info 挂载时拿不到对端地址，按非本机拒绝。
+        let remote = spawn_mock(build_router(state)).await;
+        let res = client
+            .post(format!("{remote}/api/bili/raw-playurl"))
+            .json(&json!({ "bvid": "BV1xx411c7mD" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
     }
 
-    async fn resolve_bilibili(&self, input: &str) -> Result<ResolvedMedia, ApiError> {
-        let bvid =
-            extract_bvid(input).ok_or_else(|| ApiError::bad_request("invalid bilibili id"))?;
-        let client = init_client()
+    #[tokio::test]
+    async fn library_groups_entries_by_labeled_root() {
+        let base = std::env::temp_dir().join("vo_sync_labeled_roots");
+        let (movies, anime) = (base.join("films"), base.join("shows"));
+        std::fs::create_dir_all(&movies).unwrap();
+        std::fs::create_dir_all(anime.join("s1")).unwrap();
+        write_mp4(&movies.join("feature.mp4"));
+        write_mp4(&anime.join("s1/ep1.mp4"));
+        write_mp4(&anime.join("s1/ep2.mp4"));
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let app = spawn_mock(build_router(state.clone())).await;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .post(format!("{app}/api/media/roots"))
+            .json(&json!({ "roots": [
+                { "path": movies, "label": "Movies" },
+                { "path": anime, "label": "Anime" }
+            ] }))
+            .send()
             .await
-            .map_err(|e| ApiError::bad_request(format!("client init failed: {e}")))?;
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let library: serde_json::Value = client
+            .get(format!("{app}/api/media/library"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let groups = library["roots"].as_array().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0]["label"], "Movies");
+        assert_eq!(groups[0]["files"].as_array().unwrap().len(), 1);
+        assert_eq!(groups[1]["label"], "Anime");
+        let episodes: Vec<&str> = groups[1]["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(episodes, ["s1/ep1.mp4", "s1/ep2.mp4"]);
+        assert_eq!(groups[1]["files"][0]["root"], "Anime");
+
+        // 两个根下的文件都能按绝对路径或 lib id 解析。
+        let manager = &state.manager;
+        let (host, _) = manager.join_room("r", "p").await.unwrap();
+        let feature = movies.join("feature.mp4");
+        manager
+            .resolve_media_path("r", "p", &host, feature.to_str().unwrap())
+            .await
+            .unwrap();
+        let id = groups[1]["files"][0]["id"].as_str().unwrap();
+        manager
+            .resolve_media_path("r", "p", &host, &format!("lib://{id}"))
+            .await
+            .unwrap();
+
+        let err = manager
+            .set_media_roots(&[
+                MediaRootRequest {
+                    path: movies.to_str().unwrap().into(),
+                    label: Some("Same".into()),
+                },
+                MediaRootRequest {
+                    path: anime.to_str().unwrap().into(),
+                    label: Some("Same".into()),
+                },
+            ])
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(manager.media_roots.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn check_credentials_needs_password_but_not_membership() {
+        let state = AppState {
+            manager: Arc::new(Manager::new(None, true)),
+            hub: Arc::new(Hub::new()),
+        };
+        let manager = &state.manager;
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+
+        assert_eq!(manager.check_credentials("room", "pwd").await.unwrap(), 1);
+        let err = manager
+            .authorize("room", "pwd", "outsider")
+            .await
+            .unwrap_err();
+        assert_eq!(err.message, "user not in room");
+        assert!(manager.authorize("room", "pwd", &host).await.unwrap());
+
+        let err = manager.check_credentials("room", "nope").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        let err = manager
+            .check_credentials("missing", "pwd")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+
+        let app = spawn_mock(build_router(state.clone())).await;
+        let check = |password: &str| {
+            reqwest::Client::new()
+                .post(format!("{app}/api/room/check"))
+                .json(&json!({ "room": "room", "password": password }))
+                .send()
+        };
+        let res = check("pwd").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["members"], 1);
+        assert_eq!(check("nope").await.unwrap().status(), StatusCode::FORBIDDEN);
+        // 预检不会把调用者加入房间。
+        assert_eq!(manager.check_credentials("room", "pwd").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_state_strings_are_rejected() {
+        let manager =
+            Manager::new(None, true).with_state_limits(StateLimits { title: 16, url: 64 });
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let state = RoomState {
+            title: "a".repeat(17),
+            duration: 60.0,
+            ..room_state("/media/a")
+        };
+        let err = manager
+            .update_state("room", &host, state.clone(), true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains("title exceeds 16"));
+        assert!(manager.latest_state("room").await.is_none());
+
+        let err = manager
+            .update_state(
+                "room",
+                &host,
+                RoomState {
+                    title: "ok".into(),
+                    cover: Some(format!("https://i0.hdslb.com/{}", "x".repeat(64))),
+                    ..state.clone()
+                },
+                true,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("url or cover"));
+
+        // 服务端生成的初始状态按字符截断标题，不会截断在多字节字符中间。
+        let mut generated = RoomState {
+            title: "视".repeat(20),
+            ..state
+        };
+        manager.state_limits.clamp(&mut generated);
+        assert_eq!(generated.title.chars().count(), 16);
+        manager
+            .update_state("room", &host, generated, true)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn markers_broadcast_and_clear_on_source_change() {
+        let manager = Arc::new(Manager::new(None, true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "member", &member, ClientSender::Ws(tx))
+            .await;
+        let ctx = |user: &str| WsContext {
+            room: "room".into(),
+            temp_user: user.to_string(),
+            client_id: user.to_string(),
+            debug: false,
+            acks: false,
+        };
+        let update = |url: &str| {
+            Message::Text(format!(
+                r#"{{"type":"host_update","state":{{"url":"{url}","title":"t","currentTime":1,"duration":100,"paused":true,"playbackRate":1.0,"sourceType":"file","updatedAt":0}}}}"#
+            ))
+        };
+        let marker =
+            Message::Text(r#"{"type":"add_marker","position":42.5,"label":"watch this"}"#.into());
+
+        handle_ws_message(update("/media/a"), &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+        rx.recv().await.unwrap();
+        assert!(
+            handle_ws_message(marker.clone(), &manager, &hub, &ctx(&member))
+                .await
+                .is_err()
+        );
+        handle_ws_message(marker, &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+        let Some(Message::Text(text)) = rx.recv().await else {
+            panic!("expected markers broadcast");
+        };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["type"], "markers");
+        assert_eq!(msg["markers"][0]["position"], 42.5);
+        assert_eq!(msg["markers"][0]["label"], "watch this");
+
+        handle_ws_message(update("/media/a"), &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+        assert_eq!(manager.markers("room").await.len(), 1);
+        handle_ws_message(update("/media/b"), &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+        assert!(manager.markers("room").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn muted_users_chat_is_dropped() {
+        let manager = Arc::new(Manager::new(None, true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "host", &host, ClientSender::Ws(tx))
+            .await;
+        let ctx = |user: &str| WsContext {
+            room: "room".into(),
+            temp_user: user.to_string(),
+            client_id: user.to_string(),
+            debug: false,
+            acks: false,
+        };
+        let send = |text: String| Message::Text(text);
+        let chat = || send(r#"{"type":"chat","text":"hi"}"#.into());
+        let target = member_id(&manager, "room", &member).await;
+        let moderate = |kind: &str| send(format!(r#"{{"type":"{kind}","target":"{target}"}}"#));
 
-        let view: ViewResp = client
-            .get("https://api.bilibili.com/x/web-interface/view")
-            .query(&[("bvid", &bvid)])
-            .send()
+        assert!(
+            handle_ws_message(moderate("mute_user"), &manager, &hub, &ctx(&member))
+                .await
+                .is_err()
+        );
+        handle_ws_message(moderate("mute_user"), &manager, &hub, &ctx(&host))
             .await
-            .map_err(|e| ApiError::bad_request(format!("view request failed: {e}")))?
-            .json()
+            .unwrap();
+        handle_ws_message(chat(), &manager, &hub, &ctx(&member))
             .await
-            .map_err(|e| ApiError::bad_request(format!("view parse failed: {e}")))?;
-
-        let cid = view.data.cid;
-        let mut params = BTreeMap::new();
-        params.insert("bvid".into(), bvid.clone());
-        params.insert("cid".into(), cid.to_string());
-        params.insert("qn".into(), "112".into()); // 1080P+ 高码率
-        params.insert("fnval".into(), "1".into()); // MP4 格式（包含音频），fnval=16 是 DASH（音视频分离）
-        params.insert("fourk".into(), "1".into());
+            .unwrap();
+        assert!(rx.try_recv().is_err());
 
-        let query = wbi_sign(&client, params).await?;
-        let play_url = format!("https://api.bilibili.com/x/player/wbi/playurl?{query}");
-        let play_resp: PlayUrlResp = client
-            .get(play_url)
-            .send()
+        handle_ws_message(moderate("unmute_user"), &manager, &hub, &ctx(&host))
             .await
-            .map_err(|e| ApiError::bad_request(format!("playurl request failed: {e}")))?
-            .json()
+            .unwrap();
+        handle_ws_message(chat(), &manager, &hub, &ctx(&member))
             .await
-            .map_err(|e| ApiError::bad_request(format!("playurl parse failed: {e}")))?;
-        if play_resp.code != 0 {
-            return Err(ApiError::bad_request(format!(
-                "playurl error: {}",
-                play_resp.message
-            )));
-        }
-        // 使用传统 durl 格式（MP4，包含音频）
-        let media_url = if let Some(d) = play_resp.data.durl.first() {
-            d.url.clone()
-        } else if let Some(dash) = &play_resp.data.dash {
-            // DASH 格式音视频分离，需要客户端支持 MSE，这里暂不支持
-            return Err(ApiError::bad_request("DASH format not supported (audio/video separated)"));
-        } else {
-            return Err(ApiError::bad_request("no playable stream"));
+            .unwrap();
+        let Some(Message::Text(text)) = rx.recv().await else {
+            panic!("expected chat broadcast");
         };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["type"], "chat");
+        assert_eq!(msg["chat"]["from"], target.as_str());
+        assert_eq!(msg["chat"]["text"], "hi");
 
-        let token = Uuid::new_v4().to_string();
-        self.media_tokens.write().await.insert(
-            token.clone(),
-            MediaToken {
-                target: MediaTarget::Remote(RemoteTarget {
-                    url: media_url,
-                    strategy: RemoteStrategy::ProxyWithHeaders,
-                }),
-                expires_at: Instant::now() + self.token_ttl,
-            },
-        );
-        Ok(ResolvedMedia {
-            url: format!("/media/{token}"),
-            token,
-            source_type: "bili".into(),
-            cover: view.data.pic,
-        })
+        // 令牌桶耗尽后限速。
+        for _ in 1..CHAT_BURST as usize {
+            handle_ws_message(chat(), &manager, &hub, &ctx(&member))
+                .await
+                .unwrap();
+        }
+        assert!(handle_ws_message(chat(), &manager, &hub, &ctx(&member))
+            .await
+            .is_err());
     }
 
-    async fn cleanup(&self) {
-        let mut rooms = self.rooms.write().await;
-        let mut tokens = self.media_tokens.write().await;
-        let now = Instant::now();
-        rooms.retain(|_, room| {
-            let mut last_seen = room.last_update.unwrap_or(now);
-            for seen in room.members.values() {
-                if *seen > last_seen {
-                    last_seen = *seen;
+    #[tokio::test]
+    async fn danmaku_segments_become_sorted_track() {
+        let seen = Arc::new(StdMutex::new(Vec::new()));
+        let log = seen.clone();
+        let router = mock_bili_router(
+            json!({
+                "code": 0,
+                "message": "0",
+                "data": { "quality": 80, "durl": [{ "url": "https://upos.bilivideo.com/v.mp4" }] }
+            }),
+            Arc::new(AtomicUsize::new(0)),
+        )
+        .route(
+            "/x/v2/dm/web/seg.so",
+            get(move |Query(params): Query<HashMap<String, String>>| {
+                log.lock().unwrap().push(params.clone());
+                async move {
+                    danmaku::tests::encode(&[
+                        (90_000, 1, 0xFFFFFF, "第二条"),
+                        (1_250, 5, 0xFF0000, "第一条"),
+                    ])
                 }
-            }
-            now.duration_since(last_seen) <= self.room_ttl
-        });
-        tokens.retain(|_, token| now <= token.expires_at);
-    }
-}
+            }),
+        );
+        let base = spawn_mock(router).await;
+        let manager = Manager::new(None, true).with_bili_api_base(base);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
 
-#[derive(Clone)]
-struct Hub {
-    clients: Arc<RwLock<HashMap<String, HashMap<String, mpsc::UnboundedSender<Message>>>>>,
-}
+        let plain = manager
+            .resolve_media_with(
+                "room",
+                "pwd",
+                &host,
+                "BV1xx411c7mD",
+                &ResolveOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(plain.danmaku_url.is_none());
+        assert!(seen.lock().unwrap().is_empty());
 
-impl Hub {
-    fn new() -> Self {
-        Self {
-            clients: Arc::new(RwLock::new(HashMap::new())),
-        }
-    }
+        let options = ResolveOptions {
+            danmaku: true,
+            ..Default::default()
+        };
+        let res = manager
+            .resolve_media_with("room", "pwd", &host, "BV1xx411c7mD", &options)
+            .await
+            .unwrap();
+        let params = seen.lock().unwrap().clone();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0]["oid"], "1176840");
+        assert_eq!(params[0]["segment_index"], "1");
 
-    async fn register(&self, room: &str, client_id: &str, tx: mpsc::UnboundedSender<Message>) {
-        let mut clients = self.clients.write().await;
-        let room_clients = clients.entry(room.to_string()).or_default();
-        room_clients.insert(client_id.to_string(), tx);
+        let token = res
+            .danmaku_url
+            .unwrap()
+            .trim_start_matches("/media/")
+            .to_string();
+        let MediaTarget::Inline(inline) = manager.open_media(&token).await.unwrap() else {
+            panic!("expected inline danmaku track");
+        };
+        assert_eq!(inline.content_type, "application/json");
+        let track: serde_json::Value = serde_json::from_slice(&inline.body).unwrap();
+        assert_eq!(
+            track,
+            json!([
+                { "time": 1.25, "mode": 5, "color": 0xFF0000, "text": "第一条" },
+                { "time": 90.0, "mode": 1, "color": 0xFFFFFF, "text": "第二条" }
+            ])
+        );
     }
 
-    async fn unregister(&self, room: &str, client_id: &str) {
-        let mut clients = self.clients.write().await;
-        if let Some(room_clients) = clients.get_mut(room) {
-            room_clients.remove(client_id);
-            if room_clients.is_empty() {
-                clients.remove(room);
-            }
+    #[tokio::test]
+    async fn token_reuse_returns_same_token_for_same_file() {
+        let root = std::env::temp_dir().join("vo_sync_token_reuse");
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("same.mp4");
+        write_mp4(&file_path);
+        let path = file_path.to_str().unwrap();
+
+        for reuse in [false, true] {
+            let manager = Manager::new(Some(root.clone()), true).with_token_reuse(reuse);
+            let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+            let first = manager
+                .resolve_media_path("room", "pwd", &host, path)
+                .await
+                .unwrap();
+            let second = manager
+                .resolve_media_path("room", "pwd", &host, path)
+                .await
+                .unwrap();
+            assert_eq!(first.token == second.token, reuse);
+            assert_eq!(
+                manager.media_tokens.read().await.len(),
+                if reuse { 1 } else { 2 }
+            );
         }
     }
 
-    async fn broadcast_state(&self, room: &str, state: &RoomState) {
-        let payload = Message::Text(
-            serde_json::to_string(&WsOutgoing {
-                r#type: "room_state".into(),
-                state: Some(state.clone()),
-                error: None,
-            })
-            .unwrap(),
+    #[tokio::test]
+    async fn member_playback_errors_reach_host() {
+        let manager = Arc::new(Manager::new(None, true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (host_tx, mut host_rx) = mpsc::unbounded_channel();
+        let (member_tx, mut member_rx) = mpsc::unbounded_channel();
+        hub.register("room", "host", &host, ClientSender::Ws(host_tx))
+            .await;
+        hub.register("room", "member", &member, ClientSender::Ws(member_tx))
+            .await;
+        let ctx = WsContext {
+            room: "room".into(),
+            temp_user: member.clone(),
+            client_id: "member".into(),
+            debug: false,
+            acks: false,
+        };
+        let state = RoomState {
+            title: "a".into(),
+            duration: 10.0,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
+
+        let report = Message::Text(
+            r#"{"type":"playback_error","code":"MEDIA_ERR_NETWORK","message":"403 from upstream"}"#
+                .into(),
         );
-        let mut clients = self.clients.write().await;
-        if let Some(room_clients) = clients.get_mut(room) {
-            room_clients.retain(|_, tx| tx.send(payload.clone()).is_ok());
-        }
-    }
+        handle_ws_message(report, &manager, &hub, &ctx)
+            .await
+            .unwrap();
+        let Some(Message::Text(text)) = host_rx.recv().await else {
+            panic!("expected playback error for host");
+        };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["type"], "playback_error");
+        let member_public = member_id(&manager, "room", &member).await;
+        assert_eq!(msg["playbackError"]["user"], member_public.as_str());
+        assert!(!text.contains(&member));
+        assert_eq!(msg["playbackError"]["code"], "MEDIA_ERR_NETWORK");
+        assert_eq!(msg["playbackError"]["url"], "/media/a");
+        // 两名成员中一人报错，达到默认的一半比例。
+        assert_eq!(msg["sourceFlagged"], true);
+        assert!(member_rx.try_recv().is_err());
 
-    async fn send_to(&self, room: &str, client_id: &str, msg: WsOutgoing) -> Result<(), ApiError> {
-        let mut clients = self.clients.write().await;
-        if let Some(room_clients) = clients.get_mut(room) {
-            if let Some(tx) = room_clients.get(client_id) {
-                let payload =
-                    Message::Text(serde_json::to_string(&msg).unwrap_or_else(|_| "{}".into()));
-                tx.send(payload)
-                    .map_err(|_| ApiError::bad_request("send error"))?;
-                return Ok(());
-            }
-        }
-        Err(ApiError::not_found("client not found"))
+        let rooms = manager.playback_errors().await;
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(
+            rooms[0].errors[0].message.as_deref(),
+            Some("403 from upstream")
+        );
+        assert_eq!(rooms[0].errors[0].user, member_public);
+        assert!(!serde_json::to_string(&rooms).unwrap().contains(&member));
     }
-}
 
-async fn bind_listener(addr: &str) -> anyhow::Result<(TcpListener, SocketAddr)> {
-    let mut candidates = Vec::new();
-    candidates.push(addr.to_string());
-    if addr == DEFAULT_LISTEN_ADDR {
-        for port in 18080..18090 {
-            candidates.push(format!("127.0.0.1:{port}"));
-        }
-        candidates.push("127.0.0.1:0".to_string());
-    }
-    for candidate in candidates {
-        match TcpListener::bind(&candidate).await {
-            Ok(listener) => {
-                let local = listener.local_addr()?;
-                if candidate != addr {
-                    warn!("sync server fallback to {}", local);
-                }
-                return Ok((listener, local));
-            }
-            Err(err) => {
-                warn!("bind {} failed: {err}", candidate);
-            }
-        }
+    #[tokio::test]
+    async fn stalled_upstream_times_out_with_504() {
+        let upstream = spawn_mock(Router::new().route(
+            "/slow.mp4",
+            get(|| async {
+                tokio_time::sleep(Duration::from_secs(5)).await;
+                "late"
+            }),
+        ))
+        .await;
+        let manager = Manager::new(None, true).with_proxy_timeouts(ProxyTimeouts {
+            connect: Duration::from_secs(1),
+            first_byte: Duration::from_millis(200),
+        });
+        let target = MediaTarget::Remote(RemoteTarget {
+            url: format!("{upstream}/slow.mp4"),
+            strategy: RemoteStrategy::ProxyWithHeaders,
+        });
+        let token = manager.mint_token("room", target).await;
+        let state = AppState {
+            manager: Arc::new(manager),
+            hub: Arc::new(Hub::new()),
+        };
+        let base = spawn_mock(build_router(state)).await;
+
+        let started = Instant::now();
+        let resp = reqwest::get(format!("{base}/media/{token}")).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(resp
+            .text()
+            .await
+            .unwrap()
+            .contains("no response within 0.2s"));
     }
-    Err(anyhow::anyhow!(
-        "failed to bind sync server; consider setting {}",
-        ENV_LISTEN_ADDR
-    ))
-}
 
-fn now_millis() -> i64 {
-    let now = OffsetDateTime::now_utc();
-    (now.unix_timestamp_nanos() / 1_000_000)
-        .try_into()
-        .unwrap_or(i64::MAX)
-}
+    #[tokio::test]
+    async fn coordinated_play_fires_once_all_members_are_ready() {
+        let manager = Arc::new(Manager::new(None, true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (a, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (b, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (host_tx, mut host_rx) = mpsc::unbounded_channel();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (b_tx, _b_rx) = mpsc::unbounded_channel();
+        hub.register("room", "host", &host, ClientSender::Ws(host_tx))
+            .await;
+        hub.register("room", "a", &a, ClientSender::Ws(tx)).await;
+        hub.register("room", "b", &b, ClientSender::Ws(b_tx)).await;
+        let ctx = |user: &str| WsContext {
+            room: "room".into(),
+            temp_user: user.to_string(),
+            client_id: user.to_string(),
+            debug: false,
+            acks: false,
+        };
+        let send = |text: &str| Message::Text(text.to_string());
+        let next = |rx: &mut mpsc::UnboundedReceiver<Message>| {
+            let Ok(Message::Text(text)) = rx.try_recv() else {
+                panic!("expected message");
+            };
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
 
-fn clean_path<P: AsRef<Path>>(path: P) -> PathBuf {
-    let p = path.as_ref();
-    std::fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf())
-}
+        handle_ws_message(
+            send(r#"{"type":"host_update","state":{"url":"/media/a","title":"a","currentTime":30,"duration":600,"paused":true,"playbackRate":1.0,"sourceType":"file","updatedAt":0}}"#),
+            &manager,
+            &hub,
+            &ctx(&host),
+        )
+        .await
+        .unwrap();
+        next(&mut host_rx);
+        next(&mut rx);
+        assert!(
+            handle_ws_message(send(r#"{"type":"await_ready"}"#), &manager, &hub, &ctx(&a))
+                .await
+                .is_err()
+        );
+        handle_ws_message(
+            send(r#"{"type":"await_ready","startDelayMs":500}"#),
+            &manager,
+            &hub,
+            &ctx(&host),
+        )
+        .await
+        .unwrap();
+        let tally = next(&mut host_rx);
+        assert_eq!(
+            (tally["readyCount"].as_u64(), tally["readyTotal"].as_u64()),
+            (Some(0), Some(2))
+        );
 
-fn is_under_root(path: &Path, root: &Path) -> bool {
-    path.starts_with(root)
-}
+        handle_ws_message(send(r#"{"type":"ready"}"#), &manager, &hub, &ctx(&a))
+            .await
+            .unwrap();
+        assert_eq!(next(&mut host_rx)["readyCount"], 1);
+        assert!(rx.try_recv().is_err());
+        assert!(manager.latest_state("room").await.unwrap().paused);
 
-fn is_bilibili_source(input: &str) -> Option<&str> {
-    if input.starts_with("BV")
-        || input.starts_with("bv")
-        || input.starts_with("ep")
-        || input.starts_with("EP")
-    {
-        return Some(input);
-    }
-    if input.starts_with("http://") || input.starts_with("https://") {
-        if input.contains("bilibili.com") || input.contains("bilivideo.com") {
-            return Some(input);
-        }
+        handle_ws_message(send(r#"{"type":"ready"}"#), &manager, &hub, &ctx(&b))
+            .await
+            .unwrap();
+        assert_eq!(next(&mut host_rx)["readyCount"], 2);
+        let play = next(&mut rx);
+        assert_eq!(play["type"], "coordinated_play");
+        assert_eq!(play["state"]["paused"], false);
+        assert_eq!(play["state"]["currentTime"], 30.0);
+        let state = manager.latest_state("room").await.unwrap();
+        assert!(!state.paused);
+        assert_eq!(play["startAt"], state.updated_at);
     }
-    None
-}
 
-fn copy_header(headers: &HeaderMap, key: axum::http::header::HeaderName, builder: &mut Builder) {
-    if let Some(val) = headers.get(&key) {
-        if let Some(map) = builder.headers_mut() {
-            map.insert(key.clone(), val.clone());
+    #[tokio::test]
+    async fn ready_tally_skips_departed_members() {
+        let manager = Manager::new(None, true);
+        let hub = Hub::new();
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (a, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (b, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (gone, _) = manager.join_room("room", "pwd").await.unwrap();
+        let mut receivers = Vec::new();
+        for user in [&host, &a, &b, &gone] {
+            let (tx, rx) = mpsc::unbounded_channel();
+            hub.register("room", user, user, ClientSender::Ws(tx)).await;
+            receivers.push(rx);
         }
-    }
-}
-
-async fn log_requests(req: Request<Body>, next: Next) -> impl IntoResponse {
-    info!("sync request {} {}", req.method(), req.uri());
-    let res = next.run(req).await;
-    res
-}
-
-const MIXIN_KEY_ENC_TAB: [usize; 64] = [
-    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29,
-    28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25,
-    54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52,
-];
-
-#[derive(Debug, Deserialize)]
-struct NavResp {
-    data: NavData,
-}
-
-#[derive(Debug, Deserialize)]
-struct NavData {
-    wbi_img: WbiImg,
-}
+        let state = RoomState {
+            title: "a".into(),
+            duration: 600.0,
+            ..room_state("/media/a")
+        };
+        manager
+            .update_state("room", &host, state, true)
+            .await
+            .unwrap();
 
-#[derive(Debug, Deserialize)]
-struct WbiImg {
-    img_url: String,
-    sub_url: String,
-}
+        // 离开过的成员仍留在 members 里，但不计入就绪人数
+        hub.unregister("room", &gone).await;
+        let online = hub.connected_users("room").await;
+        let tally = manager
+            .await_ready("room", &host, None, None, &online)
+            .await
+            .unwrap();
+        assert_eq!((tally.ready, tally.total), (0, 2));
+        let tally = manager.mark_ready("room", &a, &online).await.unwrap();
+        assert_eq!((tally.ready, tally.total), (1, 2));
+        assert!(tally.start.is_none());
 
-#[derive(Debug, Deserialize)]
-struct ViewResp {
-    data: ViewData,
-}
+        // 握手期间剩下未就绪的成员断线，重新统计后随即起播
+        hub.unregister("room", &b).await;
+        let online = hub.connected_users("room").await;
+        let tally = manager.retally_ready("room", &online).await.unwrap();
+        assert_eq!((tally.ready, tally.total), (1, 1));
+        assert!(tally.start.is_some());
+        assert!(manager.retally_ready("room", &online).await.is_none());
+    }
 
-#[derive(Debug, Deserialize)]
-struct ViewData {
-    bvid: String,
-    cid: i64,
-    title: String,
-    #[serde(default)]
-    pic: Option<String>, // Bilibili cover
-    #[serde(default)]
-    duration: i64,
-}
+    #[tokio::test]
+    async fn concat_range_spans_part_boundary() {
+        let root = std::env::temp_dir().join("vo_sync_concat_parts");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        write_mp4(&root.join("movie.part1.mp4"));
+        let first = std::fs::read(root.join("movie.part1.mp4")).unwrap();
+        std::fs::write(root.join("movie.part2.mp4"), b"0123456789").unwrap();
 
-#[derive(Debug, Deserialize)]
-struct PlayUrlResp {
-    code: i32,
-    message: String,
-    data: PlayUrlData,
-}
+        let manager = Manager::new(Some(root.clone()), true);
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let path = root.join("movie.part1.mp4");
+        let single = manager
+            .resolve_media_path("room", "pwd", &host, path.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(single.source_type, SourceType::File);
+        let options = ResolveOptions {
+            join_parts: true,
+            ..Default::default()
+        };
+        let res = manager
+            .resolve_media_with("room", "pwd", &host, path.to_str().unwrap(), &options)
+            .await
+            .unwrap();
+        assert_eq!(res.source_type, SourceType::Concat);
+        let state = AppState {
+            manager: Arc::new(manager),
+            hub: Arc::new(Hub::new()),
+        };
+        let base = spawn_mock(build_router(state)).await;
 
-#[derive(Debug, Deserialize)]
-struct PlayUrlData {
-    #[serde(default)]
-    durl: Vec<Durl>,
-    #[serde(default)]
-    dash: Option<Dash>,
-}
+        let total = first.len() + 10;
+        let start = first.len() - 3;
+        let resp = reqwest::Client::new()
+            .get(format!("{base}{}", res.url))
+            .header("range", format!("bytes={start}-{}", first.len() + 4))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 206);
+        assert_eq!(
+            resp.headers()["content-range"],
+            format!("bytes {start}-{}/{total}", first.len() + 4).as_str()
+        );
+        assert_eq!(resp.headers()["content-length"], "8");
+        let mut expected = first[start..].to_vec();
+        expected.extend_from_slice(b"01234");
+        assert_eq!(resp.bytes().await.unwrap().as_ref(), expected.as_slice());
 
-#[derive(Debug, Deserialize)]
-struct Durl {
-    url: String,
-}
+        let resp = reqwest::get(format!("{base}{}", res.url)).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(resp.bytes().await.unwrap().len(), total);
+    }
 
-#[derive(Debug, Deserialize)]
-struct Dash {
-    #[serde(default)]
-    video: Vec<DashStream>,
-}
+    #[tokio::test]
+    async fn media_is_served_only_on_media_listener() {
+        let root = std::env::temp_dir().join("vo_sync_media_listener");
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("split.mp4");
+        write_mp4(&file_path);
+        let bytes = std::fs::read(&file_path).unwrap();
 
-#[derive(Debug, Deserialize)]
-struct DashStream {
-    #[serde(rename = "baseUrl")]
-    base_url: String,
-}
+        let media_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let media_addr = media_listener.local_addr().unwrap();
+        let manager =
+            Manager::new(Some(root.clone()), true).with_media_port(Some(media_addr.port()));
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let state = AppState {
+            manager: Arc::new(manager),
+            hub: Arc::new(Hub::new()),
+        };
+        let media_router = build_media_router(state.clone());
+        tokio::spawn(async move { axum::serve(media_listener, media_router).await.unwrap() });
+        let base = spawn_mock(build_router(state)).await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
 
-fn extract_bvid(input: &str) -> Option<String> {
-    if let Some(idx) = input.find("BV") {
-        let slice = &input[idx..];
-        let mut bvid = String::new();
-        for c in slice.chars() {
-            if c.is_alphanumeric() {
-                bvid.push(c);
-                if bvid.len() >= 12 {
-                    break;
-                }
-            } else if !bvid.is_empty() {
-                break;
-            }
-        }
-        if bvid.starts_with("BV") && bvid.len() >= 10 {
-            return Some(bvid);
-        }
-    }
-    if input.starts_with("BV") && input.len() >= 10 {
-        return Some(input.to_string());
-    }
-    None
-}
+        let res: serde_json::Value = client
+            .post(format!("{base}/api/media/resolve"))
+            .json(&json!({
+                "room": "room",
+                "password": "pwd",
+                "tempUser": host,
+                "path": file_path.to_str().unwrap(),
+                "autoPublish": false,
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let url = res["url"].as_str().unwrap();
+        let token = res["token"].as_str().unwrap();
+        assert_eq!(
+            url,
+            format!("http://127.0.0.1:{}/media/{token}", media_addr.port())
+        );
 
-async fn wbi_sign(
-    client: &reqwest::Client,
-    mut params: BTreeMap<String, String>,
-) -> Result<String, ApiError> {
-    let nav: NavResp = client
-        .get("https://api.bilibili.com/x/web-interface/nav")
-        .send()
-        .await
-        .map_err(|e| ApiError::bad_request(format!("nav request failed: {e}")))?
-        .json()
-        .await
-        .map_err(|e| ApiError::bad_request(format!("nav parse failed: {e}")))?;
-    let img_key = nav
-        .data
-        .wbi_img
-        .img_url
-        .rsplit('/')
-        .next()
-        .and_then(|s| s.split('.').next())
-        .unwrap_or("");
-    let sub_key = nav
-        .data
-        .wbi_img
-        .sub_url
-        .rsplit('/')
-        .next()
-        .and_then(|s| s.split('.').next())
-        .unwrap_or("");
-    let mixin_source = format!("{img_key}{sub_key}");
-    let mixin_key: String = MIXIN_KEY_ENC_TAB
-        .iter()
-        .filter_map(|idx| mixin_source.chars().nth(*idx))
-        .take(32)
-        .collect();
-    let curr_time = OffsetDateTime::now_utc().unix_timestamp();
-    params.insert("wts".into(), curr_time.to_string());
+        let media = client.get(url).send().await.unwrap();
+        assert_eq!(media.status().as_u16(), 200);
+        assert_eq!(media.bytes().await.unwrap().as_ref(), bytes.as_slice());
 
-    let chr_filter = |s: &str| s.replace(&['\'', '!', '(', ')', '*'][..], "");
-    let query = params
-        .iter()
-        .map(|(k, v)| (k.as_str(), chr_filter(v)))
-        .collect::<Vec<_>>();
-    let mut query_sorted = query;
-    query_sorted.sort_by(|a, b| a.0.cmp(b.0));
-    let encoded = query_sorted
-        .into_iter()
-        .map(|(k, v)| {
-            format!(
-                "{}={}",
-                utf8_percent_encode(k, NON_ALPHANUMERIC),
-                utf8_percent_encode(&v, NON_ALPHANUMERIC)
-            )
-        })
-        .collect::<Vec<_>>()
-        .join("&");
-    let sign = format!("{:x}", md5::compute(format!("{encoded}{mixin_key}")));
-    Ok(format!("{encoded}&w_rid={sign}"))
-}
+        let primary = client
+            .get(format!("{base}/media/{token}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(primary.status().as_u16(), 307);
+        assert_eq!(primary.headers()["location"], url);
+        assert!(primary.bytes().await.unwrap().is_empty());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::{fs::File as StdFile, io::Write};
+        let api_on_media = client
+            .get(format!("http://{media_addr}/api/media/library"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(api_on_media.status().as_u16(), 404);
+    }
 
     #[tokio::test]
-    async fn join_and_authorize_flow() {
+    async fn unrooted_paths_get_specific_errors() {
+        let dir = std::env::temp_dir().join("vo_sync_unrooted");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("clip.mp4");
+        write_mp4(&file_path);
+        let absolute = file_path.to_str().unwrap();
+
         let manager = Manager::new(None, true);
-        let (host, is_host) = manager.join_room("room", "pwd").await.unwrap();
-        assert!(is_host);
-        let (member, member_host) = manager.join_room("room", "pwd").await.unwrap();
-        assert!(!member_host);
-        assert!(manager.authorize("room", "pwd", &host).await.unwrap());
-        assert!(!manager.authorize("room", "pwd", &member).await.unwrap());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let err = manager
+            .resolve_media_path("room", "pwd", &host, "videos/clip.mp4")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains("relative path"));
+        let err = manager
+            .resolve_media_path("room", "pwd", &host, absolute)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains(ENV_ALLOW_ABSOLUTE_WITHOUT_ROOT));
+
+        let manager = Manager::new(None, true).with_root_policy(root_policy::RootPolicy {
+            allow_unrooted: true,
+            ..Default::default()
+        });
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let err = manager
+            .resolve_media_path("room", "pwd", &host, "videos/clip.mp4")
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("relative path"));
+        let resolved = manager
+            .resolve_media_path("room", "pwd", &host, absolute)
+            .await
+            .unwrap();
+        assert_eq!(resolved.source_type, SourceType::File);
+        let err = manager
+            .resolve_media_path("room", "pwd", &host, "/etc/hosts")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
     }
 
     #[tokio::test]
-    async fn member_control_merges_playback_only() {
-        let manager = Manager::new(None, true);
+    async fn paused_rooms_with_host_get_periodic_resync() {
+        let manager =
+            Arc::new(Manager::new(None, false).with_paused_resync(Some(Duration::from_millis(50))));
+        let hub = Arc::new(Hub::new());
         let (host, _) = manager.join_room("room", "pwd").await.unwrap();
         let (member, _) = manager.join_room("room", "pwd").await.unwrap();
-        let host_state = RoomState {
-            url: "file:///movie.mp4".into(),
-            title: "Movie".into(),
-            current_time: 0.0,
-            duration: 120.0,
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "member", &member, ClientSender::Ws(tx))
+            .await;
+        let (host_tx, _host_rx) = mpsc::unbounded_channel();
+        hub.register("room", "host", &host, ClientSender::Ws(host_tx))
+            .await;
+        // 另一个房间的房主不在线。
+        let (absent, _) = manager.join_room("other", "pwd").await.unwrap();
+        let (other, _) = manager.join_room("other", "pwd").await.unwrap();
+        let (other_tx, mut other_rx) = mpsc::unbounded_channel();
+        hub.register("other", "member", &other, ClientSender::Ws(other_tx))
+            .await;
+        let mut state = RoomState {
+            title: "a".into(),
+            current_time: 42.0,
+            duration: 600.0,
             paused: false,
-            playback_rate: 1.0,
-            source_type: "file".into(),
-            updated_at: 0,
-            cover: None,
+            ..room_state("/media/a")
         };
         manager
-            .update_state("room", &host, host_state, true)
+            .update_state("room", &host, state.clone(), true)
+            .await
+            .unwrap();
+        // 播放中的房间由 seek_correction 负责，不在此重发。
+        assert!(manager.paused_resyncs().await.is_empty());
+        state.paused = true;
+        manager
+            .update_state("room", &host, state.clone(), true)
+            .await
+            .unwrap();
+        manager
+            .update_state("other", &absent, state, true)
             .await
             .unwrap();
 
-        let member_update = RoomState {
-            url: "hijack".into(),
-            title: "hijack".into(),
+        manager.spawn_cleanup(hub.clone());
+        tokio_time::sleep(Duration::from_millis(180)).await;
+        manager.shutdown();
+        let mut resyncs = 0;
+        while let Ok(Message::Text(text)) = rx.try_recv() {
+            let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if msg["reason"] == "paused_resync" {
+                assert_eq!(msg["type"], "room_state");
+                assert_eq!(msg["state"]["paused"], true);
+                assert_eq!(msg["state"]["currentTime"], 42.0);
+                resyncs += 1;
+            }
+        }
+        assert!(resyncs >= 2, "{resyncs}");
+        while let Ok(Message::Text(text)) = other_rx.try_recv() {
+            assert!(!text.contains("paused_resync"), "{text}");
+        }
+    }
+
+    #[tokio::test]
+    async fn host_focus_loss_pauses_and_regain_resumes() {
+        let manager = Arc::new(Manager::new(None, false).with_focus_pause(true));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "member", &member, ClientSender::Ws(tx))
+            .await;
+        let state = RoomState {
+            title: "a".into(),
             current_time: 30.0,
-            duration: 999.0,
-            paused: true,
-            playback_rate: 1.5,
-            source_type: "other".into(),
-            updated_at: 0,
-            cover: None,
+            duration: 600.0,
+            paused: false,
+            updated_at: now_millis(),
+            ..room_state("/media/a")
         };
-        let merged = manager
-            .update_state("room", &member, member_update, false)
+        manager
+            .update_state("room", &host, state, true)
             .await
             .unwrap();
-        assert_eq!(merged.url, "file:///movie.mp4");
-        assert_eq!(merged.title, "Movie");
-        assert_eq!(merged.duration, 120.0);
-        assert_eq!(merged.source_type, "file");
-        assert_eq!(merged.current_time, 30.0);
-        assert_eq!(merged.paused, true);
-        assert_eq!(merged.playback_rate, 1.5);
+
+        let ctx = |temp_user: &str| WsContext {
+            room: "room".into(),
+            temp_user: temp_user.into(),
+            client_id: "client".into(),
+            debug: false,
+            acks: false,
+        };
+        let blur = Message::Text(r#"{"type":"host_focus","focused":false}"#.into());
+        let err = handle_ws_message(blur.clone(), &manager, &hub, &ctx(&member))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        handle_ws_message(blur, &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+        let Ok(Message::Text(text)) = rx.try_recv() else {
+            panic!("member should receive the focus pause");
+        };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["type"], "room_state");
+        assert_eq!(msg["reason"], "host_focus");
+        assert_eq!(msg["state"]["paused"], true);
+        assert!(manager.latest_state("room").await.unwrap().paused);
+        assert!(!manager.room_settings("room").await.unwrap().host_focused);
+
+        // 协同房主的上报不会覆盖主房主的焦点。
+        let focus = Message::Text(r#"{"type":"host_focus","focused":true}"#.into());
+        let (co_host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let co_host_id = member_id(&manager, "room", &co_host).await;
+        manager
+            .set_co_host("room", "pwd", &host, &co_host_id, true)
+            .await
+            .unwrap();
+        let err = handle_ws_message(focus.clone(), &manager, &hub, &ctx(&co_host))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(!manager.room_settings("room").await.unwrap().host_focused);
+
+        handle_ws_message(focus.clone(), &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+        let Ok(Message::Text(text)) = rx.try_recv() else {
+            panic!("member should receive the resume");
+        };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["state"]["paused"], false);
+
+        // 房主手动暂停后，重新聚焦不会擅自恢复播放。
+        let mut paused = manager.latest_state("room").await.unwrap();
+        paused.paused = true;
+        manager
+            .update_state("room", &host, paused, true)
+            .await
+            .unwrap();
+        handle_ws_message(focus, &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+        assert!(manager.latest_state("room").await.unwrap().paused);
+    }
+
+    #[test]
+    fn source_types_keep_wire_strings() {
+        let cases = [
+            (SourceType::File, "file"),
+            (SourceType::Transcode, "transcode"),
+            (SourceType::Concat, "concat"),
+            (SourceType::Disc, "disc"),
+            (SourceType::Remote, "remote"),
+            (SourceType::Bili, "bili"),
+            (SourceType::BiliAudio, "bili-audio"),
+            (SourceType::Inline, "inline"),
+            (SourceType::Unknown, "unknown"),
+        ];
+        for (source_type, wire) in cases {
+            assert_eq!(serde_json::to_value(&source_type).unwrap(), json!(wire));
+            let parsed: SourceType = serde_json::from_value(json!(wire)).unwrap();
+            assert_eq!(parsed, source_type);
+        }
+    }
+
+    #[test]
+    fn misspelled_source_type_is_rejected() {
+        for wire in ["flie", "bili_audio", "local", ""] {
+            assert!(serde_json::from_value::<SourceType>(json!(wire)).is_err());
+        }
+        let state = json!({
+            "url": "/media/a",
+            "title": "a",
+            "currentTime": 0.0,
+            "duration": 10.0,
+            "paused": true,
+            "playbackRate": 1.0,
+            "sourceType": "flie",
+            "updatedAt": 0
+        });
+        assert!(serde_json::from_value::<RoomState>(state).is_err());
     }
 
     #[tokio::test]
-    async fn resolve_local_media_and_block_online() {
-        let root = std::env::temp_dir().join("vo_sync_test");
+    async fn media_responses_expose_range_headers() {
+        let root = std::env::temp_dir().join("vo_sync_cors_expose");
         std::fs::create_dir_all(&root).unwrap();
-        let file_path = root.join("sample.mp4");
-        let mut file = StdFile::create(&file_path).unwrap();
-        writeln!(file, "dummy").unwrap();
-
-        let manager = Manager::new(Some(root.clone()), true);
+        let file_path = root.join("clip.mp4");
+        write_mp4(&file_path);
+        let manager = Manager::new(Some(root), true)
+            .with_cors_expose(vec![HeaderName::from_static("x-quality")]);
         let (host, _) = manager.join_room("room", "pwd").await.unwrap();
-        let res = manager
+        let resolved = manager
             .resolve_media_path("room", "pwd", &host, file_path.to_str().unwrap())
             .await
-            .expect("should generate token");
-        assert_eq!(res.source_type, "file");
-        assert!(res.url.contains("/media/"));
-        assert!(!res.token.is_empty());
-
-        let err = manager
-            .resolve_media_path("room", "pwd", &host, "https://bilibili.com/video/BVxxx")
-            .await
-            .unwrap_err();
-        let resp = err.into_response();
-        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            .unwrap();
+        let base = spawn_mock(build_router(AppState {
+            manager: Arc::new(manager),
+            hub: Arc::new(Hub::new()),
+        }))
+        .await;
 
-        let remote = manager
-            .resolve_media_path("room", "pwd", &host, "https://example.com/video.mp4")
+        let resp = reqwest::Client::new()
+            .get(format!("{base}{}", resolved.url))
+            .header("origin", "http://example.com")
+            .send()
             .await
-            .expect("remote should be tokenized");
-        assert_eq!(remote.source_type, "remote");
-        assert!(remote.url.contains("/media/"));
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        let exposed = resp.headers()["access-control-expose-headers"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let exposed: Vec<&str> = exposed.split(',').map(str::trim).collect();
+        for name in [
+            "content-range",
+            "accept-ranges",
+            "content-length",
+            "x-quality",
+        ] {
+            assert!(exposed.contains(&name), "{name} missing from {exposed:?}");
+        }
     }
 
     #[tokio::test]
-    async fn set_media_root_and_resolve_local() {
-        let root = std::env::temp_dir().join("vo_sync_root_set");
+    async fn reset_clears_playback_but_keeps_members_and_host() {
+        let root = std::env::temp_dir().join("vo_sync_room_reset");
         std::fs::create_dir_all(&root).unwrap();
-        let manager = Manager::new(None, true);
+        let file_path = root.join("movie.mp4");
+        write_mp4(&file_path);
+        let manager = Arc::new(Manager::new(Some(root), false));
+        let hub = Arc::new(Hub::new());
+        let (host, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (member, _) = manager.join_room("room", "pwd").await.unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.register("room", "member", &member, ClientSender::Ws(tx))
+            .await;
+        let resolved = manager
+            .resolve_media_path("room", "pwd", &host, file_path.to_str().unwrap())
+            .await
+            .unwrap();
         manager
-            .set_media_root(root.to_str().unwrap())
+            .update_state(
+                "room",
+                &host,
+                RoomState {
+                    title: "movie".into(),
+                    current_time: 12.0,
+                    duration: 600.0,
+                    paused: false,
+                    updated_at: now_millis(),
+                    ..room_state(&resolved.url)
+                },
+                true,
+            )
             .await
-            .expect("set media root");
-        let file_path = root.join("movie.mp4");
-        let mut file = StdFile::create(&file_path).unwrap();
-        writeln!(file, "dummy").unwrap();
-        let (host, _) = manager.join_room("r", "p").await.unwrap();
-        let res = manager
-            .resolve_media_path("r", "p", &host, file_path.to_str().unwrap())
+            .unwrap();
+        manager
+            .set_playlist(
+                "room",
+                "pwd",
+                &host,
+                vec![PlaylistItem {
+                    path: "BV1xx411c7mD".into(),
+                    title: None,
+                }],
+            )
             .await
             .unwrap();
-        assert_eq!(res.source_type, "file");
+        manager
+            .add_marker("room", &host, 30.0, "intro ends".into())
+            .await
+            .unwrap();
+
+        let ctx = |temp_user: &str| WsContext {
+            room: "room".into(),
+            temp_user: temp_user.into(),
+            client_id: "client".into(),
+            debug: false,
+            acks: false,
+        };
+        let reset = Message::Text(r#"{"type":"reset"}"#.into());
+        let err = handle_ws_message(reset.clone(), &manager, &hub, &ctx(&member))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        handle_ws_message(reset, &manager, &hub, &ctx(&host))
+            .await
+            .unwrap();
+        let Ok(Message::Text(text)) = rx.try_recv() else {
+            panic!("member should receive room_reset");
+        };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["type"], "room_reset");
+
+        assert!(manager.latest_state("room").await.is_none());
+        assert!(!manager
+            .media_tokens
+            .read()
+            .await
+            .contains_key(&resolved.token));
+        let rooms = manager.rooms.read().await;
+        let room = &rooms["room"];
+        assert!(room.playlist.is_empty());
+        assert!(room.markers.is_empty());
+        assert!(room.members.contains_key(&host) && room.members.contains_key(&member));
+        assert!(room.is_host(&host));
+        assert!(!room.is_host(&member));
+    }
+
+    #[test]
+    fn rooms_expire_by_latest_heartbeat() {
+        let ttl = Duration::from_secs(60);
+        let now = Instant::now() + Duration::from_secs(120);
+        let mut room = Room::new("pwd");
+        assert!(!room.is_expired(now, ttl));
+
+        room.members.insert("member".into(), Member::new(None));
+        assert!(room.is_expired(now, ttl));
+        room.members.get_mut("member").unwrap().last_seen = now;
+        assert!(!room.is_expired(now, ttl));
     }
 }
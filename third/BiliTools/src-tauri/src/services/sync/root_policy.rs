@@ -0,0 +1,108 @@
+//! 媒体根目录的设置策略：拒绝文件系统根、用户主目录和系统目录，
+//! 避免一次误操作把整台机器的文件暴露给房间成员。
+
+use std::path::{Path, PathBuf};
+
+use super::ApiError;
+
+/// 这些目录本身及其子目录都不允许作为媒体根。
+const SYSTEM_DIRS: &[&str] = &[
+    "/bin", "/boot", "/dev", "/etc", "/lib", "/lib64", "/proc", "/sbin", "/sys", "/usr", "/System",
+];
+/// Windows 下通过环境变量定位的系统目录。
+const SYSTEM_DIR_VARS: &[&str] = &["SystemRoot", "ProgramFiles", "ProgramFiles(x86)"];
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct RootPolicy {
+    /// 关闭危险目录检查，供明确知道风险的用户使用。
+    pub(super) allow_dangerous: bool,
+    /// 未配置媒体根时允许播放绝对路径，文件所在目录按媒体根的规则检查。
+    pub(super) allow_unrooted: bool,
+    /// 设置后媒体根必须位于该目录之下。
+    pub(super) base: Option<PathBuf>,
+}
+
+impl RootPolicy {
+    /// `root` 应为已规范化的绝对路径。
+    pub(super) fn check(&self, root: &Path) -> Result<(), ApiError> {
+        if let Some(base) = &self.base {
+            if !root.starts_with(base) {
+                return Err(ApiError::forbidden(format!(
+                    "media root must be inside {}",
+                    base.display()
+                )));
+            }
+        }
+        if self.allow_dangerous {
+            return Ok(());
+        }
+        if root.parent().is_none() {
+            return Err(ApiError::forbidden(
+                "media root cannot be the filesystem root, choose a media folder instead",
+            ));
+        }
+        if home_dirs().iter().any(|home| root == home) {
+            return Err(ApiError::forbidden(
+                "media root cannot be the home directory, choose a media folder inside it",
+            ));
+        }
+        if system_dirs().iter().any(|dir| root.starts_with(dir)) {
+            return Err(ApiError::forbidden(
+                "media root cannot be a system directory",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn home_dirs() -> Vec<PathBuf> {
+    ["HOME", "USERPROFILE"]
+        .iter()
+        .filter_map(std::env::var_os)
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn system_dirs() -> Vec<PathBuf> {
+    SYSTEM_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .chain(
+            SYSTEM_DIR_VARS
+                .iter()
+                .filter_map(std::env::var_os)
+                .map(PathBuf::from),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_dangerous_roots_unless_opted_out() {
+        let policy = RootPolicy::default();
+        let err = policy.check(Path::new("/")).unwrap_err();
+        assert!(err.message.contains("filesystem root"));
+        assert!(policy.check(Path::new("/etc")).is_err());
+        assert!(policy.check(Path::new("/usr/share/videos")).is_err());
+        policy.check(Path::new("/srv/media")).unwrap();
+
+        let lax = RootPolicy {
+            allow_dangerous: true,
+            ..Default::default()
+        };
+        lax.check(Path::new("/")).unwrap();
+    }
+
+    #[test]
+    fn enforces_allowed_base() {
+        let policy = RootPolicy {
+            base: Some(PathBuf::from("/srv/media")),
+            ..Default::default()
+        };
+        policy.check(Path::new("/srv/media/movies")).unwrap();
+        assert!(policy.check(Path::new("/srv/other")).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! 远程流的分段缓存：多人同时代理同一个 B 站直链时，相同的 Range 请求
+//! 只回源一次，后续直接用内存中的副本响应。按总字节数限额，LRU 淘汰。
+
+use std::{collections::HashMap, sync::Mutex};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+
+/// 单段上限，超过的请求直接透传，避免一段大 Range 挤掉整个缓存。
+const MAX_SEGMENT_BYTES: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub(super) struct Segment {
+    pub(super) status: StatusCode,
+    pub(super) headers: HeaderMap,
+    pub(super) body: Bytes,
+}
+
+impl Segment {
+    pub(super) fn into_response(self) -> Response {
+        let mut resp = Response::new(Body::from(self.body));
+        *resp.status_mut() = self.status;
+        *resp.headers_mut() = self.headers;
+        resp
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    segment: Segment,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<(String, String), Entry>,
+    total: usize,
+    tick: u64,
+}
+
+#[derive(Debug)]
+pub(super) struct SegmentCache {
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+impl SegmentCache {
+    pub(super) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// 只缓存首尾都明确的 `bytes=a-b` 且长度不超过单段上限的请求。
+    pub(super) fn accepts(&self, range: &str) -> bool {
+        segment_len(range).is_some_and(|len| len <= MAX_SEGMENT_BYTES.min(self.max_bytes as u64))
+    }
+
+    pub(super) fn get(&self, token: &str, range: &str) -> Option<Segment> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner
+            .entries
+            .get_mut(&(token.to_string(), range.to_string()))?;
+        entry.last_used = tick;
+        Some(entry.segment.clone())
+    }
+
+    pub(super) fn insert(&self, token: &str, range: &str, segment: Segment) {
+        let size = segment.body.len();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.tick += 1;
+        let entry = Entry {
+            segment,
+            last_used: inner.tick,
+        };
+        if let Some(old) = inner
+            .entries
+            .insert((token.to_string(), range.to_string()), entry)
+        {
+            inner.total -= old.segment.body.len();
+        }
+        inner.total += size;
+        while inner.total > self.max_bytes {
+            let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.total -= evicted.segment.body.len();
+            }
+        }
+    }
+
+    /// token 失效或直链刷新后丢弃它的所有分段。
+    pub(super) fn remove_token(&self, token: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut freed = 0;
+        inner.entries.retain(|(t, _), entry| {
+            if t != token {
+                return true;
+            }
+            freed += entry.segment.body.len();
+            false
+        });
+        inner.total -= freed;
+    }
+}
+
+fn segment_len(range: &str) -> Option<u64> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end: u64 = end.trim().parse().ok()?;
+    end.checked_sub(start).map(|len| len + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(len: usize) -> Segment {
+        Segment {
+            status: StatusCode::PARTIAL_CONTENT,
+            headers: HeaderMap::new(),
+            body: Bytes::from(vec![0u8; len]),
+        }
+    }
+
+    #[test]
+    fn accepts_only_bounded_ranges() {
+        let cache = SegmentCache::new(1024);
+        assert!(cache.accepts("bytes=0-1023"));
+        assert!(!cache.accepts("bytes=0-1024"));
+        assert!(!cache.accepts("bytes=100-"));
+        assert!(!cache.accepts("bytes=0-1,4-5"));
+        assert!(!cache.accepts("bytes=10-5"));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = SegmentCache::new(100);
+        cache.insert("t", "bytes=0-39", segment(40));
+        cache.insert("t", "bytes=40-79", segment(40));
+        // 访问第一段后，再插入时应淘汰第二段
+        assert!(cache.get("t", "bytes=0-39").is_some());
+        cache.insert("t", "bytes=80-119", segment(40));
+        assert!(cache.get("t", "bytes=0-39").is_some());
+        assert!(cache.get("t", "bytes=40-79").is_none());
+        assert!(cache.get("t", "bytes=80-119").is_some());
+
+        cache.remove_token("t");
+        assert!(cache.get("t", "bytes=0-39").is_none());
+        assert_eq!(cache.inner.lock().unwrap().total, 0);
+    }
+}
@@ -0,0 +1,88 @@
+//! 经 shell 插件的 sidecar 接口启动 ffmpeg，与下载合并走同一套进程管理，
+//! Windows 下不会弹出控制台窗口。
+
+use std::path::Path;
+
+use tauri_plugin_shell::{
+    process::{Command, CommandChild, CommandEvent},
+    ShellExt,
+};
+use tokio::sync::mpsc::Receiver;
+
+use super::ApiError;
+use crate::shared::APP_HANDLE;
+
+/// 应用句柄尚未就绪（如测试环境）时返回 503。
+pub(super) fn command(ffmpeg: &Path) -> Result<Command, ApiError> {
+    let app = APP_HANDLE
+        .get()
+        .ok_or_else(|| ApiError::unavailable("ffmpeg sidecar unavailable"))?;
+    app.shell()
+        .sidecar(ffmpeg)
+        .map(|cmd| cmd.arg("-nostdin"))
+        .map_err(|e| ApiError::unavailable(format!("ffmpeg sidecar unavailable: {e}")))
+}
+
+/// 运行命令并返回 stderr 文本，用于 `ffmpeg -i` 探测，不关心退出状态。
+pub(super) async fn stderr(cmd: Command) -> Result<String, ApiError> {
+    let output = cmd
+        .output()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("ffmpeg probe failed: {e}")))?;
+    Ok(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+/// 以原始字节模式启动，stdout 按读到的块原样转发。
+pub(super) fn spawn(cmd: Command) -> Result<Running, ApiError> {
+    let (events, child) = cmd
+        .set_raw_out(true)
+        .spawn()
+        .map_err(|e| ApiError::bad_request(format!("ffmpeg spawn failed: {e}")))?;
+    Ok(Running {
+        events,
+        child: Some(child),
+        code: None,
+    })
+}
+
+/// 运行中的 ffmpeg；drop 时结束进程，客户端断开即停止转码。
+pub(super) struct Running {
+    events: Receiver<CommandEvent>,
+    child: Option<CommandChild>,
+    code: Option<i32>,
+}
+
+impl Running {
+    /// 下一块 stdout；进程退出且输出全部读完后返回 None。
+    pub(super) async fn next_stdout(&mut self) -> Option<Vec<u8>> {
+        // 退出事件可能早于最后几块输出到达，读到通道关闭为止。
+        while let Some(event) = self.events.recv().await {
+            match event {
+                CommandEvent::Stdout(chunk) => return Some(chunk),
+                CommandEvent::Terminated(payload) => {
+                    self.code = payload.code;
+                    self.child = None;
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// 读完全部 stdout 并等待退出，非零退出码时返回 None。
+    pub(super) async fn collect(mut self) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        while let Some(chunk) = self.next_stdout().await {
+            out.extend(chunk);
+        }
+        (self.code == Some(0)).then_some(out)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.take() {
+            let _ = child.kill();
+        }
+    }
+}
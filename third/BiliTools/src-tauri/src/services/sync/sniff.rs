@@ -0,0 +1,122 @@
+//! 本地文件的格式嗅探：只读文件头几 KB，按魔数判断是否为音视频，
+//! 避免把任意文件签发出去后播放器报出难以理解的错误。
+
+use std::path::Path;
+
+use tokio::{fs::File, io::AsyncReadExt};
+
+use super::ApiError;
+
+const SNIFF_LEN: usize = 4096;
+/// MPEG-TS 包长，连续两个同步字节才认定为 TS。
+const TS_PACKET_LEN: usize = 188;
+
+pub(super) async fn ensure_media_file(path: &Path) -> Result<(), ApiError> {
+    let mut file = File::open(path)
+        .await
+        .map_err(|_| ApiError::bad_request("invalid path"))?;
+    let mut head = vec![0u8; SNIFF_LEN];
+    let mut len = 0;
+    // 单次 read 可能读不满，循环到读满或 EOF。
+    while len < SNIFF_LEN {
+        let n = file
+            .read(&mut head[len..])
+            .await
+            .map_err(|e| ApiError::bad_request(format!("read media failed: {e}")))?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    match detect(&head[..len]) {
+        Some(_) => Ok(()),
+        None => Err(ApiError::bad_request(
+            "not a recognized audio/video file (pass allowAny to override)",
+        )),
+    }
+}
+
+/// 返回识别出的容器名，无法识别时为 None。
+fn detect(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    if at(4, b"ftyp") {
+        return Some("mp4");
+    }
+    if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+        return Some("matroska");
+    }
+    if at(0, b"RIFF") {
+        if at(8, b"AVI ") {
+            return Some("avi");
+        }
+        if at(8, b"WAVE") {
+            return Some("wav");
+        }
+    }
+    if at(0, b"FLV") {
+        return Some("flv");
+    }
+    if at(0, b"OggS") {
+        return Some("ogg");
+    }
+    if at(0, b"fLaC") {
+        return Some("flac");
+    }
+    if at(0, b"ID3") {
+        return Some("mp3");
+    }
+    if at(0, b".RMF") {
+        return Some("realmedia");
+    }
+    if at(0, &[0x30, 0x26, 0xB2, 0x75]) {
+        return Some("asf");
+    }
+    if at(0, &[0x00, 0x00, 0x01, 0xBA]) {
+        return Some("mpeg-ps");
+    }
+    // TS 直接以 0x47 开头；M2TS 每包前有 4 字节时间戳。
+    for start in [0, 4] {
+        let stride = if start == 0 {
+            TS_PACKET_LEN
+        } else {
+            TS_PACKET_LEN + 4
+        };
+        if at(start, &[0x47]) && at(start + stride, &[0x47]) {
+            return Some("mpeg-ts");
+        }
+    }
+    // MP3 / AAC(ADTS) 帧同步字
+    if head.len() >= 2 && head[0] == 0xFF && head[1] & 0xE0 == 0xE0 {
+        return Some("mpeg-audio");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_common_containers() {
+        assert_eq!(
+            detect(b"\x00\x00\x00\x18ftypisom\x00\x00\x02\x00isommp41"),
+            Some("mp4")
+        );
+        assert_eq!(detect(&[0x1A, 0x45, 0xDF, 0xA3, 0x01]), Some("matroska"));
+        assert_eq!(detect(b"RIFF\x24\x00\x00\x00WAVEfmt "), Some("wav"));
+        assert_eq!(detect(b"FLV\x01\x05"), Some("flv"));
+        assert_eq!(detect(&[0xFF, 0xFB, 0x90, 0x00]), Some("mpeg-audio"));
+
+        let mut ts = vec![0u8; TS_PACKET_LEN * 2];
+        ts[0] = 0x47;
+        ts[TS_PACKET_LEN] = 0x47;
+        assert_eq!(detect(&ts), Some("mpeg-ts"));
+    }
+
+    #[test]
+    fn rejects_text_and_empty() {
+        assert_eq!(detect(b"hello world\n"), None);
+        assert_eq!(detect(b"{\"not\": \"media\"}"), None);
+        assert_eq!(detect(b""), None);
+    }
+}
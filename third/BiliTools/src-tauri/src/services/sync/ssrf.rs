@@ -0,0 +1,121 @@
+//! 通用远程源的 SSRF 防护：拒绝解析到回环、内网、链路本地等地址的 URL。
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use tauri_plugin_http::reqwest::Url;
+use tokio::net::lookup_host;
+
+use super::ApiError;
+
+/// B 站 CDN / 静态资源域名，直接放行，不做 DNS 检查。
+const BILIBILI_HOSTS: &[&str] = &["bilivideo.com", "bilivideo.cn", "hdslb.com", "bilibili.com"];
+
+/// 在 resolve 和代理/重定向前各调用一次，避免 DNS rebinding 绕过。
+pub(super) async fn ensure_public_url(raw: &str) -> Result<(), ApiError> {
+    let url = Url::parse(raw).map_err(|_| ApiError::bad_request("invalid remote url"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ApiError::bad_request("unsupported url scheme"));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| ApiError::bad_request("remote url has no host"))?;
+    if is_bilibili_host(host) {
+        return Ok(());
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    // Url 会给 IPv6 字面量加方括号，lookup_host 需要去掉。
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = lookup_host((host, port))
+        .await
+        .map_err(|e| ApiError::bad_request(format!("remote host lookup failed: {e}")))?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(ApiError::bad_request("remote host has no address"));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+        return Err(ApiError::forbidden(format!(
+            "remote address {} is not allowed",
+            addr.ip()
+        )));
+    }
+    Ok(())
+}
+
+fn is_bilibili_host(host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    BILIBILI_HOSTS
+        .iter()
+        .any(|d| host == *d || host.ends_with(&format!(".{d}")))
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_public_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(v6),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // 100.64.0.0/10 运营商级 NAT
+        || (a == 100 && (64..128).contains(&b))
+        || a == 0)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 唯一本地地址
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 链路本地地址
+        || (first & 0xffc0) == 0xfe80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_loopback_and_link_local() {
+        for url in [
+            "http://127.0.0.1:8080/video.mp4",
+            "http://localhost/video.mp4",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.8/a.mp4",
+            "http://[::1]:18080/media",
+        ] {
+            assert!(
+                ensure_public_url(url).await.is_err(),
+                "{url} should be rejected"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn allows_public_and_bilibili_hosts() {
+        ensure_public_url("http://1.1.1.1/video.mp4").await.unwrap();
+        ensure_public_url("https://upos-sz-mirrorcos.bilivideo.com/upgcxcode/a.mp4")
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn classifies_addresses() {
+        assert!(!is_public_ip("100.64.1.1".parse().unwrap()));
+        assert!(!is_public_ip("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!is_public_ip("fd00::1".parse().unwrap()));
+        assert!(is_public_ip("8.8.8.8".parse().unwrap()));
+        assert!(is_public_ip("2001:4860:4860::8888".parse().unwrap()));
+    }
+}
@@ -0,0 +1,154 @@
+//! 本地媒体的封面生成：用 ffmpeg 截取约 10% 处的一帧并缩放为 JPEG，
+//! 按路径与修改时间缓存，同一文件反复 resolve 不会重复截图。
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use axum::body::Bytes;
+use log::warn;
+use regex::Regex;
+
+use super::sidecar;
+
+/// 截图位置占总时长的比例，避开片头黑屏。
+const SEEK_RATIO: f64 = 0.1;
+/// 缩略图宽度，高度按比例取偶数。
+const THUMB_WIDTH: u32 = 480;
+/// 缓存的缩略图数量上限，超出时淘汰最早生成的。
+const MAX_CACHED: usize = 256;
+
+static DURATION_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"Duration: (\d+):(\d{2}):(\d{2}(?:\.\d+)?)").unwrap());
+
+#[derive(Debug)]
+enum Generator {
+    Ffmpeg(PathBuf),
+    /// 测试中不依赖 ffmpeg，直接返回固定内容。
+    #[cfg(test)]
+    Fixed(Bytes),
+}
+
+type CacheKey = (PathBuf, SystemTime);
+
+#[derive(Debug, Default)]
+struct Cache {
+    entries: HashMap<CacheKey, Bytes>,
+    order: VecDeque<CacheKey>,
+}
+
+#[derive(Debug)]
+pub(super) struct Thumbnailer {
+    generator: Generator,
+    cache: Mutex<Cache>,
+    #[cfg(test)]
+    generated: std::sync::atomic::AtomicUsize,
+}
+
+impl Thumbnailer {
+    pub(super) fn ffmpeg(ffmpeg: PathBuf) -> Self {
+        Self::new(Generator::Ffmpeg(ffmpeg))
+    }
+
+    #[cfg(test)]
+    pub(super) fn fixed(jpeg: &'static [u8]) -> Self {
+        Self::new(Generator::Fixed(Bytes::from_static(jpeg)))
+    }
+
+    fn new(generator: Generator) -> Self {
+        Self {
+            generator,
+            cache: Mutex::new(Cache::default()),
+            #[cfg(test)]
+            generated: Default::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) fn generated(&self) -> usize {
+        self.generated.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// 生成失败（无视频流、ffmpeg 出错等）时返回 None，不影响 resolve。
+    pub(super) async fn thumbnail(&self, path: &Path) -> Option<Bytes> {
+        let mtime = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        let key = (path.to_path_buf(), mtime);
+        if let Some(jpeg) = self.lock().entries.get(&key) {
+            return Some(jpeg.clone());
+        }
+        let jpeg = match &self.generator {
+            Generator::Ffmpeg(ffmpeg) => extract_frame(ffmpeg, path).await?,
+            #[cfg(test)]
+            Generator::Fixed(jpeg) => jpeg.clone(),
+        };
+        #[cfg(test)]
+        self.generated
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        let mut cache = self.lock();
+        if cache.entries.insert(key.clone(), jpeg.clone()).is_none() {
+            cache.order.push_back(key);
+        }
+        while cache.order.len() > MAX_CACHED {
+            if let Some(oldest) = cache.order.pop_front() {
+                cache.entries.remove(&oldest);
+            }
+        }
+        Some(jpeg)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 从 `ffmpeg -i` 的 stderr 中取总时长（秒）。
+fn parse_duration(stderr: &str) -> Option<f64> {
+    let caps = DURATION_RE.captures(stderr)?;
+    let hours: f64 = caps[1].parse().ok()?;
+    let minutes: f64 = caps[2].parse().ok()?;
+    let seconds: f64 = caps[3].parse().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+async fn extract_frame(ffmpeg: &Path, path: &Path) -> Option<Bytes> {
+    let probe = sidecar::command(ffmpeg)
+        .ok()?
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(path);
+    let seek = parse_duration(&sidecar::stderr(probe).await.ok()?).unwrap_or(0.0) * SEEK_RATIO;
+    let cmd = sidecar::command(ffmpeg)
+        .ok()?
+        .args(["-hide_banner", "-loglevel", "error", "-ss"])
+        .arg(format!("{seek:.3}"))
+        .arg("-i")
+        .arg(path)
+        .args(["-frames:v", "1", "-vf"])
+        .arg(format!("scale={THUMB_WIDTH}:-2"))
+        .args(["-f", "image2", "-c:v", "mjpeg", "pipe:1"]);
+    let jpeg = match sidecar::spawn(cmd).ok()?.collect().await {
+        Some(jpeg) if !jpeg.is_empty() => jpeg,
+        _ => {
+            warn!("thumbnail generation failed for {}", path.display());
+            return None;
+        }
+    };
+    Some(Bytes::from(jpeg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_duration_from_probe_output() {
+        let stderr = "Input #0, matroska,webm, from 'movie.mkv':\n  \
+                      Duration: 01:42:10.05, start: 0.000000, bitrate: 8120 kb/s";
+        assert_eq!(parse_duration(stderr), Some(6130.05));
+        assert_eq!(parse_duration("Duration: N/A, bitrate: N/A"), None);
+    }
+}
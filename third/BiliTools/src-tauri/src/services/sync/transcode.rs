@@ -0,0 +1,152 @@
+//! 本地媒体的按需转码：浏览器无法解码的编码（HEVC、AC3 等）经 ffmpeg
+//! 转为 H.264/AAC 分片 MP4，直接把 stdout 作为响应体流式返回。
+
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use axum::body::{Body, Bytes};
+use futures_util::stream;
+use regex::Regex;
+
+use super::{sidecar, ApiError};
+
+/// 主流浏览器 `<video>` 可直接解码的编码，命中时只做 remux。
+const BROWSER_VIDEO: &[&str] = &["h264", "vp8", "vp9", "av1"];
+const BROWSER_AUDIO: &[&str] = &["aac", "mp3", "opus", "vorbis", "flac"];
+
+static STREAM_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"Stream #\d+:\d+\S*: (Video|Audio): (\w+)").unwrap());
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct Codecs {
+    video: Option<String>,
+    audio: Option<String>,
+}
+
+impl Codecs {
+    pub(super) fn needs_transcode(&self) -> bool {
+        !video_supported(self.video.as_deref()) || !audio_supported(self.audio.as_deref())
+    }
+
+    fn args(&self) -> [&'static str; 4] {
+        let video = if video_supported(self.video.as_deref()) {
+            "copy"
+        } else {
+            "libx264"
+        };
+        let audio = if audio_supported(self.audio.as_deref()) {
+            "copy"
+        } else {
+            "aac"
+        };
+        ["-c:v", video, "-c:a", audio]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct TranscodeTarget {
+    pub(super) path: PathBuf,
+    pub(super) codecs: Codecs,
+}
+
+fn video_supported(codec: Option<&str>) -> bool {
+    codec.map_or(true, |c| BROWSER_VIDEO.contains(&c))
+}
+
+fn audio_supported(codec: Option<&str>) -> bool {
+    codec.map_or(true, |c| BROWSER_AUDIO.contains(&c))
+}
+
+/// 从 `ffmpeg -i` 的 stderr 中取第一条视频流和音频流的编码名。
+pub(super) fn parse_codecs(stderr: &str) -> Codecs {
+    let mut codecs = Codecs::default();
+    for caps in STREAM_RE.captures_iter(stderr) {
+        let slot = match &caps[1] {
+            "Video" => &mut codecs.video,
+            _ => &mut codecs.audio,
+        };
+        if slot.is_none() {
+            *slot = Some(caps[2].to_string());
+        }
+    }
+    codecs
+}
+
+pub(super) async fn probe(ffmpeg: &Path, path: &Path) -> Result<Codecs, ApiError> {
+    // 不指定输出时 ffmpeg 以非零状态退出，但流信息已写入 stderr。
+    let cmd = sidecar::command(ffmpeg)?
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(path);
+    let codecs = parse_codecs(&sidecar::stderr(cmd).await?);
+    if codecs == Codecs::default() {
+        return Err(ApiError::bad_request("no media stream found"));
+    }
+    Ok(codecs)
+}
+
+/// 启动 ffmpeg 并把输出作为响应体；客户端断开时 body 被丢弃，进程随之结束。
+/// `slot` 与进程同生命周期，用于限制同一 token 的并发转码数。
+pub(super) fn stream<S: Send + 'static>(
+    ffmpeg: &Path,
+    target: &TranscodeTarget,
+    slot: S,
+) -> Result<Body, ApiError> {
+    let cmd = sidecar::command(ffmpeg)?
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(&target.path)
+        .args(target.codecs.args())
+        .args([
+            "-preset",
+            "veryfast",
+            "-movflags",
+            "frag_keyframe+empty_moov+default_base_moof",
+            "-f",
+            "mp4",
+            "pipe:1",
+        ]);
+    let running = sidecar::spawn(cmd)?;
+    let chunks = stream::unfold((running, slot), |(mut running, slot)| async move {
+        let chunk = running.next_stdout().await?;
+        Some((Ok::<_, Infallible>(Bytes::from(chunk)), (running, slot)))
+    });
+    Ok(Body::from_stream(chunks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEVC_AC3: &str = "\
+Input #0, matroska,webm, from 'movie.mkv':
+  Duration: 01:42:10.05, start: 0.000000, bitrate: 8120 kb/s
+  Stream #0:0(eng): Video: hevc (Main 10), yuv420p10le(tv), 3840x2160, SAR 1:1 DAR 16:9, 23.98 fps
+  Stream #0:1(eng): Audio: ac3, 48000 Hz, 5.1(side), fltp, 640 kb/s (default)
+  Stream #0:2(chi): Audio: aac (LC), 48000 Hz, stereo, fltp
+At least one output file must be specified";
+
+    #[test]
+    fn parses_first_video_and_audio_codec() {
+        let codecs = parse_codecs(HEVC_AC3);
+        assert_eq!(codecs.video.as_deref(), Some("hevc"));
+        assert_eq!(codecs.audio.as_deref(), Some("ac3"));
+        assert!(codecs.needs_transcode());
+        assert_eq!(codecs.args(), ["-c:v", "libx264", "-c:a", "aac"]);
+    }
+
+    #[test]
+    fn browser_friendly_sources_skip_transcoding() {
+        let codecs = parse_codecs(
+            "  Stream #0:0[0x1](und): Video: h264 (High) (avc1 / 0x31637661), yuv420p\n  \
+             Stream #0:1[0x2](und): Audio: aac (LC) (mp4a / 0x6134706D), 44100 Hz",
+        );
+        assert_eq!(codecs.video.as_deref(), Some("h264"));
+        assert!(!codecs.needs_transcode());
+
+        let audio_only = parse_codecs("  Stream #0:0: Audio: flac, 44100 Hz, stereo");
+        assert!(!audio_only.needs_transcode());
+    }
+}
@@ -0,0 +1,85 @@
+//! 房间事件的出站 webhook：房间创建、关闭以及播放源切换时向配置的地址 POST JSON，
+//! 供 Discord 机器人、家庭自动化等外部系统订阅。发送在后台进行，不阻塞广播。
+
+use std::time::Duration;
+
+use log::warn;
+use serde::Serialize;
+use tauri_plugin_http::reqwest;
+
+use super::{now_millis, RoomState, SourceType};
+
+/// 单次投递的超时，接收方慢或不可达时尽快放弃。
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 投递内容只含房间名和播放信息，不含口令与成员身份。
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct WebhookEvent {
+    event: &'static str,
+    room: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_type: Option<SourceType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    timestamp: i64,
+}
+
+impl WebhookEvent {
+    pub(super) fn new(event: &'static str, room: &str) -> Self {
+        Self {
+            event,
+            room: room.to_string(),
+            title: None,
+            source_type: None,
+            duration: None,
+            timestamp: now_millis(),
+        }
+    }
+
+    /// 附带当前播放信息；url 中的 token 可直接访问媒体，不对外发送。
+    pub(super) fn with_state(mut self, state: &RoomState) -> Self {
+        self.title = Some(state.title.clone());
+        self.source_type = Some(state.source_type.clone());
+        self.duration = Some(state.duration);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Webhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub(super) fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// 后台发送，失败只记日志，不重试。
+    pub(super) fn send(&self, event: WebhookEvent) {
+        let request = self
+            .client
+            .post(&self.url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(&event);
+        tokio::spawn(async move {
+            let result = request
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            if let Err(err) = result {
+                warn!(
+                    "webhook event={} room={} failed: {}",
+                    event.event, event.room, err
+                );
+            }
+        });
+    }
+}
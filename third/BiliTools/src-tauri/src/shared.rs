@@ -7,13 +7,14 @@ use std::{
     collections::BTreeMap,
     path::PathBuf,
     sync::{Arc, LazyLock},
+    time::Duration,
 };
 use tauri::{
     http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
     utils::WindowEffect as TauriWindowEffect,
     AppHandle, Manager, Theme as TauriTheme, Wry,
 };
-use tauri_plugin_http::reqwest::{Client, Proxy};
+use tauri_plugin_http::reqwest::{Client, ClientBuilder, Proxy};
 use tauri_specta::Event;
 use time::OffsetDateTime;
 use tokio::sync::{OnceCell, RwLock};
@@ -281,15 +282,26 @@ pub async fn init_client_no_proxy() -> Result<Client> {
 }
 
 pub async fn init_client_inner(use_proxy: bool) -> Result<Client> {
+    Ok(client_builder(use_proxy).await?.build()?)
+}
+
+/// Same as `init_client`, but gives up when establishing a connection takes longer than `timeout`.
+pub async fn init_client_with_connect_timeout(timeout: Duration) -> Result<Client> {
+    Ok(client_builder(true)
+        .await?
+        .connect_timeout(timeout)
+        .build()?)
+}
+
+async fn client_builder(use_proxy: bool) -> Result<ClientBuilder> {
     let proxy = &config::read().proxy;
     let client_builder = Client::builder().default_headers(HEADERS.to_header_map().await?);
-    let client_builder = if !proxy.address.is_empty() && use_proxy {
+    Ok(if !proxy.address.is_empty() && use_proxy {
         client_builder
             .proxy(Proxy::all(&proxy.address)?.basic_auth(&proxy.username, &proxy.password))
     } else {
         client_builder.no_proxy()
-    };
-    Ok(client_builder.build()?)
+    })
 }
 
 pub fn get_app_handle() -> &'static AppHandle<Wry> {